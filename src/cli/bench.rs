@@ -0,0 +1,286 @@
+use crate::cli::commands::{handle_import_csv, CsvImportOptions};
+use crate::cli::output::Output;
+use crate::cli::seed::{self, Columns, Rng};
+use crate::core::database::CommitStorage;
+use crate::core::merge::{load_state, merge_states};
+use crate::core::pool::CommitStoragePool;
+use crate::core::query::QueryProcessor;
+use crate::error::{BranchDBError, Result};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const TABLE: &str = "bench";
+const REPEATS: usize = 5;
+const CONCURRENT_READERS: usize = 10;
+
+pub struct BenchOptions {
+    pub rows: usize,
+    pub commits: usize,
+    pub seed: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchResult {
+    pub operation: String,
+    // What one unit of throughput is (commits, rows, reads, merges)
+    pub unit: &'static str,
+    pub units: usize,
+    pub total_secs: f64,
+    pub per_sec: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl BenchResult {
+    // `latencies` holds one sample per iteration; `units` is the total work done across them.
+    fn new(operation: impl Into<String>, unit: &'static str, units: usize, mut latencies: Vec<f64>) -> Self {
+        latencies.sort_by(|a, b| a.total_cmp(b));
+        let total: f64 = latencies.iter().sum();
+        let percentile = |q: f64| {
+            if latencies.is_empty() {
+                return 0.0;
+            }
+            latencies[((latencies.len() - 1) as f64 * q).round() as usize] * 1000.0
+        };
+        Self {
+            operation: operation.into(),
+            unit,
+            units,
+            total_secs: total,
+            per_sec: if total > 0.0 { units as f64 / total } else { 0.0 },
+            mean_ms: if latencies.is_empty() { 0.0 } else { total * 1000.0 / latencies.len() as f64 },
+            p50_ms: percentile(0.5),
+            p99_ms: percentile(0.99),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub seed: u64,
+    pub rows: usize,
+    pub commits: usize,
+    pub results: Vec<BenchResult>,
+}
+
+impl BenchReport {
+    pub fn to_lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("seed={} rows={} commits={}", self.seed, self.rows, self.commits),
+            format!(
+                "{:<32} {:>10} {:>10} {:>12} {:>10} {:>10} {:>10}",
+                "operation", "units", "total(s)", "per sec", "mean(ms)", "p50(ms)", "p99(ms)"
+            ),
+        ];
+        for r in &self.results {
+            lines.push(format!(
+                "{:<32} {:>10} {:>10.3} {:>12.1} {:>10.3} {:>10.3} {:>10.3}",
+                r.operation,
+                format!("{} {}", r.units, r.unit),
+                r.total_secs,
+                r.per_sec,
+                r.mean_ms,
+                r.p50_ms,
+                r.p99_ms
+            ));
+        }
+        lines
+    }
+}
+
+// Scratch directory removed on drop, so an aborted run doesn't leave a repository behind.
+struct TempRepo(PathBuf);
+
+impl TempRepo {
+    fn create() -> Result<Self> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let path = std::env::temp_dir().join(format!("branchdb-bench-{}-{}", std::process::id(), nanos));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempRepo {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// The columns of the bench table, as `seed` would be given them.
+fn columns() -> Columns {
+    [("id", "INT"), ("name", "TEXT"), ("score", "INT")].into_iter()
+        .map(|(name, kind)| (name.to_string(), kind.to_string()))
+        .collect()
+}
+
+// Reader threads scan the table at HEAD through a pool while one writer commits `commits` rows.
+// Each reader checks that it never sees fewer rows than before the writer started.
+fn concurrent_reads(storage: CommitStorage, rng: &mut Rng, first_id: usize, commits: usize) -> Result<Vec<BenchResult>> {
+    let pool = CommitStoragePool::new(storage);
+    let baseline = {
+        let storage = pool.acquire()?;
+        let head = storage.get_head()?.ok_or_else(|| BranchDBError::InvalidInput("HEAD not found".into()))?;
+        QueryProcessor::new(&storage.db).get_table_at_commit(TABLE, &head)?.len()
+    };
+    let columns = columns();
+    let changes = (0..commits).map(|i| seed::row_change(rng, TABLE, &columns, first_id + i)).collect::<Result<Vec<_>>>()?;
+
+    std::thread::scope(|scope| {
+        let writer = {
+            let storage = pool.acquire()?;
+            scope.spawn(move || -> Result<Vec<f64>> {
+                let mut latencies = Vec::with_capacity(changes.len());
+                for change in changes {
+                    let started = Instant::now();
+                    storage.commit("bench: concurrent write", vec![change])?;
+                    latencies.push(started.elapsed().as_secs_f64());
+                }
+                Ok(latencies)
+            })
+        };
+        let readers = (0..CONCURRENT_READERS)
+            .map(|_| {
+                let storage = pool.acquire()?;
+                Ok(scope.spawn(move || -> Result<Vec<f64>> {
+                    let mut latencies = Vec::with_capacity(REPEATS);
+                    for _ in 0..REPEATS {
+                        let started = Instant::now();
+                        let head = storage.get_head()?
+                            .ok_or_else(|| BranchDBError::InvalidInput("HEAD not found".into()))?;
+                        let rows = QueryProcessor::new(&storage.db).get_table_at_commit(TABLE, &head)?.len();
+                        latencies.push(started.elapsed().as_secs_f64());
+                        if rows < baseline {
+                            return Err(BranchDBError::CorruptData(
+                                format!("concurrent read saw {} rows, expected at least {}", rows, baseline)
+                            ));
+                        }
+                    }
+                    Ok(latencies)
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let join = |handle: std::thread::ScopedJoinHandle<'_, Result<Vec<f64>>>| {
+            handle.join().map_err(|_| BranchDBError::InvalidInput("benchmark thread panicked".into()))?
+        };
+        let mut read_latencies = Vec::new();
+        for reader in readers {
+            read_latencies.extend(join(reader)?);
+        }
+        let write_latencies = join(writer)?;
+        Ok(vec![
+            BenchResult::new(
+                format!("read during writes ({} threads)", CONCURRENT_READERS),
+                "reads", read_latencies.len(), read_latencies,
+            ),
+            BenchResult::new("commit during reads", "commits", commits, write_latencies),
+        ])
+    })
+}
+
+// Runs every benchmark against a fresh repository in the system temp directory.
+pub fn run(options: &BenchOptions) -> Result<BenchReport> {
+    let repo = TempRepo::create()?;
+    let storage = CommitStorage::open(&repo.0.join("data").to_string_lossy())?;
+    let mut rng = Rng(options.seed);
+    let mut results = Vec::new();
+    let mut next_id = 0;
+
+    // Each entry is the commit at depth `index + 1` on the first-parent chain
+    let columns = columns();
+    let mut chain = vec![storage.create_commit("bench: schema", vec![seed::schema_change(TABLE, &columns)?])?];
+
+    let mut latencies = Vec::with_capacity(options.commits);
+    for _ in 0..options.commits {
+        let change = seed::row_change(&mut rng, TABLE, &columns, next_id)?;
+        next_id += 1;
+        let started = Instant::now();
+        chain.push(storage.create_commit("bench: single row", vec![change])?);
+        latencies.push(started.elapsed().as_secs_f64());
+    }
+    results.push(BenchResult::new("single-row commit", "commits", options.commits, latencies));
+
+    let batches = (options.commits / 10).max(1);
+    let mut latencies = Vec::with_capacity(batches);
+    for _ in 0..batches {
+        let mut changes = Vec::with_capacity(options.rows);
+        for _ in 0..options.rows {
+            changes.push(seed::row_change(&mut rng, TABLE, &columns, next_id)?);
+            next_id += 1;
+        }
+        let started = Instant::now();
+        chain.push(storage.create_commit("bench: batch", changes)?);
+        latencies.push(started.elapsed().as_secs_f64());
+    }
+    results.push(BenchResult::new(
+        format!("batched commit ({} rows)", options.rows), "rows", batches * options.rows, latencies,
+    ));
+
+    let processor = QueryProcessor::new(&storage.db);
+    let mut depth = 1;
+    loop {
+        let depth_used = depth.min(chain.len());
+        let hash = chain[depth_used - 1];
+        let mut latencies = Vec::with_capacity(REPEATS);
+        for _ in 0..REPEATS {
+            let started = Instant::now();
+            processor.get_table_at_commit(TABLE, &hash)?;
+            latencies.push(started.elapsed().as_secs_f64());
+        }
+        results.push(BenchResult::new(format!("get_table_at_commit depth {}", depth_used), "reads", REPEATS, latencies));
+        if depth_used == chain.len() {
+            break;
+        }
+        depth *= 10;
+    }
+
+    // Diverge from HEAD on two sides that overwrite some of the same rows, then merge them
+    let mut latencies = Vec::with_capacity(REPEATS);
+    for _ in 0..REPEATS {
+        let base = storage.get_head()?.ok_or_else(|| BranchDBError::InvalidInput("HEAD not found".into()))?;
+        let side = |rng: &mut Rng| -> Result<[u8; 32]> {
+            storage.move_head(&base)?;
+            let mut hash = base;
+            for _ in 0..10 {
+                let id = rng.below(next_id as u64) as usize;
+                hash = storage.create_commit("bench: diverge", vec![seed::row_change(rng, TABLE, &columns, id)?])?;
+            }
+            Ok(hash)
+        };
+        let theirs = side(&mut rng)?;
+        let ours = side(&mut rng)?;
+
+        let started = Instant::now();
+        let mut engine = load_state(&storage, ours.to_vec())?;
+        let changes = merge_states(&mut engine, &load_state(&storage, theirs.to_vec())?)?;
+        storage.create_merge_commit("bench: merge", changes, &theirs)?;
+        latencies.push(started.elapsed().as_secs_f64());
+    }
+    results.push(BenchResult::new("merge of diverged branches", "merges", REPEATS, latencies));
+
+    let csv_path = repo.0.join("bench.csv");
+    let mut writer = csv::Writer::from_path(&csv_path)?;
+    writer.write_record(["id", "name", "score"])?;
+    for i in 0..options.rows {
+        writer.write_record([(next_id + i).to_string(), rng.word(), rng.below(1_000_000).to_string()])?;
+    }
+    writer.flush()?;
+    // The import reports through a JSON-mode Output that is never finished, so it stays quiet
+    for (name, bulk) in [("csv import", false), ("csv import (bulk)", true)] {
+        let started = Instant::now();
+        let import = CsvImportOptions { bulk, ..CsvImportOptions::default() };
+        handle_import_csv(&storage, &csv_path.to_string_lossy(), TABLE, &import, &mut Output::new(true))?;
+        results.push(BenchResult::new(name, "rows", options.rows, vec![started.elapsed().as_secs_f64()]));
+    }
+
+    results.extend(concurrent_reads(storage, &mut rng, next_id + options.rows, options.commits)?);
+
+    Ok(BenchReport {
+        seed: options.seed,
+        rows: options.rows,
+        commits: options.commits,
+        results,
+    })
+}
@@ -1,20 +1,88 @@
-use clap::{Parser, Subcommand};
-use crate::core::database::CommitStorage;
-use crate::core::branch::BranchManager;
-use crate::core::merge::merge_states;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use crate::core::batch::BatchWriter;
+use crate::core::database::{
+    decode_row_key, encode_row_key, get_commit_raw, table_key_prefix, CoercionPolicy, CommitStorage, DiffOptions, StorageOptions,
+};
+use crate::core::branch::{BranchManager, DivergenceCounter, ProtectionPolicy};
+use crate::core::conflict::MergeConflicts;
+use crate::core::audit;
+use crate::core::config::{
+    Config, AUDIT_KEY, CONFIG_FILE, ENFORCE_FK_KEY, INCREMENTAL_VIEWS_KEY, INITIAL_BRANCH_KEY, KEY_FILE_KEY, LOCK_TIMEOUT_KEY,
+};
+use crate::core::encryption::{self, ValueCipher};
+use crate::core::lock::RepoLock;
+use crate::core::namespace::{self, NamespacedDb};
+use crate::core::format;
+use crate::core::history::HistoryWalk;
+use crate::core::import::{SchemaInferencer, TypedRowBuilder, INFER_SAMPLE_ROWS};
+use crate::core::json_patch::JsonPatch;
+use crate::core::merge::{MergeResult, RebaseResult};
 use crate::core::query::QueryProcessor;
+use crate::core::{refs, row, sql, trash, ttl};
+use crate::core::sql_executor::{SqlExecutor, SqlOutcome};
+use crate::core::table_stats::Bucket;
+use crate::core::verify::DataFix;
+use crate::cli::bench::{self, BenchOptions};
+use crate::cli::completions;
+use crate::cli::confirm::{confirm, rows_summary};
+use crate::cli::copy::{self, CopyFormat};
+use crate::cli::fmt::{self, MarkdownFormatter, RowProjector, TableFormatter};
+use crate::cli::editor::{self, Draft};
+use crate::cli::diff::{decode_change_value, decode_row, ColorChoice, DiffFormat, DiffRenderer, DiffStat};
+use crate::cli::graph::GraphRenderer;
+use crate::cli::http::HttpSource;
+use crate::cli::logging::LogFormat;
+use crate::cli::outfile::{self, Outfile};
+use crate::cli::output::{change_json, commit_json, crdt_json, Output};
+use crate::cli::patch;
+use crate::cli::seed::{self, SeedOptions};
+use crate::cli::suggest;
+use crate::cli::watch::{self, WatchOptions};
 use crate::error::{BranchDBError, Result};
+use crate::metrics::{self, Counter};
+use regex::Regex;
 use rocksdb::DB;
 use hex;
 use csv;
 use crate::core::models::Change;
-use crate::core::crdt::{CrdtEngine, CrdtValue};
+use crate::core::crdt::CrdtValue;
 use std::path::Path;
 use std::fs;
-use std::collections::HashSet;
+use std::io::IsTerminal;
+use std::cell::OnceCell;
+use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::warn;
+
+// Where commands look for the repository, relative to the working directory.
+pub const DATA_DIR: &str = "data";
+// Where `rekey` reads the new key from without --new-key-file.
+const NEW_KEY_ENV: &str = "BRANCHDB_NEW_KEY";
+// `rekey` reports progress after every this many values
+const REKEY_PROGRESS_EVERY: usize = 10_000;
 
 #[derive(Parser)]
 pub struct CommandsWrapper {
+    #[arg(short = 'v', action = ArgAction::Count, help = "Increase diagnostic output (-v info, -vv debug, -vvv trace)")]
+    pub verbosity: u8,
+    #[arg(short, long, conflicts_with = "verbosity", help = "Only report errors")]
+    pub quiet: bool,
+    #[arg(long, value_enum, default_value = "text", help = "Format of diagnostics written to stderr")]
+    pub log_format: LogFormat,
+    #[arg(long, help = "Print each command's result as a single JSON document")]
+    pub json: bool,
+    #[arg(short, long, help = "Skip confirmation prompts for destructive commands")]
+    pub yes: bool,
+    #[arg(long, help = "Never send output through a pager")]
+    pub no_pager: bool,
+    #[arg(long, help = "Free-form note recorded on the commits this command makes, such as a ticket")]
+    pub source: Option<String>,
+    #[arg(long, help = "Work in this namespace of the repository, made with create-namespace")]
+    pub namespace: Option<String>,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -22,28 +90,97 @@ pub struct CommandsWrapper {
 #[derive(Subcommand)]
 pub enum Commands {
     Init {
-        #[arg(help = "Path to initialize repository")]
-        path: String,
+        #[arg(help = "Directory to initialize; the repository goes in its data/ subdirectory (defaults to the current directory)")]
+        path: Option<String>,
+        #[arg(long, help = "Create a repository meant only as a push/pull target")]
+        bare: bool,
+        #[arg(short, long, help = "Initialize even if the directory is not empty")]
+        force: bool,
+        #[arg(short = 'b', long, help = "Branch created at the first commit")]
+        initial_branch: Option<String>,
+        #[arg(long, help = "Encrypt stored values with the key in BRANCHDB_KEY or --key-file")]
+        encrypted: bool,
+        #[arg(long, requires = "encrypted", help = "File holding the key, recorded as core.key-file")]
+        key_file: Option<String>,
     },
 
     Commit {
-        #[arg(help = "Message to attach to the commit")]
-        message: String,
+        #[arg(help = "Message to attach to the commit; $EDITOR opens when there is none")]
+        message: Option<String>,
+        #[arg(short = 'm', long = "message", value_name = "MESSAGE", conflicts_with = "message", help = "Message to attach to the commit")]
+        message_flag: Option<String>,
+        #[arg(long, help = "File the message starts from in the editor (default: commit.template)")]
+        template: Option<String>,
     },
     Branch {
-        #[arg(help = "Name of the branch to create or delete")]
-        name: String,
+        #[arg(required_unless_present = "prune_merged", help = "Name of the branch to create or delete")]
+        name: Option<String>,
 
         #[arg(short, long, help = "Delete the specified branch")]
         delete: bool,
+
+        #[arg(long, conflicts_with = "delete", help = "Protect the new branch against deletion and force updates")]
+        protected: bool,
+
+        #[arg(long, conflicts_with_all = ["name", "delete", "protected"], help = "Delete every unprotected branch already merged into HEAD")]
+        prune_merged: bool,
+
+        #[arg(long, requires = "prune_merged", help = "With --prune-merged, only list the branches that would be deleted")]
+        dry_run: bool,
+    },
+    // Delete every unprotected branch already merged into HEAD
+    Prune {
+        #[arg(long, help = "Only list the branches that would be deleted")]
+        dry_run: bool,
+    },
+    // Delete the rows at HEAD that have outlived their table's TTL, in one commit
+    Expire {
+        #[arg(long, help = "Only expire rows of this table")]
+        table: Option<String>,
+        #[arg(long, help = "Only list the rows that would be deleted")]
+        dry_run: bool,
+    },
+    // Put a row deleted from a soft delete table back, in a new commit
+    RestoreRow {
+        #[arg(help = "Table the row was deleted from")]
+        table: String,
+        #[arg(help = "Row id")]
+        id: String,
+    },
+    // Delete trashed rows for good, in one commit
+    EmptyTrash {
+        #[arg(long, help = "Only empty the trash of this table")]
+        table: Option<String>,
+        #[arg(long, help = "Only rows deleted at least this long ago, such as 30d or 12h")]
+        older_than: Option<String>,
+        #[arg(long, help = "Only list the rows that would be deleted")]
+        dry_run: bool,
+    },
+    UnprotectBranch {
+        #[arg(help = "Name of the protected branch")]
+        name: String,
     },
     Query {
-        #[arg(help = "SQL query: SELECT * FROM <table> WITH <commit_hash>")]
+        #[arg(help = "SQL statement; writes are committed like `sql`, reads accept WITH <commit_hash>")]
         sql: String,
+        #[arg(long, value_enum, default_value = "table", help = "Output format")]
+        format: OutputFormat,
+        #[arg(long, help = "Fail the query when a CAST can't convert a value instead of giving NULL")]
+        strict_cast: bool,
+        #[arg(long, help = "Cut markdown cells longer than this many characters")]
+        max_col_width: Option<usize>,
     },
     Sql {
-        #[arg(help = "SQL command to execute (CREATE TABLE/INSERT INTO)")]
-        command: String,
+        #[arg(required_unless_present = "file", help = "SQL command to execute (CREATE TABLE/INSERT INTO/SELECT, or COPY <table> FROM stdin)")]
+        command: Option<String>,
+        #[arg(long, conflicts_with = "command", help = "Run the statements in this file, separated by semicolons, one after another")]
+        file: Option<String>,
+        #[arg(long, value_enum, default_value = "table", help = "Output format for SELECT")]
+        format: OutputFormat,
+        #[arg(long, help = "Convert values to their column types instead of rejecting mismatches")]
+        coerce: bool,
+        #[arg(long, help = "Fail a SELECT when a CAST can't convert a value instead of giving NULL")]
+        strict_cast: bool,
     },
     ImportCsv {
         #[arg(help = "Path to CSV file")]
@@ -51,6 +188,88 @@ pub enum Commands {
         
         #[arg(help = "Target table name")]
         table: String,
+
+        #[arg(long, help = "Write all rows in one batch and record a single commit")]
+        bulk: bool,
+
+        #[arg(long, conflicts_with = "bulk", help = "Record progress in this file after every batch commit")]
+        checkpoint: Option<String>,
+
+        #[arg(long, requires = "checkpoint", help = "Continue after the rows the checkpoint file records as imported")]
+        resume: bool,
+
+        #[arg(long, help = "Store numbers, booleans and nulls as typed JSON, inferring a schema from the first 100 rows if the table has none")]
+        infer_types: bool,
+
+        #[arg(long, requires = "infer_types", help = "Fail on a field that doesn't match its column's type instead of keeping it as text")]
+        strict: bool,
+    },
+    // Import the objects a JSON HTTP endpoint returns, following its pagination
+    ImportHttp {
+        #[arg(help = "URL to GET")]
+        url: String,
+
+        #[arg(help = "Target table name")]
+        table: String,
+
+        #[arg(long, default_value = "id", help = "Field of each object that holds the row id")]
+        id_field: String,
+
+        #[arg(long, help = "Dot-separated path to the array of objects in the response (default: the response itself)")]
+        jsonpath: Option<String>,
+
+        #[arg(long = "header", action = ArgAction::Append, help = "Request header as 'Name: value'; repeatable")]
+        headers: Vec<String>,
+
+        #[arg(long, help = "Dot-separated path to the next page's URL or cursor in each response")]
+        next_field: Option<String>,
+
+        #[arg(long, default_value = "cursor", help = "Query parameter a cursor from --next-field is sent as")]
+        cursor_param: String,
+
+        #[arg(long, help = "Fetch and count the rows without committing them")]
+        dry_run: bool,
+    },
+    ExportCsv {
+        #[arg(help = "Table to export")]
+        table: String,
+
+        #[arg(help = "Path to output CSV file")]
+        file: String,
+
+        #[arg(long, help = "Comma-separated list of columns to export, in order")]
+        columns: Option<String>,
+
+        #[arg(long, help = "Do not write the header row")]
+        no_header: bool,
+
+        #[arg(long, help = "Field delimiter (default ',')")]
+        delimiter: Option<char>,
+
+        #[arg(long, help = "Commit to export the table at (default: HEAD)")]
+        commit_hash: Option<String>,
+    },
+    // Write a table to a file as a GitHub-flavored markdown table
+    ExportMd {
+        #[arg(help = "Table to export")]
+        table: String,
+
+        #[arg(help = "Path to output markdown file")]
+        file: String,
+
+        #[arg(long, help = "Commit to export the table at (default: HEAD)")]
+        commit_hash: Option<String>,
+
+        #[arg(long, help = "Start with a line naming the table and commit")]
+        caption: bool,
+
+        #[arg(long, help = "Cut cells longer than this many characters")]
+        max_col_width: Option<usize>,
+    },
+    // Write the history to a new bare git repository, one `<table>.json` file per table
+    ExportGit {
+        #[arg(help = "Directory for the git repository; must not exist or be empty")]
+        dest: String,
     },
     ShowTable {
         #[arg(help = "Table name to display")]
@@ -58,10 +277,43 @@ pub enum Commands {
         
         #[arg(long, help = "Commit hash to view at")]
         commit_hash: Option<String>,
+
+        #[arg(long, value_enum, help = "Output format (default: table, without borders when stdout is not a terminal)")]
+        format: Option<OutputFormat>,
+
+        #[arg(long, help = "Draw the table without box-drawing characters")]
+        no_border: bool,
+
+        #[arg(long, help = "Start markdown output with a line naming the table and commit")]
+        caption: bool,
+
+        #[arg(long, help = "Cut markdown cells longer than this many characters")]
+        max_col_width: Option<usize>,
+
+        #[arg(long, help = "Also show the rows in the trash of a soft delete table, with the time each was deleted")]
+        include_deleted: bool,
+
+        #[arg(long, help = "Show only these columns, in this order (comma-separated)")]
+        columns: Option<String>,
+
+        #[arg(long = "where", value_name = "COLUMN=VALUE", help = "Show only rows whose column has this value")]
+        where_clause: Option<String>,
+    },
+    Show {
+        #[arg(help = "Table the row belongs to")]
+        table: String,
+
+        #[arg(help = "Row id")]
+        id: String,
+
+        #[arg(long, help = "Branch or commit hash to read from (defaults to HEAD)")]
+        commit: Option<String>,
     },
     Revert {
         #[arg(help = "Commit hash to revert to")]
         commit_hash: String,
+        #[arg(long, help = "Show which rows would change without reverting")]
+        dry_run: bool,
     },
     
     Diff {
@@ -70,22 +322,80 @@ pub enum Commands {
         
         #[arg(help = "Second commit hash")]
         to: String,
+
+        #[arg(long, conflicts_with = "data_only", help = "Only show schema changes")]
+        schema_only: bool,
+
+        #[arg(long, help = "Only show row changes")]
+        data_only: bool,
+
+        #[arg(long, help = "Only print per-table counts of inserted, updated and deleted rows")]
+        stat: bool,
+
+        #[arg(long, help = "Show what the second commit changed since its merge base with the first (git diff A...B)")]
+        three_dot: bool,
+
+        #[arg(long, value_enum, default_value = "auto", help = "Color the output: auto (when stdout is a terminal), always or never")]
+        color: ColorChoice,
+
+        #[arg(long, value_enum, default_value = "text", conflicts_with = "stat", help = "text; sql for statements that `sql --file` can replay on another repository; json-patch for a change set `apply-patch` can commit")]
+        format: DiffFormat,
     },
     
-    History {
-        #[arg(help = "Show commit history")]
-        #[arg(short, long, help = "Limit number of commits")]
-        limit: Option<usize>,
-    },
 
     Checkout {
-        #[arg(help = "Commit hash or branch name")]
+        #[arg(help = "Commit hash or branch name, or table:id with --ours/--theirs")]
         target: String,
+        #[arg(long, conflicts_with = "theirs", help = "Restore the local version of a conflicted row")]
+        ours: bool,
+        #[arg(long, help = "Take the merged branch's version of a conflicted row")]
+        theirs: bool,
+        #[arg(long, help = "Conflicted row as table:id (defaults to TARGET)")]
+        path: Option<String>,
+        #[arg(short, long, help = "Switch even if staged changes or commits on no branch would be left behind")]
+        force: bool,
     },
-    // Show commit history
+    // Show commit history; `history` is kept as an alias for scripts written against it
+    #[command(alias = "history")]
     Log {
-        #[arg(short, long, help = "Show full details")]
+        #[arg(short, long, help = "Limit number of commits")]
+        limit: Option<usize>,
+
+        #[arg(short, long, conflicts_with = "oneline", help = "Show full details")]
         verbose: bool,
+
+        #[arg(long, help = "Show each commit on one line with an abbreviated hash")]
+        oneline: bool,
+
+        #[arg(long, help = "List the tables each commit changed with per-table row counts")]
+        stat: bool,
+
+        #[arg(long, help = "Draw the commit graph of all branches")]
+        graph: bool,
+
+        #[arg(long, help = "Only show commits at or after this ISO 8601 date")]
+        after: Option<String>,
+
+        #[arg(long, help = "Only show commits before this ISO 8601 date")]
+        before: Option<String>,
+
+        #[arg(long, help = "Only show commits that touch this table")]
+        table: Option<String>,
+
+        #[arg(long, help = "Show commits reachable from any branch, not only from HEAD")]
+        all: bool,
+
+        #[arg(long, value_name = "PATTERN", help = "Only show commits reachable from branches matching this glob (`*`, `?`)")]
+        branches: Option<String>,
+
+        #[arg(long, help = "Skip commits that are quarantined or fail their checksum instead of stopping")]
+        best_effort: bool,
+
+        #[arg(long, value_name = "TEXT", help = "Only show commits whose --source contains this text, ignoring case")]
+        grep_source: Option<String>,
+
+        #[arg(long, value_name = "REGEX", help = "Only show commits whose author (`user@host`) matches this regular expression")]
+        author: Option<String>,
     },
     // Show list of branches
     /* 
@@ -96,738 +406,3341 @@ pub enum Commands {
     or with verbose output:
 
     cargo run -- branch-list --verbose
+
+    or with each branch's commits ahead of and behind HEAD (or another branch):
+
+    cargo run -- branch-list --divergence [main]
     */
     BranchList {
         #[arg(short, long, help = "Show additional branch information")]
         verbose: bool,
+        #[arg(long, value_name = "BASE", num_args = 0..=1, default_missing_value = "HEAD", help = "Count each branch's commits ahead of and behind BASE (default: HEAD)")]
+        divergence: Option<String>,
     },
     // Merge branches
     Merge {
         #[arg(help = "Branch name to merge")]
         branch: String,
+        #[arg(short, long, help = "Merge commit message; $EDITOR opens with \"Merge branch '<branch>'\" when there is none")]
+        message: Option<String>,
     },
-}
+    // Recommit HEAD's commits on top of another branch or commit
+    Rebase {
+        #[arg(help = "Branch or commit to rebase onto")]
+        onto: String,
+    },
+    // Commit the changes of a patch written by `diff --format json-patch`
+    ApplyPatch {
+        #[arg(help = "JSON patch file")]
+        file: String,
+        #[arg(short, long, help = "Commit message (default: Apply patch <file>)")]
+        message: Option<String>,
+        #[arg(long, help = "Skip the changes whose preconditions fail and commit the rest")]
+        force: bool,
+    },
+    // Write a commit as a compact binary patch, for `import-patch` in another repository
+    ExportPatch {
+        #[arg(help = "Commit or branch to export")]
+        commit: String,
+        #[arg(short, long, help = "File to write the patch to")]
+        output: String,
+    },
+    // Store the commits of binary patches, oldest first, without moving HEAD or any branch
+    ImportPatch {
+        #[arg(required = true, help = "Patch files written by export-patch")]
+        files: Vec<String>,
+    },
+    // Start an empty repository under `<name>:` in the same database, for use with --namespace
+    CreateNamespace {
+        #[arg(help = "Namespace name: letters, digits, '_' and '-'")]
+        name: String,
+    },
+    // Delete a namespace and everything in it
+    DropNamespace {
+        #[arg(help = "Namespace to delete")]
+        name: String,
+    },
+    // Re-encrypt every stored value with a new key, or encrypt a plaintext repository
+    Rekey {
+        #[arg(long, help = "File holding the new key (default: the BRANCHDB_NEW_KEY environment variable)")]
+        new_key_file: Option<String>,
+        #[arg(long, value_name = "DIR", help = "Write a re-encrypted copy to DIR/data instead of rewriting in place")]
+        into: Option<String>,
+    },
+    // Show the row changes that would turn one table into another, as of one commit
+    DiffTables {
+        #[arg(help = "Table the changes apply to")]
+        table_a: String,
+        #[arg(help = "Table to compare it with")]
+        table_b: String,
+        #[arg(long, help = "Commit to compare at (default: HEAD)")]
+        commit_hash: Option<String>,
+    },
+    // List the tables that exist at a commit
+    ListTables {
+        #[arg(long, help = "Commit to list the tables of (default: HEAD)")]
+        commit_hash: Option<String>,
+    },
+    // Copy a table's rows and schema to a new table in one commit
+    CloneTable {
+        #[arg(help = "Table to copy")]
+        src: String,
+        #[arg(help = "Name of the copy")]
+        dst: String,
+        #[arg(long, help = "Copy the table as of this commit (default: HEAD)")]
+        commit_hash: Option<String>,
+        #[arg(long, help = "Replace the destination table if it exists")]
+        overwrite: bool,
+    },
+    // Check whether one commit is in the history of another
+    AncestorCheck {
+        #[arg(help = "Branch or commit hash that may be an ancestor")]
+        ancestor: String,
+        #[arg(help = "Branch or commit hash whose history is searched")]
+        descendant: String,
+    },
+    // Check HEAD, the branches and pending state for what an interrupted operation left behind
+    Fsck {
+        #[arg(long, help = "Repair what was found by discarding the interrupted operation's state")]
+        abort_pending: bool,
 
-pub fn handle_commit(storage: &CommitStorage, message: &str) -> Result<()> {
-    if message.trim().is_empty() {
-        return Err(BranchDBError::InvalidInput("Commit message cannot be empty.".into()));
-    }
+        #[arg(long, help = "Check every commit's checksum and move corrupt ones out of the way")]
+        quarantine: bool,
 
-    let changes = Vec::new();
-    let hash = storage.create_commit(message, changes)?;
-    println!("Created commit with hash: {}", hex::encode(hash));
-    Ok(())
+        #[arg(long, help = "Rewrite commits whose checksum is missing, cut short or repeated but whose contents are intact")]
+        repair_checksums: bool,
+    },
+    // Rewrite commits whose checksum is damaged but whose contents still match their hash
+    Repair,
+    // Report rows whose foreign keys reference rows that don't exist
+    VerifyForeignKeys {
+        #[arg(help = "Only check this table (default: every table with foreign keys)")]
+        table: Option<String>,
+    },
+    // Check that every row decodes and fits its table's schema
+    VerifyData {
+        #[arg(long, help = "Only check this table (default: every table)")]
+        table: Option<String>,
+        #[arg(long, help = "Check the rows as of this commit instead of the live rows")]
+        commit_hash: Option<String>,
+        #[arg(long, value_enum, conflicts_with = "commit_hash", help = "Repair what can be in one commit: nulls sets mistyped values to NULL, drop deletes failing rows")]
+        fix: Option<DataFixArg>,
+    },
+    // Show repository and process statistics
+    Stats {
+        #[arg(long, help = "Also print this process's internal counters and histograms")]
+        internal: bool,
+    },
+    // Show how a table has changed over the history reachable from HEAD
+    TableStats {
+        #[arg(help = "Table to report on")]
+        table: String,
+        #[arg(long, value_enum, default_value = "week", help = "Span of time changes are counted over")]
+        bucket: BucketArg,
+        #[arg(long, default_value_t = 10, help = "Number of most updated rows to list")]
+        top: usize,
+    },
+    // Measure core operations against a throwaway repository
+    Bench {
+        #[arg(long, default_value_t = 1000, help = "Rows per batched commit and CSV import")]
+        rows: usize,
+        #[arg(long, default_value_t = 100, help = "Number of single-row commits")]
+        commits: usize,
+        #[arg(long, default_value_t = 42, help = "Seed for the synthetic data generators")]
+        seed: u64,
+        #[arg(long, help = "Print results as JSON")]
+        json: bool,
+    },
+    // Fill a table with deterministic pseudo-random rows, creating it if needed
+    Seed {
+        #[arg(long, help = "Table to fill")]
+        table: String,
+        #[arg(long, default_value_t = 1000, help = "Rows to generate, on each branch when --branch is given")]
+        rows: usize,
+        #[arg(long, help = "Columns and types as a JSON object, first column the id; required for a new table")]
+        schema: Option<String>,
+        #[arg(long, default_value_t = 42, help = "Seed for the generator; the same seed gives the same rows")]
+        seed: u64,
+        #[arg(long, help = "Spread the rows over this many commits (default: one per 1000 rows)")]
+        commits: Option<usize>,
+        #[arg(long = "branch", action = ArgAction::Append, help = "Seed this branch instead of HEAD, creating it from HEAD if missing; repeatable")]
+        branches: Vec<String>,
+    },
+    // Print new commits and their row changes as they are made, until Ctrl-C
+    Watch {
+        #[arg(long, help = "Only report changes to this table")]
+        table: Option<String>,
+        #[arg(long, value_enum, default_value = "text", help = "Output format; json prints one object per commit and line")]
+        format: WatchFormat,
+        #[arg(long, help = "Replay the commits after this sequence number, branch or commit first")]
+        since: Option<String>,
+        #[arg(long, default_value_t = 1.0, help = "Seconds between checks for new commits")]
+        interval: f64,
+        #[arg(long, value_name = "ADDR", help = "Serve this process's metrics at http://ADDR/metrics for Prometheus while watching (needs the prometheus feature)")]
+        metrics_listen: Option<String>,
+    },
+    // Collapse all history up to a commit into a single root commit
+    Squash {
+        #[arg(help = "Last commit to include in the squash")]
+        before_hash: String,
+        #[arg(short, long, help = "Message for the new root commit")]
+        message: String,
+        #[arg(long, help = "Required: older commits become unreachable")]
+        confirm: bool,
+    },
+    // Get or set repository settings
+    Config {
+        #[arg(help = "Setting to read or write, e.g. core.pager (lists all settings when omitted)")]
+        key: Option<String>,
+        #[arg(help = "New value for the setting")]
+        value: Option<String>,
+        #[arg(long, requires = "key", conflicts_with = "value", help = "Remove the setting")]
+        unset: bool,
+    },
+    // Upgrade a repository written by an older version to the current on-disk format
+    Migrate,
+    Completions {
+        #[arg(value_enum, help = "Shell to generate the completion script for")]
+        shell: Shell,
+    },
+    // Called by the completion scripts with the words typed so far
+    #[command(name = "__complete", hide = true)]
+    Complete {
+        #[arg(last = true)]
+        words: Vec<String>,
+    },
 }
 
-pub fn handle_branch(branch_mgr: &BranchManager, name: &str, delete: bool) -> Result<()> {
-    if delete {
-        branch_mgr.delete_branch(name)?;
-        println!("Deleted branch '{}'.", name);
-    } else {
-        branch_mgr.create_branch(name)?;
-        println!("Created branch '{}'.", name);
+impl Commands {
+    // Name used for the per-command tracing span
+    pub fn name(&self) -> &'static str {
+        match self {
+            Commands::Init { .. } => "init",
+            Commands::Commit { .. } => "commit",
+            Commands::Branch { .. } => "branch",
+            Commands::Prune { .. } => "prune",
+            Commands::Expire { .. } => "expire",
+            Commands::RestoreRow { .. } => "restore-row",
+            Commands::EmptyTrash { .. } => "empty-trash",
+            Commands::UnprotectBranch { .. } => "unprotect-branch",
+            Commands::Query { .. } => "query",
+            Commands::Sql { .. } => "sql",
+            Commands::ImportCsv { .. } => "import-csv",
+            Commands::ImportHttp { .. } => "import-http",
+            Commands::ExportCsv { .. } => "export-csv",
+            Commands::ExportMd { .. } => "export-md",
+            Commands::ExportGit { .. } => "export-git",
+            Commands::ShowTable { .. } => "show-table",
+            Commands::Show { .. } => "show",
+            Commands::Revert { .. } => "revert",
+            Commands::Diff { .. } => "diff",
+            Commands::Checkout { .. } => "checkout",
+            Commands::Log { .. } => "log",
+            Commands::BranchList { .. } => "branch-list",
+            Commands::Merge { .. } => "merge",
+            Commands::Rebase { .. } => "rebase",
+            Commands::ApplyPatch { .. } => "apply-patch",
+            Commands::ExportPatch { .. } => "export-patch",
+            Commands::ImportPatch { .. } => "import-patch",
+            Commands::CreateNamespace { .. } => "create-namespace",
+            Commands::DropNamespace { .. } => "drop-namespace",
+            Commands::Rekey { .. } => "rekey",
+            Commands::AncestorCheck { .. } => "ancestor-check",
+            Commands::DiffTables { .. } => "diff-tables",
+            Commands::ListTables { .. } => "list-tables",
+            Commands::CloneTable { .. } => "clone-table",
+            Commands::Fsck { .. } => "fsck",
+            Commands::Repair => "repair",
+            Commands::VerifyForeignKeys { .. } => "verify-foreign-keys",
+            Commands::VerifyData { .. } => "verify-data",
+            Commands::Stats { .. } => "stats",
+            Commands::TableStats { .. } => "table-stats",
+            Commands::Bench { .. } => "bench",
+            Commands::Seed { .. } => "seed",
+            Commands::Watch { .. } => "watch",
+            Commands::Squash { .. } => "squash",
+            Commands::Config { .. } => "config",
+            Commands::Migrate => "migrate",
+            Commands::Completions { .. } => "completions",
+            Commands::Complete { .. } => "__complete",
+        }
     }
-    Ok(())
-}
 
-pub fn handle_query(sql: &str, db: &DB) -> Result<()> {
-    let processor = QueryProcessor::new(db);
-    processor.execute(sql)
-}
+    // Commands whose output can run past a screen and goes through the pager
+    pub fn pages(&self) -> bool {
+        matches!(self, Commands::Log { .. } | Commands::ShowTable { .. } | Commands::Diff { .. })
+    }
 
-pub fn handle_sql(storage: &CommitStorage, command: &str) -> Result<()> {
-    let cmd_upper = command.to_uppercase();
-    
-    if cmd_upper.starts_with("CREATE TABLE") {
-        let table_name = command.split_whitespace()
-            .nth(2)
-            .ok_or_else(|| BranchDBError::InvalidInput("Missing table name".into()))?;
-        
-        let changes = vec![Change::Insert {
-            table: table_name.to_string(),
-            id: "!schema".to_string(),
-            value: bincode::serialize(&CrdtValue::Register(b"{}".to_vec()))?,
-        }];
-        
-        storage.create_commit(&format!("SQL: {}", command), changes)?;
-        Ok(())
-    } 
-    else if cmd_upper.starts_with("INSERT INTO") {
-        let table = command.split_whitespace()
-            .nth(2)
-            .ok_or_else(|| BranchDBError::InvalidInput("Missing table name".into()))?;
-        
-        let values_start = command.find("VALUES")
-            .ok_or_else(|| BranchDBError::InvalidInput("Missing VALUES clause".into()))? + 6;
-        let values_part = &command[values_start..].trim();
-        
-        let values = parse_sql_values(values_part)?;
-        if values.is_empty() {
-            return Err(BranchDBError::InvalidInput("No values provided".into()));
-        }
-        
-        // Dynamic type checking - works with any schema format
-        if let Ok(schema) = storage.get_table_schema(table, None) {
-            if let Some(columns) = schema.get("columns") {
-                // Match values to columns by position when column names aren't specified
-                for (i, field) in values.iter().enumerate() {
-                    if let Some((_, col_type)) = columns.as_object()
-                        .and_then(|cols| cols.iter().nth(i))
-                    {
-                        validate_value_type(
-                            field,
-                            col_type.as_str().unwrap_or("TEXT")
-                        )?;
-                    }
+    // Storage settings for this command; `sql --coerce` relaxes type checks for that statement
+    // and `--strict-cast` makes failed CASTs errors
+    pub fn storage_options(&self) -> StorageOptions {
+        let coercion = match self {
+            Commands::Sql { coerce: true, .. } => CoercionPolicy::Coerce,
+            _ => CoercionPolicy::Strict,
+        };
+        let strict_cast = matches!(self, Commands::Query { strict_cast: true, .. } | Commands::Sql { strict_cast: true, .. });
+        StorageOptions { coercion, strict_cast, ..StorageOptions::default() }
+    }
+
+    // Every variant maps to its handler here, so adding a command without one fails to compile.
+    pub fn run(self, ctx: &CommandContext, out: &mut Output) -> Result<()> {
+        let _span = tracing::info_span!("command", name = self.name()).entered();
+        match self {
+            Commands::Init { path, bare, force, initial_branch, encrypted, key_file } => {
+                let options = InitOptions { bare, force, initial_branch, encrypted, key_file };
+                handle_init(path.as_deref().unwrap_or("."), &options, out)
+            }
+            Commands::Commit { message, message_flag, template } => {
+                handle_commit(ctx.storage()?, ctx.data_dir(), message.or(message_flag).as_deref(), template.as_deref(), out)
+            }
+            Commands::Branch { prune_merged: true, dry_run, .. } | Commands::Prune { dry_run } => {
+                handle_prune(&ctx.branch_mgr()?, ctx.storage()?, dry_run, out)
+            }
+            Commands::Expire { table, dry_run } => handle_expire(ctx.storage()?, table.as_deref(), dry_run, out),
+            Commands::RestoreRow { table, id } => handle_restore_row(ctx.storage()?, &table, &id, out),
+            Commands::EmptyTrash { table, older_than, dry_run } => {
+                handle_empty_trash(ctx.storage()?, table.as_deref(), older_than.as_deref(), dry_run, out)
+            }
+            Commands::Branch { name, delete, protected, .. } => {
+                let name = name.expect("clap requires a name without --prune-merged");
+                handle_branch(&ctx.branch_mgr()?, &name, delete, protected, ctx.yes, out)
+            }
+            Commands::UnprotectBranch { name } => handle_unprotect_branch(&ctx.branch_mgr()?, &name, out),
+            Commands::Query { sql, format, max_col_width, .. } => handle_query(ctx.storage()?, &sql, format, max_col_width, ctx.yes, out),
+            Commands::Sql { file: Some(file), format, .. } => handle_sql_file(ctx.storage()?, &file, format, ctx.yes, out),
+            Commands::Sql { command, format, .. } => handle_sql(ctx.storage()?, command.as_deref().unwrap_or_default(), format, ctx.yes, out),
+            Commands::ImportCsv { file, table, bulk, checkpoint, resume, infer_types, strict } => {
+                let options = CsvImportOptions { bulk, checkpoint: checkpoint.as_deref().map(Path::new), resume, infer_types, strict };
+                handle_import_csv(ctx.storage()?, &file, &table, &options, out)
+            }
+            Commands::ImportHttp { url, table, id_field, jsonpath, headers, next_field, cursor_param, dry_run } => {
+                let source = HttpSource { url, headers, json_path: jsonpath, next_field, cursor_param };
+                handle_import_http(ctx.storage()?, &source, &table, &id_field, dry_run, out)
+            }
+            Commands::ExportCsv { table, file, columns, no_header, delimiter, commit_hash } => {
+                let options = CsvExportOptions::from_args(columns.as_deref(), no_header, delimiter)?;
+                handle_export_csv(ctx.storage()?, &table, &file, commit_hash.as_deref(), &options, out)
+            }
+            Commands::ExportMd { table, file, commit_hash, caption, max_col_width } => {
+                handle_export_md(ctx.storage()?, &table, &file, commit_hash.as_deref(), caption, max_col_width, out)
+            }
+            Commands::ExportGit { dest } => handle_export_git(ctx.storage()?, &dest, out),
+            Commands::ShowTable { table_name, commit_hash, format, no_border, caption, max_col_width, include_deleted, columns, where_clause } => {
+                // Piped output defaults to a borderless table
+                let border = !no_border && (format.is_some() || std::io::stdout().is_terminal());
+                let format = format.unwrap_or(OutputFormat::Table);
+                let options = ShowTableOptions {
+                    format,
+                    border,
+                    include_deleted,
+                    markdown: MarkdownOptions { caption, max_col_width },
+                    columns: columns.map(|list| list.split(',').map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect()),
+                    filter: where_clause.as_deref().map(parse_row_filter).transpose()?,
+                };
+                handle_show_table(ctx.storage()?, &table_name, commit_hash.as_deref(), &options, out)
+            }
+            Commands::Show { table, id, commit } => {
+                handle_show(ctx.storage()?, &table, &id, commit.as_deref(), out)
+            }
+            Commands::Checkout { target, ours, theirs, path, force } => {
+                if ours || theirs {
+                    handle_checkout_conflict(ctx.storage()?, path.as_deref().unwrap_or(&target), theirs, out)
+                } else {
+                    handle_checkout(ctx.storage()?, &target, force, out)
+                }
+            }
+            Commands::Log { limit, verbose, oneline, stat, graph, after, before, table, all, branches, best_effort, grep_source, author } => {
+                let options = LogOptions {
+                    limit,
+                    style: if verbose { LogStyle::Verbose } else if oneline { LogStyle::Oneline } else { LogStyle::Default },
+                    stat,
+                    graph,
+                    after: after.as_deref().map(parse_date).transpose()?,
+                    before: before.as_deref().map(parse_date).transpose()?,
+                    table,
+                    all,
+                    branches,
+                    best_effort,
+                    grep_source,
+                    author,
+                };
+                handle_log(ctx.storage()?, &options, out)
+            }
+            Commands::Revert { commit_hash, dry_run } => handle_revert(ctx.storage()?, &commit_hash, dry_run, ctx.yes, out),
+            Commands::Diff { from, to, schema_only, data_only, stat, three_dot, color, format } => {
+                let options = DiffOptions {
+                    include_schema: !data_only,
+                    include_data: !schema_only,
+                };
+                let view = DiffViewOptions { stat, three_dot, color, format };
+                handle_diff(ctx.storage()?, &from, &to, &options, &view, out)
+            }
+            Commands::DiffTables { table_a, table_b, commit_hash } => {
+                handle_diff_tables(ctx.storage()?, &table_a, &table_b, commit_hash.as_deref(), out)
+            }
+            Commands::ListTables { commit_hash } => handle_list_tables(ctx.storage()?, commit_hash.as_deref(), out),
+            Commands::CloneTable { src, dst, commit_hash, overwrite } => {
+                handle_clone_table(ctx.storage()?, &src, &dst, commit_hash.as_deref(), overwrite, out)
+            }
+            Commands::BranchList { verbose, divergence } => {
+                let divergence = divergence.map(|base| BranchDivergence { base: Some(base).filter(|base| base != "HEAD") });
+                handle_branch_list(&ctx.branch_mgr()?, ctx.storage()?, verbose, divergence.as_ref(), out)
+            }
+            Commands::Merge { branch, message } => handle_merge(ctx.storage()?, ctx.data_dir(), &branch, message.as_deref(), out),
+            Commands::Rebase { onto } => handle_rebase(ctx.storage()?, &onto, out),
+            Commands::ApplyPatch { file, message, force } => {
+                handle_apply_patch(ctx.storage()?, &file, message.as_deref(), force, out)
+            }
+            Commands::ExportPatch { commit, output } => handle_export_patch(ctx.storage()?, &commit, &output, out),
+            Commands::ImportPatch { files } => handle_import_patch(ctx.storage()?, &files, out),
+            Commands::CreateNamespace { name } => handle_create_namespace(ctx.storage()?, &name, out),
+            Commands::DropNamespace { name } => handle_drop_namespace(ctx.storage()?, &name, ctx.yes, out),
+            Commands::Rekey { new_key_file, into } => {
+                handle_rekey(ctx.storage()?, ctx.data_dir(), new_key_file.as_deref(), into.as_deref(), out)
+            }
+            Commands::AncestorCheck { ancestor, descendant } => {
+                handle_ancestor_check(ctx.storage()?, &ancestor, &descendant, out)
+            }
+            Commands::Fsck { abort_pending, quarantine, repair_checksums } => {
+                handle_fsck(ctx.storage()?, abort_pending, quarantine, repair_checksums, out)
+            }
+            Commands::Repair => handle_repair(ctx.storage()?, out),
+            Commands::VerifyForeignKeys { table } => handle_verify_foreign_keys(ctx.storage()?, table.as_deref(), out),
+            Commands::VerifyData { table, commit_hash, fix } => {
+                let fix = fix.map(|fix| match fix {
+                    DataFixArg::Nulls => DataFix::Nulls,
+                    DataFixArg::Drop => DataFix::Drop,
+                });
+                handle_verify_data(ctx.storage()?, table.as_deref(), commit_hash.as_deref(), fix, ctx.yes, out)
+            }
+            Commands::Stats { internal } => handle_stats(ctx.storage()?, &ctx.branch_mgr()?, internal, out),
+            Commands::TableStats { table, bucket, top } => {
+                let bucket = match bucket {
+                    BucketArg::Day => Bucket::Day,
+                    BucketArg::Week => Bucket::Week,
+                    BucketArg::Month => Bucket::Month,
+                };
+                handle_table_stats(ctx.storage()?, &table, bucket, top, out)
+            }
+            Commands::Bench { rows, commits, seed, json } => {
+                handle_bench(&BenchOptions { rows, commits, seed }, json, out)
+            }
+            Commands::Squash { before_hash, message, confirm } => {
+                handle_squash(ctx.storage()?, &before_hash, &message, confirm, out)
+            }
+            Commands::Config { key, value, unset } => {
+                handle_config(ctx.data_dir(), key.as_deref(), value.as_deref(), unset, out)
+            }
+            Commands::Migrate => handle_migrate(ctx.data_dir(), out),
+            Commands::Seed { table, rows, schema, seed, commits, branches } => {
+                let schema = schema.as_deref().map(seed::parse_columns).transpose()?;
+                handle_seed(ctx.storage()?, &SeedOptions { table, rows, schema, seed, commits, branches }, out)
+            }
+            Commands::Watch { table, format, since, interval, metrics_listen } => {
+                if let Some(addr) = &metrics_listen {
+                    serve_metrics(addr)?;
                 }
+                let options = WatchOptions {
+                    table,
+                    json: format == WatchFormat::Json,
+                    since,
+                    interval: Duration::from_secs_f64(interval.max(0.0)),
+                };
+                handle_watch(ctx.data_dir(), &options, out)
+            }
+            Commands::Completions { shell } => completions::print_script(shell),
+            Commands::Complete { words } => {
+                // Exit status tells the shell script whether to fall back to static completion
+                let handled = completions::complete(&words, ctx.data_dir());
+                std::process::exit(if handled { 0 } else { 1 });
             }
         }
-
-        let json_value = serde_json::to_string(&values)?;  
-        
-        let changes = vec![Change::Insert {
-            table: table.to_string(),
-            id: values[0].to_string(),
-            value: bincode::serialize(&CrdtValue::Register(json_value.as_bytes().to_vec()))?,
-        }];
-        
-        storage.create_commit(&format!("SQL: {}", command), changes)?;
-        Ok(())
     }
-    
-    else if cmd_upper.starts_with("UPDATE") {
-        let table = command.split_whitespace()
-            .nth(1)
-            .ok_or_else(|| BranchDBError::InvalidInput("Missing table name".into()))?;
-
-        let set_idx = command.find("SET")
-            .ok_or_else(|| BranchDBError::InvalidInput("Missing SET clause".into()))?;
-        let where_idx = command.find("WHERE")
-            .ok_or_else(|| BranchDBError::InvalidInput("Missing WHERE clause".into()))?;
-
-        let set_clause = &command[set_idx+3..where_idx].trim();
-        let where_clause = &command[where_idx+5..].trim();
-
-        // Robust WHERE clause parsing
-        let id = if where_clause.contains("=") {
-            let parts: Vec<&str> = where_clause.splitn(2, '=').collect();
-            if parts.len() != 2 {
-                return Err(BranchDBError::InvalidInput("Invalid WHERE clause format".into()));
-            }
-            parts[1].trim().trim_matches('\'')
-        } else {
-            return Err(BranchDBError::InvalidInput("WHERE clause must contain = operator".into()));
-        };
+}
 
-        // Get current value
-        let key = format!("{}:{}", table, id);
-        let current_value = match storage.db.get(key.as_bytes())? {
-            Some(existing) => {
-                let crdt_value: CrdtValue = bincode::deserialize(&existing)?;
-                match crdt_value {
-                    CrdtValue::Register(data) => {
-                        // Parse as JSON value
-                        let mut current: serde_json::Value = serde_json::from_slice(&data)?;
-                        
-                        // Handle array format
-                        if let serde_json::Value::Array(ref mut arr) = current {
-                            // Get schema to determine field positions
-                            let schema = storage.get_table_schema(table, None)?;
-                            let columns = schema.get("columns")
-                                .and_then(|c| c.as_object())
-                                .ok_or_else(|| BranchDBError::InvalidInput("Invalid schema format".into()))?;
-                            
-                            // Process each SET clause
-                            for pair in set_clause.split(',') {
-                                let mut parts = pair.split('=').map(|s| s.trim());
-                                let field = parts.next()
-                                    .ok_or_else(|| BranchDBError::InvalidInput("Invalid SET clause".into()))?;
-                                let value = parts.next()
-                                    .ok_or_else(|| BranchDBError::InvalidInput("Invalid SET clause".into()))?
-                                    .trim_matches('\'');
-                                
-                                // Find field position in schema
-                                if let Some((pos, _)) = columns.iter().enumerate().find(|(_, (name, _))| name.as_str() == field) {
-                                    if pos < arr.len() {
-                                        // Type checking
-                                        if let Some(existing_val) = arr.get(pos) {
-                                            match existing_val {
-                                                serde_json::Value::Number(_) => {
-                                                    arr[pos] = value.parse::<f64>()
-                                                        .map(serde_json::Value::from)
-                                                        .map_err(|_| BranchDBError::TypeMismatch(
-                                                            format!("Expected number for field {}", field)
-                                                        ))?;
-                                                }
-                                                _ => arr[pos] = value.into(),
-                                            }
-                                        } else {
-                                            arr.push(value.into());
-                                        }
-                                    } else {
-                                        return Err(BranchDBError::InvalidInput(
-                                            format!("Field position {} out of bounds", pos)
-                                        ));
-                                    }
-                                } else {
-                                    return Err(BranchDBError::InvalidInput(
-                                        format!("Field '{}' not found in schema", field)
-                                    ));
-                                }
-                            }
-                        }
-                        current
-                    }
-                    _ => return Err(BranchDBError::TypeMismatch("Expected Register type".into())),
-                }
-            }
-            None => return Err(BranchDBError::InvalidInput(
-                format!("Row '{}' not found in table '{}'", id, table)
-            )),
-        };
+// What commands share. The repository is opened on first use, so commands that never touch it
+// (init, completions) neither need nor create one.
+pub struct CommandContext {
+    data_dir: String,
+    options: StorageOptions,
+    pub yes: bool,
+    storage: OnceCell<CommitStorage>,
+}
 
-        // Create and commit changes
-        let changes = vec![Change::Update {
-            table: table.to_string(),
-            id: id.to_string(),
-            value: bincode::serialize(&CrdtValue::Register(
-                serde_json::to_vec(&current_value)?
-            ))?,
-        }];
-        
-        storage.create_commit(&format!("SQL: {}", command), changes)?;
-        Ok(())
+impl CommandContext {
+    pub fn new(data_dir: &str, options: StorageOptions, yes: bool) -> Self {
+        Self { data_dir: data_dir.to_string(), options, yes, storage: OnceCell::new() }
     }
 
-    // NEW COMMAND SUPPORT: ALTER TABLE
-    else if cmd_upper.starts_with("ALTER TABLE") {
-        let table = command.split_whitespace()
-            .nth(2)
-            .ok_or_else(|| BranchDBError::InvalidInput("Missing table name".into()))?;
-
-        // Get current schema
-        let schema_key = format!("{}:!schema", table);
-        let mut schema: serde_json::Value = match storage.db.get(schema_key.as_bytes())? {
-            Some(data) => serde_json::from_slice(&data)?,
-            None => serde_json::json!({}),
-        };
+    pub fn data_dir(&self) -> &str {
+        &self.data_dir
+    }
 
-        // Parse ALTER command
-        if cmd_upper.contains("ADD COLUMN") {
-            let column_name = command.split_whitespace()
-                .nth(4)
-                .ok_or_else(|| BranchDBError::InvalidInput("Missing column name".into()))?;
-            let column_type = command.split_whitespace()
-                .nth(5)
-                .ok_or_else(|| BranchDBError::InvalidInput("Missing column type".into()))?;
-
-            schema["columns"][column_name] = serde_json::Value::String(column_type.to_string());
-        } else if cmd_upper.contains("DROP COLUMN") {
-            let column_name = command.split_whitespace()
-                .nth(4)
-                .ok_or_else(|| BranchDBError::InvalidInput("Missing column name".into()))?;
-            schema["columns"].as_object_mut()
-                .ok_or(BranchDBError::TypeMismatch("Invalid schema format".into()))?
-                .remove(column_name);
-        } else {
-            return Err(BranchDBError::InvalidInput("Unsupported ALTER TABLE operation".into()));
+    pub fn storage(&self) -> Result<&CommitStorage> {
+        if let Some(storage) = self.storage.get() {
+            return Ok(storage);
         }
+        if !Path::new(&self.data_dir).exists() {
+            fs::create_dir(&self.data_dir)?;
+        }
+        let mut options = self.options.clone();
+        let config = Config::load(Path::new(&self.data_dir))?;
+        options.incremental_view_maintenance = config.get(INCREMENTAL_VIEWS_KEY) == Some("true");
+        options.enforce_fk = config.get(ENFORCE_FK_KEY) == Some("true");
+        options.commit_context.collect = config.get(AUDIT_KEY) != Some("false");
+        if let Some(seconds) = config.get(LOCK_TIMEOUT_KEY) {
+            let seconds: f64 = seconds.parse().map_err(|_| BranchDBError::InvalidInput(
+                format!("{} must be a number of seconds, got '{}'", LOCK_TIMEOUT_KEY, seconds)
+            ))?;
+            options.lock_timeout = Duration::from_secs_f64(seconds.max(0.0));
+        }
+        let storage = CommitStorage::open_with_options(&self.data_dir, options)?;
+        Ok(self.storage.get_or_init(|| storage))
+    }
 
-        // Update schema
-        let changes = vec![Change::Update {
-            table: table.to_string(),
-            id: "!schema".to_string(),
-            value: bincode::serialize(&CrdtValue::Register(
-                serde_json::to_vec(&schema)?
-            ))?,
-        }];
-
-        storage.create_commit(&format!("SQL: {}", command), changes)?;
-        Ok(())
+    pub fn branch_mgr(&self) -> Result<BranchManager> {
+        Ok(BranchManager::new(self.storage()?.db.clone()))
     }
-    else {
-        Err(BranchDBError::InvalidInput("Unsupported SQL command".into()))
+
+    // Did-you-mean candidates for a not-found error. Only looks at storage the command already
+    // opened, so a failure before that never creates the data directory.
+    pub fn suggestions(&self, error: &BranchDBError) -> Vec<String> {
+        self.storage.get().map(|storage| suggest::suggestions(error, storage)).unwrap_or_default()
     }
 }
 
-fn parse_sql_values(values_part: &str) -> Result<Vec<String>> {
-    let mut values = Vec::new();
-    let mut in_quotes = false;
-    let mut current = String::new();
-    let mut chars = values_part.chars().peekable();
-    
-    if values_part.starts_with('(') {
-        chars.next();
-    }
-    
-    while let Some(c) = chars.next() {
-        match c {
-            '\'' => {
-                in_quotes = !in_quotes;
-                if !in_quotes {
-                    values.push(current.trim().to_string());
-                    current.clear();
-                }
-            },
-            ',' if !in_quotes => {
-                // Skip commas between values
-                while let Some(&next) = chars.peek() {
-                    if next.is_whitespace() || next == ',' {
-                        chars.next();
-                    } else {
-                        break;
-                    }
-                }
-            },
-            ')' if !in_quotes => break,
-            _ => current.push(c),
+pub fn handle_commit(storage: &CommitStorage, data_dir: &str, message: Option<&str>, template: Option<&str>, out: &mut Output) -> Result<()> {
+    let changes = storage.staged_changes()?;
+    let count = changes.len();
+    let merge_head = storage.pending_merge_head()?;
+    // Finishing a merge after its conflicts starts from the message the merge would have had
+    let merging = match (message, merge_head) {
+        (None, Some(_)) => Some(MergeConflicts::load(&storage.conflicts_path())?.branch),
+        _ => None,
+    };
+    let draft = match &merging {
+        Some(branch) => Draft::Default(format!("Merge branch '{}'", branch)),
+        None if message.is_some() => Draft::Blank,
+        None => editor::template(data_dir, template)?.map_or(Draft::Blank, Draft::Template),
+    };
+    let prompt = message_prompt(storage, "commit", merging.as_deref(), &changes)?;
+    let message = editor::message(data_dir, message, draft, &prompt)?;
+    let message = message.as_str();
+
+    // Finishing a merge that stopped for conflicts records the branch as the second parent
+    let hash = match merge_head {
+        Some(merge_head) => {
+            let hash = storage.create_merge_commit(message, changes, &merge_head)?;
+            storage.clear_pending_merge()?;
+            hash
         }
-    }
-    
-    Ok(values)
+        None => storage.create_commit(message, changes)?,
+    };
+    storage.clear_staged()?;
+    out.line(format!("Created commit with hash: {}", hex::encode(hash)));
+    out.set_json(json!({ "hash": hex::encode(hash), "changes": count }));
+    Ok(())
 }
 
-pub fn handle_import_csv(storage: &CommitStorage, file: &str, table: &str) -> Result<()> {
-    const BATCH_SIZE: usize = 100;
-    
-    let mut rdr = csv::Reader::from_path(file)?;
-    let headers = rdr.headers()?.clone();
-    let mut changes = Vec::new();
-    
-    for (i, result) in rdr.records().enumerate() {
-        let record = result?;
-        let id = record.get(0)
-            .ok_or_else(|| BranchDBError::InvalidInput("CSV missing ID column".into()))?;
-        
-        let mut row = Vec::new();
-        for (i, field) in record.iter().enumerate() {
-            row.push(format!("\"{}\":\"{}\"", headers.get(i).unwrap_or(&i.to_string()), field));
-        }
-        
-        let change = Change::Insert {
-            table: table.to_string(),
-            id: id.to_string(),
-            value: bincode::serialize(&CrdtValue::Register(
-                format!("{{{}}}", row.join(",")).as_bytes().to_vec()
-            ))?,
-        };
-        
-        changes.push(change);
+// The context the editor shows under a commit or merge message.
+fn message_prompt<'a>(storage: &CommitStorage, action: &'a str, merging: Option<&'a str>, changes: &'a [Change]) -> Result<editor::MessagePrompt<'a>> {
+    Ok(editor::MessagePrompt {
+        action,
+        branch: BranchManager::new(storage.db.clone()).get_current_branch()?,
+        head: storage.get_head()?,
+        merging,
+        changes,
+    })
+}
 
-        // Batch processing
-        if i % BATCH_SIZE == 0 && i > 0 {
-            storage.create_commit(&format!("Batch import {} into {}", file, table), changes)?;
-            changes = Vec::new();
+pub fn handle_branch(branch_mgr: &BranchManager, name: &str, delete: bool, protected: bool, yes: bool, out: &mut Output) -> Result<()> {
+    if delete {
+        let head = branch_mgr.get_branch_head(name)?
+            .ok_or_else(|| BranchDBError::BranchNotFound { name: name.to_string() })?;
+        confirm(&format!("This will delete branch '{}' at {}", name, hex::encode(head)), yes)?;
+        branch_mgr.delete_branch(name)?;
+        out.line(format!("Deleted branch '{}'.", name));
+    } else {
+        branch_mgr.create_branch(name)?;
+        if protected {
+            branch_mgr.protect_branch(name, ProtectionPolicy::default())?;
+            out.line(format!("Created protected branch '{}'.", name));
+        } else {
+            out.line(format!("Created branch '{}'.", name));
         }
     }
+    let action = if delete { "deleted" } else { "created" };
+    out.set_json(json!({ "branch": name, "action": action, "protected": protected }));
+    Ok(())
+}
 
-    // Final commit for remaining changes
-    if !changes.is_empty() {
-        storage.create_commit(&format!("Import {} into {}", file, table), changes)?;
+pub fn handle_prune(branch_mgr: &BranchManager, storage: &CommitStorage, dry_run: bool, out: &mut Output) -> Result<()> {
+    let branches = if dry_run { branch_mgr.merged_branches(storage)? } else { branch_mgr.prune_merged(storage)? };
+    for name in &branches {
+        if dry_run {
+            out.line(format!("Would delete branch '{}'.", name));
+        } else {
+            out.line(format!("Deleted branch '{}'.", name));
+        }
     }
-    
+    if branches.is_empty() {
+        out.line("No merged branches to prune.");
+    }
+    out.set_json(json!({ "branches": branches, "dry_run": dry_run }));
     Ok(())
 }
 
-pub fn handle_export_csv(db: &DB, table: &str, file_path: &str) -> Result<()> {
-    let mut wtr = csv::Writer::from_path(file_path)?;
-    
-    // Get schema
-    let schema_key = format!("{}:!schema", table);
-    let schema: serde_json::Value = match db.get(schema_key.as_bytes())? {
-        Some(data) => serde_json::from_slice(&data)?,
-        None => serde_json::json!({}),
+pub fn handle_expire(storage: &CommitStorage, table: Option<&str>, dry_run: bool, out: &mut Output) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let rows = storage.expired_rows(table, now)?;
+    for row in &rows {
+        out.line(format!(
+            "{} {}/{}: written {} ago, TTL {}",
+            if dry_run { "Would expire" } else { "Expired" },
+            row.table, row.id, ttl::format_age(now.saturating_sub(row.written) / 1000), ttl::format_age(row.ttl)
+        ));
+    }
+    let hash = match rows.is_empty() || dry_run {
+        true => None,
+        false => Some(storage.expire_rows(&rows)?),
     };
-
-    // Write headers
-    if let Some(columns) = schema.get("columns") {
-        let headers: Vec<_> = columns.as_object()
-            .ok_or(BranchDBError::TypeMismatch("Invalid schema format".into()))?
-            .keys()
-            .collect();
-        wtr.write_record(headers)?;
+    match (rows.is_empty(), hash) {
+        (true, _) => out.line("No rows have outlived their TTL."),
+        (false, Some(hash)) => out.line(format!("Deleted {} row(s) in commit {}", rows.len(), hex::encode(hash))),
+        (false, None) => {}
     }
+    out.set_json(json!({ "rows": rows, "dry_run": dry_run, "hash": hash.map(hex::encode) }));
+    Ok(())
+}
 
-    // Write data
-    let prefix = format!("{}:", table);
-    let iter = db.prefix_iterator(prefix.as_bytes());
-    for item in iter {
-        let (key, value) = item?;
-        let id = String::from_utf8_lossy(&key[prefix.len()..]);
-        
-        if id == "!schema" {
-            continue;
-        }
+pub fn handle_restore_row(storage: &CommitStorage, table: &str, id: &str, out: &mut Output) -> Result<()> {
+    let hash = storage.restore_row(table, id)?;
+    out.line(format!("Restored {}/{} in commit {}", table, id, hex::encode(hash)));
+    out.set_json(json!({ "table": table, "id": id, "hash": hex::encode(hash) }));
+    Ok(())
+}
 
-        let crdt_value: CrdtValue = bincode::deserialize(&value)?;
-        if let CrdtValue::Register(data) = crdt_value {
-            let row: serde_json::Value = serde_json::from_slice(&data)?;
-            
-            let mut record = Vec::new();
-            if let Some(columns) = schema.get("columns") {
-                for column in columns.as_object().unwrap().keys() {
-                    let value = row.get(column).unwrap_or(&serde_json::Value::Null);
-                    record.push(value.to_string().trim_matches('"').to_string());
-                }
-            }
-            
-            wtr.write_record(&record)?;
-        }
+pub fn handle_empty_trash(storage: &CommitStorage, table: Option<&str>, older_than: Option<&str>, dry_run: bool, out: &mut Output) -> Result<()> {
+    let min_age = older_than.map(ttl::parse_duration).transpose()?.unwrap_or(0);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let rows: Vec<_> = storage.trash(table)?.into_iter()
+        .filter(|row| now.saturating_sub(row.deleted_at) >= min_age * 1000)
+        .collect();
+    for row in &rows {
+        out.line(format!(
+            "{} {}/{}: deleted {} ago",
+            if dry_run { "Would delete" } else { "Deleted" },
+            row.table, row.id, ttl::format_age(now.saturating_sub(row.deleted_at) / 1000)
+        ));
+    }
+    let hash = match rows.is_empty() || dry_run {
+        true => None,
+        false => Some(storage.empty_trash(&rows)?),
+    };
+    match (rows.is_empty(), hash) {
+        (true, _) => out.line("The trash has no rows to delete."),
+        (false, Some(hash)) => out.line(format!("Emptied {} row(s) from the trash in commit {}", rows.len(), hex::encode(hash))),
+        (false, None) => {}
     }
+    out.set_json(json!({ "rows": rows, "dry_run": dry_run, "hash": hash.map(hex::encode) }));
+    Ok(())
+}
 
-    wtr.flush()?;
+pub fn handle_unprotect_branch(branch_mgr: &BranchManager, name: &str, out: &mut Output) -> Result<()> {
+    branch_mgr.unprotect_branch(name)?;
+    out.line(format!("Branch '{}' is no longer protected.", name));
+    out.set_json(json!({ "branch": name, "protected": false }));
     Ok(())
 }
 
-pub fn handle_show_table(db: &DB, table_name: &str, commit_hash: Option<&str>) -> Result<()> {
-    let processor = QueryProcessor::new(db);
-    let hash = match commit_hash {
-        Some(h) => hex::decode(h)?,
-        None => processor.get_head_hash()?,
-    };
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+    Markdown,
+}
 
-    println!("Table '{}' at commit {}:", table_name, hex::encode(&hash));
-    
-    match processor.get_table_at_commit(table_name, &hash) {
-        Ok(rows) => {
-            // First print schema if it exists
-            if let Some(CrdtValue::Register(schema_data)) = rows.get("!schema") {
-                println!("Schema: {}", String::from_utf8_lossy(schema_data));
-            }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WatchFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DataFixArg {
+    Nulls,
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BucketArg {
+    Day,
+    Week,
+    Month,
+}
 
-            // Then print other rows
-            for (id, value) in rows {
-                if id == "!schema" {
-                    continue;
+pub fn handle_query(storage: &CommitStorage, sql: &str, format: OutputFormat, max_col_width: Option<usize>, yes: bool, out: &mut Output) -> Result<()> {
+    // DROP TABLE and the like are confirmed with a count of the rows their changes touch,
+    // the same changes that are then committed
+    let check = |changes: &[Change]| {
+        if !sql::is_destructive(sql) {
+            return Ok(());
+        }
+        let mut stats = BTreeMap::new();
+        for change in changes.iter().filter(|change| !change.id().starts_with('!')) {
+            DiffStat::count(&mut stats, change);
+        }
+        confirm(&rows_summary(&stats), yes)
+    };
+    match SqlExecutor::execute_checked(storage, sql, check)? {
+        SqlOutcome::Rows(result) => {
+            match format {
+                OutputFormat::Table => {
+                    for line in result.to_lines() {
+                        out.line(line);
+                    }
                 }
-                match value {
-                    CrdtValue::Register(data) => {
-                        println!("{}: {}", id, String::from_utf8_lossy(&data));
+                OutputFormat::Json => out.line(serde_json::to_string_pretty(&result.to_json())?),
+                OutputFormat::Csv => {
+                    let records = result.rows.iter().map(|row| row.iter().map(fmt::cell_text).collect());
+                    for line in fmt::write_csv(&result.columns, records)? {
+                        out.line(line);
                     }
-                    CrdtValue::Counter(count) => {
-                        println!("{}: {}", id, count);
+                }
+                OutputFormat::Markdown => {
+                    let records: Vec<Vec<(String, bool)>> = result.rows.iter()
+                        .map(|row| row.iter().map(|value| (fmt::cell_text(value), value.is_number())).collect())
+                        .collect();
+                    for line in MarkdownFormatter::new(None, max_col_width).render(&result.columns, &records) {
+                        out.line(line);
                     }
                 }
             }
-            Ok(())
+            out.set_json(result.to_json());
         }
-        Err(e) => {
-            eprintln!("Showing partial data due to: {}", e);
-            eprintln!("Falling back to direct table scan...");
-            
-            // Direct table scan fallback
-            let iter = db.prefix_iterator(table_name.as_bytes());
-            for item in iter {
-                let (key, value) = item?;
-                println!("{}: {}", 
-                    String::from_utf8_lossy(&key),
-                    String::from_utf8_lossy(&value));
+        SqlOutcome::Committed { hash, changes } => {
+            out.set_json(json!({ "hash": hex::encode(hash), "changes": changes }));
+        }
+        SqlOutcome::Unchanged => {
+            out.line("No rows changed");
+            out.set_json(json!({ "hash": null, "changes": 0 }));
+        }
+        SqlOutcome::CounterSwap { hash } => {
+            // Like UPDATE's row count in other databases: 0 means the expected value didn't match
+            out.line(format!("UPDATE {}", u8::from(hash.is_some())));
+            out.set_json(json!({ "swapped": hash.is_some(), "hash": hash.map(hex::encode) }));
+        }
+        SqlOutcome::View { name, hash } => {
+            match hash {
+                Some(hash) => out.line(format!("Materialized view '{}' updated in commit {}", name, hex::encode(hash))),
+                None => out.line(format!("Materialized view '{}' is up to date", name)),
             }
-            Ok(())
+            out.set_json(json!({ "view": name, "hash": hash.map(hex::encode) }));
+        }
+        SqlOutcome::Function { name, dropped, existed } => {
+            match (dropped, existed) {
+                (false, _) => out.line(format!("Function '{}' created", name)),
+                (true, true) => out.line(format!("Function '{}' dropped", name)),
+                (true, false) => out.line(format!("No function named '{}'", name)),
+            }
+            out.set_json(json!({ "function": name, "dropped": dropped, "existed": existed }));
+        }
+        SqlOutcome::Attachment { alias, commit } => {
+            match commit {
+                Some(commit) => out.line(format!("Attached '{}' at {}", alias, hex::encode(commit))),
+                None => out.line(format!("Detached '{}'", alias)),
+            }
+            out.set_json(json!({ "alias": alias, "attached": commit.is_some(), "commit": commit.map(hex::encode) }));
+        }
+        SqlOutcome::PhoneticIndex { table, column, dropped, rows } => {
+            match dropped {
+                false => out.line(format!("Phonetic index on {}({}) created over {} row(s)", table, column, rows)),
+                true => out.line(format!("Phonetic index on {}({}) dropped", table, column)),
+            }
+            out.set_json(json!({ "table": table, "column": column, "dropped": dropped, "rows": rows }));
         }
     }
+    Ok(())
 }
 
-pub fn handle_revert(storage: &CommitStorage, commit_hash: &str) -> Result<()> {
-    // Validate commit hash format
-    if commit_hash.len() != 64 {
-        return Err(BranchDBError::InvalidInput(
-            "Commit hash must be 64 characters long".into()
-        ));
-    }
-    
-    let hash_bytes = hex::decode(commit_hash)?;
-    let hash_array: [u8; 32] = hash_bytes.try_into()
-        .map_err(|_| BranchDBError::InvalidInput("Invalid commit hash".into()))?;
-    
-    // Verify the commit exists and show info
-    let target_commit = storage.get_commit_by_hash(&hash_array)?;
-    println!("Reverting to commit: {}", commit_hash);
-    println!("Original commit message: {}", target_commit.message);
-    println!("Date: {}", target_commit.timestamp);
-    
-    // Get current state before revert
-    println!("\nCurrent state:");
-    let before_state: Vec<_> = storage.db.iterator(rocksdb::IteratorMode::Start)
-        .filter_map(|item| item.ok())
-        .collect();
-    
-    // Filter and display only relevant table data (skip internal metadata)
-    for (key, value) in &before_state {
-        let key_str = String::from_utf8_lossy(key);
-        if !key_str.starts_with("_internal") {  // Skip internal metadata
-            println!("{}: {}", key_str, String::from_utf8_lossy(value));
-        }
-    }
-    
-    // Perform the revert
-    storage.revert_to_commit(&hash_array)?;
-    
-    // Verify and show new state
-    let current_head = storage.get_head()?
-        .ok_or(BranchDBError::InvalidInput("No HEAD commit".into()))?;
-    let current_commit = storage.get_commit_by_hash(&current_head)?;
-    
-    println!("\nSuccessfully reverted to commit {}", commit_hash);
-    println!("New HEAD: {}", hex::encode(current_head));
-    println!("New commit message: {}", current_commit.message);
-    
-    // Get state after revert
-    println!("\nState after revert:");
-    let after_state: Vec<_> = storage.db.iterator(rocksdb::IteratorMode::Start)
-        .filter_map(|item| item.ok())
-        .collect();
-    
-    // Filter and display only relevant table data
-    for (key, value) in &after_state {
-        let key_str = String::from_utf8_lossy(key);
-        if !key_str.starts_with("_internal") {
-            println!("{}: {}", key_str, String::from_utf8_lossy(value));
-        }
+pub fn handle_sql(storage: &CommitStorage, command: &str, format: OutputFormat, yes: bool, out: &mut Output) -> Result<()> {
+    if let Some(statement) = sql::explain_target(command) {
+        return handle_explain(storage, statement, format, out);
     }
-    
-    // Compare states (only for user-visible data)
-    println!("\nChanges:");
-    let before_keys: HashSet<_> = before_state.iter()
-        .filter(|(k, _)| !String::from_utf8_lossy(k).starts_with("_internal"))
-        .map(|(k, _)| k)
-        .collect();
-        
-    let after_keys: HashSet<_> = after_state.iter()
-        .filter(|(k, _)| !String::from_utf8_lossy(k).starts_with("_internal"))
-        .map(|(k, _)| k)
-        .collect();
-    
-    // Added entries
-    for key in after_keys.difference(&before_keys) {
-        println!("+ {}", String::from_utf8_lossy(key));
+    if let Some((query, outfile)) = outfile::parse_into_outfile(command)? {
+        return handle_outfile(storage, query, &outfile, out);
     }
-    
-    // Removed entries
-    for key in before_keys.difference(&after_keys) {
-        println!("- {}", String::from_utf8_lossy(key));
+    match copy::parse_copy(command)? {
+        Some((table, copy_format)) => handle_copy(storage, &table, copy_format, out),
+        None => handle_query(storage, command, format, None, yes, out),
     }
-    
-    // Changed entries
-    for (key, after_value) in &after_state {
-        let key_str = String::from_utf8_lossy(key);
-        if !key_str.starts_with("_internal") {
-            if let Some((_, before_value)) = before_state.iter().find(|(k, _)| k == key) {
-                if before_value != after_value {
-                    println!("≠ {} (changed)", key_str);
-                }
-            }
+}
+
+// `sql --file`: the file's statements in order, each run as `sql` runs one, so every write is
+// its own commit. Stops at the first statement that fails; those before it stay committed.
+pub fn handle_sql_file(storage: &CommitStorage, path: &str, format: OutputFormat, yes: bool, out: &mut Output) -> Result<()> {
+    let statements = sql::split_statements(&fs::read_to_string(path)?);
+    let mut results = Vec::new();
+    for (number, statement) in statements.iter().enumerate() {
+        if let Err(e) = handle_sql(storage, statement, format, yes, out) {
+            out.line(format!("Statement {} of {} failed: {}", number + 1, path, statement));
+            out.set_json(json!({ "file": path, "statements": statements.len(), "failed": number + 1, "results": results }));
+            return Err(e);
         }
+        results.push(out.take_json());
     }
-    
+
+    let commits = results.iter().filter(|result| result.get("hash").is_some_and(|hash| !hash.is_null())).count();
+    out.line(format!("Ran {} statements from {} ({} commits)", statements.len(), path, commits));
+    out.set_json(json!({ "file": path, "statements": statements.len(), "commits": commits, "results": results }));
     Ok(())
 }
 
-pub fn handle_diff(storage: &CommitStorage, from: &str, to: &str) -> Result<()> {
-    let from_bytes = hex::decode(from)?;
-    let from_array: [u8; 32] = from_bytes.try_into()
-        .map_err(|_| BranchDBError::InvalidInput("Invalid commit hash length".into()))?;
-    
-    let to_bytes = hex::decode(to)?;
-    let to_array: [u8; 32] = to_bytes.try_into()
-        .map_err(|_| BranchDBError::InvalidInput("Invalid commit hash length".into()))?;
-    
-    let diffs = storage.get_commit_diffs(&from_array, &to_array)?;
-    
-    println!("Changes from {} to {}:", from, to);
-    for diff in diffs {
-        println!("- {:?}", diff);
+// `SELECT ... INTO OUTFILE`: writes the query's rows to a file, which may not exist yet, as
+// MySQL does. The query's columns are left out of the file and reported instead.
+pub fn handle_outfile(storage: &CommitStorage, query: &str, outfile: &Outfile, out: &mut Output) -> Result<()> {
+    let result = match SqlExecutor::execute(storage, query)? {
+        SqlOutcome::Rows(result) => result,
+        _ => return Err(BranchDBError::InvalidInput("INTO OUTFILE needs a query that returns rows".into())),
+    };
+    let file = fs::OpenOptions::new().write(true).create_new(true).open(&outfile.path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => BranchDBError::InvalidInput(format!("File '{}' already exists", outfile.path)),
+        _ => e.into(),
+    })?;
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(outfile.delimiter)
+        .quote(outfile.quote)
+        .quote_style(outfile.quote_style)
+        .terminator(outfile.terminator)
+        .from_writer(file);
+    for row in &result.rows {
+        wtr.write_record(row.iter().map(fmt::cell_text))?;
     }
-    
+    wtr.flush()?;
+
+    out.line(format!("Wrote {} rows to {}", result.rows.len(), outfile.path));
+    out.set_json(json!({ "file": outfile.path, "rows": result.rows.len(), "columns": result.columns }));
     Ok(())
 }
 
-pub fn handle_history(storage: &CommitStorage, limit: Option<usize>) -> Result<()> {
-    let history = storage.get_commit_history()?;
-    
-    let display_count = limit.unwrap_or(history.len());
-    for commit in history.iter().take(display_count) {
-        let hash = blake3::hash(&bincode::serialize(commit)?);
-        println!("{}: {}", hex::encode(&hash.as_bytes()[..8]), commit.message);
-        println!("  Date: {}", commit.timestamp);
-        println!("  Changes: {}", commit.changes.len());
-        println!();
+// `EXPLAIN INSERT|UPDATE|DELETE`: how many rows the write would read and change, without
+// running it.
+pub fn handle_explain(storage: &CommitStorage, statement: &str, format: OutputFormat, out: &mut Output) -> Result<()> {
+    let plan = sql::explain_dml(storage, statement)?;
+    match format {
+        OutputFormat::Json => out.line(serde_json::to_string_pretty(&plan)?),
+        OutputFormat::Table | OutputFormat::Csv | OutputFormat::Markdown => {
+            for line in plan.to_lines() {
+                out.line(line);
+            }
+        }
     }
-    
+    out.set_json(serde_json::to_value(&plan)?);
     Ok(())
 }
 
-pub fn handle_init(path: &str) -> Result<()> {
-    if Path::new(path).exists() {
-        return Err(BranchDBError::InvalidInput("Path already exists".into()));
-    }
-    
-    fs::create_dir_all(path)?;
-    let _storage = CommitStorage::open(path)?;
-    println!("Initialized empty GitDB repository in {}", path);
+// `COPY <table> FROM stdin`: imports rows from stdin until EOF, like `import-csv` does from a file.
+pub fn handle_copy(storage: &CommitStorage, table: &str, format: CopyFormat, out: &mut Output) -> Result<()> {
+    let reader = copy::stdin_reader();
+    let (rows, commits) = match format {
+        CopyFormat::Csv => {
+            let mut rdr = csv::Reader::from_reader(reader);
+            let headers = rdr.headers().map_err(csv_io_error)?.clone();
+            let records = rdr.into_records().map(|record| csv_row(&headers, record.map_err(csv_io_error)?));
+            import_rows(storage, "stdin", table, false, records, &mut |_, _| Ok(()))?
+        }
+        CopyFormat::Json => {
+            let lines = std::io::BufRead::lines(std::io::BufReader::new(reader))
+                .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+                .map(|line| json_row(&line?));
+            import_rows(storage, "stdin", table, false, lines, &mut |_, _| Ok(()))?
+        }
+    };
+
+    out.line(format!("COPY {}", rows));
+    out.set_json(json!({
+        "table": table,
+        "rows": rows,
+        "commits": commits.iter().map(hex::encode).collect::<Vec<_>>(),
+    }));
     Ok(())
 }
 
-pub fn handle_checkout(storage: &CommitStorage, target: &str) -> Result<()> {
-    // Try as branch first
-    let branch_key = format!("branch:{}", target);
-    if let Some(branch_head) = storage.db.get(branch_key.as_bytes())? {
-        // Verify the branch head exists
-        if storage.db.get(&branch_head)?.is_none() {
-            return Err(BranchDBError::InvalidInput(
-                format!("Branch '{}' points to invalid commit", target)
-            ));
+// Progress of an import run with `--checkpoint`, rewritten after every batch commit so a rerun
+// with `--resume` can skip what is already committed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportCheckpoint {
+    pub file: String,
+    pub table: String,
+    // Data rows, counted from the first one after the header, committed so far; the next run
+    // starts at this index
+    pub last_row_index: usize,
+    // Hex hash of the commit holding the last of them
+    pub last_commit_hash: String,
+}
+
+impl ImportCheckpoint {
+    fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
         }
-        
-        storage.db.put(b"HEAD", &branch_head)?;
-        println!("Switched to branch '{}'", target);
-        return Ok(());
     }
 
-    // Try as commit hash
-    if target.len() == 64 {
-        let hash_bytes = hex::decode(target)?;
-        if hash_bytes.len() != 32 {
-            return Err(BranchDBError::InvalidInput(
-                "Commit hash must be 32 bytes".into()
-            ));
+    // Written to a temporary file and renamed over the old one, so a crash mid-write leaves the
+    // previous checkpoint in place.
+    fn save(&self, path: &Path) -> Result<()> {
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct CsvImportOptions<'a> {
+    pub bulk: bool,
+    pub checkpoint: Option<&'a Path>,
+    pub resume: bool,
+    pub infer_types: bool,
+    pub strict: bool,
+}
+
+// With `checkpoint`, progress is recorded after every batch and the file removed once the whole
+// CSV is in. `resume` starts after the rows a checkpoint records; without one it starts over.
+// Rows are skipped by reading past them, since a CSV reader can't seek. With `infer_types`, a
+// table without declared columns first gets a schema inferred from the rows about to be
+// imported, committed on its own.
+pub fn handle_import_csv(storage: &CommitStorage, file: &str, table: &str, options: &CsvImportOptions, out: &mut Output) -> Result<()> {
+    let CsvImportOptions { bulk, checkpoint, resume, .. } = *options;
+    let mut skip = 0;
+    if let Some(saved) = checkpoint.filter(|_| resume).map(ImportCheckpoint::load).transpose()?.flatten() {
+        if saved.file != file || saved.table != table {
+            return Err(BranchDBError::InvalidInput(format!(
+                "The checkpoint is for importing {} into {}, not {} into {}", saved.file, saved.table, file, table
+            )));
         }
-        
-        // Create array copy without consuming hash_bytes
-        let mut hash_array = [0u8; 32];
-        hash_array.copy_from_slice(&hash_bytes);
-        
-        if storage.db.get(&hash_array)?.is_some() {
-            storage.db.put(b"HEAD", &hash_bytes)?;
-            println!("Switched to commit {}", target);
-            return Ok(());
+        let hash = refs::resolve(storage, &saved.last_commit_hash)?;
+        let in_history = match storage.get_head()? {
+            Some(head) => storage.is_ancestor(&hash, &head)?,
+            None => false,
+        };
+        if !in_history {
+            return Err(BranchDBError::InvalidInput(format!(
+                "The checkpoint's last commit {} is not in the history of HEAD", saved.last_commit_hash
+            )));
+        }
+        skip = saved.last_row_index;
+        out.line(format!("Resuming after row {}", skip));
+    }
+
+    let mut rdr = csv::Reader::from_path(file)?;
+    let headers = rdr.headers()?.clone();
+    let mut records = rdr.into_records().skip(skip);
+    let mut commits = Vec::new();
+    let rows: Box<dyn Iterator<Item = Result<(String, Vec<u8>)>>> = match options.infer_types {
+        false => Box::new(records.map(move |record| csv_row(&headers, record?))),
+        true => {
+            let mut schema = storage.get_table_schema(table, None)?;
+            let mut sample = Vec::new();
+            if row::schema_columns(&schema).is_empty() {
+                sample = records.by_ref().take(INFER_SAMPLE_ROWS).collect::<csv::Result<Vec<_>>>()?;
+                schema = SchemaInferencer::infer(&headers, &sample);
+                let value = bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&schema)?))?;
+                let message = format!("Infer schema of {} from {}", table, file);
+                let (table, id) = (table.to_string(), "!schema".to_string());
+                let change = match storage.db.get(encode_row_key(&table, &id).as_bytes())? {
+                    Some(_) => Change::Update { table, id, value },
+                    None => Change::Insert { table, id, value },
+                };
+                commits.push(storage.create_commit(&message, vec![change])?);
+                out.line(format!("Inferred columns: {}", schema["columns"]));
+            }
+            let builder = TypedRowBuilder { headers, strict: options.strict };
+            Box::new(sample.into_iter().map(Ok).chain(records).map(move |record| {
+                let record = record?;
+                let id = record.get(0).ok_or_else(|| BranchDBError::InvalidInput("CSV missing ID column".into()))?.to_string();
+                Ok((id, serde_json::to_vec(&builder.build(&record, &schema)?)?))
+            }))
         }
+    };
+    let mut record_progress = |imported: usize, hash: &[u8; 32]| -> Result<()> {
+        let Some(path) = checkpoint else { return Ok(()) };
+        ImportCheckpoint {
+            file: file.to_string(),
+            table: table.to_string(),
+            last_row_index: skip + imported,
+            last_commit_hash: hex::encode(hash),
+        }.save(path)
+    };
+    let (rows, imported) = import_rows(storage, file, table, bulk, rows, &mut record_progress)?;
+    commits.extend(imported);
+    if let Some(path) = checkpoint.filter(|path| path.exists()) {
+        fs::remove_file(path)?;
     }
 
-    Err(BranchDBError::InvalidInput(
-        format!("No branch or commit found with reference '{}'", target)
-    ))
+    out.set_json(json!({
+        "table": table,
+        "rows": rows,
+        "skipped": skip,
+        "commits": commits.iter().map(hex::encode).collect::<Vec<_>>(),
+    }));
+    Ok(())
 }
 
-pub fn handle_log(storage: &CommitStorage, verbose: bool) -> Result<()> {
-    let mut current_hash = storage.get_head()?;
+// A CSV record as an (id, JSON object) row; the first column is the id.
+fn csv_row(headers: &csv::StringRecord, record: csv::StringRecord) -> Result<(String, Vec<u8>)> {
+    let id = record.get(0)
+        .ok_or_else(|| BranchDBError::InvalidInput("CSV missing ID column".into()))?;
     
-    while let Some(hash) = current_hash {
-        let commit = storage.get_commit_by_hash(&hash)?;
-        
-        if verbose {
-            println!("commit {}", hex::encode(&hash)); // Show full hash
-            println!("Author: <user>");
-            println!("Date:   {}", commit.timestamp);
-            println!("\n    {}\n", commit.message);
-        } else {
-            println!("{} {}", hex::encode(&hash), commit.message); // Show full hash instead of short_hash
+    let mut row = Vec::new();
+    for (i, field) in record.iter().enumerate() {
+        row.push(format!("\"{}\":\"{}\"", headers.get(i).unwrap_or(&i.to_string()), field));
+    }
+    Ok((id.to_string(), format!("{{{}}}", row.join(",")).into_bytes()))
+}
+
+// An NDJSON line as an (id, JSON object) row; the object must have an `id` field.
+fn json_row(line: &str) -> Result<(String, Vec<u8>)> {
+    json_object_row(serde_json::from_str(line)?, "id")
+}
+
+// A JSON object as an (id, JSON object) row, keyed by the string or number in `id_field`.
+fn json_object_row(value: serde_json::Value, id_field: &str) -> Result<(String, Vec<u8>)> {
+    let id = match value.get(id_field) {
+        Some(serde_json::Value::String(id)) => id.clone(),
+        Some(id @ serde_json::Value::Number(_)) => id.to_string(),
+        _ => return Err(BranchDBError::InvalidInput(format!("JSON row has no string or number `{}`: {}", id_field, value))),
+    };
+    if !value.is_object() {
+        return Err(BranchDBError::InvalidInput(format!("Expected a JSON object per row, got: {}", value)));
+    }
+    Ok((id, serde_json::to_vec(&value)?))
+}
+
+// Fetches every page before anything is committed, and checks each object against the
+// table's schema, so a bad page or row leaves the table as it was. The commits name the URL.
+pub fn handle_import_http(storage: &CommitStorage, source: &HttpSource, table: &str, id_field: &str, dry_run: bool, out: &mut Output) -> Result<()> {
+    let pages = source.fetch_pages()?;
+    let schema = storage.get_table_schema(table, None)?;
+    let mut rows = Vec::new();
+    for page in &pages {
+        for value in &page.rows {
+            row::check_row_against_schema(table, value, &schema)?;
+            rows.push(json_object_row(value.clone(), id_field)?);
         }
-        
-        current_hash = commit.parents.get(0).cloned();
     }
-    
+    let page_counts: Vec<serde_json::Value> = pages.iter()
+        .map(|page| json!({ "url": page.url, "rows": page.rows.len() }))
+        .collect();
+
+    if dry_run {
+        for page in &pages {
+            out.line(format!("{}: {} row(s)", page.url, page.rows.len()));
+        }
+        out.line(format!("Would import {} row(s) from {} page(s) into {}", rows.len(), pages.len(), table));
+        out.set_json(json!({ "table": table, "rows": rows.len(), "pages": page_counts, "dry_run": true, "commits": [] }));
+        return Ok(());
+    }
+
+    let (count, commits) = import_rows(storage, &source.url, table, false, rows.into_iter().map(Ok), &mut |_, _| Ok(()))?;
+    out.line(format!("Imported {} row(s) from {} page(s) into {}", count, pages.len(), table));
+    out.set_json(json!({
+        "table": table,
+        "rows": count,
+        "pages": page_counts,
+        "dry_run": false,
+        "commits": commits.iter().map(hex::encode).collect::<Vec<_>>(),
+    }));
     Ok(())
 }
 
-pub fn handle_branch_list(branch_mgr: &BranchManager, verbose: bool) -> Result<()> {
-    let branches = branch_mgr.list_branches()?;
-    let current = branch_mgr.get_current_branch()?;
+// Read failures on stdin (like the terminal timeout) surface as I/O errors, not CSV errors.
+fn csv_io_error(error: csv::Error) -> BranchDBError {
+    if !error.is_io_error() {
+        return BranchDBError::CsvError(error);
+    }
+    match error.into_kind() {
+        csv::ErrorKind::Io(e) => BranchDBError::IoError(e),
+        _ => unreachable!("is_io_error checked the kind"),
+    }
+}
+
+// Inserts rows into `table`, committing every `import_batch_size` rows, or once at the end
+// through a BatchWriter with `bulk`. `on_commit` is called after each commit with the number of
+// rows committed so far. Returns the row count and the commits created.
+fn import_rows(
+    storage: &CommitStorage,
+    source: &str,
+    table: &str,
+    bulk: bool,
+    rows: impl Iterator<Item = Result<(String, Vec<u8>)>>,
+    on_commit: &mut dyn FnMut(usize, &[u8; 32]) -> Result<()>,
+) -> Result<(usize, Vec<[u8; 32]>)> {
+    let batch_size = storage.options().import_batch_size.max(1);
+    let mut changes = Vec::new();
+    let mut commits = Vec::new();
+    let mut count = 0;
+    // With --bulk every row goes through one BatchWriter and a single commit
+    let mut writer = bulk.then(|| BatchWriter::new(storage));
     
-    println!("Branches:");
-    for branch in branches {
-        if current.as_ref() == Some(&branch) {
-            print!("* ");
-        } else {
-            print!("  ");
-        }
-        
-        print!("{}", branch);
+    for row in rows {
+        let (id, value) = row?;
+        let change = Change::Insert {
+            table: table.to_string(),
+            id,
+            value: bincode::serialize(&CrdtValue::Register(value))?,
+        };
         
-        if verbose {
-            if let Some(commit_hash) = branch_mgr.get_branch_head(&branch)? {
-                println!(" ({})", hex::encode(commit_hash));
-            } else {
-                println!(" (no commit)");
-            }
-        } else {
-            println!();
+        count += 1;
+        metrics::increment(Counter::RowsImported, 1);
+        if let Some(writer) = writer.as_mut() {
+            writer.add(change)?;
+            continue;
+        }
+        changes.push(change);
+
+        // Batch processing
+        if changes.len() == batch_size {
+            let hash = storage.create_commit(&format!("Batch import {} into {}", source, table), changes)?;
+            on_commit(count, &hash)?;
+            commits.push(hash);
+            changes = Vec::new();
+        }
+    }
+
+    // Final commit for remaining changes
+    if let Some(writer) = writer.filter(|w| !w.is_empty()) {
+        commits.push(writer.finalize(&format!("Bulk import {} into {}", source, table))?);
+    } else if !changes.is_empty() {
+        let hash = storage.create_commit(&format!("Import {} into {}", source, table), changes)?;
+        on_commit(count, &hash)?;
+        commits.push(hash);
+    }
+    Ok((count, commits))
+}
+
+pub struct CsvExportOptions {
+    pub columns: Option<Vec<String>>,
+    pub include_header: bool,
+    pub delimiter: u8,
+}
+
+impl Default for CsvExportOptions {
+    fn default() -> Self {
+        Self {
+            columns: None,
+            include_header: true,
+            delimiter: b',',
         }
     }
+}
+
+impl CsvExportOptions {
+    pub fn from_args(columns: Option<&str>, no_header: bool, delimiter: Option<char>) -> Result<Self> {
+        let columns = columns.map(|list| {
+            list.split(',')
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty())
+                .collect::<Vec<_>>()
+        });
+
+        let delimiter = match delimiter {
+            Some(c) if c.is_ascii() => c as u8,
+            Some(c) => return Err(BranchDBError::InvalidInput(
+                format!("Delimiter must be a single ASCII character, got '{}'", c)
+            )),
+            None => b',',
+        };
+
+        Ok(Self {
+            columns,
+            include_header: !no_header,
+            delimiter,
+        })
+    }
+}
+
+pub fn handle_export_git(storage: &CommitStorage, dest: &str, out: &mut Output) -> Result<()> {
+    let commits = storage.export_git(dest)?;
+    out.line(format!("Exported {} commit(s) to {}", commits, dest));
+    out.set_json(json!({ "dest": dest, "commits": commits }));
     Ok(())
 }
 
-pub fn handle_merge(storage: &CommitStorage, branch_name: &str) -> Result<()> {
-    let branch_key = format!("branch:{}", branch_name);
-    let branch_head = storage.db.get(branch_key.as_bytes())?
-        .ok_or_else(|| BranchDBError::InvalidInput(format!("Branch {} not found", branch_name)))?;
-    
-    let current_head = storage.db.get(b"HEAD")?
-        .ok_or_else(|| BranchDBError::InvalidInput("HEAD not found".into()))?;
-    
-    if branch_head == current_head {
-        return Err(BranchDBError::InvalidInput("Already up to date".into()));
+// Writes the table as of a commit (HEAD by default). Rows are read with the schema of that
+// commit, so columns dropped since then are still exported from an older commit.
+pub fn handle_export_csv(
+    storage: &CommitStorage,
+    table: &str,
+    file_path: &str,
+    commit_hash: Option<&str>,
+    options: &CsvExportOptions,
+    out: &mut Output,
+) -> Result<()> {
+    let hash = match commit_hash {
+        Some(reference) => Some(refs::resolve(storage, reference)?),
+        None => storage.get_head()?,
+    };
+    let entries: Box<dyn Iterator<Item = Result<(String, CrdtValue)>>> = match &hash {
+        Some(hash) => Box::new(storage.iter_table(table, hash)?),
+        None => Box::new(std::iter::empty()),
+    };
+
+    // A table exists through its schema alone, so only one with nothing at all is missing
+    let mut entries = entries.peekable();
+    if entries.peek().is_none() {
+        return Err(BranchDBError::TableNotFound { name: table.to_string() });
     }
-    
-    let mut current_engine = CrdtEngine::new();
-    let mut branch_engine = CrdtEngine::new();
-    
-    // Helper function to load state from a commit hash
-    fn load_state(storage: &CommitStorage, mut hash: Vec<u8>, engine: &mut CrdtEngine) -> Result<()> {
-        while !hash.is_empty() {
-            // Convert Vec<u8> to [u8; 32]
-            let hash_array: [u8; 32] = hash.as_slice().try_into()
-                .map_err(|_| BranchDBError::InvalidInput("Invalid commit hash length".into()))?;
-            
-            let commit = storage.get_commit_by_hash(&hash_array)?;
-            for change in &commit.changes {
-                engine.apply_change(change)?;
+
+    // Metadata keys come first, so the schema is known before any row is decoded
+    let mut schema = serde_json::json!({});
+    while let Some(entry) = entries.next_if(|entry| matches!(entry, Ok((id, _)) if id.starts_with('!'))) {
+        let (id, value) = entry?;
+        if id == "!schema" {
+            schema = crdt_json(&value);
+        }
+    }
+
+    let schema_columns: Vec<String> = match schema.get("columns") {
+        Some(columns) => columns.as_object()
+            .ok_or(BranchDBError::TypeMismatch("Invalid schema format".into()))?
+            .keys()
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Select and order the requested columns
+    let columns = match &options.columns {
+        Some(requested) => {
+            for column in requested {
+                if !schema_columns.contains(column) {
+                    return Err(BranchDBError::InvalidInput(
+                        format!("Column '{}' does not exist in table '{}'", column, table)
+                    ));
+                }
             }
-            hash = commit.parents.get(0).map(|p| p.to_vec()).unwrap_or_default();
+            requested.clone()
         }
-        Ok(())
+        None => schema_columns,
+    };
+
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(options.delimiter)
+        .from_path(file_path)?;
+
+    // Write headers
+    if options.include_header && !columns.is_empty() {
+        wtr.write_record(&columns)?;
     }
-    
-    // Load current branch state
-    load_state(storage, current_head.to_vec(), &mut current_engine)?;
-    
-    // Load other branch state
-    load_state(storage, branch_head.to_vec(), &mut branch_engine)?;
-    
-    // Merge the states
-    let changes = merge_states(&mut current_engine, &branch_engine)?;
-    
-    if changes.is_empty() {
-        println!("Already up to date");
-        return Ok(());
+
+    // Write data
+    let mut rows = 0;
+    for entry in entries {
+        let (_, value) = entry?;
+        if let CrdtValue::Register(_) = value {
+            let row = decode_row(&value, &schema);
+            let record: Vec<String> = columns.iter()
+                .map(|column| row.get(column).map(fmt::cell_text).unwrap_or_default())
+                .collect();
+            
+            wtr.write_record(&record)?;
+            rows += 1;
+        }
     }
-    
-    // Create merge commit
-    let hash = storage.create_commit(
-        &format!("Merge branch '{}'", branch_name),
-        changes
-    )?;
-    
-    println!("Created merge commit: {}", hex::encode(hash));
+
+    wtr.flush()?;
+    out.set_json(json!({
+        "table": table,
+        "file": file_path,
+        "commit": hash.map(hex::encode),
+        "rows": rows,
+        "columns": columns,
+    }));
     Ok(())
 }
 
-fn validate_value_type(value: &str, expected_type: &str) -> Result<()> {
-    match expected_type.to_uppercase().as_str() {
-        "INTEGER" | "INT" => {
-            value.parse::<i64>()
-                .map_err(|_| BranchDBError::TypeMismatch(format!("Expected integer, got {}", value)))?;
-        },
-        "FLOAT" | "REAL" => {
-            value.parse::<f64>()
-                .map_err(|_| BranchDBError::TypeMismatch(format!("Expected float, got {}", value)))?;
-        },
-        "BOOLEAN" | "BOOL" => {
-            if !value.eq_ignore_ascii_case("true") && !value.eq_ignore_ascii_case("false") {
-                return Err(BranchDBError::TypeMismatch(format!("Expected boolean, got {}", value)));
-            }
-        },
-        _ => {} // No validation for TEXT/STRING
+// Settings for `--format markdown` and `export-md`.
+pub struct MarkdownOptions {
+    pub caption: bool,
+    pub max_col_width: Option<usize>,
+}
+
+impl MarkdownOptions {
+    fn formatter(&self, table: &str, commit: &[u8]) -> MarkdownFormatter {
+        let caption = self.caption.then(|| format!("Table `{}` at commit `{}`", table, hex::encode(commit)));
+        MarkdownFormatter::new(caption, self.max_col_width)
+    }
+}
+
+// Settings for `show-table`.
+pub struct ShowTableOptions {
+    pub format: OutputFormat,
+    pub border: bool,
+    pub include_deleted: bool,
+    pub markdown: MarkdownOptions,
+    // `--columns`: every column when None
+    pub columns: Option<Vec<String>>,
+    // `--where`: a column and the value, as a cell shows it, that rows must have
+    pub filter: Option<(String, String)>,
+}
+
+// `--where <column>=<value>`; quotes around the value are dropped.
+fn parse_row_filter(clause: &str) -> Result<(String, String)> {
+    let (column, value) = clause.split_once('=')
+        .filter(|(column, _)| !column.trim().is_empty())
+        .ok_or_else(|| BranchDBError::InvalidInput(format!("Expected --where <column>=<value>, got '{}'", clause)))?;
+    let value = value.trim();
+    let value = ['\'', '"'].iter()
+        .find_map(|q| value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)))
+        .unwrap_or(value);
+    Ok((column.trim().to_string(), value.to_string()))
+}
+
+// Writes the table as of the commit, or HEAD, as a markdown table with a trailing newline.
+pub fn handle_export_md(
+    storage: &CommitStorage,
+    table: &str,
+    file_path: &str,
+    commit_hash: Option<&str>,
+    caption: bool,
+    max_col_width: Option<usize>,
+    out: &mut Output,
+) -> Result<()> {
+    let hash = match commit_hash {
+        Some(reference) => refs::resolve(storage, reference)?,
+        None => storage.get_head()?.ok_or_else(|| BranchDBError::TableNotFound { name: table.to_string() })?,
+    };
+    let rows = storage.table_state_at(table, &hash)?;
+    if rows.is_empty() {
+        return Err(BranchDBError::TableNotFound { name: table.to_string() });
     }
+    let schema = match rows.get("!schema") {
+        Some(value) => crdt_json(value),
+        None => json!({}),
+    };
+    let options = MarkdownOptions { caption, max_col_width };
+    let mut lines = options.formatter(table, &hash).render_table(&rows, &schema, None);
+    lines.push(String::new());
+    fs::write(file_path, lines.join("\n"))?;
+
+    let count = rows.keys().filter(|id| !id.starts_with('!')).count();
+    out.line(format!("Exported {} row(s) of {} to {}", count, table, file_path));
+    out.set_json(json!({ "table": table, "file": file_path, "rows": count, "commit": hex::encode(hash) }));
     Ok(())
-}
\ No newline at end of file
+}
+
+pub fn handle_show_table(
+    storage: &CommitStorage,
+    table_name: &str,
+    commit_hash: Option<&str>,
+    options: &ShowTableOptions,
+    out: &mut Output,
+) -> Result<()> {
+    let db = &*storage.db;
+    let processor = QueryProcessor::new(db);
+    let hash = match commit_hash {
+        Some(reference) => refs::resolve(storage, reference)?.to_vec(),
+        None => processor.get_head_hash()?,
+    };
+
+    // A table with neither a schema nor rows at the commit does not exist there
+    let state = processor.get_table_at_commit(table_name, &hash);
+    if state.as_ref().is_ok_and(|rows| rows.is_empty()) {
+        return Err(BranchDBError::TableNotFound { name: table_name.to_string() });
+    }
+
+    let mut schema = serde_json::Value::Null;
+    let mut json_rows = serde_json::Map::new();
+    
+    match state {
+        Ok(mut rows) => {
+            if let Some(value @ CrdtValue::Register(_)) = rows.get("!schema") {
+                schema = crdt_json(value);
+            }
+            if options.include_deleted {
+                trash::reveal_trashed(&mut rows, &mut schema)?;
+            }
+            if let Some((column, value)) = &options.filter {
+                rows.retain(|id, row| {
+                    // Without a field of its own, a row's id is its key
+                    id.starts_with('!') || match decode_row(row, &schema).get(column) {
+                        Some(field) => fmt::cell_text(field) == *value,
+                        None => column == "id" && id == value,
+                    }
+                });
+            }
+            // Named after the columns of the schema at that commit, not today's
+            for (id, value) in &rows {
+                if id != "!schema" && !id.starts_with(trash::TRASH_PREFIX) {
+                    let row = decode_row(value, &schema);
+                    let row = match &options.columns {
+                        Some(columns) => RowProjector::project(&row, columns),
+                        None => row,
+                    };
+                    json_rows.insert(id.clone(), row);
+                }
+            }
+            let columns = options.columns.as_deref();
+
+            let document = json!({
+                "table": table_name,
+                "commit": hex::encode(&hash),
+                "schema": schema,
+                "rows": json_rows,
+            });
+            match options.format {
+                OutputFormat::Table => {
+                    out.line(format!("Table '{}' at commit {}:", table_name, hex::encode(&hash)));
+                    out.line(TableFormatter::new(options.border).render(&rows, &schema, columns));
+                }
+                OutputFormat::Json => out.line(serde_json::to_string_pretty(&document)?),
+                OutputFormat::Csv => {
+                    for line in fmt::csv_lines(&rows, &schema, columns)? {
+                        out.line(line);
+                    }
+                }
+                OutputFormat::Markdown => {
+                    for line in options.markdown.formatter(table_name, &hash).render_table(&rows, &schema, columns) {
+                        out.line(line);
+                    }
+                }
+            }
+            out.set_json(document);
+            return Ok(());
+        }
+        Err(e) => {
+            warn!(error = %e, "showing partial data, falling back to direct table scan");
+            out.line(format!("Table '{}' at commit {}:", table_name, hex::encode(&hash)));
+            
+            // Direct table scan fallback
+            let prefix = table_key_prefix(table_name);
+            for item in db.prefix_iterator(prefix.as_bytes()) {
+                let (key, value) = item?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                let Some((_, id)) = decode_row_key(&key) else { continue };
+                out.line(format!("{}: {}", id, String::from_utf8_lossy(&value)));
+                json_rows.insert(id, json!(String::from_utf8_lossy(&value)));
+            }
+        }
+    }
+
+    out.set_json(json!({
+        "table": table_name,
+        "commit": hex::encode(&hash),
+        "schema": schema,
+        "rows": json_rows,
+    }));
+    Ok(())
+}
+
+pub fn handle_show(storage: &CommitStorage, table: &str, id: &str, commit: Option<&str>, out: &mut Output) -> Result<()> {
+    let not_found = || BranchDBError::RowNotFound { table: table.to_string(), id: id.to_string() };
+    let hash = match commit {
+        Some(reference) => refs::resolve(storage, reference)?,
+        None => storage.get_head()?.ok_or_else(not_found)?,
+    };
+
+    let rows = QueryProcessor::new(&storage.db).get_table_at_commit(table, &hash)?;
+    let value = rows.get(id).ok_or_else(not_found)?;
+    let schema = rows.get("!schema").map(crdt_json).unwrap_or(serde_json::Value::Null);
+
+    out.line(format!("{}:{} at {}", table, id, hex::encode(hash)));
+    match decode_row(value, &schema) {
+        serde_json::Value::Object(fields) => {
+            let width = fields.keys().map(String::len).max().unwrap_or(0);
+            for (column, field) in fields {
+                out.line(format!("  {:<width$}  {}", column, field, width = width));
+            }
+        }
+        other => out.line(format!("  {}", other)),
+    }
+    if let Some((modified, last)) = storage.row_last_modified(table, id, &hash)? {
+        out.line(format!("Last modified by {} {}", hex::encode(modified), last.message));
+    }
+
+    out.set_json(crdt_json(value));
+    Ok(())
+}
+
+pub fn handle_revert(storage: &CommitStorage, commit_hash: &str, dry_run: bool, yes: bool, out: &mut Output) -> Result<()> {
+    let hash_array = refs::resolve(storage, commit_hash)?;
+    let target_commit = storage.get_commit_by_hash(&hash_array)?;
+
+    // The row changes taking HEAD's state to the target's, which is what the revert writes
+    let mut stats = BTreeMap::new();
+    if let Some(head) = storage.get_head()? {
+        for change in storage.get_state_diffs(&head, &hash_array)? {
+            DiffStat::count(&mut stats, &change);
+        }
+    }
+
+    out.line(format!("Reverting to commit: {}", hex::encode(hash_array)));
+    out.line(format!("Original commit message: {}", target_commit.message));
+    out.line(format!("Date: {}", fmt::commit_time(&target_commit)));
+    if stats.is_empty() {
+        out.line("No rows change");
+    }
+    for line in DiffRenderer::new(false).render_stat(&stats) {
+        out.line(format!(" {}", line));
+    }
+
+    if dry_run {
+        out.line("Dry run: nothing was written");
+        out.set_json(json!({
+            "reverted_to": hex::encode(hash_array),
+            "dry_run": true,
+            "stat": stat_json(&stats),
+        }));
+        return Ok(());
+    }
+
+    confirm(&rows_summary(&stats), yes)?;
+    let new_head = storage.revert_to_commit(&hash_array)?;
+    let current_commit = storage.get_commit_by_hash(&new_head)?;
+
+    out.line(format!("New HEAD: {}", hex::encode(new_head)));
+    out.line(format!("New commit message: {}", current_commit.message));
+    out.set_json(json!({
+        "reverted_to": hex::encode(hash_array),
+        "head": hex::encode(new_head),
+        "message": current_commit.message,
+        "stat": stat_json(&stats),
+    }));
+    Ok(())
+}
+
+// Per-table row counts as `{"table": {"inserted": n, "updated": n, "deleted": n}}`.
+fn stat_json(stats: &BTreeMap<String, DiffStat>) -> serde_json::Value {
+    stats.iter()
+        .map(|(table, stat)| (table.clone(), json!({
+            "inserted": stat.inserted,
+            "updated": stat.updated,
+            "deleted": stat.deleted,
+        })))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+// How `diff` picks its base and presents the result.
+pub struct DiffViewOptions {
+    pub stat: bool,
+    pub three_dot: bool,
+    pub color: ColorChoice,
+    pub format: DiffFormat,
+}
+
+pub fn handle_diff(
+    storage: &CommitStorage,
+    from: &str,
+    to: &str,
+    options: &DiffOptions,
+    view: &DiffViewOptions,
+    out: &mut Output,
+) -> Result<()> {
+    let mut from_array = refs::resolve(storage, from)?;
+    let to_array = refs::resolve(storage, to)?;
+
+    // With --three-dot everything below compares against the merge base instead of `from`
+    let mut merge_base = None;
+    if view.three_dot {
+        let ancestor = storage.get_common_ancestor(&from_array, &to_array)?.ok_or_else(|| {
+            BranchDBError::InvalidInput(format!("{} and {} have no common ancestor", from, to))
+        })?;
+        // A patch names the merge base as its `from` instead, so the output stays SQL or JSON
+        if view.format == DiffFormat::Text {
+            out.line(format!("Merge base: {}", hex::encode(ancestor)));
+        }
+        merge_base = Some(hex::encode(ancestor));
+        from_array = ancestor;
+    }
+
+    if view.format == DiffFormat::JsonPatch {
+        let changes = storage.get_commit_diffs(&from_array, &to_array, options)?;
+        let patch = JsonPatch::from_changes(&from_array, &to_array, &changes)?;
+        out.line(serde_json::to_string_pretty(&patch)?);
+        out.set_json(serde_json::to_value(&patch)?);
+        return Ok(());
+    }
+    if view.format == DiffFormat::Sql {
+        let patch = patch::render(storage, &from_array, &to_array, options)?;
+        for line in patch.to_lines(&hex::encode(from_array), &hex::encode(to_array)) {
+            out.line(line);
+        }
+        out.set_json(json!({
+            "from": hex::encode(from_array),
+            "to": hex::encode(to_array),
+            "merge_base": merge_base,
+            "statements": patch.statements,
+            "notes": patch.notes,
+        }));
+        return Ok(());
+    }
+    
+    let diffs = storage.get_commit_diffs(&from_array, &to_array, options)?;
+    let renderer = DiffRenderer::new(view.color.enabled());
+    
+    // Schema rows are reported column by column below
+    let row_changes: Vec<Change> = if options.include_data {
+        diffs.into_iter().filter(|diff| diff.id() != "!schema").collect()
+    } else {
+        Vec::new()
+    };
+
+    if view.stat {
+        let mut stats = BTreeMap::new();
+        for change in &row_changes {
+            DiffStat::count(&mut stats, change);
+        }
+        for line in renderer.render_stat(&stats) {
+            out.line(line);
+        }
+        out.set_json(stats.iter()
+            .map(|(table, s)| json!({ "table": table, "inserted": s.inserted, "updated": s.updated, "deleted": s.deleted }))
+            .collect());
+        return Ok(());
+    }
+
+    out.line(format!("Changes from {} to {}:", hex::encode(from_array), to));
+    let mut entries = Vec::new();
+    // Schemas and `from` states are loaded once per table
+    let mut schemas: HashMap<String, (serde_json::Value, serde_json::Value)> = HashMap::new();
+    let mut from_states = HashMap::new();
+    for change in &row_changes {
+        let table = change.table();
+        if !schemas.contains_key(table) {
+            let schema_pair = (
+                storage.get_table_schema(table, Some(&from_array))?,
+                storage.get_table_schema(table, Some(&to_array))?,
+            );
+            schemas.insert(table.to_string(), schema_pair);
+            from_states.insert(table.to_string(), storage.table_state_at(table, &from_array)?);
+        }
+        let (from_schema, to_schema) = &schemas[table];
+        let before = from_states[table].get(change.id()).map(|value| decode_row(value, from_schema));
+        let after = match change {
+            Change::Insert { value, .. } | Change::Update { value, .. } => Some(decode_change_value(value, to_schema)?),
+            Change::Delete { .. } => None,
+        };
+        for line in renderer.render_change(change, before.as_ref(), after.as_ref()) {
+            out.line(line);
+        }
+        let mut entry = change_json(change);
+        if let Some(before) = before {
+            entry["before"] = before;
+        }
+        if let Some(after) = after {
+            entry["value"] = after;
+        }
+        entries.push(entry);
+    }
+
+    if options.include_schema {
+        for (table, changes) in storage.get_schema_diffs(&from_array, &to_array)? {
+            out.line(format!("schema: {}", table));
+            for change in changes {
+                out.line(format!("  {}", change));
+                entries.push(json!({ "op": "schema", "table": table, "change": change.to_string() }));
+            }
+        }
+    }
+    
+    if merge_base.is_some() {
+        out.set_json(json!({ "merge_base": merge_base, "changes": entries }));
+    } else {
+        out.set_json(serde_json::Value::Array(entries));
+    }
+    Ok(())
+}
+
+pub fn handle_diff_tables(storage: &CommitStorage, table_a: &str, table_b: &str, commit: Option<&str>, out: &mut Output) -> Result<()> {
+    let commit = match commit {
+        Some(reference) => refs::resolve(storage, reference)?,
+        None => storage.get_head()?.ok_or_else(|| BranchDBError::InvalidInput("No HEAD commit".into()))?,
+    };
+    let changes = storage.diff_tables(table_a, table_b, Some(&commit))?;
+    let schema_a = storage.get_table_schema(table_a, Some(&commit))?;
+    let schema_b = storage.get_table_schema(table_b, Some(&commit))?;
+    let rows_a = storage.table_state_at(table_a, &commit)?;
+
+    out.line(format!("Changes from {} to {} at {}:", table_a, table_b, hex::encode(commit)));
+    let renderer = DiffRenderer::new(false);
+    let mut entries = Vec::new();
+    for change in &changes {
+        let before = rows_a.get(change.id()).map(|value| decode_row(value, &schema_a));
+        let after = match change {
+            Change::Insert { value, .. } | Change::Update { value, .. } => Some(decode_change_value(value, &schema_b)?),
+            Change::Delete { .. } => None,
+        };
+        for line in renderer.render_change(change, before.as_ref(), after.as_ref()) {
+            out.line(line);
+        }
+        let mut entry = change_json(change);
+        if let Some(before) = before {
+            entry["before"] = before;
+        }
+        if let Some(after) = after {
+            entry["value"] = after;
+        }
+        entries.push(entry);
+    }
+    if changes.is_empty() {
+        out.line("No differences");
+    }
+    out.set_json(json!({ "commit": hex::encode(commit), "changes": entries }));
+    Ok(())
+}
+
+pub fn handle_list_tables(storage: &CommitStorage, commit: Option<&str>, out: &mut Output) -> Result<()> {
+    let commit = match commit {
+        Some(reference) => Some(refs::resolve(storage, reference)?),
+        None => storage.get_head()?,
+    };
+    let tables = match &commit {
+        Some(commit) => storage.list_tables_at(commit)?,
+        None => Vec::new(),
+    };
+    for table in &tables {
+        out.line(table);
+    }
+    if tables.is_empty() {
+        out.line("No tables");
+    }
+    out.set_json(json!({ "commit": commit.map(hex::encode), "tables": tables }));
+    Ok(())
+}
+
+pub fn handle_clone_table(storage: &CommitStorage, src: &str, dst: &str, commit: Option<&str>, overwrite: bool, out: &mut Output) -> Result<()> {
+    let commit = commit.map(|reference| refs::resolve(storage, reference)).transpose()?;
+    let hash = match overwrite {
+        true => storage.clone_table_replacing(src, dst, commit.as_ref())?,
+        false => storage.clone_table(src, dst, commit.as_ref())?,
+    };
+    out.line(format!("Cloned table '{}' to '{}' in commit {}", src, dst, hex::encode(hash)));
+    out.set_json(json!({ "source": src, "destination": dst, "commit": hex::encode(hash) }));
+    Ok(())
+}
+
+// Parses an ISO 8601 date or date-time into a Unix timestamp in milliseconds, as commits
+// record them. Dates without a time component are taken as midnight UTC.
+fn parse_date(input: &str) -> Result<u64> {
+    let timestamp = if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(input) {
+        dt.timestamp_millis()
+    } else if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M:%S%.f") {
+        dt.and_utc().timestamp_millis()
+    } else if let Ok(date) = chrono::NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        date.and_hms_opt(0, 0, 0)
+            .ok_or_else(|| BranchDBError::InvalidInput(format!("Invalid date '{}'", input)))?
+            .and_utc()
+            .timestamp_millis()
+    } else {
+        return Err(BranchDBError::InvalidInput(
+            format!("Invalid date '{}': expected ISO 8601 (e.g. 2024-01-31 or 2024-01-31T12:00:00Z)", input)
+        ));
+    };
+
+    u64::try_from(timestamp)
+        .map_err(|_| BranchDBError::InvalidInput(format!("Date '{}' is before 1970", input)))
+}
+
+pub struct InitOptions {
+    pub bare: bool,
+    pub force: bool,
+    pub initial_branch: Option<String>,
+    pub encrypted: bool,
+    // Recorded as core.key-file, made absolute
+    pub key_file: Option<String>,
+}
+
+// Creates the repository in `<path>/data`, where commands run from `path` look for it. `path`
+// may already exist as long as it is an empty directory, or any directory with `force`.
+pub fn handle_init(path: &str, options: &InitOptions, out: &mut Output) -> Result<()> {
+    let root = Path::new(path);
+    if root.exists() {
+        if !root.is_dir() {
+            return Err(BranchDBError::InvalidInput(format!("{} is not a directory", path)));
+        }
+        if !options.force && fs::read_dir(root)?.next().is_some() {
+            return Err(BranchDBError::InvalidInput(format!(
+                "{} is not empty; pass --force to initialize it anyway", path
+            )));
+        }
+    }
+    if let Some(branch) = &options.initial_branch {
+        if branch.trim().is_empty() {
+            return Err(BranchDBError::InvalidInput("Branch name cannot be empty".into()));
+        }
+    }
+
+    let data_dir = root.join(DATA_DIR);
+    let existed = data_dir.is_dir() && fs::read_dir(&data_dir)?.next().is_some();
+    fs::create_dir_all(&data_dir)?;
+    let data_path = data_dir.to_str()
+        .ok_or_else(|| BranchDBError::InvalidInput(format!("{} is not valid UTF-8", data_dir.display())))?;
+    let storage = CommitStorage::open(data_path)?;
+
+    let mut config = Config::load(&data_dir)?;
+    if options.bare {
+        config.set("core.bare", "true")?;
+    }
+    if let Some(branch) = &options.initial_branch {
+        config.set(INITIAL_BRANCH_KEY, branch)?;
+    }
+    if let Some(file) = &options.key_file {
+        config.set(KEY_FILE_KEY, &fs::canonicalize(file)?.display().to_string())?;
+    }
+    if options.bare || options.initial_branch.is_some() || options.key_file.is_some() {
+        config.save()?;
+    }
+    if options.encrypted {
+        let secret = encryption::secret(&data_dir)?.ok_or_else(|| BranchDBError::EncryptionError(format!(
+            "--encrypted needs a key in {} or --key-file", encryption::KEY_ENV
+        )))?;
+        storage.enable_encryption(ValueCipher::from_secret(&secret)?)?;
+    }
+
+    let absolute = fs::canonicalize(&data_dir)?;
+    let kind = match (options.bare, options.encrypted) {
+        (true, true) => "bare encrypted ",
+        (true, false) => "bare ",
+        (false, true) => "encrypted ",
+        (false, false) => "",
+    };
+    if existed {
+        out.line(format!("Reinitialized existing {}BranchDB repository in {}", kind, absolute.display()));
+    } else {
+        out.line(format!("Initialized empty {}BranchDB repository in {}", kind, absolute.display()));
+    }
+    out.set_json(json!({
+        "path": absolute.display().to_string(),
+        "bare": options.bare,
+        "initial_branch": options.initial_branch,
+        "encrypted": options.encrypted,
+        "reinitialized": existed,
+    }));
+    Ok(())
+}
+
+// Resolves one merge conflict in favour of our or their side; the result is staged, not committed.
+pub fn handle_checkout_conflict(storage: &CommitStorage, row: &str, theirs: bool, out: &mut Output) -> Result<()> {
+    let (table, id) = row.split_once(':')
+        .ok_or_else(|| BranchDBError::InvalidInput(format!("Expected table:id, got '{}'", row)))?;
+
+    if theirs {
+        storage.checkout_theirs(table, id)?;
+    } else {
+        storage.checkout_ours(table, id)?;
+    }
+
+    let side = if theirs { "theirs" } else { "ours" };
+    out.line(format!("Staged {} version of {}:{}; run `commit` to record it", side, table, id));
+    out.set_json(json!({ "staged": side, "table": table, "id": id }));
+    Ok(())
+}
+
+pub fn handle_checkout(storage: &CommitStorage, target: &str, force: bool, out: &mut Output) -> Result<()> {
+    // Try as branch first, then as any other reference
+    let branch_key = format!("branch:{}", target);
+    let (new_head, is_branch) = if let Some(branch_head) = storage.db.get(branch_key.as_bytes())? {
+        // Verify the branch head exists
+        if get_commit_raw(&storage.db, &branch_head)?.is_none() {
+            return Err(BranchDBError::InvalidInput(
+                format!("Branch '{}' points to invalid commit", target)
+            ));
+        }
+        (branch_head, true)
+    } else {
+        (refs::resolve(storage, target)?.to_vec(), false)
+    };
+    if !force {
+        check_nothing_left_behind(storage, &new_head)?;
+    }
+
+    let hooks = storage.hooks();
+    let context = vec![
+        ("BRANCHDB_CHECKOUT_TARGET", target.to_string()),
+        ("BRANCHDB_CHECKOUT_FROM", storage.get_head()?.map(hex::encode).unwrap_or_default()),
+        ("BRANCHDB_CHECKOUT_TO", hex::encode(&new_head)),
+    ];
+    hooks.run_pre("pre-checkout", "checkout", &context)?;
+    let head: [u8; 32] = new_head.as_slice().try_into()
+        .map_err(|_| BranchDBError::InvalidInput(format!("'{}' points to an invalid commit", target)))?;
+    storage.move_head(&head)?;
+    hooks.run("post-checkout", &context)?;
+
+    if is_branch {
+        out.line(format!("Switched to branch '{}'", target));
+        out.set_json(json!({ "branch": target, "head": hex::encode(&new_head) }));
+    } else {
+        out.line(format!("Switched to commit {}", hex::encode(&new_head)));
+        out.set_json(json!({ "branch": null, "head": hex::encode(&new_head) }));
+    }
+    Ok(())
+}
+
+// Branches don't follow new commits, so after committing HEAD is often the only thing
+// pointing at them. Moving HEAD away would leave those commits unreachable, and staged changes
+// would be committed on top of a different base.
+fn check_nothing_left_behind(storage: &CommitStorage, new_head: &[u8]) -> Result<()> {
+    let staged = storage.staged_changes()?.len();
+    if staged > 0 {
+        return Err(BranchDBError::WouldDiscardWork(format!(
+            "{} staged change(s); run `commit` first", staged
+        )));
+    }
+
+    let Some(head) = storage.get_head()? else { return Ok(()) };
+    if head[..] == *new_head {
+        return Ok(());
+    }
+    // Still reachable afterwards when the target is a descendant of HEAD
+    let kept = match <[u8; 32]>::try_from(new_head) {
+        Ok(new_head) => storage.is_ancestor(&head, &new_head)?,
+        Err(_) => false,
+    };
+    if !kept && !storage.is_on_branch(&head)? {
+        return Err(BranchDBError::WouldDiscardWork(format!(
+            "HEAD commit {} is not on any branch; keep it with `branch <name>` first",
+            hex::encode(&head[..8])
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStyle {
+    Default,
+    Oneline,
+    Verbose,
+}
+
+pub struct LogOptions {
+    pub limit: Option<usize>,
+    pub style: LogStyle,
+    pub stat: bool,
+    pub graph: bool,
+    // Unix timestamps in milliseconds bounding the commits shown, `after <= timestamp < before`
+    pub after: Option<u64>,
+    pub before: Option<u64>,
+    pub table: Option<String>,
+    // Start from every branch as well as HEAD
+    pub all: bool,
+    // Start from the branches matching this glob instead of HEAD
+    pub branches: Option<String>,
+    // Skip unreadable commits with a warning
+    pub best_effort: bool,
+    // Only commits whose source contains this
+    pub grep_source: Option<String>,
+    // Only commits whose `user@host` matches this regular expression
+    pub author: Option<String>,
+}
+
+// Walks every commit reachable from HEAD (or the chosen branches) through all parents, newest
+// first, printing each one as it is reached.
+pub fn handle_log(storage: &CommitStorage, options: &LogOptions, out: &mut Output) -> Result<()> {
+    let branches = options.branches.as_deref().map(|pattern| refs::matching_branches(storage, pattern)).transpose()?;
+    let author = options.author.as_deref()
+        .map(|pattern| Regex::new(pattern).map_err(|e| BranchDBError::InvalidInput(format!("--author: {}", e))))
+        .transpose()?;
+
+    // With --json the graph is the whole DAG from get_commit_dag, whatever branches are chosen
+    if options.graph && out.is_json() {
+        out.set_json(serde_json::to_value(storage.get_commit_dag()?)?);
+        return Ok(());
+    }
+
+    // The text graph always shows every branch unless some are chosen
+    if options.graph {
+        let graph = match branches {
+            Some(branches) => GraphRenderer::for_branches(storage, branches),
+            None => GraphRenderer::new(storage),
+        };
+        for line in graph.render()? {
+            out.line(line);
+        }
+        return Ok(());
+    }
+
+    let tips = match branches {
+        Some(branches) => branches.into_iter().map(|(_, hash)| hash).collect(),
+        None if options.all => storage.all_tips()?,
+        None => storage.get_head()?.into_iter().collect(),
+    };
+    let renderer = DiffRenderer::new(false);
+    let mut entries = Vec::new();
+    let walk = if options.best_effort { HistoryWalk::best_effort(storage, tips)? } else { HistoryWalk::from_tips(storage, tips)? };
+    let commits = walk.filter(|entry| match entry {
+        Ok((_, commit)) => {
+            options.after.is_none_or(|a| commit.timestamp >= a)
+                && options.before.is_none_or(|b| commit.timestamp < b)
+                && options.table.as_deref().is_none_or(|t| commit.changes.iter().any(|c| c.table() == t))
+                && options.grep_source.as_deref().is_none_or(|p| audit::source_matches(commit.audit.as_ref(), p))
+                && author.as_ref().is_none_or(|r| audit::author(commit.audit.as_ref()).is_some_and(|a| r.is_match(&a)))
+        }
+        Err(_) => true,
+    });
+    for entry in commits.take(options.limit.unwrap_or(usize::MAX)) {
+        let (hash, commit) = entry?;
+        match options.style {
+            LogStyle::Verbose => {
+                out.line(format!("commit {}", hex::encode(hash)));
+                let audit = commit.audit.clone().unwrap_or_default();
+                out.line(format!("Author: {}", audit::author(Some(&audit)).as_deref().unwrap_or("<user>")));
+                // Commits from before sequence numbers were recorded have none to show
+                match commit.sequence {
+                    0 => out.line(format!("Date:   {}", fmt::commit_time(&commit))),
+                    sequence => out.line(format!("Date:   {} (#{})", fmt::commit_time(&commit), sequence)),
+                }
+                if let Some(command) = &audit.command {
+                    out.line(format!("Command: {}", command));
+                }
+                if let Some(source) = &audit.source {
+                    out.line(format!("Source: {}", source));
+                }
+                out.line(format!("\n    {}\n", commit.message));
+            }
+            LogStyle::Oneline => out.line(format!("{} {}", hex::encode(&hash[..8]), commit.message)),
+            LogStyle::Default => out.line(format!("{} {}", hex::encode(hash), commit.message)),
+        }
+
+        let mut json = commit_json(&hash, &commit);
+        if options.stat {
+            let mut stats = BTreeMap::new();
+            for change in &commit.changes {
+                DiffStat::count(&mut stats, change);
+            }
+            for line in renderer.render_stat(&stats) {
+                out.line(format!(" {}", line));
+            }
+            if options.style != LogStyle::Verbose {
+                out.line("");
+            }
+            json["stat"] = stat_json(&stats);
+        }
+        entries.push(json);
+    }
+
+    out.set_json(serde_json::Value::Array(entries));
+    Ok(())
+}
+
+// `branch-list --divergence`: what each branch is compared with, HEAD when `base` is None.
+pub struct BranchDivergence {
+    pub base: Option<String>,
+}
+
+pub fn handle_branch_list(
+    branch_mgr: &BranchManager,
+    storage: &CommitStorage,
+    verbose: bool,
+    divergence: Option<&BranchDivergence>,
+    out: &mut Output,
+) -> Result<()> {
+    let branches = branch_mgr.list_branches()?;
+    let current = branch_mgr.get_current_branch()?;
+    let mut entries = Vec::new();
+    let mut counter = match divergence {
+        Some(divergence) => {
+            let base = refs::resolve(storage, divergence.base.as_deref().unwrap_or("HEAD"))?;
+            Some(DivergenceCounter::new(storage, &base)?)
+        }
+        None => None,
+    };
+    
+    out.line("Branches:");
+    for branch in branches {
+        let is_current = current.as_ref() == Some(&branch);
+        let marker = if is_current { "* " } else { "  " };
+        let raw_head = branch_mgr.get_branch_head(&branch)?;
+        let head = raw_head.as_ref().map(hex::encode);
+        
+        let mut line = match (verbose, &head) {
+            (true, Some(commit_hash)) => format!("{}{} ({})", marker, branch, commit_hash),
+            (true, None) => format!("{}{} (no commit)", marker, branch),
+            (false, _) => format!("{}{}", marker, branch),
+        };
+        let mut entry = json!({ "name": branch, "head": head, "current": is_current });
+        let hash = raw_head.and_then(|raw| <[u8; 32]>::try_from(raw.as_slice()).ok());
+        if let (Some(counter), Some(hash)) = (counter.as_mut(), hash) {
+            let (ahead, behind) = counter.count(&hash)?;
+            line.push_str(&format!(" (ahead {}, behind {})", ahead, behind));
+            entry["ahead"] = json!(ahead);
+            entry["behind"] = json!(behind);
+        }
+        out.line(line);
+        entries.push(entry);
+    }
+    out.set_json(serde_json::Value::Array(entries));
+    Ok(())
+}
+
+pub fn handle_merge(storage: &CommitStorage, data_dir: &str, branch_name: &str, message: Option<&str>, out: &mut Output) -> Result<()> {
+    let branch_key = format!("branch:{}", branch_name);
+    let branch_head = storage.db.get(branch_key.as_bytes())?
+        .ok_or_else(|| BranchDBError::BranchNotFound { name: branch_name.to_string() })?;
+    
+    let current_head = storage.db.get(b"HEAD")?
+        .ok_or_else(|| BranchDBError::InvalidInput("HEAD not found".into()))?;
+    
+    if branch_head == current_head {
+        return Err(BranchDBError::InvalidInput("Already up to date".into()));
+    }
+
+    let branch_hash: [u8; 32] = branch_head.as_slice().try_into()
+        .map_err(|_| BranchDBError::InvalidInput(format!("Branch '{}' points to an invalid commit", branch_name)))?;
+    let current_hash: [u8; 32] = current_head.as_slice().try_into()
+        .map_err(|_| BranchDBError::CorruptData("HEAD is not a commit hash".into()))?;
+    if storage.is_ancestor(&branch_hash, &current_hash)? {
+        return Err(BranchDBError::InvalidInput("Already up to date".into()));
+    }
+
+    let hooks = storage.hooks();
+    let mut context = vec![
+        ("BRANCHDB_MERGE_BRANCH", branch_name.to_string()),
+        ("BRANCHDB_MERGE_HEAD", hex::encode(&branch_head)),
+        ("BRANCHDB_HEAD", hex::encode(&current_head)),
+    ];
+    hooks.run_pre("pre-merge", "merge", &context)?;
+
+    let merge_message = |changes: &[Change]| {
+        let prompt = message_prompt(storage, "merge", Some(branch_name), changes)?;
+        editor::message(data_dir, message, Draft::Default(format!("Merge branch '{}'", branch_name)), &prompt)
+    };
+    let (hash, conflicts) = match storage.merge_with_ancestor(branch_name, merge_message)? {
+        // HEAD was behind the branch, so there was nothing to combine
+        MergeResult::FastForward(hash) => {
+            context.push(("BRANCHDB_MERGE_COMMIT_HASH", hex::encode(hash)));
+            context.push(("BRANCHDB_MERGE_CONFLICTS", "0".to_string()));
+            hooks.run("post-merge", &context)?;
+
+            out.line(format!("Fast-forward to {}", hex::encode(hash)));
+            out.set_json(json!({ "branch": branch_name, "hash": hex::encode(hash), "fast_forward": true, "conflicts": [] }));
+            return Ok(());
+        }
+        MergeResult::UpToDate => {
+            out.line("Already up to date");
+            out.set_json(json!({ "branch": branch_name, "hash": null, "fast_forward": false, "conflicts": [] }));
+            return Ok(());
+        }
+        MergeResult::Conflict(conflicts) => {
+            out.line(format!("Merge stopped: {} row(s) changed differently on both sides:", conflicts.len()));
+            for conflict in &conflicts {
+                out.line(format!("  {}:{}", conflict.table, conflict.id));
+            }
+            out.line("Pick a side with `checkout --ours table:id` or `checkout --theirs table:id`, then `commit`.");
+            out.set_json(json!({
+                "branch": branch_name,
+                "hash": null,
+                "fast_forward": false,
+                "conflicts": conflicts.iter().map(|c| json!({ "table": c.table, "id": c.id })).collect::<Vec<_>>(),
+            }));
+            return Err(BranchDBError::InvalidInput(format!("Merge of '{}' has {} conflict(s)", branch_name, conflicts.len())));
+        }
+        MergeResult::Merged { hash, conflicts } => (hash, conflicts),
+    };
+    context.push(("BRANCHDB_MERGE_COMMIT_HASH", hex::encode(hash)));
+    context.push(("BRANCHDB_MERGE_CONFLICTS", conflicts.len().to_string()));
+    hooks.run("post-merge", &context)?;
+
+    out.line(format!("Created merge commit: {}", hex::encode(hash)));
+    if !conflicts.is_empty() {
+        out.line(format!("{} conflicting rows took the version from '{}':", conflicts.len(), branch_name));
+        for conflict in &conflicts {
+            out.line(format!("  {}:{}", conflict.table, conflict.id));
+        }
+        out.line("Use `checkout --ours table:id` to restore the local version, then `commit`.");
+    }
+    out.set_json(json!({
+        "branch": branch_name,
+        "hash": hex::encode(hash),
+        "fast_forward": false,
+        "conflicts": conflicts.iter()
+            .map(|c| json!({ "table": c.table, "id": c.id }))
+            .collect::<Vec<_>>(),
+    }));
+    Ok(())
+}
+
+pub fn handle_rebase(storage: &CommitStorage, onto: &str, out: &mut Output) -> Result<()> {
+    let onto_hash = refs::resolve(storage, onto)?;
+    match storage.rebase_onto(&onto_hash)? {
+        RebaseResult::UpToDate => {
+            out.line(format!("Already up to date: {} is in HEAD's history", onto));
+            out.set_json(json!({ "onto": hex::encode(onto_hash), "head": null, "fast_forward": false, "commits": [], "conflicts": [] }));
+        }
+        RebaseResult::FastForward(hash) => {
+            out.line(format!("Fast-forward to {}", hex::encode(hash)));
+            out.set_json(json!({ "onto": hex::encode(onto_hash), "head": hex::encode(hash), "fast_forward": true, "commits": [], "conflicts": [] }));
+        }
+        RebaseResult::Rebased { head, commits } => {
+            out.line(format!("Rebased {} commit(s) onto {}; HEAD is now {}", commits.len(), onto, hex::encode(head)));
+            out.set_json(json!({
+                "onto": hex::encode(onto_hash),
+                "head": hex::encode(head),
+                "fast_forward": false,
+                "commits": commits.iter().map(hex::encode).collect::<Vec<_>>(),
+                "conflicts": [],
+            }));
+        }
+        RebaseResult::Conflict(conflicts) => {
+            out.line(format!("Rebase stopped: {} row(s) changed differently on both sides:", conflicts.len()));
+            for conflict in &conflicts {
+                out.line(format!("  {}:{}", conflict.table, conflict.id));
+            }
+            out.line("Nothing was changed; `merge` can combine them instead.");
+            out.set_json(json!({
+                "onto": hex::encode(onto_hash),
+                "head": null,
+                "fast_forward": false,
+                "commits": [],
+                "conflicts": conflicts.iter().map(|c| json!({ "table": c.table, "id": c.id })).collect::<Vec<_>>(),
+            }));
+            return Err(BranchDBError::InvalidInput(format!("Rebase onto '{}' has {} conflict(s)", onto, conflicts.len())));
+        }
+    }
+    Ok(())
+}
+
+// Checks every change of the patch against HEAD first. Any that fail stop the whole patch
+// unless `force`, which commits the others; either way everything applied is one commit.
+pub fn handle_apply_patch(storage: &CommitStorage, file: &str, message: Option<&str>, force: bool, out: &mut Output) -> Result<()> {
+    let patch = JsonPatch::parse(&fs::read_to_string(file)?)?;
+    let problems = storage.check_patch(&patch)?;
+    for problem in &problems {
+        out.line(format!("{}: {}", if force { "skipped" } else { "conflict" }, problem));
+    }
+    if !problems.is_empty() && !force {
+        out.set_json(json!({ "hash": null, "problems": problems }));
+        return Err(BranchDBError::ConstraintViolation(format!(
+            "{} of {} changes in {} don't apply to HEAD; nothing was committed (--force commits the rest)",
+            problems.len(), patch.changes.len(), file
+        )));
+    }
+
+    let skipped: Vec<usize> = problems.iter().map(|problem| problem.index).collect();
+    let changes = patch.changes.iter().enumerate()
+        .filter(|(index, _)| !skipped.contains(&(index + 1)))
+        .map(|(_, change)| change.to_change())
+        .collect::<Result<Vec<_>>>()?;
+    if changes.is_empty() {
+        return Err(BranchDBError::NothingToCommit);
+    }
+    let count = changes.len();
+    let message = message.map_or_else(|| format!("Apply patch {}", file), str::to_string);
+    let hash = storage.create_commit(&message, changes)?;
+    out.line(format!("Applied {} of {} changes in commit {}", count, patch.changes.len(), hex::encode(hash)));
+    out.set_json(json!({ "hash": hex::encode(hash), "changes": count, "problems": problems }));
+    Ok(())
+}
+
+pub fn handle_export_patch(storage: &CommitStorage, commit: &str, output: &str, out: &mut Output) -> Result<()> {
+    let hash = refs::resolve(storage, commit)?;
+    let patch = storage.export_patch(&hash)?;
+    fs::write(output, &patch)?;
+    out.line(format!("Wrote commit {} to {} ({} bytes)", hex::encode(hash), output, patch.len()));
+    out.set_json(json!({ "hash": hex::encode(hash), "file": output, "bytes": patch.len() }));
+    Ok(())
+}
+
+pub fn handle_import_patch(storage: &CommitStorage, files: &[String], out: &mut Output) -> Result<()> {
+    let mut imported = Vec::new();
+    for file in files {
+        let hash = storage.import_patch(&fs::read(file)?)?;
+        out.line(format!("Imported commit {} from {}", hex::encode(hash), file));
+        imported.push(hex::encode(hash));
+    }
+    out.set_json(json!({ "imported": imported }));
+    Ok(())
+}
+
+pub fn handle_create_namespace(storage: &CommitStorage, name: &str, out: &mut Output) -> Result<()> {
+    storage.create_namespace(name)?;
+    out.line(format!("Created namespace '{}'; pass --namespace {} to use it", name, name));
+    out.set_json(json!({ "namespace": name }));
+    Ok(())
+}
+
+pub fn handle_drop_namespace(storage: &CommitStorage, name: &str, yes: bool, out: &mut Output) -> Result<()> {
+    if storage.db.namespace() == name {
+        return Err(BranchDBError::InvalidInput(format!("Namespace '{}' is in use by this command", name)));
+    }
+    confirm(&format!("This will delete namespace '{}' with all its commits, branches and rows", name), yes)?;
+    let keys = storage.drop_namespace(name)?;
+    out.line(format!("Dropped namespace '{}' ({} keys)", name, keys));
+    out.set_json(json!({ "namespace": name, "keys": keys }));
+    Ok(())
+}
+
+// The new key comes from `new_key_file` or BRANCHDB_NEW_KEY. A key file is recorded as
+// core.key-file of the repository that ends up with the new key; with BRANCHDB_NEW_KEY, the
+// caller has to switch BRANCHDB_KEY over itself.
+pub fn handle_rekey(storage: &CommitStorage, data_dir: &str, new_key_file: Option<&str>, into: Option<&str>, out: &mut Output) -> Result<()> {
+    let secret = match new_key_file {
+        Some(file) => fs::read_to_string(file)?,
+        None => std::env::var(NEW_KEY_ENV).map_err(|_| BranchDBError::EncryptionError(format!(
+            "no new key; set {} or pass --new-key-file", NEW_KEY_ENV
+        )))?,
+    };
+    let cipher = ValueCipher::from_secret(&secret)?;
+
+    let target_dir = match into {
+        Some(dir) => {
+            let target_dir = Path::new(dir).join(DATA_DIR);
+            if target_dir.is_dir() && fs::read_dir(&target_dir)?.next().is_some() {
+                return Err(BranchDBError::InvalidInput(format!("{} is not empty", target_dir.display())));
+            }
+            fs::create_dir_all(&target_dir)?;
+            target_dir
+        }
+        None => Path::new(data_dir).to_path_buf(),
+    };
+    let target = match into {
+        Some(_) => {
+            let path = target_dir.to_str()
+                .ok_or_else(|| BranchDBError::InvalidInput(format!("{} is not valid UTF-8", target_dir.display())))?;
+            Some(CommitStorage::open(path)?)
+        }
+        None => None,
+    };
+
+    let values = storage.rekey(cipher, target.as_ref(), |count| {
+        if count % REKEY_PROGRESS_EVERY == 0 {
+            out.line(format!("Re-encrypted {} values", count));
+        }
+    })?;
+
+    // A copy keeps the settings of the original
+    let config_file = Path::new(data_dir).join(CONFIG_FILE);
+    if into.is_some() && config_file.is_file() {
+        fs::copy(&config_file, target_dir.join(CONFIG_FILE))?;
+    }
+    // A key file of the old key would no longer open the repository
+    let mut config = Config::load(&target_dir)?;
+    let changed = match new_key_file {
+        Some(file) => {
+            config.set(KEY_FILE_KEY, &fs::canonicalize(file)?.display().to_string())?;
+            true
+        }
+        None => config.unset(KEY_FILE_KEY),
+    };
+    if changed {
+        config.save()?;
+    }
+
+    let absolute = fs::canonicalize(&target_dir)?;
+    out.line(format!("Encrypted {} values in {} with the new key", values, absolute.display()));
+    if new_key_file.is_none() {
+        out.line(format!("Set {} to the new key to open it", encryption::KEY_ENV));
+    }
+    out.set_json(json!({
+        "path": absolute.display().to_string(),
+        "values": values,
+        "in_place": into.is_none(),
+    }));
+    Ok(())
+}
+
+pub fn handle_ancestor_check(storage: &CommitStorage, ancestor: &str, descendant: &str, out: &mut Output) -> Result<()> {
+    let ancestor_hash = refs::resolve(storage, ancestor)?;
+    let descendant_hash = refs::resolve(storage, descendant)?;
+    let is_ancestor = storage.is_ancestor(&ancestor_hash, &descendant_hash)?;
+
+    if is_ancestor {
+        out.line(format!("{} is an ancestor of {}", ancestor, descendant));
+    } else {
+        out.line("Not an ancestor");
+    }
+    out.set_json(json!({
+        "ancestor": hex::encode(ancestor_hash),
+        "descendant": hex::encode(descendant_hash),
+        "is_ancestor": is_ancestor,
+    }));
+    Ok(())
+}
+
+// Quarantine runs first, so commits it moves show up as missing in the consistency check.
+pub fn handle_fsck(storage: &CommitStorage, abort_pending: bool, quarantine: bool, repair_checksums: bool, out: &mut Output) -> Result<()> {
+    // Repaired first, so only commits that can't be recovered are quarantined
+    let checksums_repaired = if repair_checksums { storage.repair_checksums()? } else { 0 };
+    if checksums_repaired > 0 {
+        out.line(format!("repaired the checksum of {} commit(s)", checksums_repaired));
+    }
+    let quarantined = if quarantine { storage.quarantine_corrupt()? } else { Vec::new() };
+    for hash in &quarantined {
+        out.line(format!("quarantined corrupt commit {}", hex::encode(hash)));
+    }
+    let found = if abort_pending { storage.abort_pending()? } else { storage.check_consistency()? };
+    for problem in &found {
+        if abort_pending {
+            out.line(format!("{}: {}", problem, problem.repair()));
+        } else {
+            out.line(format!("{} (--abort-pending will {})", problem, problem.repair()));
+        }
+    }
+    if found.is_empty() {
+        out.line("No problems found");
+    }
+    out.set_json(json!({
+        "problems": found,
+        "repaired": abort_pending && !found.is_empty(),
+        "quarantined": quarantined.iter().map(hex::encode).collect::<Vec<_>>(),
+        "checksums_repaired": checksums_repaired,
+    }));
+    Ok(())
+}
+
+pub fn handle_repair(storage: &CommitStorage, out: &mut Output) -> Result<()> {
+    let repaired = storage.repair_checksums()?;
+    match repaired {
+        0 => out.line("No commits needed repair"),
+        count => out.line(format!("repaired the checksum of {} commit(s)", count)),
+    }
+    out.set_json(json!({ "checksums_repaired": repaired }));
+    Ok(())
+}
+
+pub fn handle_verify_foreign_keys(storage: &CommitStorage, table: Option<&str>, out: &mut Output) -> Result<()> {
+    let violations = storage.verify_foreign_keys(table)?;
+    for violation in &violations {
+        out.line(violation);
+    }
+    match violations.len() {
+        0 => out.line("No foreign key violations"),
+        count => out.line(format!("{} foreign key violation(s)", count)),
+    }
+    out.set_json(json!({ "violations": violations }));
+    Ok(())
+}
+
+pub fn handle_seed(storage: &CommitStorage, options: &SeedOptions, out: &mut Output) -> Result<()> {
+    let summary = seed::run(storage, options)?;
+    if summary.created_table {
+        out.line(format!("Created table '{}'", summary.table));
+    }
+    for target in &summary.targets {
+        let place = match &target.branch {
+            Some(branch) => format!("branch '{}'", branch),
+            None => "HEAD".to_string(),
+        };
+        out.line(format!(
+            "Seeded {} with rows {}-{} in {} commit(s), now at {}",
+            place, target.first_id, target.last_id, target.commits, &target.head[..12]
+        ));
+    }
+    out.set_json(serde_json::to_value(&summary)?);
+    Ok(())
+}
+
+// Reports every row that fails verification, after committing the `fix` for those it covers.
+// Fails when any remain, so it can gate a pipeline; the report is still printed.
+pub fn handle_verify_data(
+    storage: &CommitStorage,
+    table: Option<&str>,
+    commit: Option<&str>,
+    fix: Option<DataFix>,
+    yes: bool,
+    out: &mut Output,
+) -> Result<()> {
+    let commit = commit.map(|reference| refs::resolve(storage, reference)).transpose()?;
+    let mut violations = storage.verify_data(table, commit.as_ref())?;
+
+    let mut repair = None;
+    if let Some(fix) = fix {
+        let changes = storage.repair_data(&violations, fix)?;
+        if !changes.is_empty() {
+            let action = match fix {
+                DataFix::Nulls => "set mistyped values to NULL in",
+                DataFix::Drop => "delete",
+            };
+            confirm(&format!("This will {} {} row(s)", action, changes.len()), yes)?;
+            let count = changes.len();
+            let hash = storage.create_commit(&format!("Repair {} row(s) failing verify-data", count), changes)?;
+            out.line(format!("Repaired {} row(s) in commit {}", count, hex::encode(hash)));
+            repair = Some(json!({ "commit": hex::encode(hash), "rows": count }));
+            violations = storage.verify_data(table, None)?;
+        }
+    }
+
+    for violation in &violations {
+        out.line(violation);
+    }
+    out.set_json(json!({ "violations": violations, "repair": repair }));
+    match violations.len() {
+        0 => {
+            out.line("No data violations");
+            Ok(())
+        }
+        count => {
+            out.line(format!("{} data violation(s)", count));
+            Err(BranchDBError::ConstraintViolation(format!("{} row problem(s) found by verify-data", count)))
+        }
+    }
+}
+
+// Backs the repository up, then runs the pending format migrations in order.
+pub fn handle_migrate(data_dir: &str, out: &mut Output) -> Result<()> {
+    let path = Path::new(data_dir);
+    if !path.is_dir() {
+        return Err(BranchDBError::InvalidInput(format!("No repository in {}; run `init` first", data_dir)));
+    }
+
+    // Opened directly: CommitStorage refuses repositories that need migrating
+    let db = DB::open_for_read_only(&rocksdb::Options::default(), data_dir, false)?;
+    let version = format::stored_version(&NamespacedDb::new(Arc::new(db), ""))?;
+    if version > format::FORMAT_VERSION {
+        return Err(BranchDBError::FormatTooNew { found: version, supported: format::FORMAT_VERSION });
+    }
+    if format::pending(version).is_empty() {
+        out.line(format!("Already at format version {}", version));
+        out.set_json(json!({ "from": version, "to": version, "backup": null, "steps": [] }));
+        return Ok(());
+    }
+
+    let _lock = RepoLock::acquire(path, StorageOptions::default().lock_timeout)?;
+    let backup = format::backup(path, version)?;
+    out.line(format!("Backed up the repository to {}", backup.display()));
+    let root = NamespacedDb::new(Arc::new(DB::open(&rocksdb::Options::default(), data_dir)?), "");
+    let mut steps = Vec::new();
+    let mut migrated = version;
+    // Namespaces are repositories of their own, at the version of the build that created them
+    for name in std::iter::once(String::new()).chain(namespace::namespaces(&root)?) {
+        let db = root.namespaced(&name);
+        let db = db.with_encryption(encryption::load(&db, path)?);
+        let to = format::migrate(&db, |step| {
+            match name.as_str() {
+                "" => out.line(format!("Migrated to version {}: {}", step.version, step.description)),
+                name => out.line(format!("Migrated namespace '{}' to version {}: {}", name, step.version, step.description)),
+            }
+            steps.push(json!({ "namespace": name, "version": step.version, "description": step.description }));
+        })?;
+        if name.is_empty() {
+            migrated = to;
+        }
+    }
+    out.set_json(json!({
+        "from": version,
+        "to": migrated,
+        "backup": backup.display().to_string(),
+        "steps": steps,
+    }));
+    Ok(())
+}
+
+pub fn handle_config(data_dir: &str, key: Option<&str>, value: Option<&str>, unset: bool, out: &mut Output) -> Result<()> {
+    let mut config = Config::load(Path::new(data_dir))?;
+    let Some(key) = key else {
+        let mut entries = serde_json::Map::new();
+        for (key, value) in config.entries() {
+            out.line(format!("{} = {}", key, value));
+            entries.insert(key.to_string(), json!(value));
+        }
+        out.set_json(serde_json::Value::Object(entries));
+        return Ok(());
+    };
+
+    if unset {
+        if !config.unset(key) {
+            return Err(BranchDBError::InvalidInput(format!("Config key '{}' is not set", key)));
+        }
+        config.save()?;
+        out.set_json(json!({ "key": key, "value": null }));
+    } else if let Some(value) = value {
+        config.set(key, value)?;
+        config.save()?;
+        out.set_json(json!({ "key": key, "value": value }));
+    } else {
+        let value = config.get(key)
+            .ok_or_else(|| BranchDBError::InvalidInput(format!("Config key '{}' is not set", key)))?;
+        out.line(value);
+        out.set_json(json!({ "key": key, "value": value }));
+    }
+    Ok(())
+}
+
+pub fn handle_stats(storage: &CommitStorage, branch_mgr: &BranchManager, internal: bool, out: &mut Output) -> Result<()> {
+    let history = storage.get_commit_history()?;
+    let branches = branch_mgr.list_branches()?;
+    out.line(format!("Commits on HEAD: {}", history.len()));
+    out.line(format!("Branches: {}", branches.len()));
+    let mut document = json!({ "commits": history.len(), "branches": branches.len() });
+
+    if internal {
+        let snapshot = metrics::snapshot();
+        let mut counters = serde_json::Map::new();
+        let mut histograms = serde_json::Map::new();
+        out.line("");
+        out.line("Counters:");
+        for (counter, value) in &snapshot.counters {
+            out.line(format!("  {}: {}", counter.name(), value));
+            counters.insert(counter.name().to_string(), json!(value));
+        }
+        out.line("Histograms:");
+        for h in &snapshot.histograms {
+            let mean = if h.count > 0 { h.sum / h.count as f64 } else { 0.0 };
+            out.line(format!("  {}: count={} sum={} mean={}", h.histogram.name(), h.count, h.sum, mean));
+            histograms.insert(h.histogram.name().to_string(), json!({ "count": h.count, "sum": h.sum, "mean": mean }));
+        }
+        document["internal"] = json!({ "counters": counters, "histograms": histograms });
+    }
+    out.set_json(document);
+    Ok(())
+}
+
+pub fn handle_table_stats(storage: &CommitStorage, table: &str, bucket: Bucket, top: usize, out: &mut Output) -> Result<()> {
+    let stats = storage.table_stats(table, bucket, top)?;
+    out.line(format!("Table {} at {}", stats.table, &stats.head[..12]));
+    out.line(format!("Commits touching it: {}", stats.commits));
+    out.line(format!("Schema changes: {}", stats.schema_changes));
+    out.line(format!(
+        "Row changes: {} inserts, {} updates, {} deletes",
+        stats.changes.inserts, stats.changes.updates, stats.changes.deletes
+    ));
+    out.line(format!("Rows: {} ({} bytes)", stats.rows, stats.bytes));
+    if !stats.buckets.is_empty() {
+        let span = match stats.bucket {
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+        };
+        out.line("");
+        out.line(format!("Changes per {} (inserts/updates/deletes):", span));
+        for bucket in &stats.buckets {
+            let counts = &bucket.changes;
+            out.line(format!("  {}  {}/{}/{}", bucket.start, counts.inserts, counts.updates, counts.deletes));
+        }
+    }
+    if !stats.most_updated.is_empty() {
+        out.line("");
+        out.line("Most updated rows:");
+        for row in &stats.most_updated {
+            out.line(format!("  {}  {} updates", row.id, row.updates));
+        }
+    }
+    out.set_json(serde_json::to_value(&stats)?);
+    Ok(())
+}
+
+pub fn handle_squash(storage: &CommitStorage, before_hash: &str, message: &str, confirm: bool, out: &mut Output) -> Result<()> {
+    if !confirm {
+        return Err(BranchDBError::InvalidInput(
+            "Squashing rewrites history and cannot be undone; pass --confirm to proceed".into()
+        ));
+    }
+    if message.trim().is_empty() {
+        return Err(BranchDBError::InvalidInput("Commit message cannot be empty.".into()));
+    }
+
+    let hash_array = refs::resolve(storage, before_hash)?;
+
+    let root = storage.squash_to_single(&hash_array, message)?;
+    let commit = storage.get_commit_by_hash(&root)?;
+    out.line(format!("Squashed history up to {} into {}", before_hash, hex::encode(root)));
+    out.line(format!("New root commit holds {} entries", commit.changes.len()));
+    out.set_json(json!({ "root": hex::encode(root), "entries": commit.changes.len() }));
+    Ok(())
+}
+
+// Opens the repository read-only for each check instead of through the context, so the watch
+// never holds the write lock.
+pub fn handle_watch(data_dir: &str, options: &WatchOptions, out: &mut Output) -> Result<()> {
+    let summary = watch::run(data_dir, options, out)?;
+    out.set_json(json!({ "last_sequence": summary.last_sequence, "commits": summary.commits }));
+    Ok(())
+}
+
+// `watch --metrics-listen`: the endpoint lasts as long as the watch does.
+#[cfg(feature = "prometheus")]
+fn serve_metrics(addr: &str) -> Result<()> {
+    let bound = metrics::serve_prometheus(addr)?;
+    tracing::info!(%bound, "serving metrics at /metrics");
+    Ok(())
+}
+
+#[cfg(not(feature = "prometheus"))]
+fn serve_metrics(_addr: &str) -> Result<()> {
+    Err(BranchDBError::InvalidInput("--metrics-listen needs a build with `--features prometheus`".to_string()))
+}
+
+pub fn handle_bench(options: &BenchOptions, json: bool, out: &mut Output) -> Result<()> {
+    let report = bench::run(options)?;
+    if json {
+        out.line(serde_json::to_string_pretty(&report)?);
+    } else {
+        for line in report.to_lines() {
+            out.line(line);
+        }
+    }
+    out.set_json(serde_json::to_value(&report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::database::commit_key;
+    use crate::core::test_support::{data_path, head, live_rows, replayed_rows, rows, run, temp_repo};
+    use std::time::Duration;
+
+    // `people.csv` with `count` rows of id, age and score; `bad_row` gets an age that isn't a number.
+    fn people_csv(dir: &Path, count: usize, bad_row: Option<usize>) -> String {
+        let mut csv = String::from("id,age,score\n");
+        for i in 0..count {
+            let age = if Some(i) == bad_row { "unknown".to_string() } else { (20 + i).to_string() };
+            let score = if i % 2 == 0 { String::new() } else { format!("{}.5", i) };
+            csv.push_str(&format!("{},{},{}\n", i, age, score));
+        }
+        let path = dir.join("people.csv");
+        fs::write(&path, csv).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn open_with_batches(dir: &tempfile::TempDir, import_batch_size: usize) -> CommitStorage {
+        let options = StorageOptions { import_batch_size, lock_timeout: Duration::ZERO, ..StorageOptions::default() };
+        CommitStorage::open_with_options(&data_path(dir), options).unwrap()
+    }
+
+    #[test]
+    fn import_csv_infers_the_schema_and_stores_typed_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with_batches(&dir, 100);
+        let file = people_csv(dir.path(), 3, None);
+        let options = CsvImportOptions { infer_types: true, ..CsvImportOptions::default() };
+        handle_import_csv(&storage, &file, "people", &options, &mut Output::new(true)).unwrap();
+
+        let schema = storage.get_table_schema("people", None).unwrap();
+        assert_eq!(schema["columns"], json!({ "id": "INTEGER", "age": "INTEGER", "score": "FLOAT" }));
+        assert_eq!(rows(&storage, "SELECT age, score FROM people WHERE id = '1'"), vec![vec![json!(21), json!(1.5)]]);
+        assert_eq!(rows(&storage, "SELECT age, score FROM people WHERE id = '2'"), vec![vec![json!(22), json!(null)]]);
+    }
+
+    #[test]
+    fn strict_import_csv_fails_on_a_field_of_the_wrong_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with_batches(&dir, 100);
+        run(&storage, "CREATE TABLE people (id TEXT, age INTEGER, score FLOAT)");
+        let file = people_csv(dir.path(), 3, Some(1));
+        let options = CsvImportOptions { infer_types: true, strict: true, ..CsvImportOptions::default() };
+        let result = handle_import_csv(&storage, &file, "people", &options, &mut Output::new(true));
+        assert!(matches!(result, Err(BranchDBError::TypeMismatch(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn resumed_import_csv_keeps_the_schema_it_inferred() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with_batches(&dir, 10);
+        let checkpoint = dir.path().join("people.import.json");
+        // Past the rows the schema is inferred from, so age is still inferred as INTEGER
+        let bad_row = INFER_SAMPLE_ROWS + 15;
+        let file = people_csv(dir.path(), INFER_SAMPLE_ROWS + 30, Some(bad_row));
+        let options = CsvImportOptions {
+            checkpoint: Some(&checkpoint),
+            resume: true,
+            infer_types: true,
+            strict: true,
+            ..CsvImportOptions::default()
+        };
+        assert!(handle_import_csv(&storage, &file, "people", &options, &mut Output::new(true)).is_err());
+        assert!(checkpoint.exists());
+
+        people_csv(dir.path(), INFER_SAMPLE_ROWS + 30, None);
+        let mut out = Output::new(true);
+        handle_import_csv(&storage, &file, "people", &options, &mut out).unwrap();
+        let report = out.take_json();
+        assert!(report["skipped"].as_u64().unwrap() > 0);
+        assert!(!checkpoint.exists());
+
+        let history = storage.get_commit_history().unwrap();
+        assert_eq!(history.iter().filter(|c| c.message.starts_with("Infer schema")).count(), 1);
+        let ages = rows(&storage, "SELECT age FROM people");
+        assert_eq!(ages.len(), INFER_SAMPLE_ROWS + 30);
+        assert!(ages.iter().all(|row| row[0].is_i64()), "{:?}", ages);
+    }
+
+    #[test]
+    fn import_csv_resumes_after_a_crash_without_duplicating_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with_batches(&dir, 100);
+        let checkpoint = dir.path().join("events.import.json");
+        let path = dir.path().join("events.csv");
+        let events = |broken: bool| {
+            let mut csv = String::from("id,kind\n");
+            for i in 0..1000 {
+                // A record with a field too many stops the import after the 500 rows before it
+                let extra = if broken && i == 500 { ",extra" } else { "" };
+                csv.push_str(&format!("{},click{}\n", i, extra));
+            }
+            fs::write(&path, csv).unwrap();
+        };
+        let file = path.to_str().unwrap();
+        let options = CsvImportOptions { checkpoint: Some(&checkpoint), resume: true, ..CsvImportOptions::default() };
+
+        events(true);
+        assert!(handle_import_csv(&storage, file, "events", &options, &mut Output::new(true)).is_err());
+        assert_eq!(ImportCheckpoint::load(&checkpoint).unwrap().unwrap().last_row_index, 500);
+        assert_eq!(live_rows(&storage, "events").len(), 500);
+
+        events(false);
+        let mut out = Output::new(true);
+        handle_import_csv(&storage, file, "events", &options, &mut out).unwrap();
+        assert_eq!(out.take_json()["skipped"], 500);
+        assert!(!checkpoint.exists());
+
+        // Every row was written by exactly one change across the ten batch commits
+        let mut written: Vec<String> = storage.get_commit_history().unwrap().iter()
+            .flat_map(|commit| commit.changes.iter().map(|change| change.id().to_string()))
+            .collect();
+        assert_eq!(written.len(), 1000);
+        written.sort_by_key(|id| id.parse::<usize>().unwrap());
+        assert_eq!(written, (0..1000).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert_eq!(live_rows(&storage, "events").len(), 1000);
+    }
+
+    // Parses and runs `branchdb --json <args>` as main does, returning what it printed as JSON.
+    fn branchdb(ctx: &CommandContext, args: &[&str]) -> serde_json::Value {
+        let cli = CommandsWrapper::try_parse_from(["branchdb", "--json"].iter().chain(args)).unwrap();
+        let mut out = Output::new(true);
+        cli.command.run(ctx, &mut out).unwrap_or_else(|e| panic!("{:?}: {}", args, e));
+        out.take_json()
+    }
+
+    #[test]
+    fn branch_list_and_merge_run_from_the_command_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = CommandContext::new(&data_path(&dir), StorageOptions::default(), true);
+        branchdb(&ctx, &["sql", "CREATE TABLE users (id TEXT, name TEXT)"]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('1', 'ann')"]);
+        branchdb(&ctx, &["branch", "main"]);
+        // Branches don't follow commits, so `feature` is made where its commit already is
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('2', 'bob')"]);
+        branchdb(&ctx, &["branch", "feature"]);
+
+        let branches = branchdb(&ctx, &["branch-list", "--divergence", "main"]);
+        let listed: Vec<_> = branches.as_array().unwrap().iter()
+            .map(|b| (b["name"].as_str().unwrap(), b["current"].as_bool().unwrap(), b["ahead"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(listed, vec![("feature", true, 1), ("main", false, 0)]);
+        let feature = branches[0]["head"].clone();
+
+        branchdb(&ctx, &["checkout", "main"]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('3', 'cy')"]);
+        let merge = branchdb(&ctx, &["merge", "feature", "-m", "Merge feature"]);
+        assert_eq!((&merge["fast_forward"], &merge["conflicts"]), (&json!(false), &json!([])));
+        let storage = ctx.storage().unwrap();
+        let merged = storage.get_commit_by_hash(&head(storage)).unwrap();
+        assert_eq!(merged.message, "Merge feature");
+        assert_eq!(merged.parents.iter().map(hex::encode).map(serde_json::Value::from).nth(1), Some(feature));
+        assert_eq!(rows(storage, "SELECT id FROM users ORDER BY id"), vec![vec![json!("1")], vec![json!("2")], vec![json!("3")]]);
+
+        let again = CommandsWrapper::try_parse_from(["branchdb", "merge", "feature"]).unwrap();
+        let err = again.command.run(&ctx, &mut Output::new(true)).unwrap_err();
+        assert!(matches!(&err, BranchDBError::InvalidInput(message) if message == "Already up to date"), "{}", err);
+    }
+
+    #[test]
+    fn show_table_columns_keeps_only_the_named_columns_in_their_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = CommandContext::new(&data_path(&dir), StorageOptions::default(), true);
+        branchdb(&ctx, &["sql", "CREATE TABLE people (id TEXT, name TEXT, city TEXT, email TEXT, age TEXT)"]);
+        branchdb(&ctx, &["sql", "INSERT INTO people VALUES ('1', 'ann', 'Paris', 'ann@example.com', '30')"]);
+        branchdb(&ctx, &["sql", "INSERT INTO people VALUES ('2', 'bob', 'Rome', 'bob@example.com', '41')"]);
+
+        let shown = branchdb(&ctx, &["show-table", "people", "--columns", "city,name"]);
+        assert_eq!(shown["rows"], json!({
+            "1": {"city": "Paris", "name": "ann"},
+            "2": {"city": "Rome", "name": "bob"},
+        }));
+        assert_eq!(shown["rows"]["1"].as_object().unwrap().keys().collect::<Vec<_>>(), ["city", "name"]);
+
+        // Combined with --where, the filter may be on a column that isn't shown
+        let shown = branchdb(&ctx, &["show-table", "people", "--columns", "email", "--where", "age=41"]);
+        assert_eq!(shown["rows"], json!({"2": {"email": "bob@example.com"}}));
+    }
+
+    // The schema and rows `show-table --format json` prints.
+    fn show_table(storage: &CommitStorage, table: &str, commit_hash: Option<&str>) -> (serde_json::Value, serde_json::Value) {
+        let options = ShowTableOptions {
+            format: OutputFormat::Json,
+            border: false,
+            include_deleted: false,
+            markdown: MarkdownOptions { caption: false, max_col_width: None },
+            columns: None,
+            filter: None,
+        };
+        let mut out = Output::new(true);
+        handle_show_table(storage, table, commit_hash, &options, &mut out).unwrap();
+        let mut shown = out.take_json();
+        (shown["schema"].take(), shown["rows"].take())
+    }
+
+    #[test]
+    fn show_table_at_a_revert_commit_matches_the_live_table() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let target = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+        run(&storage, "UPDATE users SET name = 'anne' WHERE id = '1'");
+
+        let reverted = hex::encode(storage.revert_to_commit(&target).unwrap());
+        let live = show_table(&storage, "users", None);
+        assert_eq!(show_table(&storage, "users", Some(&reverted)), live);
+        assert_eq!(show_table(&storage, "users", Some(&hex::encode(target))), live);
+    }
+
+    // Every table's live rows hold exactly its state replayed from history at HEAD.
+    fn assert_live_matches_head(storage: &CommitStorage) {
+        let head = head(storage);
+        for table in storage.list_tables_at(&head).unwrap() {
+            assert_eq!(live_rows(storage, &table), replayed_rows(storage, &table, &head), "table {}", table);
+        }
+    }
+
+    #[test]
+    fn live_rows_match_head_after_every_kind_of_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with_batches(&dir, 2);
+        let out = &mut Output::new(true);
+        handle_sql(&storage, "CREATE TABLE users (id TEXT, name TEXT)", OutputFormat::Table, false, out).unwrap();
+        handle_sql(&storage, "INSERT INTO users VALUES ('1', 'ann')", OutputFormat::Table, false, out).unwrap();
+        let first = head(&storage);
+        assert_live_matches_head(&storage);
+
+        let file = people_csv(dir.path(), 5, None);
+        handle_import_csv(&storage, &file, "people", &CsvImportOptions::default(), out).unwrap();
+        assert_live_matches_head(&storage);
+
+        handle_sql(&storage, "UPDATE users SET name = 'anne' WHERE id = '1'", OutputFormat::Table, false, out).unwrap();
+        handle_sql(&storage, "DELETE FROM people WHERE id = '3'", OutputFormat::Table, false, out).unwrap();
+        assert_live_matches_head(&storage);
+
+        // A branch with a row of its own, then a merge of it into a HEAD that moved on
+        let base = hex::encode(head(&storage));
+        handle_sql(&storage, "INSERT INTO users VALUES ('2', 'bob')", OutputFormat::Table, false, out).unwrap();
+        BranchManager::new(storage.db.clone()).create_branch("feature").unwrap();
+        handle_checkout(&storage, &base, false, out).unwrap();
+        assert_live_matches_head(&storage);
+        handle_sql(&storage, "INSERT INTO users VALUES ('3', 'cy')", OutputFormat::Table, false, out).unwrap();
+        handle_merge(&storage, &data_path(&dir), "feature", None, out).unwrap();
+        assert_live_matches_head(&storage);
+        assert_eq!(live_rows(&storage, "users").len(), 4);
+
+        storage.revert_to_commit(&first).unwrap();
+        assert_live_matches_head(&storage);
+        assert!(live_rows(&storage, "people").is_empty());
+    }
+
+    #[test]
+    fn migrate_upgrades_every_namespace() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (storage, out) = (CommitStorage::open(&data_path(&dir)).unwrap(), &mut Output::new(true));
+            storage.create_namespace("acme").unwrap();
+            let acme = storage.with_namespace("acme").unwrap();
+            handle_sql(&acme, "CREATE TABLE users (id TEXT, name TEXT)", OutputFormat::Table, false, out).unwrap();
+            for db in [storage.db.namespaced(""), storage.db.namespaced("acme")] {
+                db.put(format::FORMAT_VERSION_KEY, (format::FORMAT_VERSION - 1).to_le_bytes()).unwrap();
+            }
+        }
+        assert!(CommitStorage::open(&data_path(&dir)).is_err());
+
+        handle_migrate(&data_path(&dir), &mut Output::new(true)).unwrap();
+        let storage = CommitStorage::open(&data_path(&dir)).unwrap();
+        let acme = storage.with_namespace("acme").unwrap();
+        assert_eq!(acme.list_tables().unwrap(), vec!["users".to_string()]);
+    }
+
+    #[test]
+    fn destructive_statements_need_yes_without_a_terminal() {
+        let (_dir, storage) = temp_repo();
+        let out = &mut Output::new(true);
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+
+        // Writes that name their rows aren't confirmed
+        handle_sql(&storage, "DELETE FROM users WHERE id = '2'", OutputFormat::Table, false, out).unwrap();
+        if !std::io::stdin().is_terminal() {
+            let before = head(&storage);
+            let err = handle_sql(&storage, "TRUNCATE TABLE users", OutputFormat::Table, false, out).unwrap_err();
+            assert!(matches!(&err, BranchDBError::Aborted(summary) if summary.starts_with("This will insert 0, update 0 and delete 1 rows across 1 table")));
+            assert!(handle_sql(&storage, "DROP TABLE users", OutputFormat::Table, false, out).is_err());
+            assert_eq!(head(&storage), before);
+        }
+
+        handle_sql(&storage, "TRUNCATE TABLE users", OutputFormat::Table, true, out).unwrap();
+        assert!(rows(&storage, "SELECT id FROM users").is_empty());
+        assert_eq!(live_rows(&storage, "users").into_keys().collect::<Vec<_>>(), vec!["!schema".to_string()]);
+        handle_sql(&storage, "DROP TABLE users", OutputFormat::Table, true, out).unwrap();
+        assert!(SqlExecutor::execute(&storage, "SELECT id FROM users").is_err());
+    }
+
+    #[test]
+    fn repair_rewrites_commits_whose_checksum_is_damaged() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        let first = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let second = head(&storage);
+        let stored: Vec<_> = [first, second].iter().map(|hash| storage.db.get(commit_key(hash)).unwrap().unwrap()).collect();
+        // Checksum written twice, and cut short
+        storage.db.put(commit_key(&first), [&stored[0][..], &first[..]].concat()).unwrap();
+        storage.db.put(commit_key(&second), &stored[1][..stored[1].len() - 5]).unwrap();
+        assert!(storage.get_commit_by_hash(&second).is_err());
+
+        let mut out = Output::new(true);
+        handle_repair(&storage, &mut out).unwrap();
+        assert_eq!(out.take_json()["checksums_repaired"], 2);
+        for (hash, stored) in [first, second].iter().zip(&stored) {
+            assert_eq!(&storage.db.get(commit_key(hash)).unwrap().unwrap(), stored);
+        }
+        assert_eq!(rows(&storage, "SELECT name FROM users"), vec![vec![json!("ann")]]);
+        handle_repair(&storage, &mut out).unwrap();
+        assert_eq!(out.take_json()["checksums_repaired"], 0);
+    }
+
+    #[test]
+    fn log_author_keeps_the_commits_whose_author_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        for (user, count) in [("alice", 6), ("bob", 4)] {
+            let mut options = StorageOptions { lock_timeout: Duration::ZERO, ..StorageOptions::default() };
+            options.commit_context.user = Some(user.to_string());
+            let storage = CommitStorage::open_with_options(&data_path(&dir), options).unwrap();
+            if user == "alice" {
+                run(&storage, "CREATE TABLE notes (id TEXT, body TEXT)");
+            }
+            for i in 0..count - usize::from(user == "alice") {
+                run(&storage, &format!("INSERT INTO notes VALUES ('{}{}', 'x')", user, i));
+            }
+        }
+        let storage = CommitStorage::open(&data_path(&dir)).unwrap();
+        let log = |author: Option<&str>| {
+            let options = LogOptions {
+                limit: None,
+                style: LogStyle::Default,
+                stat: false,
+                graph: false,
+                after: None,
+                before: None,
+                table: None,
+                all: false,
+                branches: None,
+                best_effort: false,
+                grep_source: None,
+                author: author.map(str::to_string),
+            };
+            let mut out = Output::new(true);
+            handle_log(&storage, &options, &mut out).map(|()| out.take_json().as_array().unwrap().len())
+        };
+
+        assert_eq!(log(None).unwrap(), 10);
+        assert_eq!(log(Some("^alice@")).unwrap(), 6);
+        assert_eq!(log(Some("^bob(@|$)")).unwrap(), 4);
+        assert_eq!(log(Some("ALICE")).unwrap(), 0);
+        assert_eq!(log(Some("(?i)^(alice|BOB)")).unwrap(), 10);
+        assert!(matches!(log(Some("(")), Err(BranchDBError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn diff_reports_an_added_column_as_a_schema_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = CommandContext::new(&data_path(&dir), StorageOptions::default(), true);
+        branchdb(&ctx, &["sql", "CREATE TABLE users (id TEXT, name TEXT)"]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('1', 'ann')"]);
+        let from = hex::encode(head(ctx.storage().unwrap()));
+        branchdb(&ctx, &["sql", "ALTER TABLE users ADD COLUMN email TEXT"]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('2', 'bob', 'bob@example.com')"]);
+        let to = hex::encode(head(ctx.storage().unwrap()));
+
+        let added = json!({"op": "schema", "table": "users", "change": "+ email TEXT"});
+        assert_eq!(branchdb(&ctx, &["diff", &from, &to, "--schema-only"]), json!([added]));
+        let both = branchdb(&ctx, &["diff", &from, &to]);
+        assert!(both.as_array().unwrap().contains(&added), "{}", both);
+        assert!(both.as_array().unwrap().iter().any(|entry| entry["op"] != "schema"), "{}", both);
+        let data = branchdb(&ctx, &["diff", &from, &to, "--data-only"]);
+        assert!(data.as_array().unwrap().iter().all(|entry| entry["op"] != "schema"), "{}", data);
+        assert_eq!(branchdb(&ctx, &["diff", &to, &from, "--schema-only"])[0]["change"], "- email TEXT");
+    }
+
+    #[test]
+    fn checkout_theirs_stages_the_merged_branchs_row() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = CommandContext::new(&data_path(&dir), StorageOptions::default(), true);
+        branchdb(&ctx, &["sql", "CREATE TABLE users (id TEXT, name TEXT)"]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('1', 'ann')"]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('2', 'bob')"]);
+        branchdb(&ctx, &["branch", "main"]);
+        branchdb(&ctx, &["sql", "UPDATE users SET name = 'theirs' WHERE id = '1'"]);
+        branchdb(&ctx, &["sql", "UPDATE users SET name = 'bo' WHERE id = '2'"]);
+        branchdb(&ctx, &["branch", "feature"]);
+        branchdb(&ctx, &["checkout", "main"]);
+        branchdb(&ctx, &["sql", "UPDATE users SET name = 'ours' WHERE id = '1'"]);
+        branchdb(&ctx, &["sql", "UPDATE users SET name = 'b' WHERE id = '2'"]);
+        let merge = CommandsWrapper::try_parse_from(["branchdb", "merge", "feature"]).unwrap();
+        let err = merge.command.run(&ctx, &mut Output::new(true)).unwrap_err();
+        assert!(err.to_string().contains("2 conflict(s)"), "{}", err);
+        let storage = ctx.storage().unwrap();
+        let before = head(storage);
+
+        assert_eq!(branchdb(&ctx, &["checkout", "--theirs", "users:1"]), json!({"staged": "theirs", "table": "users", "id": "1"}));
+        branchdb(&ctx, &["checkout", "--ours", "users:2"]);
+        // Nothing is committed until `commit`: queries still read HEAD
+        assert_eq!(head(storage), before);
+        assert_eq!(rows(storage, "SELECT name FROM users ORDER BY id"), vec![vec![json!("ours")], vec![json!("b")]]);
+        assert_eq!(storage.staged_changes().unwrap().len(), 2);
+        let again = CommandsWrapper::try_parse_from(["branchdb", "checkout", "--theirs", "users:1"]).unwrap();
+        assert!(matches!(again.command.run(&ctx, &mut Output::new(true)), Err(BranchDBError::InvalidInput(_))));
+
+        branchdb(&ctx, &["commit", "-m", "Resolve conflicts"]);
+        assert!(storage.staged_changes().unwrap().is_empty());
+        assert_eq!(storage.get_commit_by_hash(&head(storage)).unwrap().parents.len(), 2);
+        assert_eq!(rows(storage, "SELECT name FROM users ORDER BY id"), vec![vec![json!("theirs")], vec![json!("b")]]);
+        assert_live_matches_head(storage);
+    }
+
+    #[test]
+    fn sql_answers_a_select_exactly_as_query_does() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let old = hex::encode(head(&storage));
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+
+        let selects = [
+            "SELECT name FROM users ORDER BY name".to_string(),
+            format!("SELECT * FROM users WITH {}", old),
+            format!("WITH \"{}\" AS (SELECT * FROM users) SELECT name FROM users", old),
+        ];
+        let answers: Vec<_> = selects.iter().map(|select| {
+            let mut out = Output::new(true);
+            handle_query(&storage, select, OutputFormat::Json, None, false, &mut out).unwrap();
+            let queried = out.take_json();
+            handle_sql(&storage, select, OutputFormat::Json, false, &mut out).unwrap();
+            assert_eq!(out.take_json(), queried, "{}", select);
+            queried
+        }).collect();
+        assert_eq!(answers, vec![
+            json!([{"name": "ann"}, {"name": "bob"}]),
+            json!([{"id": "1", "name": "ann"}]),
+            json!([{"name": "ann"}]),
+        ]);
+    }
+
+    #[test]
+    fn into_outfile_writes_the_selected_rows_with_the_given_separators() {
+        let (dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT, city TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann', 'Paris')");
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob', 'Rome, Italy')");
+        run(&storage, "INSERT INTO users VALUES ('3', 'cy', 'Oslo')");
+
+        let path = dir.path().join("users.csv");
+        let select = format!(
+            "SELECT id, name, city FROM users WHERE id <> '3' ORDER BY id INTO OUTFILE '{}' FIELDS TERMINATED BY ',' OPTIONALLY ENCLOSED BY '\"'",
+            path.display()
+        );
+        let mut out = Output::new(true);
+        handle_sql(&storage, &select, OutputFormat::Table, false, &mut out).unwrap();
+        assert_eq!(out.take_json(), json!({"file": path.display().to_string(), "rows": 2, "columns": ["id", "name", "city"]}));
+
+        let written = fs::read_to_string(&path).unwrap();
+        let fields: Vec<Vec<String>> = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(written.as_bytes())
+            .records()
+            .map(|record| record.unwrap().iter().map(String::from).collect())
+            .collect();
+        assert_eq!(fields, vec![vec!["1", "ann", "Paris"], vec!["2", "bob", "Rome, Italy"]]);
+        // OPTIONALLY encloses only the fields that aren't numbers
+        assert_eq!(written, "1,\"ann\",\"Paris\"\n2,\"bob\",\"Rome, Italy\"\n");
+
+        // The file is never overwritten
+        let again = handle_sql(&storage, &select, OutputFormat::Table, false, &mut out);
+        assert!(matches!(again, Err(BranchDBError::InvalidInput(_))));
+    }
+
+    // The field names of a JSON object, sorted.
+    fn keys(value: &serde_json::Value) -> Vec<&str> {
+        let mut keys: Vec<_> = value.as_object().unwrap_or_else(|| panic!("not an object: {}", value)).keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn json_output_of_each_command_has_its_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = CommandContext::new(&data_path(&dir), StorageOptions::default(), true);
+        let created = branchdb(&ctx, &["sql", "CREATE TABLE users (id TEXT, name TEXT)"]);
+        assert_eq!(keys(&created), ["changes", "hash"]);
+        assert_eq!(created["hash"].as_str().unwrap().len(), 64);
+        let inserted = branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('1', 'ann')"]);
+        assert_eq!(inserted["changes"], 1);
+        assert_eq!(keys(&branchdb(&ctx, &["branch", "main"])), ["action", "branch", "protected"]);
+
+        let log = branchdb(&ctx, &["log"]);
+        assert_eq!(log.as_array().unwrap().len(), 2);
+        for commit in log.as_array().unwrap() {
+            assert_eq!(keys(commit), ["audit", "changes", "hash", "message", "parents", "sequence", "timestamp"]);
+        }
+        assert_eq!(log[0]["parents"], json!([created["hash"]]));
+
+        let branches = branchdb(&ctx, &["branch-list"]);
+        assert_eq!(branches, json!([{"name": "main", "head": inserted["hash"], "current": true}]));
+
+        let from = created["hash"].as_str().unwrap();
+        let diff = branchdb(&ctx, &["diff", from, "HEAD"]);
+        assert_eq!(diff, json!([{"op": "insert", "table": "users", "id": "1", "value": {"id": "1", "name": "ann"}}]));
+
+        // main prints a failure as {"error": <this>}
+        let missing = CommandsWrapper::try_parse_from(["branchdb", "--json", "checkout", &"0".repeat(64)]).unwrap();
+        let err = missing.command.run(&ctx, &mut Output::new(true)).unwrap_err();
+        let error = serde_json::to_value(&err).unwrap();
+        assert_eq!(keys(&error), ["code", "message"]);
+        assert_eq!(error["code"], err.code());
+    }
+
+    #[test]
+    fn writes_through_query_commit_what_sql_commits() {
+        let statements = [
+            "CREATE TABLE users (id TEXT, name TEXT)",
+            "INSERT INTO users VALUES ('1', 'ann')",
+            "INSERT INTO users VALUES ('2', 'bob')",
+            "UPDATE users SET name = 'bo' WHERE id = '2'",
+            "DELETE FROM users WHERE id = '1'",
+        ];
+        let commits: Vec<Vec<_>> = ["query", "sql"].iter().map(|command| {
+            let dir = tempfile::tempdir().unwrap();
+            let ctx = CommandContext::new(&data_path(&dir), StorageOptions::default(), true);
+            for statement in statements {
+                assert_eq!(branchdb(&ctx, &[command, statement])["changes"], 1, "{} {}", command, statement);
+            }
+            let storage = ctx.storage().unwrap();
+            let mut history = storage.get_commit_history().unwrap();
+            history.reverse();
+            history
+        }).collect();
+
+        assert_eq!(commits[0].len(), statements.len());
+        for (queried, executed) in commits[0].iter().zip(&commits[1]) {
+            assert_eq!(queried.message, executed.message);
+            assert_eq!(queried.tree, executed.tree, "{}", queried.message);
+            assert_eq!(bincode::serialize(&queried.changes).unwrap(), bincode::serialize(&executed.changes).unwrap(), "{}", queried.message);
+        }
+    }
+
+    #[test]
+    fn three_dot_diff_shows_only_what_the_second_side_changed_since_the_merge_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = CommandContext::new(&data_path(&dir), StorageOptions::default(), true);
+        branchdb(&ctx, &["sql", "CREATE TABLE users (id TEXT, name TEXT)"]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('1', 'ann')"]);
+        branchdb(&ctx, &["branch", "root"]);
+        let root = hex::encode(head(ctx.storage().unwrap()));
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('a', 'on a')"]);
+        branchdb(&ctx, &["branch", "side-a"]);
+        branchdb(&ctx, &["checkout", "root"]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('b', 'on b')"]);
+        branchdb(&ctx, &["sql", "UPDATE users SET name = 'anne' WHERE id = '1'"]);
+        branchdb(&ctx, &["branch", "side-b"]);
+
+        let ops = |diff: &serde_json::Value| -> Vec<(String, String)> {
+            let mut ops: Vec<_> = diff.as_array().unwrap().iter()
+                .map(|c| (c["op"].as_str().unwrap().to_string(), c["id"].as_str().unwrap().to_string()))
+                .collect();
+            ops.sort();
+            ops
+        };
+        let pair = |op: &str, id: &str| (op.to_string(), id.to_string());
+
+        let three_dot = branchdb(&ctx, &["diff", "side-a", "side-b", "--three-dot"]);
+        assert_eq!(three_dot["merge_base"], root);
+        assert_eq!(ops(&three_dot["changes"]), [pair("insert", "b"), pair("update", "1")]);
+        let reversed = branchdb(&ctx, &["diff", "side-b", "side-a", "--three-dot"]);
+        assert_eq!(ops(&reversed["changes"]), [pair("insert", "a")]);
+        // Two dots compare the commits themselves, so side-a's row shows up as deleted
+        let two_dot = branchdb(&ctx, &["diff", "side-a", "side-b"]);
+        assert_eq!(ops(&two_dot), [pair("delete", "a"), pair("insert", "b"), pair("update", "1")]);
+    }
+
+    #[test]
+    fn log_all_follows_every_branch_and_branches_picks_some() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = CommandContext::new(&data_path(&dir), StorageOptions::default(), true);
+        branchdb(&ctx, &["sql", "CREATE TABLE users (id TEXT, name TEXT)"]);
+        let root = hex::encode(head(ctx.storage().unwrap()));
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('m', 'on main')"]);
+        branchdb(&ctx, &["branch", "main"]);
+        branchdb(&ctx, &["checkout", &root]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('f', 'on feature')"]);
+        branchdb(&ctx, &["sql", "INSERT INTO users VALUES ('g', 'on feature')"]);
+        branchdb(&ctx, &["branch", "feature-1"]);
+
+        let messages = |args: &[&str]| -> Vec<String> {
+            let log = branchdb(&ctx, &[&["log"], args].concat());
+            log.as_array().unwrap().iter().map(|c| c["message"].as_str().unwrap().trim_start_matches("SQL: ").to_string()).collect()
+        };
+        let create = "CREATE TABLE users (id TEXT, name TEXT)";
+        let insert = |id: &str, name: &str| format!("INSERT INTO users VALUES ('{}', '{}')", id, name);
+
+        assert_eq!(messages(&[]), [insert("g", "on feature"), insert("f", "on feature"), create.to_string()]);
+        let all = messages(&["--all"]);
+        assert_eq!(all, [insert("g", "on feature"), insert("f", "on feature"), insert("m", "on main"), create.to_string()]);
+        assert_eq!(messages(&["--all", "--branches", "main"]), [insert("m", "on main"), create.to_string()]);
+        assert_eq!(messages(&["--all", "--branches", "feature-*"]), messages(&[]));
+        let unmatched = CommandsWrapper::try_parse_from(["branchdb", "log", "--all", "--branches", "nothing*"]).unwrap();
+        assert!(matches!(unmatched.command.run(&ctx, &mut Output::new(true)), Err(BranchDBError::BranchNotFound { .. })));
+    }
+
+    #[test]
+    fn an_old_commit_shows_a_column_dropped_since() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT, email TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann', 'ann@example.com')");
+        let old = hex::encode(head(&storage));
+        run(&storage, "ALTER TABLE users DROP COLUMN email");
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+
+        let (schema, shown) = show_table(&storage, "users", Some(&old));
+        assert!(schema["columns"].get("email").is_some(), "{}", schema);
+        assert_eq!(shown, json!({"1": {"id": "1", "name": "ann", "email": "ann@example.com"}}));
+        let (schema, shown) = show_table(&storage, "users", None);
+        assert!(schema["columns"].get("email").is_none(), "{}", schema);
+        assert_eq!(shown, json!({"1": {"id": "1", "name": "ann"}, "2": {"id": "2", "name": "bob"}}));
+
+        assert_eq!(rows(&storage, &format!("SELECT * FROM users WITH {}", old)),
+            vec![vec![json!("1"), json!("ann"), json!("ann@example.com")]]);
+        assert_eq!(rows(&storage, "SELECT * FROM users WHERE id = '1'"), vec![vec![json!("1"), json!("ann")]]);
+    }
+
+    #[test]
+    fn an_empty_table_exists_from_create_to_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = CommandContext::new(&data_path(&dir), StorageOptions::default(), true);
+        branchdb(&ctx, &["sql", "CREATE TABLE products (id TEXT, name TEXT)"]);
+
+        assert_eq!(branchdb(&ctx, &["list-tables"])["tables"], json!(["products"]));
+        let shown = branchdb(&ctx, &["show-table", "products"]);
+        assert_eq!((&shown["schema"], &shown["rows"]), (&json!({"columns": {"id": "TEXT", "name": "TEXT"}}), &json!({})));
+        assert_eq!(branchdb(&ctx, &["sql", "SELECT * FROM products"]), json!([]));
+        let csv = dir.path().join("products.csv");
+        let csv = csv.to_str().unwrap();
+        assert_eq!(branchdb(&ctx, &["export-csv", "products", csv])["rows"], 0);
+        assert_eq!(fs::read_to_string(csv).unwrap(), "id,name\n");
+
+        fs::write(csv, "id,name\n1,pen\n").unwrap();
+        assert_eq!(branchdb(&ctx, &["import-csv", csv, "products"])["rows"], 1);
+        assert_eq!(branchdb(&ctx, &["sql", "SELECT * FROM products"]), json!([{"id": "1", "name": "pen"}]));
+        branchdb(&ctx, &["sql", "DELETE FROM products WHERE id = '1'"]);
+        assert_eq!(branchdb(&ctx, &["show-table", "products"])["rows"], json!({}));
+
+        // Empty again, and still droppable
+        branchdb(&ctx, &["sql", "DROP TABLE products"]);
+        assert_eq!(branchdb(&ctx, &["list-tables"])["tables"], json!([]));
+        let select = SqlExecutor::execute(ctx.storage().unwrap(), "SELECT * FROM products");
+        assert!(matches!(select, Err(BranchDBError::TableNotFound { .. })), "{:?}", select);
+    }
+}
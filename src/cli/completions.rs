@@ -0,0 +1,146 @@
+use crate::cli::commands::CommandsWrapper;
+use crate::core::branch::BranchManager;
+use crate::core::database::CommitStorage;
+use crate::error::Result;
+use clap::CommandFactory;
+use clap_complete::Shell;
+use std::io::Write;
+
+const BIN: &str = "branchdb";
+
+// Shell glue for dynamic candidates. Each shell passes the words typed so far to the hidden
+// `__complete` command; it prints candidates, or exits nonzero when the position has no dynamic
+// completion and the static clap completion should be used instead.
+const BASH_DYNAMIC: &str = r#"
+_branchdb_dynamic() {
+    local candidates
+    if candidates=$(branchdb __complete -- "${COMP_WORDS[@]:0:COMP_CWORD+1}" 2>/dev/null); then
+        COMPREPLY=($(compgen -W "$candidates" -- "${COMP_WORDS[COMP_CWORD]}"))
+    else
+        _branchdb "$@"
+    fi
+}
+complete -F _branchdb_dynamic -o nosort -o bashdefault -o default branchdb
+"#;
+
+const ZSH_DYNAMIC: &str = r#"
+_branchdb_dynamic() {
+    local output
+    if output=$(branchdb __complete -- "${(@)words[1,CURRENT]}" 2>/dev/null); then
+        local -a candidates
+        candidates=(${(f)output})
+        compadd -a candidates
+    else
+        _branchdb "$@"
+    fi
+}
+compdef _branchdb_dynamic branchdb
+"#;
+
+const FISH_DYNAMIC: &str = r#"
+function __branchdb_dynamic
+    branchdb __complete -- (commandline -opc) (commandline -ct) 2>/dev/null
+end
+complete -c branchdb -f -n '__branchdb_dynamic >/dev/null' -a '(__branchdb_dynamic)'
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompletionKind {
+    Branches,
+    Tables,
+    Commits,
+    // Branches or commits, wherever either is accepted
+    Refs,
+}
+
+pub fn print_script(shell: Shell) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    clap_complete::generate(shell, &mut CommandsWrapper::command(), BIN, &mut stdout);
+    let dynamic = match shell {
+        Shell::Bash => BASH_DYNAMIC,
+        Shell::Zsh => ZSH_DYNAMIC,
+        Shell::Fish => FISH_DYNAMIC,
+        _ => "",
+    };
+    stdout.write_all(dynamic.as_bytes())?;
+    Ok(())
+}
+
+// Prints candidates for the last word of `words`. Returns false when the position has no
+// dynamic completion. A missing or unreadable repository yields no candidates, never an error.
+pub fn complete(words: &[String], data_dir: &str) -> bool {
+    let Some((current, before)) = words.split_last() else { return false };
+    let Some(kind) = completion_kind(before.get(1..).unwrap_or_default(), current) else { return false };
+
+    for candidate in candidates(kind, data_dir).unwrap_or_default() {
+        if candidate.starts_with(current.as_str()) {
+            println!("{}", candidate);
+        }
+    }
+    true
+}
+
+// `before` holds the words after the program name and before the one being completed.
+fn completion_kind(before: &[String], current: &str) -> Option<CompletionKind> {
+    match before.last().map(String::as_str) {
+        Some("--commit") => return Some(CompletionKind::Refs),
+        Some("--commit-hash") => return Some(CompletionKind::Commits),
+        _ => {}
+    }
+    if current.starts_with('-') {
+        return None;
+    }
+
+    // Skip global flags (and the value of --log-format) to find the subcommand
+    let mut words = before.iter().map(String::as_str);
+    let subcommand = loop {
+        match words.next()? {
+            "--log-format" => {
+                words.next();
+            }
+            flag if flag.starts_with('-') => {}
+            subcommand => break subcommand,
+        }
+    };
+    let rest: Vec<&str> = words.collect();
+    let position = rest.iter().filter(|w| !w.starts_with('-')).count();
+    let deleting = rest.iter().any(|w| *w == "-d" || *w == "--delete");
+
+    match (subcommand, position) {
+        ("checkout" | "rebase", 0) | ("ancestor-check", 0 | 1) => Some(CompletionKind::Refs),
+        ("merge" | "unprotect-branch", 0) => Some(CompletionKind::Branches),
+        ("branch", 0) if deleting => Some(CompletionKind::Branches),
+        ("show-table" | "show" | "export-csv" | "export-md", 0) | ("import-csv", 1) => Some(CompletionKind::Tables),
+        ("diff", 0 | 1) | ("revert" | "squash", 0) => Some(CompletionKind::Commits),
+        _ => None,
+    }
+}
+
+// Only reads branch refs, the table registry and HEAD, so completion stays fast on large
+// repositories.
+fn candidates(kind: CompletionKind, data_dir: &str) -> Result<Vec<String>> {
+    let storage = CommitStorage::open_read_only(data_dir)?;
+    let branch_mgr = BranchManager::new(storage.db.clone());
+    let commits = || -> Result<Vec<String>> {
+        let mut hashes: Vec<String> = storage.get_head()?.map(hex::encode).into_iter().collect();
+        for branch in branch_mgr.list_branches()? {
+            if let Some(head) = branch_mgr.get_branch_head(&branch)? {
+                hashes.push(hex::encode(head));
+            }
+        }
+        hashes.sort();
+        hashes.dedup();
+        Ok(hashes)
+    };
+
+    match kind {
+        CompletionKind::Branches => branch_mgr.list_branches(),
+        CompletionKind::Tables => storage.list_tables(),
+        CompletionKind::Commits => commits(),
+        CompletionKind::Refs => {
+            let mut refs = branch_mgr.list_branches()?;
+            refs.extend(commits()?);
+            Ok(refs)
+        }
+    }
+}
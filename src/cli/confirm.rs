@@ -0,0 +1,49 @@
+use crate::cli::diff::DiffStat;
+use crate::error::{BranchDBError, Result};
+use std::collections::BTreeMap;
+use std::io::{BufRead, IsTerminal, Write};
+
+// Asks before a destructive command runs. `--yes` skips the prompt; without a terminal on stdin
+// there is nobody to ask, so the command is refused instead of silently going ahead.
+pub fn confirm(summary: &str, yes: bool) -> Result<()> {
+    if yes {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(BranchDBError::Aborted(format!("{} and stdin is not a terminal", summary)));
+    }
+
+    let mut stderr = std::io::stderr();
+    write!(stderr, "{}. Continue? [y/N] ", summary)?;
+    stderr.flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(BranchDBError::Aborted(summary.to_string())),
+    }
+}
+
+// "This will insert 2, update 1 and delete 1,204 rows across 3 tables"
+pub fn rows_summary(stats: &BTreeMap<String, DiffStat>) -> String {
+    let (inserted, updated, deleted) = stats.values()
+        .fold((0, 0, 0), |(i, u, d), s| (i + s.inserted, u + s.updated, d + s.deleted));
+    let tables = stats.len();
+    format!(
+        "This will insert {}, update {} and delete {} rows across {} table{}",
+        thousands(inserted), thousands(updated), thousands(deleted), tables,
+        if tables == 1 { "" } else { "s" },
+    )
+}
+
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
@@ -0,0 +1,98 @@
+use crate::error::{BranchDBError, Result};
+use std::io::{self, IsTerminal, Read};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+// How long an interactive stdin may stay silent before COPY gives up.
+const TERMINAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyFormat {
+    Csv,
+    // One JSON object per line
+    Json,
+}
+
+// Recognizes `COPY <table> FROM stdin [FORMAT CSV|JSON]`, case-insensitively and with an
+// optional trailing `;`. Returns None for any other statement.
+pub fn parse_copy(sql: &str) -> Result<Option<(String, CopyFormat)>> {
+    let words: Vec<&str> = sql.trim().trim_end_matches(';').split_whitespace().collect();
+    if !words.first().is_some_and(|w| w.eq_ignore_ascii_case("COPY")) {
+        return Ok(None);
+    }
+    let invalid = || BranchDBError::InvalidInput(
+        "Expected COPY <table> FROM stdin [FORMAT CSV|JSON]".into()
+    );
+    let [_, table, from, source, rest @ ..] = words.as_slice() else { return Err(invalid()) };
+    if !from.eq_ignore_ascii_case("FROM") || !source.eq_ignore_ascii_case("stdin") {
+        return Err(invalid());
+    }
+    let format = match rest {
+        [] => CopyFormat::Csv,
+        [keyword, format] if keyword.eq_ignore_ascii_case("FORMAT") => match format.to_ascii_uppercase().as_str() {
+            "CSV" => CopyFormat::Csv,
+            "JSON" => CopyFormat::Json,
+            other => return Err(BranchDBError::InvalidInput(format!("Unsupported COPY format '{}'", other))),
+        },
+        _ => return Err(invalid()),
+    };
+    Ok(Some((table.to_string(), format)))
+}
+
+// Stdin for COPY. A pipe or file is read directly; a terminal is read on a background thread
+// so the first read can time out instead of waiting forever for input that isn't coming.
+pub fn stdin_reader() -> Box<dyn Read> {
+    if !io::stdin().is_terminal() {
+        return Box::new(io::stdin());
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin();
+        loop {
+            let mut chunk = vec![0; 8192];
+            let result = stdin.read(&mut chunk).map(|n| {
+                chunk.truncate(n);
+                chunk
+            });
+            let done = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+            if sender.send(result).is_err() || done {
+                break;
+            }
+        }
+    });
+    Box::new(TerminalReader { receiver, pending: Vec::new(), position: 0, started: false })
+}
+
+struct TerminalReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    pending: Vec<u8>,
+    position: usize,
+    started: bool,
+}
+
+impl Read for TerminalReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            let chunk = if self.started {
+                self.receiver.recv().unwrap_or_else(|_| Ok(Vec::new()))
+            } else {
+                match self.receiver.recv_timeout(TERMINAL_TIMEOUT) {
+                    Ok(chunk) => chunk,
+                    Err(RecvTimeoutError::Timeout) => return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no input on stdin after 5 seconds; pipe data in with `< file`",
+                    )),
+                    Err(RecvTimeoutError::Disconnected) => Ok(Vec::new()),
+                }
+            };
+            self.started = true;
+            self.pending = chunk?;
+            self.position = 0;
+        }
+        let n = buf.len().min(self.pending.len() - self.position);
+        buf[..n].copy_from_slice(&self.pending[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,200 @@
+use crate::cli::output::crdt_json;
+use crate::core::crdt::CrdtValue;
+use crate::core::models::Change;
+use crate::core::row;
+use crate::error::Result;
+use clap::ValueEnum;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    // `auto` colors only when stdout is a terminal, so pipes and files get plain text.
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => std::io::stdout().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DiffFormat {
+    Text,
+    // Statements that replay the changes; see cli::patch
+    Sql,
+    // The changes with decoded values; see core::json_patch
+    JsonPatch,
+}
+
+// Per-table row counts for `diff --stat`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiffStat {
+    pub inserted: usize,
+    pub updated: usize,
+    pub deleted: usize,
+}
+
+impl DiffStat {
+    pub fn count(stats: &mut BTreeMap<String, DiffStat>, change: &Change) {
+        let stat = stats.entry(change.table().to_string()).or_default();
+        match change {
+            Change::Insert { .. } => stat.inserted += 1,
+            Change::Update { .. } => stat.updated += 1,
+            Change::Delete { .. } => stat.deleted += 1,
+        }
+    }
+}
+
+// Renders row changes in a unified style: `+` for inserts, `-` for deletes, and for updates
+// a `~` header followed by a before/after pair for every field that changed.
+pub struct DiffRenderer {
+    color: bool,
+}
+
+impl DiffRenderer {
+    pub fn new(color: bool) -> Self {
+        Self { color }
+    }
+
+    fn paint(&self, color: &str, text: String) -> String {
+        if self.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text
+        }
+    }
+
+    // `before` is the row as of the `from` commit and is required for updates and deletes;
+    // both sides are expected to be already decoded with `decode_row`.
+    pub fn render_change(&self, change: &Change, before: Option<&Value>, after: Option<&Value>) -> Vec<String> {
+        let key = format!("{}:{}", change.table(), change.id());
+        let null = Value::Null;
+        match change {
+            Change::Insert { .. } => {
+                vec![self.paint(GREEN, format!("+ {} {}", key, after.unwrap_or(&null)))]
+            }
+            Change::Delete { .. } => {
+                vec![self.paint(RED, format!("- {} {}", key, before.unwrap_or(&null)))]
+            }
+            Change::Update { .. } => {
+                let mut lines = vec![self.paint(CYAN, format!("~ {}", key))];
+                match (before, after) {
+                    (Some(Value::Object(old)), Some(Value::Object(new))) => {
+                        let mut fields: Vec<&String> = old.keys().collect();
+                        fields.extend(new.keys().filter(|k| !old.contains_key(*k)));
+                        for field in fields {
+                            let (old_value, new_value) = (old.get(field), new.get(field));
+                            if old_value == new_value {
+                                continue;
+                            }
+                            if let Some(value) = old_value {
+                                lines.push(self.paint(RED, format!("-   {}: {}", field, value)));
+                            }
+                            if let Some(value) = new_value {
+                                lines.push(self.paint(GREEN, format!("+   {}: {}", field, value)));
+                            }
+                        }
+                    }
+                    // Counters and non-object payloads are compared as a whole
+                    (old, new) => {
+                        lines.push(self.paint(RED, format!("-   {}", old.unwrap_or(&null))));
+                        lines.push(self.paint(GREEN, format!("+   {}", new.unwrap_or(&null))));
+                    }
+                }
+                lines
+            }
+        }
+    }
+
+    pub fn render_stat(&self, stats: &BTreeMap<String, DiffStat>) -> Vec<String> {
+        let width = stats.keys().map(String::len).max().unwrap_or(0);
+        stats.iter()
+            .map(|(table, stat)| format!(
+                "{:<width$} | {} {} {}",
+                table,
+                self.paint(GREEN, format!("+{}", stat.inserted)),
+                self.paint(CYAN, format!("~{}", stat.updated)),
+                self.paint(RED, format!("-{}", stat.deleted)),
+                width = width,
+            ))
+            .collect()
+    }
+}
+
+// Decodes a stored row into JSON, naming array positions after the table's columns.
+pub fn decode_row(value: &CrdtValue, schema: &Value) -> Value {
+    row::row_to_object(crdt_json(value), schema)
+}
+
+pub fn decode_change_value(bytes: &[u8], schema: &Value) -> Result<Value> {
+    let value: CrdtValue = bincode::deserialize(bytes)?;
+    Ok(decode_row(&value, schema))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn change(kind: &str) -> Change {
+        let (table, id, value) = ("users".to_string(), "7".to_string(), Vec::new());
+        match kind {
+            "insert" => Change::Insert { table, id, value },
+            "update" => Change::Update { table, id, value },
+            _ => Change::Delete { table, id },
+        }
+    }
+
+    #[test]
+    fn changes_render_as_unified_lines() {
+        let renderer = DiffRenderer::new(false);
+        let old = json!({"id": "7", "name": "ann", "city": "Oslo"});
+        let new = json!({"id": "7", "name": "anne", "city": "Oslo", "email": "a@x"});
+
+        assert_eq!(renderer.render_change(&change("insert"), None, Some(&new)),
+            [r#"+ users:7 {"id":"7","name":"anne","city":"Oslo","email":"a@x"}"#]);
+        assert_eq!(renderer.render_change(&change("delete"), Some(&old), None),
+            [r#"- users:7 {"id":"7","name":"ann","city":"Oslo"}"#]);
+        assert_eq!(renderer.render_change(&change("update"), Some(&old), Some(&new)), [
+            "~ users:7",
+            r#"-   name: "ann""#,
+            r#"+   name: "anne""#,
+            r#"+   email: "a@x""#,
+        ]);
+        assert_eq!(renderer.render_change(&change("update"), Some(&json!(3)), Some(&json!(5))),
+            ["~ users:7", "-   3", "+   5"]);
+
+        let mut stats = BTreeMap::new();
+        for kind in ["insert", "insert", "update", "delete"] {
+            DiffStat::count(&mut stats, &change(kind));
+        }
+        DiffStat::count(&mut stats, &Change::Insert { table: "orders_archive".to_string(), id: "1".to_string(), value: Vec::new() });
+        assert_eq!(renderer.render_stat(&stats), [
+            "orders_archive | +1 ~0 -0",
+            "users          | +2 ~1 -1",
+        ]);
+    }
+
+    #[test]
+    fn color_wraps_each_line_in_its_own_color() {
+        let renderer = DiffRenderer::new(true);
+        assert_eq!(renderer.render_change(&change("insert"), None, Some(&json!({"id": "7"}))),
+            ["\x1b[32m+ users:7 {\"id\":\"7\"}\x1b[0m"]);
+        assert_eq!(renderer.render_change(&change("update"), Some(&json!({"n": 1})), Some(&json!({"n": 2}))),
+            ["\x1b[36m~ users:7\x1b[0m", "\x1b[31m-   n: 1\x1b[0m", "\x1b[32m+   n: 2\x1b[0m"]);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::cli::diff::DiffStat;
+use crate::core::config::Config;
+use crate::core::models::Change;
+use crate::error::{BranchDBError, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::process::Command;
+
+const DEFAULT_EDITOR: &str = "vi";
+// The file the editor opens, in the data directory, like git's COMMIT_EDITMSG. It's left
+// behind afterwards so a message lost to a failed commit can be recovered.
+const MESSAGE_FILE: &str = "COMMIT_EDITMSG";
+
+// What the editor starts with above the comments.
+pub enum Draft {
+    Blank,
+    // Left unchanged, a template aborts the commit like an empty message
+    Template(String),
+    // A message that stands as it is, such as "Merge branch 'x'"
+    Default(String),
+}
+
+// What the editor shows below the message as comments.
+pub struct MessagePrompt<'a> {
+    // "commit" or "merge", as in "an empty message aborts the commit"
+    pub action: &'a str,
+    // The current branch, or the abbreviated HEAD when it's detached
+    pub branch: Option<String>,
+    pub head: Option<[u8; 32]>,
+    pub merging: Option<&'a str>,
+    pub changes: &'a [Change],
+}
+
+// The message for a commit: `given` when there is one, otherwise what the user writes in the
+// editor, starting from `draft`. Without a terminal on stdin there's nobody to write one, so
+// only a default message can stand in.
+pub fn message(data_dir: &str, given: Option<&str>, draft: Draft, prompt: &MessagePrompt) -> Result<String> {
+    if let Some(given) = given {
+        if given.trim().is_empty() {
+            return Err(BranchDBError::InvalidInput("Commit message cannot be empty.".into()));
+        }
+        return Ok(given.to_string());
+    }
+    if !std::io::stdin().is_terminal() {
+        return match draft {
+            Draft::Default(message) => Ok(message),
+            _ => Err(BranchDBError::InvalidInput(
+                "No commit message given and stdin is not a terminal to open an editor on; pass one with -m".into()
+            )),
+        };
+    }
+
+    let initial = match &draft {
+        Draft::Blank => "",
+        Draft::Template(text) | Draft::Default(text) => text,
+    };
+    let path = Path::new(data_dir).join(MESSAGE_FILE);
+    fs::write(&path, format!("{}\n{}", initial, prompt.comments()))?;
+    let editor = editor(data_dir)?;
+    // Through the shell like git, so the editor may carry arguments (`code --wait`)
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(&path)
+        .status()
+        .map_err(|e| BranchDBError::InvalidInput(format!("Could not run editor '{}': {}", editor, e)))?;
+    if !status.success() {
+        return Err(BranchDBError::InvalidInput(format!("Editor '{}' exited with {}; not committing", editor, status)));
+    }
+
+    let written = strip_comments(&fs::read_to_string(&path)?);
+    if written.is_empty() {
+        return Err(BranchDBError::InvalidInput(format!("Aborting {} due to empty message", prompt.action)));
+    }
+    if matches!(&draft, Draft::Template(template) if strip_comments(template) == written) {
+        return Err(BranchDBError::InvalidInput(format!("Aborting {}; the template was left unchanged", prompt.action)));
+    }
+    Ok(written)
+}
+
+// The template a new commit message starts from: `--template`, or else the file named by
+// `commit.template`, which is read relative to the data directory. None when neither is set.
+pub fn template(data_dir: &str, file: Option<&str>) -> Result<Option<String>> {
+    let path = match file {
+        Some(file) => Path::new(file).to_path_buf(),
+        None => match Config::load(Path::new(data_dir))?.get("commit.template") {
+            Some(file) => Path::new(data_dir).join(file),
+            None => return Ok(None),
+        },
+    };
+    fs::read_to_string(&path).map(Some).map_err(|e| BranchDBError::InvalidInput(format!(
+        "Could not read commit template {}: {}", path.display(), e
+    )))
+}
+
+// `core.editor`, then `$VISUAL`, then `$EDITOR`, then vi.
+fn editor(data_dir: &str) -> Result<String> {
+    let configured = Config::load(Path::new(data_dir))?.get("core.editor").map(str::to_string);
+    Ok(configured
+        .or_else(|| std::env::var("VISUAL").ok())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_EDITOR.to_string()))
+}
+
+// Drops `#` lines and the blank lines around the message.
+fn strip_comments(text: &str) -> String {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+impl MessagePrompt<'_> {
+    fn comments(&self) -> String {
+        let mut lines = vec![
+            "Please enter the message for your changes. Lines starting with '#' are".to_string(),
+            format!("ignored, and an empty message aborts the {}.", self.action),
+            String::new(),
+        ];
+        match (&self.branch, &self.head) {
+            (Some(branch), _) => lines.push(format!("On branch {}", branch)),
+            (None, Some(head)) => lines.push(format!("HEAD detached at {}", hex::encode(&head[..4]))),
+            (None, None) => lines.push("No commits yet".to_string()),
+        }
+        if let Some(branch) = self.merging {
+            lines.push(format!("Merging branch '{}'", branch));
+        }
+
+        let mut stats = BTreeMap::new();
+        for change in self.changes {
+            DiffStat::count(&mut stats, change);
+        }
+        lines.push(String::new());
+        match stats.len() {
+            0 => lines.push("No changes to be committed".to_string()),
+            tables => {
+                lines.push(format!(
+                    "Changes to be committed: {} row{} across {} table{}",
+                    self.changes.len(), if self.changes.len() == 1 { "" } else { "s" },
+                    tables, if tables == 1 { "" } else { "s" },
+                ));
+                for (table, stat) in &stats {
+                    lines.push(format!(
+                        "\t{}: {} inserted, {} updated, {} deleted", table, stat.inserted, stat.updated, stat.deleted
+                    ));
+                }
+            }
+        }
+        lines.iter().map(|line| if line.is_empty() { "#\n".to_string() } else { format!("# {}\n", line) }).collect()
+    }
+}
@@ -0,0 +1,237 @@
+use crate::cli::diff::decode_row;
+use crate::core::crdt::CrdtValue;
+use crate::core::models::Commit;
+use crate::core::row;
+use crate::core::schema::MultiKeyPrimaryKey;
+use crate::error::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Longest value shown in a cell; longer ones are cut and end in `…`
+const MAX_WIDTH: usize = 50;
+// Rows looked at to size the columns, so a huge table doesn't have to be measured in full
+const SAMPLE_ROWS: usize = 100;
+
+// Lays out the rows of a table as aligned columns: `id` first, then the schema's columns in
+// declared order, then any other fields found in the rows. Numbers are right-aligned and
+// everything else left-aligned. With borders the table is drawn with box-drawing characters;
+// without, columns are separated by two spaces so the output can be piped.
+pub struct TableFormatter {
+    border: bool,
+}
+
+impl TableFormatter {
+    pub fn new(border: bool) -> Self {
+        Self { border }
+    }
+
+    // Only `columns`, in that order, when given.
+    pub fn render(&self, rows: &HashMap<String, CrdtValue>, schema: &Value, columns: Option<&[String]>) -> String {
+        let (columns, records) = table_cells(rows, schema, columns);
+        let mut widths: Vec<usize> = columns.iter().map(|c| c.chars().count()).collect();
+        for record in records.iter().take(SAMPLE_ROWS) {
+            for (width, (text, _)) in widths.iter_mut().zip(record) {
+                *width = (*width).max(text.chars().count());
+            }
+        }
+        for width in &mut widths {
+            *width = (*width).min(MAX_WIDTH);
+        }
+
+        let mut lines = Vec::new();
+        if self.border {
+            lines.push(self.rule('┌', '┬', '┐', &widths));
+        }
+        let header: Vec<(String, bool)> = columns.iter().map(|c| (c.clone(), false)).collect();
+        lines.push(self.row(&header, &widths));
+        if self.border {
+            lines.push(self.rule('├', '┼', '┤', &widths));
+        }
+        for record in &records {
+            lines.push(self.row(record, &widths));
+        }
+        if self.border {
+            lines.push(self.rule('└', '┴', '┘', &widths));
+        }
+        lines.join("\n")
+    }
+
+    fn row(&self, cells: &[(String, bool)], widths: &[usize]) -> String {
+        let cells: Vec<String> = cells.iter().zip(widths)
+            .map(|((text, numeric), &width)| {
+                let text = truncate(text, width);
+                if *numeric {
+                    format!("{:>width$}", text, width = width)
+                } else {
+                    format!("{:<width$}", text, width = width)
+                }
+            })
+            .collect();
+        if self.border {
+            format!("│ {} │", cells.join(" │ "))
+        } else {
+            cells.join("  ").trim_end().to_string()
+        }
+    }
+
+    fn rule(&self, left: char, middle: char, right: char, widths: &[usize]) -> String {
+        let segments: Vec<String> = widths.iter().map(|w| "─".repeat(w + 2)).collect();
+        format!("{}{}{}", left, segments.join(&middle.to_string()), right)
+    }
+}
+
+// Lays out rows as a GitHub-flavored markdown table, in the columns `TableFormatter` uses.
+// Pipes in cells are escaped and line breaks become `<br>`, so every row stays one table row.
+// A column whose cells are all numbers is right-aligned. The caption, when given, is a line of
+// its own above the table.
+pub struct MarkdownFormatter {
+    caption: Option<String>,
+    max_width: Option<usize>,
+}
+
+impl MarkdownFormatter {
+    pub fn new(caption: Option<String>, max_width: Option<usize>) -> Self {
+        Self { caption, max_width }
+    }
+
+    pub fn render_table(&self, rows: &HashMap<String, CrdtValue>, schema: &Value, columns: Option<&[String]>) -> Vec<String> {
+        let (columns, records) = table_cells(rows, schema, columns);
+        self.render(&columns, &records)
+    }
+
+    // `records` holds each row's cells as (text, is a number).
+    pub fn render(&self, columns: &[String], records: &[Vec<(String, bool)>]) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(caption) = &self.caption {
+            lines.push(caption.clone());
+            lines.push(String::new());
+        }
+        let header: Vec<String> = columns.iter().map(|c| self.cell(c)).collect();
+        lines.push(format!("| {} |", header.join(" | ")));
+        let alignments: Vec<&str> = (0..columns.len())
+            .map(|i| {
+                let numeric = !records.is_empty()
+                    && records.iter().all(|r| r.get(i).is_some_and(|(text, numeric)| *numeric || text.is_empty()));
+                if numeric { "---:" } else { "---" }
+            })
+            .collect();
+        lines.push(format!("| {} |", alignments.join(" | ")));
+        for record in records {
+            let cells: Vec<String> = record.iter().map(|(text, _)| self.cell(text)).collect();
+            lines.push(format!("| {} |", cells.join(" | ")));
+        }
+        lines
+    }
+
+    fn cell(&self, text: &str) -> String {
+        let text = match self.max_width {
+            Some(width) => truncate(text, width.max(1)),
+            None => text.to_string(),
+        };
+        text.replace('|', "\\|").replace("\r\n", "<br>").replace(['\n', '\r'], "<br>")
+    }
+}
+
+// The same columns and rows as `TableFormatter` would show, as CSV lines with a header.
+pub fn csv_lines(rows: &HashMap<String, CrdtValue>, schema: &Value, columns: Option<&[String]>) -> Result<Vec<String>> {
+    let (columns, records) = table_cells(rows, schema, columns);
+    write_csv(&columns, records.into_iter().map(|record| record.into_iter().map(|(text, _)| text).collect()))
+}
+
+// A header and records as CSV lines, quoted where needed.
+pub fn write_csv(columns: &[String], records: impl Iterator<Item = Vec<String>>) -> Result<Vec<String>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(columns)?;
+    for record in records {
+        writer.write_record(&record)?;
+    }
+    let data = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8_lossy(&data).lines().map(str::to_string).collect())
+}
+
+// A commit's time in UTC to the millisecond, in a form `--after` and `--before` accept.
+pub fn commit_time(commit: &Commit) -> String {
+    chrono::DateTime::from_timestamp_millis(commit.timestamp as i64)
+        .map(|time| time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())
+        .unwrap_or_else(|| commit.timestamp.to_string())
+}
+
+// Narrows decoded rows to the columns asked for, as `show-table --columns` does.
+pub struct RowProjector;
+
+impl RowProjector {
+    // An object with exactly `columns`, in that order; a column the row lacks is null.
+    pub fn project(row: &Value, columns: &[String]) -> Value {
+        let projected = columns.iter()
+            .map(|column| (column.clone(), row.get(column).cloned().unwrap_or(Value::Null)))
+            .collect();
+        Value::Object(projected)
+    }
+}
+
+// How a value appears in a cell: strings without quotes, NULL as nothing.
+pub fn cell_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Column names, and for every row ordered by id its cells as (text, is a number). `projection`
+// replaces the columns, so a column no row has shows as empty cells.
+fn table_cells(rows: &HashMap<String, CrdtValue>, schema: &Value, projection: Option<&[String]>) -> (Vec<String>, Vec<Vec<(String, bool)>>) {
+    let mut ids: Vec<&String> = rows.keys().filter(|id| !id.starts_with('!')).collect();
+    ids.sort();
+    let objects: Vec<(&String, serde_json::Map<String, Value>)> = ids.into_iter()
+        .map(|id| {
+            let object = match decode_row(&rows[id], schema) {
+                Value::Object(map) => map,
+                other => serde_json::Map::from_iter([("value".to_string(), other)]),
+            };
+            (id, object)
+        })
+        .collect();
+
+    // A composite key is shown as its columns rather than as one joined id
+    let key = MultiKeyPrimaryKey::from_schema(schema);
+    let mut columns = match &key {
+        Some(key) => key.columns.clone(),
+        None => vec!["id".to_string()],
+    };
+    for column in row::schema_columns(schema).into_iter().chain(objects.iter().flat_map(|(_, o)| o.keys().cloned())) {
+        if !columns.contains(&column) {
+            columns.push(column);
+        }
+    }
+    if let Some(projection) = projection {
+        columns = projection.to_vec();
+    }
+
+    // A key column missing from the row is read back from the row id
+    let key_part = |id: &str, column: &str| -> Option<String> {
+        let key = key.as_ref()?;
+        let position = key.columns.iter().position(|c| c == column)?;
+        key.split(id).get(position).map(|part| part.to_string())
+    };
+    let records = objects.iter()
+        .map(|(id, object)| columns.iter()
+            .map(|column| match object.get(column) {
+                // The id column shows the row key unless the row has an `id` field of its own
+                None if column == "id" && key.is_none() => ((*id).clone(), false),
+                None => (key_part(id, column).unwrap_or_default(), false),
+                Some(value) => (cell_text(value), value.is_number()),
+            })
+            .collect())
+        .collect();
+    (columns, records)
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut cut: String = text.chars().take(width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
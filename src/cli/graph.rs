@@ -0,0 +1,222 @@
+use crate::core::branch::BranchManager;
+use crate::core::database::CommitStorage;
+use crate::core::models::Commit;
+use crate::error::Result;
+use std::collections::HashMap;
+
+// GraphRenderer draws the commit DAG as ASCII art in the style of `git log --graph`.
+// Every commit reachable from HEAD or a branch is shown once, children before parents,
+// with the HEAD lineage kept in the leftmost column.
+pub struct GraphRenderer<'a> {
+    storage: &'a CommitStorage,
+    // Only these branches and their history, without HEAD's
+    branches: Option<Vec<(String, [u8; 32])>>,
+}
+
+impl<'a> GraphRenderer<'a> {
+    pub fn new(storage: &'a CommitStorage) -> Self {
+        Self { storage, branches: None }
+    }
+
+    pub fn for_branches(storage: &'a CommitStorage, branches: Vec<(String, [u8; 32])>) -> Self {
+        Self { storage, branches: Some(branches) }
+    }
+
+    pub fn render(&self) -> Result<Vec<String>> {
+        let (head, branches) = match &self.branches {
+            Some(branches) => (None, branches.clone()),
+            None => (self.storage.get_head()?, self.all_branches()?),
+        };
+
+        // Tips: HEAD first so its lineage claims the first lane, then every branch head
+        let mut tips: Vec<[u8; 32]> = head.into_iter().collect();
+        let mut labels: HashMap<[u8; 32], Vec<String>> = HashMap::new();
+        if let Some(hash) = head {
+            labels.entry(hash).or_default().push("HEAD".to_string());
+        }
+        for (branch, hash) in branches {
+            labels.entry(hash).or_default().push(branch);
+            if !tips.contains(&hash) {
+                tips.push(hash);
+            }
+        }
+
+        // Collect the DAG and count how many children reference each commit
+        let mut commits: HashMap<[u8; 32], Commit> = HashMap::new();
+        let mut pending = tips.clone();
+        while let Some(hash) = pending.pop() {
+            if commits.contains_key(&hash) {
+                continue;
+            }
+            let commit = self.storage.get_commit_by_hash(&hash)?;
+            pending.extend(commit.parents.iter().copied());
+            commits.insert(hash, commit);
+        }
+
+        let mut child_count: HashMap<[u8; 32], usize> = HashMap::new();
+        for commit in commits.values() {
+            for parent in &commit.parents {
+                *child_count.entry(*parent).or_default() += 1;
+            }
+        }
+
+        let mut ready: Vec<[u8; 32]> = tips.iter()
+            .filter(|hash| !child_count.contains_key(*hash))
+            .copied()
+            .collect();
+        let mut lanes: Vec<[u8; 32]> = head.into_iter().collect();
+        let mut lines = Vec::new();
+
+        while !ready.is_empty() {
+            // Newest commit first; ties go to the leftmost lane so branches stay grouped
+            let lane_of = |hash: &[u8; 32]| lanes.iter().position(|h| h == hash).unwrap_or(usize::MAX);
+            let (index, _) = ready.iter().enumerate()
+                .min_by_key(|(_, hash)| (std::cmp::Reverse(commits[*hash].order_key()), lane_of(hash)))
+                .expect("ready is not empty");
+            let hash = ready.remove(index);
+            let commit = &commits[&hash];
+
+            let label = Self::label(&hash, commit, labels.get(&hash));
+            Self::render_commit(&mut lanes, &hash, commit, &label, &mut lines);
+
+            for parent in &commit.parents {
+                let count = child_count.get_mut(parent).expect("parent was collected");
+                *count -= 1;
+                if *count == 0 {
+                    ready.push(*parent);
+                }
+            }
+        }
+
+        Ok(lines)
+    }
+
+    fn all_branches(&self) -> Result<Vec<(String, [u8; 32])>> {
+        let branch_mgr = BranchManager::new(self.storage.db.clone());
+        let mut branches = Vec::new();
+        for branch in branch_mgr.list_branches()? {
+            let Some(raw) = branch_mgr.get_branch_head(&branch)? else { continue };
+            let Ok(hash) = <[u8; 32]>::try_from(raw.as_slice()) else { continue };
+            branches.push((branch, hash));
+        }
+        Ok(branches)
+    }
+
+    fn label(hash: &[u8; 32], commit: &Commit, refs: Option<&Vec<String>>) -> String {
+        let short = hex::encode(&hash[..8]);
+        match refs {
+            Some(refs) => format!("{} ({}) {}", short, refs.join(", "), commit.message),
+            None => format!("{} {}", short, commit.message),
+        }
+    }
+
+    // Emits the node line for one commit plus any fan-out / collapse lines, and advances the
+    // lanes so each holds the next commit expected in that column.
+    fn render_commit(lanes: &mut Vec<[u8; 32]>, hash: &[u8; 32], commit: &Commit, label: &str, lines: &mut Vec<String>) {
+        let col = match lanes.iter().position(|h| h == hash) {
+            Some(col) => col,
+            None => {
+                lanes.push(*hash);
+                lanes.len() - 1
+            }
+        };
+        let extra = commit.parents.len().saturating_sub(1);
+
+        // Node line; octopus merges mark the extra parents with `-.` like git does
+        let mut node = "| ".repeat(col);
+        node.push('*');
+        if extra >= 2 {
+            node.push_str(&"-".repeat(2 * extra - 3));
+            node.push('.');
+        }
+        for _ in col + 1..lanes.len() {
+            node.push_str(" |");
+        }
+        lines.push(format!("{}  {}", node, label));
+
+        // Replace this commit's lane with its parents
+        let mut expanded: Vec<[u8; 32]> = lanes[..col].to_vec();
+        expanded.extend(commit.parents.iter().copied());
+        expanded.extend(lanes[col + 1..].iter().copied());
+
+        // Column each expanded lane occupied on the last drawn line
+        let mut positions: Vec<usize> = (0..expanded.len()).collect();
+        if extra >= 1 {
+            let mut fan = "| ".repeat(col);
+            fan.push('|');
+            for j in 0..extra {
+                if j > 0 {
+                    fan.push(' ');
+                }
+                fan.push('\\');
+            }
+            for _ in col + 1..lanes.len() {
+                fan.push_str(" \\");
+            }
+            lines.push(fan);
+        } else if commit.parents.is_empty() {
+            for position in positions.iter_mut().skip(col) {
+                *position += 1;
+            }
+        }
+
+        // Lanes waiting on the same commit join the leftmost one
+        let mut collapsed: Vec<[u8; 32]> = Vec::new();
+        let mut targets = Vec::with_capacity(expanded.len());
+        for lane in &expanded {
+            match collapsed.iter().position(|h| h == lane) {
+                Some(t) => targets.push(t),
+                None => {
+                    collapsed.push(*lane);
+                    targets.push(collapsed.len() - 1);
+                }
+            }
+        }
+
+        if positions.iter().zip(&targets).any(|(p, t)| p != t) {
+            let width = positions.iter().max().map_or(0, |p| 2 * p + 1);
+            let mut row = vec![' '; width];
+            for (&p, &t) in positions.iter().zip(&targets) {
+                if p == t {
+                    row[2 * p] = '|';
+                } else {
+                    row[2 * p - 1] = '/';
+                    for cell in row.iter_mut().take(2 * p - 1).skip(2 * t + 1) {
+                        if *cell == ' ' {
+                            *cell = '-';
+                        }
+                    }
+                }
+            }
+            lines.push(row.into_iter().collect::<String>().trim_end().to_string());
+        }
+
+        *lanes = collapsed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_support::{head, run, temp_repo};
+
+    #[test]
+    fn a_merge_fans_out_and_back_in() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        let fork = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let side = head(&storage);
+        BranchManager::new(storage.db.clone()).create_branch("feature").unwrap();
+        storage.move_head(&fork).unwrap();
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+        storage.create_merge_commit("Merge feature", Vec::new(), &side).unwrap();
+
+        let lines = GraphRenderer::new(&storage).render().unwrap();
+        let graph: Vec<&str> = lines.iter().map(|line| line.split("  ").next().unwrap()).collect();
+        assert_eq!(graph, ["*", "|\\", "* |", "| *", "|/", "*"], "\n{}", lines.join("\n"));
+        assert!(lines[0].ends_with("(HEAD) Merge feature"));
+        assert!(lines[3].contains(&format!("{} (feature) ", hex::encode(&side[..8]))));
+        assert!(lines[5].starts_with(&format!("*  {}", hex::encode(&fork[..8]))));
+    }
+}
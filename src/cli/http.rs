@@ -0,0 +1,133 @@
+use crate::error::{BranchDBError, Result};
+use std::collections::HashSet;
+use std::process::{Command, Stdio};
+
+// Seconds curl may spend on one page, connecting included.
+const REQUEST_TIMEOUT_SECS: u32 = 60;
+// How much of an error response's body ends up in the error message.
+const ERROR_BODY_CHARS: usize = 200;
+
+pub struct HttpSource {
+    pub url: String,
+    // `Name: value`, as curl takes them
+    pub headers: Vec<String>,
+    // Dot-separated path to the array of rows in each response; None when the body is the array
+    pub json_path: Option<String>,
+    // Dot-separated path to the next page: a URL, or a cursor passed as `cursor_param`
+    pub next_field: Option<String>,
+    pub cursor_param: String,
+}
+
+// One fetched page and the rows found in it.
+pub struct Page {
+    pub url: String,
+    pub rows: Vec<serde_json::Value>,
+}
+
+impl HttpSource {
+    // Fetches the first page and, with `next_field`, every page after it until the field is
+    // missing, null or empty. A next page that was already fetched ends the walk, so a server
+    // that keeps returning the same cursor can't loop forever.
+    pub fn fetch_pages(&self) -> Result<Vec<Page>> {
+        let mut pages = Vec::new();
+        let mut seen = HashSet::new();
+        let mut next = Some(self.url.clone());
+        while let Some(url) = next.take() {
+            if !seen.insert(url.clone()) {
+                break;
+            }
+            let body = self.get(&url)?;
+            let rows = match &self.json_path {
+                Some(path) => lookup(&body, path).ok_or_else(|| BranchDBError::InvalidInput(format!(
+                    "Response from {} has nothing at '{}'", url, path
+                )))?,
+                None => &body,
+            };
+            let rows = rows.as_array().cloned().ok_or_else(|| BranchDBError::InvalidInput(format!(
+                "Expected an array of objects in the response from {}, got {}", url, kind(rows)
+            )))?;
+            next = self.next_field.as_deref()
+                .and_then(|field| lookup(&body, field))
+                .and_then(|cursor| self.next_url(cursor));
+            pages.push(Page { url, rows });
+        }
+        Ok(pages)
+    }
+
+    fn next_url(&self, cursor: &serde_json::Value) -> Option<String> {
+        let cursor = match cursor {
+            serde_json::Value::String(s) if !s.is_empty() => s.clone(),
+            serde_json::Value::Number(n) => n.to_string(),
+            _ => return None,
+        };
+        if cursor.starts_with("http://") || cursor.starts_with("https://") {
+            return Some(cursor);
+        }
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        Some(format!("{}{}{}={}", self.url, separator, self.cursor_param, encode_query_value(&cursor)))
+    }
+
+    // GETs `url` with curl, following redirects, and parses the body as JSON. Statuses of 400
+    // and up are errors carrying the status and the start of the body.
+    fn get(&self, url: &str) -> Result<serde_json::Value> {
+        let mut command = Command::new("curl");
+        command.args(["--silent", "--show-error", "--location"])
+            .args(["--max-time", &REQUEST_TIMEOUT_SECS.to_string()])
+            .args(["--write-out", "\n%{http_code}"]);
+        for header in &self.headers {
+            command.arg("--header").arg(header);
+        }
+        let output = command.arg("--").arg(url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .map_err(|e| http_error(url, None, format!("could not run curl: {}", e)))?;
+        if !output.status.success() {
+            return Err(http_error(url, None, String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (body, status) = stdout.rsplit_once('\n').unwrap_or(("", &stdout));
+        let status: u16 = status.trim().parse()
+            .map_err(|_| http_error(url, None, format!("unexpected status '{}'", status.trim())))?;
+        if status >= 400 {
+            return Err(http_error(url, Some(status), body.chars().take(ERROR_BODY_CHARS).collect()));
+        }
+        serde_json::from_str(body)
+            .map_err(|e| http_error(url, Some(status), format!("response is not JSON: {}", e)))
+    }
+}
+
+fn http_error(url: &str, status: Option<u16>, message: String) -> BranchDBError {
+    BranchDBError::HttpRequest { url: url.to_string(), status, message }
+}
+
+// Follows a dot-separated path through objects; numeric segments index arrays.
+fn lookup<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.').filter(|segment| !segment.is_empty()).try_fold(value, |value, segment| match value {
+        serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+        _ => value.get(segment),
+    })
+}
+
+fn kind(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+// Percent-encodes everything but the unreserved characters of RFC 3986.
+fn encode_query_value(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,35 @@
+use clap::ValueEnum;
+use std::io::IsTerminal;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+// Maps the -v/-q flags onto an env-filter directive. Without flags RUST_LOG is honoured,
+// falling back to warnings only.
+fn filter(verbosity: u8, quiet: bool) -> EnvFilter {
+    let level = match (quiet, verbosity) {
+        (true, _) => "error",
+        (false, 0) => return EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+        (false, 1) => "info",
+        (false, 2) => "debug",
+        (false, _) => "trace",
+    };
+    EnvFilter::new(level)
+}
+
+// Installs the CLI's subscriber, which writes to stderr so command output on stdout stays
+// clean. Does nothing if a subscriber is already installed.
+pub fn init(verbosity: u8, quiet: bool, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter(verbosity, quiet))
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let _ = match format {
+        LogFormat::Text => builder.without_time().with_target(false).try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+}
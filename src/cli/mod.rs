@@ -1,2 +1,19 @@
+pub mod bench;
 pub mod commands;
+pub mod completions;
+pub mod confirm;
+pub mod copy;
+pub mod diff;
+pub mod editor;
+pub mod fmt;
+pub mod graph;
+pub mod http;
+pub mod logging;
+pub mod outfile;
+pub mod output;
+pub mod pager;
+pub mod patch;
+pub mod seed;
+pub mod suggest;
+pub mod watch;
 
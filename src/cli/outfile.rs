@@ -0,0 +1,145 @@
+use crate::error::{BranchDBError, Result};
+use csv::{QuoteStyle, Terminator};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+
+const USAGE: &str = "Expected INTO OUTFILE '<file>' [FIELDS TERMINATED BY '<c>'] [[OPTIONALLY] ENCLOSED BY '<c>'] [LINES TERMINATED BY '<s>']";
+
+// Where and how `SELECT ... INTO OUTFILE` writes the rows, MySQL style: tab-separated, one row
+// per line, and no header.
+#[derive(Debug, Clone)]
+pub struct Outfile {
+    pub path: String,
+    pub delimiter: u8,
+    pub quote: u8,
+    // Every field with ENCLOSED BY, the non-numeric ones with OPTIONALLY ENCLOSED BY. Without
+    // either, a field is quoted only when it holds the delimiter, a quote or a line break, so
+    // the file still reads back.
+    pub quote_style: QuoteStyle,
+    pub terminator: Terminator,
+}
+
+impl Default for Outfile {
+    fn default() -> Self {
+        Self { path: String::new(), delimiter: b'\t', quote: b'"', quote_style: QuoteStyle::Necessary, terminator: Terminator::Any(b'\n') }
+    }
+}
+
+// Splits `SELECT ... INTO OUTFILE ...` into the query and where to write its rows. Returns None
+// for statements that aren't a SELECT (or WITH) or have no INTO OUTFILE outside quotes.
+pub fn parse_into_outfile(sql: &str) -> Result<Option<(&str, Outfile)>> {
+    let first = sql.split_whitespace().next().unwrap_or_default();
+    if !first.eq_ignore_ascii_case("SELECT") && !first.eq_ignore_ascii_case("WITH") {
+        return Ok(None);
+    }
+    let Some(start) = find_into_outfile(sql) else { return Ok(None) };
+    let outfile = parse_clause(&sql[start..])?;
+    Ok(Some((sql[..start].trim_end(), outfile)))
+}
+
+// Byte offset of an `INTO OUTFILE` outside quotes.
+fn find_into_outfile(sql: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (offset, c) in sql.char_indices() {
+        match quote {
+            // A doubled quote closes and reopens, which keeps it inside
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' || c == '`' => quote = Some(c),
+            None if !previous.is_alphanumeric() && previous != '_' => {
+                let mut words = sql[offset..].split_whitespace();
+                let into = words.next().is_some_and(|w| w.eq_ignore_ascii_case("INTO"));
+                let outfile = words.next().is_some_and(|w| w.len() >= 7 && w[..7].eq_ignore_ascii_case("OUTFILE"));
+                if into && outfile {
+                    return Some(offset);
+                }
+            }
+            None => {}
+        }
+        previous = c;
+    }
+    None
+}
+
+fn parse_clause(clause: &str) -> Result<Outfile> {
+    let tokens = Tokenizer::new(&GenericDialect, clause).tokenize()
+        .map_err(|e| BranchDBError::InvalidInput(format!("{}: {}", USAGE, e)))?;
+    let mut tokens = tokens.into_iter().filter(|t| !matches!(t, Token::Whitespace(_) | Token::SemiColon));
+    let invalid = || BranchDBError::InvalidInput(USAGE.into());
+    if !is_word(tokens.next(), "INTO") || !is_word(tokens.next(), "OUTFILE") {
+        return Err(invalid());
+    }
+
+    let mut outfile = Outfile { path: string(tokens.next()).ok_or_else(invalid)?, ..Outfile::default() };
+    let mut section = "";
+    while let Some(token) = tokens.next() {
+        let Token::Word(word) = token else { return Err(invalid()) };
+        let word = word.value.to_ascii_uppercase();
+        match word.as_str() {
+            "FIELDS" | "COLUMNS" | "LINES" => {
+                section = if word == "LINES" { "LINES" } else { "FIELDS" };
+                continue;
+            }
+            "OPTIONALLY" | "ENCLOSED" | "TERMINATED" if section == "FIELDS" || word == "TERMINATED" => {}
+            _ => return Err(BranchDBError::InvalidInput(format!("{}; got {}", USAGE, word))),
+        }
+        let optionally = word == "OPTIONALLY";
+        if (optionally && !is_word(tokens.next(), "ENCLOSED")) || !is_word(tokens.next(), "BY") {
+            return Err(invalid());
+        }
+        let value = unescape(&string(tokens.next()).ok_or_else(invalid)?);
+        match (section, word.as_str()) {
+            ("LINES", _) => outfile.terminator = match value.as_str() {
+                "\r\n" => Terminator::CRLF,
+                _ => Terminator::Any(single_byte(&value, "LINES TERMINATED BY")?),
+            },
+            (_, "TERMINATED") => outfile.delimiter = single_byte(&value, "FIELDS TERMINATED BY")?,
+            _ => {
+                outfile.quote = single_byte(&value, "ENCLOSED BY")?;
+                outfile.quote_style = if optionally { QuoteStyle::NonNumeric } else { QuoteStyle::Always };
+            }
+        }
+    }
+    Ok(outfile)
+}
+
+fn is_word(token: Option<Token>, word: &str) -> bool {
+    matches!(token, Some(Token::Word(w)) if w.value.eq_ignore_ascii_case(word))
+}
+
+fn string(token: Option<Token>) -> Option<String> {
+    match token? {
+        Token::SingleQuotedString(s) => Some(s),
+        _ => None,
+    }
+}
+
+// MySQL's backslash escapes, so `'\t'` is a tab.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(next)) => {
+                chars.next();
+                unescaped.push(match next {
+                    't' => '\t',
+                    'n' => '\n',
+                    'r' => '\r',
+                    '0' => '\0',
+                    other => other,
+                });
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+fn single_byte(value: &str, clause: &str) -> Result<u8> {
+    match value.as_bytes() {
+        [byte] => Ok(*byte),
+        _ => Err(BranchDBError::InvalidInput(format!("{} takes a single character, got '{}'", clause, value))),
+    }
+}
@@ -0,0 +1,121 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::models::{Change, Commit};
+use crate::error::Result;
+use crate::cli::pager::Pager;
+use serde_json::{json, Value};
+use std::cell::Cell;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use tracing::warn;
+
+// Collects what a command reports. In text mode lines are printed as they are produced, through
+// the pager if one was started; with --json they are dropped and the single document set by the
+// command is printed at the end.
+pub struct Output {
+    json: bool,
+    document: Value,
+    pager: Option<Pager>,
+    // Set once the user quits the pager, after which text output is discarded
+    pager_closed: Cell<bool>,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        Self { json, document: Value::Null, pager: None, pager_closed: Cell::new(false) }
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    // Sends text output through `command` when stdout is a terminal. A pager that fails to start
+    // is reported and output goes straight to stdout instead.
+    pub fn start_pager(&mut self, command: &str) {
+        if self.json || self.pager.is_some() || !std::io::stdout().is_terminal() {
+            return;
+        }
+        match Pager::spawn(command) {
+            Ok(pager) => self.pager = Some(pager),
+            Err(e) => warn!(pager = command, error = %e, "could not start pager"),
+        }
+    }
+
+    pub fn line(&self, text: impl Display) {
+        if self.json {
+            return;
+        }
+        match &self.pager {
+            Some(pager) => {
+                if !self.pager_closed.get() && !pager.write_line(&text.to_string()) {
+                    self.pager_closed.set(true);
+                }
+            }
+            None => println!("{}", text),
+        }
+    }
+
+    pub fn set_json(&mut self, document: Value) {
+        self.document = document;
+    }
+
+    // The document set so far, leaving none; for commands that run others and report on all.
+    pub fn take_json(&mut self) -> Value {
+        std::mem::take(&mut self.document)
+    }
+
+    // Commands that have nothing to report still emit a JSON object so scripts can parse it.
+    pub fn finish(self) -> Result<()> {
+        if self.json {
+            let document = if self.document.is_null() { json!({}) } else { self.document };
+            println!("{}", serde_json::to_string_pretty(&document)?);
+        }
+        Ok(())
+    }
+
+    // For a command that failed after setting its report, such as verify-data finding
+    // violations. Commands that failed before setting one print nothing.
+    pub fn finish_failed(self) -> Result<()> {
+        if self.json && !self.document.is_null() {
+            println!("{}", serde_json::to_string_pretty(&self.document)?);
+        }
+        Ok(())
+    }
+}
+
+pub fn commit_json(hash: &[u8; 32], commit: &Commit) -> Value {
+    json!({
+        "hash": hex::encode(hash),
+        "parents": commit.parents.iter().map(hex::encode).collect::<Vec<_>>(),
+        "message": commit.message,
+        "timestamp": commit.timestamp,
+        "sequence": commit.sequence,
+        "changes": commit.changes.len(),
+        "audit": commit.audit,
+    })
+}
+
+// Row values are decoded so scripts see the stored JSON rather than bincode bytes.
+pub fn change_json(change: &Change) -> Value {
+    match change {
+        Change::Insert { table, id, value } | Change::Update { table, id, value } => {
+            let op = if matches!(change, Change::Insert { .. }) { "insert" } else { "update" };
+            json!({ "op": op, "table": table, "id": id, "value": decode_value(value) })
+        }
+        Change::Delete { table, id } => json!({ "op": "delete", "table": table, "id": id }),
+    }
+}
+
+pub fn crdt_json(value: &CrdtValue) -> Value {
+    match value {
+        CrdtValue::Register(data) => serde_json::from_slice(data)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(data).into_owned())),
+        CrdtValue::Counter(count) => json!(count),
+    }
+}
+
+fn decode_value(bytes: &[u8]) -> Value {
+    match bincode::deserialize::<CrdtValue>(bytes) {
+        Ok(value) => crdt_json(&value),
+        Err(_) => Value::Null,
+    }
+}
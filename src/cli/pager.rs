@@ -0,0 +1,71 @@
+use crate::core::config::Config;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use tracing::warn;
+
+const DEFAULT_PAGER: &str = "less -R";
+// Passed to less unless the user set LESS: quit when the output fits on one screen (-F), keep
+// colors (-R) and leave the output on the terminal afterwards (-X)
+const DEFAULT_LESS: &str = "FRX";
+
+// The pager from `core.pager`, then `$PAGER`, then `less -R`. An empty value or `cat`
+// disables paging.
+pub fn command(data_dir: &str) -> Option<String> {
+    let configured = match Config::load(Path::new(data_dir)) {
+        Ok(config) => config.get("core.pager").map(str::to_string),
+        Err(e) => {
+            warn!(error = %e, "could not read config, ignoring core.pager");
+            None
+        }
+    };
+    let command = configured
+        .or_else(|| std::env::var("PAGER").ok())
+        .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+    match command.trim() {
+        "" | "cat" => None,
+        _ => Some(command),
+    }
+}
+
+// A running pager fed through its stdin. Dropping it closes the pipe and waits for the user
+// to quit, so the shell prompt doesn't return underneath it.
+pub struct Pager {
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl Pager {
+    pub fn spawn(command: &str) -> std::io::Result<Self> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("LESS", std::env::var("LESS").unwrap_or_else(|_| DEFAULT_LESS.to_string()))
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        Ok(Self { child, stdin })
+    }
+
+    // Returns false once the pager has exited; later lines are dropped.
+    pub fn write_line(&self, text: &str) -> bool {
+        let Some(mut stdin) = self.stdin.as_ref() else { return false };
+        match writeln!(stdin, "{}", text) {
+            Ok(()) => true,
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => false,
+            Err(e) => {
+                warn!(error = %e, "writing to pager failed");
+                false
+            }
+        }
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        self.stdin.take();
+        if let Err(e) = self.child.wait() {
+            warn!(error = %e, "waiting for pager failed");
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use crate::core::crdt::{CrdtValue, TableState};
+use crate::core::database::{CommitStorage, DiffOptions};
+use crate::core::models::Change;
+use crate::core::row;
+use crate::core::schema::MultiKeyPrimaryKey;
+use crate::core::sql;
+use crate::core::trash;
+use crate::error::Result;
+use serde_json::Value;
+
+// `diff --format sql`: the changes between two commits as statements that `sql --file` can run
+// on another repository holding the first commit's state to bring it to the second's.
+//
+// Rows are written with `INSERT ... ON CONFLICT`, whose values sqlparser reads, so quotes and
+// NULLs come through where the plain INSERT and UPDATE parsers would mangle them. A row stored
+// the way SQL stores it, text values in schema order, replays byte for byte; anything else
+// replays with the same values and is called out in a note.
+pub struct SqlPatch {
+    pub statements: Vec<String>,
+    // What the statements can't carry exactly, written into the patch as `-- note:` lines
+    pub notes: Vec<String>,
+}
+
+impl SqlPatch {
+    pub fn to_lines(&self, from: &str, to: &str) -> Vec<String> {
+        let mut lines = vec![format!("-- BranchDB patch from {} to {}", from, to)];
+        lines.extend(self.notes.iter().map(|note| format!("-- note: {}", note)));
+        lines.extend(self.statements.iter().cloned());
+        lines
+    }
+}
+
+// One table's changes and its states at both ends.
+struct TablePatch<'a> {
+    table: &'a str,
+    changes: Vec<&'a Change>,
+    from_schema: Value,
+    to_schema: Value,
+    from_rows: TableState,
+    to_rows: TableState,
+}
+
+pub fn render(storage: &CommitStorage, from: &[u8; 32], to: &[u8; 32], options: &DiffOptions) -> Result<SqlPatch> {
+    let diffs = storage.get_commit_diffs(from, to, options)?;
+    let mut patch = SqlPatch { statements: Vec::new(), notes: Vec::new() };
+
+    // get_commit_diffs lists the changes table by table
+    let mut start = 0;
+    while start < diffs.len() {
+        let table = diffs[start].table();
+        let end = diffs[start..].iter().position(|c| c.table() != table).map_or(diffs.len(), |n| start + n);
+        let table_patch = TablePatch {
+            table,
+            changes: diffs[start..end].iter().collect(),
+            from_schema: storage.get_table_schema(table, Some(from))?,
+            to_schema: storage.get_table_schema(table, Some(to))?,
+            from_rows: storage.table_state_at(table, from)?,
+            to_rows: storage.table_state_at(table, to)?,
+        };
+        table_patch.render(&mut patch);
+        start = end;
+    }
+    Ok(patch)
+}
+
+impl TablePatch<'_> {
+    fn render(&self, patch: &mut SqlPatch) {
+        let schema_change = self.changes.iter().find(|c| c.id() == "!schema");
+        // Dropping the table takes its rows with it
+        if matches!(schema_change, Some(Change::Delete { .. })) && self.to_rows.is_empty() {
+            patch.statements.push(format!("DROP TABLE {};", self.table));
+            return;
+        }
+
+        let mut recreate = false;
+        match schema_change {
+            Some(Change::Insert { .. }) => self.create_table(patch, false),
+            Some(Change::Update { .. }) => match self.alter_statements() {
+                Some(statements) => patch.statements.extend(statements),
+                None => {
+                    patch.notes.push(format!(
+                        "the schema change of '{}' can't be written as ALTER TABLE, so the table is recreated with all its rows",
+                        self.table
+                    ));
+                    self.create_table(patch, true);
+                    recreate = true;
+                }
+            },
+            Some(Change::Delete { .. }) => patch.notes.push(format!(
+                "the schema of '{}' was removed while rows remain; no statement removes a schema alone", self.table
+            )),
+            None => {}
+        }
+
+        if recreate {
+            let mut ids: Vec<&String> = self.to_rows.keys().filter(|id| !id.starts_with('!')).collect();
+            ids.sort();
+            for id in ids {
+                self.insert(patch, id, &self.to_rows[id]);
+            }
+            return;
+        }
+        for change in &self.changes {
+            let id = change.id();
+            if id.starts_with('!') {
+                if id != "!schema" {
+                    patch.notes.push(format!("metadata row '{}' of '{}' is left out", id, self.table));
+                }
+                continue;
+            }
+            match change {
+                Change::Insert { .. } => self.insert(patch, id, &self.to_rows[id]),
+                Change::Update { .. } => self.update(patch, id),
+                Change::Delete { .. } => patch.statements.push(self.delete(id)),
+            }
+        }
+    }
+
+    // The columns of the target schema, extended with `column<N>` for values past them, the
+    // way INSERT ... ON CONFLICT names them.
+    fn columns(&self, width: usize) -> Vec<String> {
+        let mut columns = row::schema_columns(&self.to_schema);
+        columns.extend((columns.len()..width).map(|i| format!("column{}", i + 1)));
+        columns
+    }
+
+    fn key_columns(&self) -> Vec<String> {
+        match MultiKeyPrimaryKey::from_schema(&self.to_schema) {
+            Some(key) => key.columns,
+            None => self.columns(1).into_iter().take(1).collect(),
+        }
+    }
+
+    // A row's values in column order, or None for counters and payloads that aren't rows.
+    // Notes rows that won't be stored the same way they are here.
+    fn values(&self, patch: &mut SqlPatch, id: &str, value: &CrdtValue) -> Option<Vec<Value>> {
+        let json = match value {
+            CrdtValue::Register(data) => serde_json::from_slice::<Value>(data).ok(),
+            CrdtValue::Counter(_) => None,
+        };
+        let (values, positional) = match json {
+            Some(Value::Array(values)) => (values, true),
+            Some(Value::Object(mut fields)) => (row::schema_columns(&self.to_schema).iter()
+                .map(|column| fields.remove(column).unwrap_or(Value::Null))
+                .collect(), false),
+            _ => {
+                patch.notes.push(format!("row '{}' of '{}' is not a row SQL can write and is left out", id, self.table));
+                return None;
+            }
+        };
+        if !positional || !values.iter().all(|v| v.is_string() || v.is_null()) {
+            patch.notes.push(format!(
+                "row '{}' of '{}' isn't stored as SQL stores rows; it replays with the same values as text",
+                id, self.table
+            ));
+        }
+        let columns = self.columns(values.len());
+        let replayed_id = match MultiKeyPrimaryKey::from_schema(&self.to_schema) {
+            Some(key) => key.row_id(|column| columns.iter().position(|c| c == column)
+                .and_then(|i| values.get(i))
+                .map(text))
+                .ok(),
+            None => values.first().map(text),
+        };
+        if replayed_id.as_deref() != Some(id) {
+            patch.notes.push(format!(
+                "row '{}' of '{}' isn't keyed by its key column values and replays under another id", id, self.table
+            ));
+        }
+        Some(values)
+    }
+
+    // An insert never overwrites a row the other repository already has.
+    fn insert(&self, patch: &mut SqlPatch, id: &str, value: &CrdtValue) {
+        if let Some(values) = self.values(patch, id, value) {
+            patch.statements.push(format!("INSERT INTO {} VALUES ({}) ON CONFLICT DO NOTHING;", self.table, literals(&values)));
+        }
+    }
+
+    // Sets the columns whose values changed. When none did by position, as when a row was only
+    // rewritten from an object, every column but the key is set.
+    fn update(&self, patch: &mut SqlPatch, id: &str) {
+        let Some(after) = self.values(patch, id, &self.to_rows[id]) else { return };
+        let before = self.from_rows.get(id)
+            .and_then(|value| match value {
+                CrdtValue::Register(data) => serde_json::from_slice::<Value>(data).ok(),
+                CrdtValue::Counter(_) => None,
+            })
+            .map(|json| row::row_to_object(json, &self.from_schema));
+        let columns = self.columns(after.len());
+        let keys = self.key_columns();
+        let settable: Vec<&String> = columns.iter().take(after.len()).filter(|c| !keys.contains(c)).collect();
+        let mut changed: Vec<&String> = settable.iter().copied()
+            .filter(|column| {
+                let position = columns.iter().position(|c| c == *column).unwrap_or_default();
+                // A value missing on either side, as after ADD COLUMN, reads as NULL
+                let old = before.as_ref().and_then(|b| b.get(column.as_str())).unwrap_or(&Value::Null);
+                old != after.get(position).unwrap_or(&Value::Null)
+            })
+            .collect();
+        if changed.is_empty() {
+            changed = settable;
+        }
+
+        let action = match changed.is_empty() {
+            true => "DO NOTHING".to_string(),
+            false => format!("DO UPDATE SET {}", changed.iter()
+                .map(|column| format!("{} = excluded.{}", column, column))
+                .collect::<Vec<_>>()
+                .join(", ")),
+        };
+        patch.statements.push(format!("INSERT INTO {} VALUES ({}) ON CONFLICT {};", self.table, literals(&after), action));
+    }
+
+    // By every key column for a composite key, otherwise by the first column, or `id` when the
+    // table declares none.
+    fn delete(&self, id: &str) -> String {
+        let condition = match MultiKeyPrimaryKey::from_schema(&self.to_schema) {
+            Some(key) => key.columns.iter().zip(key.split(id))
+                .map(|(column, value)| format!("{} = {}", column, quote(value)))
+                .collect::<Vec<_>>()
+                .join(" AND "),
+            None => {
+                let column = row::schema_columns(&self.to_schema).into_iter().next().unwrap_or_else(|| "id".to_string());
+                format!("{} = {}", column, quote(id))
+            }
+        };
+        format!("DELETE FROM {} WHERE {};", self.table, condition)
+    }
+
+    fn create_table(&self, patch: &mut SqlPatch, or_replace: bool) {
+        let statement = create_table_statement(self.table, &self.to_schema, or_replace);
+        let mut declared = sql::create_table_schema(&statement);
+        patch.statements.push(format!("{};", statement));
+        // Soft delete is only switched on after the table exists
+        if trash::is_soft_delete(&self.to_schema) {
+            declared["soft_delete"] = Value::Bool(true);
+            patch.statements.push(format!("ALTER TABLE {} SET SOFT DELETE;", self.table));
+        }
+        if declared != self.to_schema {
+            patch.notes.push(format!("the schema of '{}' has parts CREATE TABLE can't declare; they are left out", self.table));
+        }
+    }
+
+    // DROP COLUMN for every column the target lacks, then ADD COLUMN for every new one, as
+    // long as replaying them ends at the target schema exactly. A column whose type changed
+    // can't be altered without losing its values, so that takes recreating the table.
+    fn alter_statements(&self) -> Option<Vec<String>> {
+        let from_columns = self.from_schema.get("columns")?.as_object()?;
+        let to_columns = self.to_schema.get("columns")?.as_object()?;
+        let key = MultiKeyPrimaryKey::from_schema(&self.from_schema);
+        let mut schema = self.from_schema.clone();
+        let mut statements = Vec::new();
+        for column in from_columns.keys() {
+            match to_columns.get(column) {
+                Some(kind) if Some(kind) == from_columns.get(column) => continue,
+                Some(_) => return None,
+                None if key.as_ref().is_some_and(|key| key.contains(column)) => return None,
+                None => {}
+            }
+            statements.push(format!("ALTER TABLE {} DROP COLUMN {};", self.table, column));
+            schema["columns"].as_object_mut()?.remove(column);
+            if let Some(not_null) = schema.get_mut("not_null").and_then(|n| n.as_array_mut()) {
+                not_null.retain(|c| c != column.as_str());
+            }
+        }
+        for (column, kind) in to_columns {
+            if from_columns.contains_key(column) {
+                continue;
+            }
+            // ALTER TABLE reads the type as a single word
+            let kind = kind.as_str().filter(|k| !k.is_empty() && !k.contains(char::is_whitespace))?;
+            statements.push(format!("ALTER TABLE {} ADD COLUMN {} {};", self.table, column, kind));
+            schema["columns"][column] = Value::String(kind.to_string());
+        }
+        let ttl = self.to_schema.get("ttl");
+        if ttl != self.from_schema.get("ttl") {
+            match ttl.and_then(Value::as_str) {
+                Some(ttl) => {
+                    statements.push(format!("ALTER TABLE {} SET TTL {};", self.table, quote(ttl)));
+                    schema["ttl"] = Value::String(ttl.to_string());
+                }
+                None => {
+                    statements.push(format!("ALTER TABLE {} DROP TTL;", self.table));
+                    schema.as_object_mut()?.remove("ttl");
+                }
+            }
+        }
+        if self.to_schema.get("soft_delete") != self.from_schema.get("soft_delete") {
+            match trash::is_soft_delete(&self.to_schema) {
+                true => {
+                    statements.push(format!("ALTER TABLE {} SET SOFT DELETE;", self.table));
+                    schema["soft_delete"] = Value::Bool(true);
+                }
+                false => {
+                    statements.push(format!("ALTER TABLE {} DROP SOFT DELETE;", self.table));
+                    schema.as_object_mut()?.remove("soft_delete");
+                }
+            }
+        }
+        (schema == self.to_schema).then_some(statements)
+    }
+}
+
+// A value as the text SQL stores it.
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// `CREATE [OR REPLACE] TABLE` declaring the schema's columns, NOT NULL columns, references
+// and composite primary key.
+fn create_table_statement(table: &str, schema: &Value, or_replace: bool) -> String {
+    let not_null: Vec<&str> = schema.get("not_null").and_then(|n| n.as_array())
+        .map(|columns| columns.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let mut definitions: Vec<String> = schema.get("columns").and_then(|c| c.as_object())
+        .map(|columns| columns.iter()
+            .map(|(column, kind)| {
+                let mut definition = format!("{} {}", column, kind.as_str().unwrap_or("TEXT"));
+                if not_null.contains(&column.as_str()) {
+                    definition.push_str(" NOT NULL");
+                }
+                if let Some(reference) = schema.get("foreign_keys").and_then(|f| f.get(column)) {
+                    let table = reference.get("table").and_then(Value::as_str).unwrap_or_default();
+                    let referred = reference.get("column").and_then(Value::as_str).unwrap_or("id");
+                    definition.push_str(&format!(" REFERENCES {}({})", table, referred));
+                }
+                definition
+            })
+            .collect())
+        .unwrap_or_default();
+    if let Some(key) = MultiKeyPrimaryKey::from_schema(schema) {
+        definitions.push(format!("PRIMARY KEY ({})", key.columns.join(", ")));
+    }
+    let create = if or_replace { "CREATE OR REPLACE TABLE" } else { "CREATE TABLE" };
+    let ttl = schema.get("ttl").and_then(Value::as_str).map(|ttl| format!(" WITH TTL {}", quote(ttl))).unwrap_or_default();
+    format!("{} {} ({}){}", create, table, definitions.join(", "), ttl)
+}
+
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::String(s) => quote(s),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+        other => quote(&other.to_string()),
+    }
+}
+
+fn literals(values: &[Value]) -> String {
+    values.iter().map(literal).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_support::{head, run, temp_repo};
+
+    const START: [&str; 4] = [
+        "CREATE TABLE users (id TEXT, name TEXT, city TEXT)",
+        "INSERT INTO users VALUES ('1', 'ann', 'Oslo')",
+        "INSERT INTO users VALUES ('2', 'bob', 'Rome')",
+        "INSERT INTO users VALUES ('3', 'cy', 'Lima')",
+    ];
+
+    #[test]
+    fn a_replayed_patch_reaches_the_same_table_hashes() {
+        let (_dir, storage) = temp_repo();
+        for statement in START {
+            run(&storage, statement);
+        }
+        let from = head(&storage);
+        run(&storage, "UPDATE users SET name = 'anne' WHERE id = '1'");
+        run(&storage, "DELETE FROM users WHERE id = '2'");
+        run(&storage, "INSERT INTO users VALUES ('4', 'di', 'Kyiv')");
+        // Rows written before the new column stay as narrow as they were
+        run(&storage, "ALTER TABLE users ADD COLUMN email TEXT");
+        run(&storage, "UPDATE users SET city = 'Bergen' WHERE id = '3'");
+        run(&storage, "INSERT INTO users VALUES ('5', 'ed', 'Pisa', 'ed@example.com')");
+        run(&storage, "CREATE TABLE teams (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO teams VALUES ('t1', 'red; blue')");
+        let to = head(&storage);
+
+        let patch = render(&storage, &from, &to, &DiffOptions::default()).unwrap();
+        assert!(patch.notes.is_empty(), "{:?}", patch.notes);
+        // Read back the way `sql --file` reads the file `diff --format sql` wrote
+        let file = patch.to_lines(&hex::encode(from), &hex::encode(to)).join("\n");
+        let (_other, copy) = temp_repo();
+        for statement in START.iter().map(|s| s.to_string()).chain(sql::split_statements(&file)) {
+            run(&copy, &statement);
+        }
+        assert_eq!(copy.get_commit_by_hash(&head(&copy)).unwrap().tree, storage.get_commit_by_hash(&to).unwrap().tree);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::database::{decode_row_key, table_key_prefix, CommitStorage};
+use crate::core::models::Change;
+use crate::core::row;
+use crate::error::{BranchDBError, Result};
+use serde::Serialize;
+
+// Rows per commit when `commits` isn't given.
+const DEFAULT_BATCH_ROWS: usize = 1000;
+
+// A table's columns in schema order, each with its declared type.
+pub type Columns = Vec<(String, String)>;
+
+pub struct SeedOptions {
+    pub table: String,
+    pub rows: usize,
+    // Required when the table doesn't exist yet; otherwise it must match the table's schema
+    pub schema: Option<Columns>,
+    pub seed: u64,
+    // Commits to spread each target's rows over; None commits DEFAULT_BATCH_ROWS at a time
+    pub commits: Option<usize>,
+    // Each branch gets its own `rows` rows; none seeds HEAD
+    pub branches: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SeedTarget {
+    // None for HEAD
+    pub branch: Option<String>,
+    pub first_id: usize,
+    pub last_id: usize,
+    pub commits: usize,
+    pub head: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SeedSummary {
+    pub table: String,
+    pub seed: u64,
+    pub created_table: bool,
+    pub targets: Vec<SeedTarget>,
+}
+
+// SplitMix64: tiny, fast and fully determined by the seed, which is all the generators need.
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    pub(crate) fn word(&mut self) -> String {
+        let len = 4 + self.below(8) as usize;
+        (0..len).map(|_| (b'a' + self.below(26) as u8) as char).collect()
+    }
+}
+
+// Parses `--schema`: a JSON object of column name to type, in column order. The first column
+// holds the row id, so it has to be an integer or text column.
+pub fn parse_columns(schema: &str) -> Result<Columns> {
+    let value: serde_json::Value = serde_json::from_str(schema)
+        .map_err(|e| BranchDBError::InvalidInput(format!("--schema is not JSON: {}", e)))?;
+    let object = value.as_object()
+        .ok_or_else(|| BranchDBError::InvalidInput("--schema must be an object of column name to type".into()))?;
+    let columns = object.iter()
+        .map(|(name, kind)| match kind.as_str() {
+            Some(kind) => Ok((name.clone(), kind.to_string())),
+            None => Err(BranchDBError::InvalidInput(format!("Type of column '{}' must be a string", name))),
+        })
+        .collect::<Result<Columns>>()?;
+    check_columns(&columns)?;
+    Ok(columns)
+}
+
+fn check_columns(columns: &Columns) -> Result<()> {
+    match columns.first() {
+        None => Err(BranchDBError::InvalidInput("--schema declares no columns".into())),
+        Some((name, kind)) if !matches!(base_type(kind).as_str(), "INT" | "INTEGER" | "BIGINT" | "SMALLINT" | "TEXT" | "VARCHAR" | "STRING") => {
+            Err(BranchDBError::InvalidInput(format!("First column '{}' holds the row id and must be INT or TEXT, not {}", name, kind)))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+fn base_type(kind: &str) -> String {
+    kind.split('(').next().unwrap_or_default().trim().to_uppercase()
+}
+
+// A positional row as an SQL INSERT stores it: the id in the first column, then a value of
+// each column's type, every one as a string. Types without a generator get a word.
+pub(crate) fn generate_row(rng: &mut Rng, columns: &Columns, id: usize) -> serde_json::Value {
+    let values = columns.iter().enumerate().map(|(i, (_, kind))| {
+        if i == 0 {
+            return id.to_string();
+        }
+        match base_type(kind).as_str() {
+            "INTEGER" | "INT" | "BIGINT" | "SMALLINT" => rng.below(1_000_000).to_string(),
+            "FLOAT" | "REAL" | "DOUBLE" | "NUMERIC" | "DECIMAL" => format!("{:.2}", rng.below(10_000_000) as f64 / 100.0),
+            "BOOLEAN" | "BOOL" => (rng.below(2) == 1).to_string(),
+            "DATE" => {
+                let days = chrono::Days::new(rng.below(365 * 30));
+                chrono::NaiveDate::from_ymd_opt(2000, 1, 1)
+                    .and_then(|start| start.checked_add_days(days))
+                    .map(|date| date.to_string())
+                    .unwrap_or_default()
+            }
+            _ => rng.word(),
+        }
+    });
+    serde_json::Value::Array(values.map(serde_json::Value::String).collect())
+}
+
+pub(crate) fn row_change(rng: &mut Rng, table: &str, columns: &Columns, id: usize) -> Result<Change> {
+    Ok(Change::Insert {
+        table: table.to_string(),
+        id: id.to_string(),
+        value: bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&generate_row(rng, columns, id))?))?,
+    })
+}
+
+pub(crate) fn schema_change(table: &str, columns: &Columns) -> Result<Change> {
+    let columns: serde_json::Map<String, serde_json::Value> = columns.iter()
+        .map(|(name, kind)| (name.clone(), serde_json::Value::String(kind.clone())))
+        .collect();
+    Ok(Change::Insert {
+        table: table.to_string(),
+        id: "!schema".to_string(),
+        value: bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&serde_json::json!({ "columns": columns }))?))?,
+    })
+}
+
+// Generates the rows and commits them on HEAD, or on each branch in turn. A missing table is
+// created on HEAD first, so every branch starts from it. Ids continue after the largest
+// numeric id already in the table, and each branch gets the next range, so seeding again adds
+// rows instead of overwriting them and the branches merge without conflicts. HEAD is back
+// where it started once the branches are seeded; branches that don't exist are created from it.
+pub fn run(storage: &CommitStorage, options: &SeedOptions) -> Result<SeedSummary> {
+    if options.rows == 0 {
+        return Err(BranchDBError::InvalidInput("--rows must be at least 1".into()));
+    }
+    let commits = match options.commits {
+        Some(0) => return Err(BranchDBError::InvalidInput("--commits must be at least 1".into())),
+        Some(commits) => commits.min(options.rows),
+        None => options.rows.div_ceil(DEFAULT_BATCH_ROWS),
+    };
+
+    let existing = storage.get_table_schema(&options.table, None)?;
+    let existing_columns: Columns = existing.get("columns")
+        .and_then(|c| c.as_object())
+        .map(|c| c.iter().map(|(name, kind)| (name.clone(), kind.as_str().unwrap_or_default().to_string())).collect())
+        .unwrap_or_default();
+    let (columns, created_table) = match (&options.schema, existing_columns.is_empty()) {
+        (Some(columns), true) => {
+            storage.create_commit(&format!("seed: create table {}", options.table), vec![schema_change(&options.table, columns)?])?;
+            (columns.clone(), true)
+        }
+        (Some(columns), false) if *columns != existing_columns => {
+            return Err(BranchDBError::InvalidInput(format!(
+                "--schema doesn't match the columns of existing table '{}' ({})",
+                options.table,
+                row::schema_columns(&existing).join(", ")
+            )));
+        }
+        (None, true) => {
+            return Err(BranchDBError::InvalidInput(format!(
+                "Table '{}' doesn't exist; pass --schema to create it", options.table
+            )));
+        }
+        (_, false) => (existing_columns, false),
+    };
+    check_columns(&columns)?;
+
+    let base = storage.get_head()?;
+    let mut rng = Rng(options.seed);
+    let mut next_id = largest_id(storage, &options.table)? + 1;
+    let mut targets = Vec::new();
+    let branches: Vec<Option<&str>> = match options.branches.is_empty() {
+        true => vec![None],
+        false => options.branches.iter().map(|b| Some(b.as_str())).collect(),
+    };
+    for branch in branches {
+        if let Some(branch) = branch {
+            let start = match storage.db.get(format!("branch:{}", branch).as_bytes())? {
+                Some(head) => <[u8; 32]>::try_from(head.as_slice())
+                    .map_err(|_| BranchDBError::InvalidInput(format!("Branch '{}' points to an invalid commit", branch)))?,
+                None => base.ok_or_else(|| BranchDBError::InvalidInput("HEAD not found".into()))?,
+            };
+            storage.move_head(&start)?;
+        }
+
+        let first_id = next_id;
+        let mut head = [0u8; 32];
+        for commit in 0..commits {
+            // The first `rows % commits` commits take one row more than the others
+            let count = options.rows / commits + usize::from(commit < options.rows % commits);
+            let changes = (next_id..next_id + count)
+                .map(|id| row_change(&mut rng, &options.table, &columns, id))
+                .collect::<Result<Vec<_>>>()?;
+            let message = format!(
+                "seed: {} rows {}-{} ({}/{})", options.table, next_id, next_id + count - 1, commit + 1, commits
+            );
+            head = storage.create_commit(&message, changes)?;
+            next_id += count;
+        }
+        if let Some(branch) = branch {
+            storage.db.put(format!("branch:{}", branch).as_bytes(), head)?;
+        }
+        targets.push(SeedTarget {
+            branch: branch.map(str::to_string),
+            first_id,
+            last_id: next_id - 1,
+            commits,
+            head: hex::encode(head),
+        });
+    }
+
+    if !options.branches.is_empty() {
+        if let Some(base) = base {
+            storage.move_head(&base)?;
+        }
+    }
+    Ok(SeedSummary { table: options.table.clone(), seed: options.seed, created_table, targets })
+}
+
+// The largest id of a live row that parses as a number, or 0.
+fn largest_id(storage: &CommitStorage, table: &str) -> Result<usize> {
+    let prefix = table_key_prefix(table);
+    let mut largest = 0;
+    for item in storage.db.prefix_iterator(prefix.as_bytes()) {
+        let (key, _) = item?;
+        if !key.starts_with(prefix.as_bytes()) {
+            break;
+        }
+        let Some((_, id)) = decode_row_key(&key) else { continue };
+        if let Ok(id) = id.parse::<usize>() {
+            largest = largest.max(id);
+        }
+    }
+    Ok(largest)
+}
@@ -0,0 +1,134 @@
+use crate::core::branch::BranchManager;
+use crate::core::database::CommitStorage;
+use crate::error::{BranchDBError, Result};
+use std::collections::BTreeSet;
+
+const MAX_SUGGESTIONS: usize = 3;
+// Shorter hash prefixes are close to every commit, so they get no suggestions
+const MIN_COMMIT_PREFIX: usize = 4;
+
+// Close matches for the name in a not-found error, best first. Errors that do not name a
+// branch, table or commit get no suggestions, and neither does a repository that cannot be read.
+pub fn suggestions(error: &BranchDBError, storage: &CommitStorage) -> Vec<String> {
+    let (target, candidates) = match error {
+        BranchDBError::BranchNotFound { name } => {
+            (name, BranchManager::new(storage.db.clone()).list_branches())
+        }
+        BranchDBError::TableNotFound { name } => (name, storage.list_tables()),
+        BranchDBError::CommitNotFound { hash } => (hash, known_commits(storage)),
+        _ => return Vec::new(),
+    };
+    let Ok(candidates) = candidates else { return Vec::new() };
+
+    // Commits are matched on a prefix as long as what was typed, so an abbreviated hash with a
+    // typo still finds the full one
+    let is_commit = matches!(error, BranchDBError::CommitNotFound { .. });
+    if is_commit && target.len() < MIN_COMMIT_PREFIX {
+        return Vec::new();
+    }
+    let max_distance = (target.chars().count() / 3).max(1);
+    let mut scored: Vec<(usize, String)> = candidates.into_iter()
+        .filter(|candidate| candidate != target)
+        .filter_map(|candidate| {
+            let compared = if is_commit { prefix(&candidate, target.len()) } else { &candidate };
+            let distance = levenshtein(target, compared);
+            (distance <= max_distance).then_some((distance, candidate))
+        })
+        .collect();
+    scored.sort();
+    scored.into_iter().take(MAX_SUGGESTIONS).map(|(_, candidate)| candidate).collect()
+}
+
+// "did you mean 'a'?", or "did you mean 'a', 'b' or 'c'?" for several candidates.
+pub fn did_you_mean(candidates: &[String]) -> Option<String> {
+    let quoted: Vec<String> = candidates.iter().map(|c| format!("'{}'", c)).collect();
+    let (last, rest) = quoted.split_last()?;
+    if rest.is_empty() {
+        Some(format!("did you mean {}?", last))
+    } else {
+        Some(format!("did you mean {} or {}?", rest.join(", "), last))
+    }
+}
+
+// Commits on the first-parent chains of HEAD and every branch.
+fn known_commits(storage: &CommitStorage) -> Result<Vec<String>> {
+    let branch_mgr = BranchManager::new(storage.db.clone());
+    let mut heads: Vec<[u8; 32]> = storage.get_head()?.into_iter().collect();
+    for branch in branch_mgr.list_branches()? {
+        if let Some(head) = branch_mgr.get_branch_head(&branch)? {
+            if let Ok(head) = head.try_into() {
+                heads.push(head);
+            }
+        }
+    }
+
+    let mut seen = BTreeSet::new();
+    for head in heads {
+        let mut current = Some(head);
+        while let Some(hash) = current {
+            if !seen.insert(hash) {
+                break;
+            }
+            current = storage.get_commit_by_hash(&hash)?.parents.first().cloned();
+        }
+    }
+    Ok(seen.into_iter().map(hex::encode).collect())
+}
+
+fn prefix(s: &str, len: usize) -> &str {
+    s.get(..len).unwrap_or(s)
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_support::{head, run, temp_repo};
+
+    #[test]
+    fn not_found_errors_suggest_the_closest_names() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "CREATE TABLE orders (id TEXT, item TEXT)");
+        let branches = BranchManager::new(storage.db.clone());
+        for name in ["feature-w", "feature-x", "feature-y", "feature-z", "main", "release"] {
+            branches.create_branch(name).unwrap();
+        }
+        let suggested = |error: BranchDBError| did_you_mean(&suggestions(&error, &storage));
+
+        // Closest first, then by name, and no more than three
+        assert_eq!(suggested(BranchDBError::BranchNotFound { name: "featur-x".into() }).as_deref(),
+            Some("did you mean 'feature-x', 'feature-w' or 'feature-y'?"));
+        assert_eq!(suggested(BranchDBError::BranchNotFound { name: "mainn".into() }).as_deref(), Some("did you mean 'main'?"));
+        assert_eq!(suggested(BranchDBError::BranchNotFound { name: "hotfix".into() }), None);
+        assert_eq!(suggested(BranchDBError::TableNotFound { name: "user".into() }).as_deref(), Some("did you mean 'users'?"));
+
+        let hash = hex::encode(head(&storage));
+        let typo = format!("{}{}", if hash.starts_with('0') { '1' } else { '0' }, &hash[1..8]);
+        assert_eq!(suggested(BranchDBError::CommitNotFound { hash: typo }), Some(format!("did you mean '{}'?", hash)));
+        assert_eq!(suggested(BranchDBError::CommitNotFound { hash: hash[..2].to_string() }), None);
+        assert_eq!(suggested(BranchDBError::InvalidInput("users".into())), None);
+    }
+
+    #[test]
+    fn did_you_mean_quotes_every_candidate() {
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(did_you_mean(&[]), None);
+        assert_eq!(did_you_mean(&names(&["a"])).as_deref(), Some("did you mean 'a'?"));
+        assert_eq!(did_you_mean(&names(&["a", "b"])).as_deref(), Some("did you mean 'a' or 'b'?"));
+        assert_eq!(did_you_mean(&names(&["a", "b", "c"])).as_deref(), Some("did you mean 'a', 'b' or 'c'?"));
+    }
+}
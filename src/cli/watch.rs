@@ -0,0 +1,153 @@
+use crate::cli::diff::{decode_change_value, decode_row, DiffRenderer};
+use crate::cli::output::{change_json, commit_json, Output};
+use crate::core::database::CommitStorage;
+use crate::core::models::{Change, Commit};
+use crate::core::refs;
+use crate::error::{BranchDBError, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+// How often a sleeping watch checks for Ctrl-C.
+const STOP_CHECK: Duration = Duration::from_millis(100);
+
+pub struct WatchOptions {
+    // Only report commits that change this table, and only its rows
+    pub table: Option<String>,
+    // One JSON object per commit and line instead of text
+    pub json: bool,
+    // A sequence number or commit to replay the commits after; default: only new ones
+    pub since: Option<String>,
+    pub interval: Duration,
+}
+
+// Where a watch got to when it was stopped.
+pub struct WatchSummary {
+    pub last_sequence: u64,
+    pub commits: usize,
+}
+
+// Polls the repository for commits with a sequence number past the last one reported, until
+// Ctrl-C. Every poll opens the repository read-only and closes it again, so the write lock is
+// never held and the poll sees whatever another process wrote, compacted or collected since.
+// A poll that fails is reported and retried at the next interval.
+pub fn run(data_dir: &str, options: &WatchOptions, out: &Output) -> Result<WatchSummary> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = stop.clone();
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .map_err(|e| BranchDBError::InvalidInput(format!("Could not install the Ctrl-C handler: {}", e)))?;
+
+    // JSON output is only the commits, one per line
+    let status = |text: String| if !options.json {
+        out.line(text);
+    };
+    let mut summary = WatchSummary { last_sequence: start_sequence(data_dir, options.since.as_deref())?, commits: 0 };
+    status(format!(
+        "Watching {} after commit #{}; press Ctrl-C to stop",
+        options.table.as_deref().map_or("all tables".to_string(), |table| format!("table '{}'", table)),
+        summary.last_sequence,
+    ));
+    while !stop.load(Ordering::SeqCst) {
+        match poll(data_dir, summary.last_sequence, options, out) {
+            Ok((last_sequence, reported)) => {
+                summary.last_sequence = last_sequence;
+                summary.commits += reported;
+            }
+            Err(e) => warn!(error = %e, "could not read the repository; retrying"),
+        }
+        let started = Instant::now();
+        while started.elapsed() < options.interval && !stop.load(Ordering::SeqCst) {
+            thread::sleep(STOP_CHECK.min(options.interval));
+        }
+    }
+    status(format!("Stopped after commit #{}", summary.last_sequence));
+    Ok(summary)
+}
+
+// `--since` as a sequence number, or as a branch or commit whose sequence number is used.
+fn start_sequence(data_dir: &str, since: Option<&str>) -> Result<u64> {
+    if let Some(sequence) = since.and_then(|since| since.parse::<u64>().ok()) {
+        return Ok(sequence);
+    }
+    let storage = CommitStorage::open_read_only(data_dir)?;
+    match since {
+        Some(reference) => Ok(storage.get_commit_by_hash(&refs::resolve(&storage, reference)?)?.sequence),
+        None => storage.last_sequence(),
+    }
+}
+
+// Reports the commits after `after` and returns the new last sequence number along with how
+// many commits were reported.
+fn poll(data_dir: &str, after: u64, options: &WatchOptions, out: &Output) -> Result<(u64, usize)> {
+    let storage = CommitStorage::open_read_only(data_dir)?;
+    if storage.last_sequence()? <= after {
+        return Ok((after, 0));
+    }
+    let mut last = after;
+    let mut reported = 0;
+    for (hash, commit) in storage.commits_after(after)? {
+        last = last.max(commit.sequence);
+        let changes: Vec<&Change> = commit.changes.iter()
+            .filter(|change| options.table.as_deref().is_none_or(|table| change.table() == table))
+            .collect();
+        if options.table.is_some() && changes.is_empty() {
+            continue;
+        }
+        report(&storage, &hash, &commit, &changes, options, out)?;
+        reported += 1;
+    }
+    debug!(after, last, reported, "polled for new commits");
+    Ok((last, reported))
+}
+
+// Prints one commit and its row changes, decoded with the schemas on either side of it.
+fn report(storage: &CommitStorage, hash: &[u8; 32], commit: &Commit, changes: &[&Change], options: &WatchOptions, out: &Output) -> Result<()> {
+    let parent = commit.parents.first();
+    let renderer = DiffRenderer::new(false);
+    let mut lines = vec![
+        format!("commit {} (#{})", hex::encode(hash), commit.sequence),
+        format!("    {}", commit.message),
+    ];
+    let mut entries = Vec::new();
+    let mut tables = HashMap::new();
+    for change in changes.iter().filter(|change| change.id() != "!schema") {
+        let table = change.table();
+        if !tables.contains_key(table) {
+            let before = match parent {
+                Some(parent) => (storage.get_table_schema(table, Some(parent))?, storage.table_state_at(table, parent)?),
+                None => (serde_json::json!({}), HashMap::new()),
+            };
+            tables.insert(table.to_string(), (before, storage.get_table_schema(table, Some(hash))?));
+        }
+        let ((before_schema, before_rows), schema) = &tables[table];
+        let before = before_rows.get(change.id()).map(|value| decode_row(value, before_schema));
+        let after = match change {
+            Change::Insert { value, .. } | Change::Update { value, .. } => Some(decode_change_value(value, schema)?),
+            Change::Delete { .. } => None,
+        };
+        lines.extend(renderer.render_change(change, before.as_ref(), after.as_ref()));
+        let mut entry = change_json(change);
+        if let Some(before) = before {
+            entry["before"] = before;
+        }
+        if let Some(after) = after {
+            entry["value"] = after;
+        }
+        entries.push(entry);
+    }
+
+    // Printed directly rather than through `out`, which keeps JSON for one document at the end
+    if options.json || out.is_json() {
+        let mut event = commit_json(hash, commit);
+        event["changes"] = serde_json::Value::Array(entries);
+        println!("{}", serde_json::to_string(&event)?);
+    } else {
+        for line in lines {
+            out.line(line);
+        }
+    }
+    Ok(())
+}
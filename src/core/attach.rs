@@ -0,0 +1,194 @@
+use crate::core::database::CommitStorage;
+use crate::core::query::QueryResult;
+use crate::core::refs;
+use crate::error::{BranchDBError, Result};
+use crate::core::namespace::NamespacedDb;
+use serde::{Deserialize, Serialize};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::collections::BTreeSet;
+use std::fs;
+
+// `attach:<alias>` holds a repository attached with ATTACH, as JSON. Like views and functions,
+// attachments aren't versioned: one made on a branch is visible from every branch and commit.
+const ATTACH_PREFIX: &str = "attach:";
+
+const USAGE: &str = "Expected ATTACH '<path>' AS <alias> [AS OF '<commit or branch>']";
+
+// Another repository whose tables queries read as `<alias>.<table>`, never writing to them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub alias: String,
+    // Absolute, so queries find it from whatever directory they run in
+    pub path: String,
+    // A commit or branch of the attached repository, `~` and `^` suffixes included, resolved
+    // on every query so a branch follows the repository as it moves. Its HEAD when None.
+    #[serde(default)]
+    pub as_of: Option<String>,
+}
+
+// An attachment opened for reading: the repository and the commit its tables are read at.
+pub struct AttachedRepo {
+    pub storage: CommitStorage,
+    pub commit: [u8; 32],
+}
+
+// `ATTACH [DATABASE] '<path>' AS <alias> [AS OF '<commit or branch>']`.
+pub(crate) fn parse_attach(command: &str) -> Option<Result<Attachment>> {
+    let tokens = words(command)?;
+    let mut tokens = tokens.iter().peekable();
+    if !is_word(tokens.next(), "ATTACH") {
+        return None;
+    }
+    tokens.next_if(|t| is_word(Some(t), "DATABASE"));
+    let invalid = || Some(Err(BranchDBError::InvalidInput(USAGE.into())));
+    let (Some(Token::SingleQuotedString(path)), true, Some(Token::Word(alias))) = (tokens.next(), is_word(tokens.next(), "AS"), tokens.next()) else {
+        return invalid();
+    };
+    let as_of = match (tokens.next(), tokens.next(), tokens.next(), tokens.next()) {
+        (None, ..) => None,
+        (as_, of, Some(reference), None) if is_word(as_, "AS") && is_word(of, "OF") => match reference {
+            Token::SingleQuotedString(reference) => Some(reference.clone()),
+            Token::Word(reference) => Some(reference.value.clone()),
+            _ => return invalid(),
+        },
+        _ => return invalid(),
+    };
+    Some(Ok(Attachment { alias: alias.value.clone(), path: path.clone(), as_of }))
+}
+
+// `DETACH [DATABASE] <alias>`: the alias.
+pub(crate) fn parse_detach(command: &str) -> Option<Result<String>> {
+    let tokens = words(command)?;
+    let mut tokens = tokens.iter().peekable();
+    if !is_word(tokens.next(), "DETACH") {
+        return None;
+    }
+    tokens.next_if(|t| is_word(Some(t), "DATABASE"));
+    Some(match (tokens.next(), tokens.next()) {
+        (Some(Token::Word(alias)), None) => Ok(alias.value.clone()),
+        _ => Err(BranchDBError::InvalidInput("Expected DETACH <alias>".into())),
+    })
+}
+
+// `SHOW ATTACHMENTS`.
+pub(crate) fn is_show_attachments(command: &str) -> bool {
+    let tokens = words(command).unwrap_or_default();
+    tokens.len() == 2 && is_word(tokens.first(), "SHOW") && is_word(tokens.get(1), "ATTACHMENTS")
+}
+
+// The statement's tokens without whitespace or a trailing `;`, or None if it doesn't tokenize.
+fn words(command: &str) -> Option<Vec<Token>> {
+    let tokens = Tokenizer::new(&GenericDialect, command).tokenize().ok()?;
+    Some(tokens.into_iter().filter(|t| !matches!(t, Token::Whitespace(_) | Token::SemiColon)).collect())
+}
+
+fn is_word(token: Option<&Token>, word: &str) -> bool {
+    matches!(token, Some(Token::Word(w)) if w.value.eq_ignore_ascii_case(word))
+}
+
+// Every attachment, ordered by alias.
+pub(crate) fn attachments(db: &NamespacedDb) -> Result<Vec<Attachment>> {
+    let mut attachments = Vec::new();
+    for item in db.prefix_iterator(ATTACH_PREFIX) {
+        let (key, value) = item?;
+        if !key.starts_with(ATTACH_PREFIX.as_bytes()) {
+            break;
+        }
+        attachments.push(serde_json::from_slice(&value)?);
+    }
+    Ok(attachments)
+}
+
+// Splits `<alias>.<table>` into the attachment and the table in it. None for a name that
+// doesn't start with an attached alias, which is a table of this repository.
+pub(crate) fn attached_table(db: &NamespacedDb, name: &str) -> Result<Option<(Attachment, String)>> {
+    let Some((alias, table)) = name.split_once('.') else { return Ok(None) };
+    let Some(value) = db.get(format!("{}{}", ATTACH_PREFIX, alias).as_bytes())? else { return Ok(None) };
+    Ok(Some((serde_json::from_slice(&value)?, table.to_string())))
+}
+
+impl Attachment {
+    // Opens the repository without taking its lock, so it may be in use by another process,
+    // and resolves the commit to read at.
+    pub fn open(&self) -> Result<AttachedRepo> {
+        let storage = CommitStorage::open_read_only(&self.path).map_err(|e| BranchDBError::InvalidInput(format!(
+            "Could not open attached repository '{}' at {}: {}", self.alias, self.path, e
+        )))?;
+        let commit = match &self.as_of {
+            Some(reference) => refs::resolve(&storage, reference)?,
+            None => storage.get_head()?.ok_or_else(|| BranchDBError::InvalidInput(format!(
+                "Attached repository '{}' has no commits", self.alias
+            )))?,
+        };
+        Ok(AttachedRepo { storage, commit })
+    }
+}
+
+impl CommitStorage {
+    // Records an attachment once the repository opens and its commit resolves, and returns
+    // that commit. The path is stored absolute; the alias may not be in use.
+    pub fn attach(&self, attachment: &Attachment) -> Result<[u8; 32]> {
+        let valid_alias = !attachment.alias.is_empty()
+            && attachment.alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_alias {
+            return Err(BranchDBError::InvalidInput(format!("Invalid alias '{}'", attachment.alias)));
+        }
+        let key = format!("{}{}", ATTACH_PREFIX, attachment.alias);
+        if self.db.get(key.as_bytes())?.is_some() {
+            return Err(BranchDBError::InvalidInput(format!(
+                "'{}' is already attached; DETACH it first", attachment.alias
+            )));
+        }
+        let path = fs::canonicalize(&attachment.path).map_err(|e| BranchDBError::InvalidInput(format!(
+            "Could not attach {}: {}", attachment.path, e
+        )))?;
+        let attachment = Attachment { path: path.to_string_lossy().into_owned(), ..attachment.clone() };
+        let commit = attachment.open()?.commit;
+        self.db.put(key.as_bytes(), serde_json::to_vec(&attachment)?)?;
+        Ok(commit)
+    }
+
+    pub fn detach(&self, alias: &str) -> Result<()> {
+        let key = format!("{}{}", ATTACH_PREFIX, alias);
+        if self.db.get(key.as_bytes())?.is_none() {
+            return Err(BranchDBError::InvalidInput(format!("Nothing is attached as '{}'", alias)));
+        }
+        self.db.delete(key.as_bytes())?;
+        Ok(())
+    }
+
+    // `SHOW ATTACHMENTS`: every attachment with the commit it reads at now, which is NULL when
+    // the repository can't be opened or the commit doesn't resolve.
+    pub fn attachments_result(&self) -> Result<QueryResult> {
+        let rows = attachments(&self.db)?.into_iter()
+            .map(|attachment| {
+                let commit = attachment.open().ok().map(|repo| hex::encode(repo.commit));
+                vec![
+                    attachment.alias.into(),
+                    attachment.path.into(),
+                    attachment.as_of.into(),
+                    commit.into(),
+                ]
+            })
+            .collect();
+        Ok(QueryResult {
+            table: "attachments".to_string(),
+            columns: ["alias", "path", "as_of", "commit"].map(str::to_string).to_vec(),
+            rows,
+        })
+    }
+
+    // Attached repositories are read-only: refuses writes to any of their tables.
+    pub(crate) fn check_writable<'t>(&self, tables: impl IntoIterator<Item = &'t str>) -> Result<()> {
+        let tables: BTreeSet<&str> = tables.into_iter().collect();
+        for table in tables {
+            if let Some((attachment, _)) = attached_table(&self.db, table)? {
+                return Err(BranchDBError::InvalidInput(format!(
+                    "'{}' belongs to the repository attached as '{}', which is read-only", table, attachment.alias
+                )));
+            }
+        }
+        Ok(())
+    }
+}
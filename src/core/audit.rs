@@ -0,0 +1,80 @@
+use crate::core::models::CommitAudit;
+use std::env;
+use std::fs;
+
+// What commits record about where they came from, set through `StorageOptions`. By default
+// every commit records the user, the host and the process's command line.
+#[derive(Debug, Clone)]
+pub struct CommitContext {
+    // Turned off, commits record nothing at all
+    pub collect: bool,
+    // Recorded as the command instead of the command line, for library callers to name themselves
+    pub caller: Option<String>,
+    // Recorded as the user instead of the OS user, for services committing on someone's behalf
+    pub user: Option<String>,
+    pub source: Option<String>,
+}
+
+impl Default for CommitContext {
+    fn default() -> Self {
+        Self { collect: true, caller: None, user: None, source: None }
+    }
+}
+
+impl CommitContext {
+    pub fn disabled() -> Self {
+        Self { collect: false, caller: None, user: None, source: None }
+    }
+
+    // The metadata for a commit made now; None with collection off.
+    pub fn audit(&self) -> Option<CommitAudit> {
+        if !self.collect {
+            return None;
+        }
+        Some(CommitAudit {
+            user: self.user.clone().or_else(|| env_value(&["USER", "USERNAME", "LOGNAME"])),
+            hostname: hostname(),
+            command: self.caller.clone().or_else(command_line),
+            source: self.source.clone(),
+        })
+    }
+}
+
+fn env_value(names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| env::var(name).ok()).filter(|value| !value.is_empty())
+}
+
+// `HOSTNAME` is often a shell variable that isn't exported, so Unix falls back to /etc/hostname.
+fn hostname() -> Option<String> {
+    env_value(&["HOSTNAME", "COMPUTERNAME"])
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+// The process's arguments as a shell would take them back, quoting the ones that need it.
+fn command_line() -> Option<String> {
+    let words: Vec<String> = env::args()
+        .map(|arg| {
+            let plain = !arg.is_empty() && !arg.chars().any(|c| c.is_whitespace() || "'\"\\$`;&|<>*?()".contains(c));
+            if plain { arg } else { format!("'{}'", arg.replace('\'', "'\\''")) }
+        })
+        .collect();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+// Who made a commit as `log` shows it: `user@host`, or whichever of the two was recorded.
+pub fn author(audit: Option<&CommitAudit>) -> Option<String> {
+    let audit = audit?;
+    match (&audit.user, &audit.hostname) {
+        (Some(user), Some(host)) => Some(format!("{}@{}", user, host)),
+        (Some(name), None) | (None, Some(name)) => Some(name.clone()),
+        (None, None) => None,
+    }
+}
+
+// `log --grep-source`: whether the commit's source contains `pattern`, ignoring case.
+pub fn source_matches(audit: Option<&CommitAudit>, pattern: &str) -> bool {
+    audit.and_then(|audit| audit.source.as_deref())
+        .is_some_and(|source| source.to_lowercase().contains(&pattern.to_lowercase()))
+}
@@ -0,0 +1,38 @@
+use crate::core::database::CommitStorage;
+use crate::core::models::Change;
+use crate::error::Result;
+
+// BatchWriter is for bulk loads. It collects every change and records a single commit for
+// everything at `finalize`, instead of paying for a commit per batch of rows. The commit writes
+// the live `table:id` rows in the same WriteBatch as the commit itself.
+pub struct BatchWriter<'a> {
+    storage: &'a CommitStorage,
+    changes: Vec<Change>,
+}
+
+impl<'a> BatchWriter<'a> {
+    pub fn new(storage: &'a CommitStorage) -> Self {
+        Self {
+            storage,
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn add(&mut self, change: Change) -> Result<()> {
+        self.changes.push(change);
+        Ok(())
+    }
+
+    // Records one commit holding every change added.
+    pub fn finalize(self, message: &str) -> Result<[u8; 32]> {
+        self.storage.create_commit(message, self.changes)
+    }
+}
@@ -1,14 +1,29 @@
+use crate::core::database::CommitStorage;
+use crate::core::history::HistoryWalk;
+use crate::core::refs;
 use crate::error::{BranchDBError, Result};
-use rocksdb::DB;
+use crate::core::namespace::NamespacedDb;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
+// What may happen to a protected branch. Stored under `protected:<name>`.
+// `require_merge_request` is recorded for tooling; BranchDB itself has no merge requests yet.
+// The default policy locks everything down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtectionPolicy {
+    pub allow_delete: bool,
+    pub require_merge_request: bool,
+    pub allow_force_push: bool,
+}
+
 // BranchManager handles creation and deletion of branches in the BranchDB database. Each branch points to a commit hash.
 pub struct BranchManager {
-    pub db: Arc<DB>,
+    pub db: Arc<NamespacedDb>,
 }
 
 impl BranchManager {
-    pub fn new(db: Arc<DB>) -> Self {
+    pub fn new(db: Arc<NamespacedDb>) -> Self {
         Self { db }
     }
 
@@ -33,11 +48,15 @@ impl BranchManager {
     pub fn delete_branch(&self, name: &str) -> Result<()> {
         let branch_key = format!("branch:{}", name);
         if self.db.get(branch_key.as_bytes())?.is_none() {
-            return Err(BranchDBError::InvalidInput(format!("Branch '{}' does not exist", name)));
+            return Err(BranchDBError::BranchNotFound { name: name.to_string() });
+        }
+        if self.protection(name)?.is_some_and(|policy| !policy.allow_delete) {
+            return Err(BranchDBError::InvalidInput(format!("Branch '{}' is protected", name)));
         }
 
         self.db.delete(branch_key.as_bytes())?;
-        println!("Deleted branch '{}" , name);
+        self.db.delete(format!("protected:{}", name).as_bytes())?;
+        tracing::info!(branch = name, "deleted branch");
         Ok(())
     }
 
@@ -46,6 +65,10 @@ impl BranchManager {
         let iter = self.db.prefix_iterator("branch:");
         for item in iter {
             let (key, _) = item?;
+            // prefix_iterator keeps going past the prefix without a prefix extractor
+            if !key.starts_with(b"branch:") {
+                break;
+            }
             let branch_name = String::from_utf8_lossy(&key["branch:".len()..]).into_owned();
             branches.push(branch_name);
         }
@@ -57,7 +80,10 @@ impl BranchManager {
             let iter = self.db.prefix_iterator("branch:");
             for item in iter {
                 let (key, value) = item?;
-                if &value[..] == &head[..] {  // Compare slices of the underlying bytes
+                if !key.starts_with(b"branch:") {
+                    break;
+                }
+                if value[..] == head[..] {  // Compare slices of the underlying bytes
                     let branch_name = String::from_utf8_lossy(&key["branch:".len()..]).into_owned();
                     return Ok(Some(branch_name));
                 }
@@ -70,4 +96,199 @@ impl BranchManager {
         let branch_key = format!("branch:{}", branch_name);
         Ok(self.db.get(branch_key.as_bytes())?.map(|v| v.to_vec()))
     }
-}
\ No newline at end of file
+
+    pub fn protect_branch(&self, name: &str, policy: ProtectionPolicy) -> Result<()> {
+        if self.get_branch_head(name)?.is_none() {
+            return Err(BranchDBError::BranchNotFound { name: name.to_string() });
+        }
+        self.db.put(format!("protected:{}", name).as_bytes(), bincode::serialize(&policy)?)?;
+        tracing::info!(branch = name, ?policy, "protected branch");
+        Ok(())
+    }
+
+    pub fn unprotect_branch(&self, name: &str) -> Result<()> {
+        let key = format!("protected:{}", name);
+        if self.db.get(key.as_bytes())?.is_none() {
+            return Err(BranchDBError::InvalidInput(format!("Branch '{}' is not protected", name)));
+        }
+        self.db.delete(key.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn protection(&self, name: &str) -> Result<Option<ProtectionPolicy>> {
+        match self.db.get(format!("protected:{}", name).as_bytes())? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Branches whose head is in HEAD's history, so that deleting them loses nothing. Protected
+    // branches and branches pointing at HEAD itself are never listed.
+    pub fn merged_branches(&self, storage: &CommitStorage) -> Result<Vec<String>> {
+        let Some(head) = storage.get_head()? else { return Ok(Vec::new()) };
+        let mut merged = Vec::new();
+        for name in self.list_branches()? {
+            let Some(raw) = self.get_branch_head(&name)? else { continue };
+            let Ok(branch_head) = <[u8; 32]>::try_from(raw.as_slice()) else { continue };
+            if branch_head == head || self.protection(&name)?.is_some() {
+                continue;
+            }
+            if storage.get_common_ancestor(&head, &branch_head)? == Some(branch_head) {
+                merged.push(name);
+            }
+        }
+        Ok(merged)
+    }
+
+    // (ahead, behind) of a branch against `base`, which is a branch, commit or `HEAD`: the
+    // commits only the branch has and those only `base` has, counted through every parent
+    // like `git rev-list --count base...branch`.
+    pub fn branch_divergence(&self, storage: &CommitStorage, branch: &str, base: &str) -> Result<(usize, usize)> {
+        let head = self.get_branch_head(branch)?
+            .ok_or_else(|| BranchDBError::BranchNotFound { name: branch.to_string() })?;
+        let head = <[u8; 32]>::try_from(head.as_slice())
+            .map_err(|_| BranchDBError::CorruptData(format!("Branch '{}' points to an invalid commit", branch)))?;
+        DivergenceCounter::new(storage, &refs::resolve(storage, base)?)?.count(&head)
+    }
+
+    // Deletes the branches `merged_branches` lists and returns their names.
+    pub fn prune_merged(&self, storage: &CommitStorage) -> Result<Vec<String>> {
+        let merged = self.merged_branches(storage)?;
+        for name in &merged {
+            self.delete_branch(name)?;
+        }
+        Ok(merged)
+    }
+
+    // Called before a branch is moved to a commit that is not a descendant of its head.
+    pub fn check_force_update(&self, name: &str) -> Result<()> {
+        if self.protection(name)?.is_some_and(|policy| !policy.allow_force_push) {
+            return Err(BranchDBError::InvalidInput(
+                format!("Branch '{}' is protected against force updates", name)
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Divergence of many heads from the same base, as `branch-list --divergence` needs: the base's
+// history is walked once, and branches pointing at the same commit are counted once.
+pub struct DivergenceCounter<'a> {
+    storage: &'a CommitStorage,
+    base_history: HashSet<[u8; 32]>,
+    counts: HashMap<[u8; 32], (usize, usize)>,
+}
+
+impl<'a> DivergenceCounter<'a> {
+    pub fn new(storage: &'a CommitStorage, base: &[u8; 32]) -> Result<Self> {
+        Ok(Self { storage, base_history: history(storage, base)?, counts: HashMap::new() })
+    }
+
+    pub fn count(&mut self, head: &[u8; 32]) -> Result<(usize, usize)> {
+        if let Some(&counts) = self.counts.get(head) {
+            return Ok(counts);
+        }
+        let history = history(self.storage, head)?;
+        let counts = (
+            history.difference(&self.base_history).count(),
+            self.base_history.difference(&history).count(),
+        );
+        self.counts.insert(*head, counts);
+        Ok(counts)
+    }
+}
+
+// Every commit reachable from `head`, itself included.
+fn history(storage: &CommitStorage, head: &[u8; 32]) -> Result<HashSet<[u8; 32]>> {
+    HistoryWalk::new(storage, Some(*head))?.map(|entry| entry.map(|(hash, _)| hash)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::merge::MergeResult;
+    use crate::core::test_support::{head, run, temp_repo};
+
+    #[test]
+    fn a_protected_branch_is_deleted_only_once_unprotected() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        let branches = BranchManager::new(storage.db.clone());
+        branches.create_branch("main").unwrap();
+        branches.protect_branch("main", ProtectionPolicy::default()).unwrap();
+
+        let err = branches.delete_branch("main").unwrap_err();
+        assert!(matches!(&err, BranchDBError::InvalidInput(message) if message == "Branch 'main' is protected"), "{}", err);
+        assert!(branches.get_branch_head("main").unwrap().is_some());
+
+        branches.unprotect_branch("main").unwrap();
+        assert_eq!(branches.protection("main").unwrap(), None);
+        assert!(matches!(branches.unprotect_branch("main"), Err(BranchDBError::InvalidInput(_))));
+        branches.delete_branch("main").unwrap();
+        assert_eq!(branches.list_branches().unwrap(), Vec::<String>::new());
+
+        // A policy may still allow deletion, and deleting forgets the policy
+        branches.create_branch("release").unwrap();
+        let policy = ProtectionPolicy { allow_delete: true, ..ProtectionPolicy::default() };
+        branches.protect_branch("release", policy).unwrap();
+        assert_eq!(branches.protection("release").unwrap(), Some(policy));
+        branches.delete_branch("release").unwrap();
+        assert_eq!(branches.protection("release").unwrap(), None);
+        assert!(matches!(branches.protect_branch("release", policy), Err(BranchDBError::BranchNotFound { .. })));
+    }
+
+    #[test]
+    fn prune_deletes_a_branch_once_it_is_fast_forward_merged() {
+        let (_dir, storage) = temp_repo();
+        let branches = BranchManager::new(storage.db.clone());
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        let root = head(&storage);
+        branches.create_branch("release").unwrap();
+        branches.protect_branch("release", ProtectionPolicy::default()).unwrap();
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        branches.create_branch("feature").unwrap();
+        storage.move_head(&root).unwrap();
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+        branches.create_branch("other").unwrap();
+        storage.move_head(&root).unwrap();
+
+        let merged = storage.merge_with_ancestor("feature", |_| Ok("Merge feature".to_string())).unwrap();
+        assert!(matches!(merged, MergeResult::FastForward(_)));
+        // A branch HEAD is on isn't listed until HEAD moves past it
+        assert!(branches.merged_branches(&storage).unwrap().is_empty());
+        run(&storage, "INSERT INTO users VALUES ('3', 'cy')");
+
+        assert_eq!(branches.merged_branches(&storage).unwrap(), ["feature"]);
+        assert_eq!(branches.prune_merged(&storage).unwrap(), ["feature"]);
+        assert_eq!(branches.list_branches().unwrap(), ["other", "release"]);
+        assert!(branches.prune_merged(&storage).unwrap().is_empty());
+    }
+
+    #[test]
+    fn divergence_counts_the_commits_each_side_has_since_the_common_ancestor() {
+        let (_dir, storage) = temp_repo();
+        let branches = BranchManager::new(storage.db.clone());
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        let root = head(&storage);
+        branches.create_branch("base").unwrap();
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+        branches.create_branch("feature").unwrap();
+        storage.move_head(&root).unwrap();
+        run(&storage, "INSERT INTO users VALUES ('3', 'cy')");
+        run(&storage, "INSERT INTO users VALUES ('4', 'di')");
+        run(&storage, "INSERT INTO users VALUES ('5', 'ed')");
+        branches.create_branch("main").unwrap();
+
+        assert_eq!(branches.branch_divergence(&storage, "feature", "main").unwrap(), (2, 3));
+        assert_eq!(branches.branch_divergence(&storage, "main", "feature").unwrap(), (3, 2));
+        // The base may be HEAD or a commit hash as well as a branch
+        assert_eq!(branches.branch_divergence(&storage, "feature", "HEAD").unwrap(), (2, 3));
+        assert_eq!(branches.branch_divergence(&storage, "base", &hex::encode(head(&storage))).unwrap(), (0, 3));
+        assert_eq!(branches.branch_divergence(&storage, "feature", "feature").unwrap(), (0, 0));
+        assert!(matches!(
+            branches.branch_divergence(&storage, "missing", "main"),
+            Err(BranchDBError::BranchNotFound { .. })
+        ));
+    }
+}
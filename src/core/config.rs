@@ -0,0 +1,85 @@
+use crate::error::{BranchDBError, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE: &str = "config";
+// Branch created pointing at the first commit, set by `init --initial-branch`.
+pub const INITIAL_BRANCH_KEY: &str = "core.initial-branch";
+// `true` refreshes the materialized views reading a table after every commit that changes it.
+pub const INCREMENTAL_VIEWS_KEY: &str = "core.incremental-views";
+// Seconds a command waits for another process to release the repository before giving up.
+pub const LOCK_TIMEOUT_KEY: &str = "core.lock-timeout";
+// `true` refuses commits that would leave a foreign key referencing a missing row.
+pub const ENFORCE_FK_KEY: &str = "core.enforce-foreign-keys";
+// `false` keeps commits from recording the user, host and command line that made them.
+pub const AUDIT_KEY: &str = "core.audit";
+// File holding the key of an encrypted repository, relative to the data directory unless absolute.
+pub const KEY_FILE_KEY: &str = "core.key-file";
+
+// Repository settings in `<data dir>/config`, one `section.name = value` per line. Lines
+// starting with `#` are comments. A missing file is an empty config.
+pub struct Config {
+    path: PathBuf,
+    values: BTreeMap<String, String>,
+}
+
+impl Config {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(CONFIG_FILE);
+        let mut values = BTreeMap::new();
+        if path.is_file() {
+            for (number, line) in fs::read_to_string(&path)?.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (key, value) = line.split_once('=').ok_or_else(|| BranchDBError::CorruptData(
+                    format!("{}:{}: expected `key = value`", path.display(), number + 1)
+                ))?;
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        Ok(Self { path, values })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        Self::validate_key(key)?;
+        self.values.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    // Returns whether the key was set.
+    pub fn unset(&mut self, key: &str) -> bool {
+        self.values.remove(key).is_some()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents: String = self.values.iter().map(|(k, v)| format!("{} = {}\n", k, v)).collect();
+        fs::write(&self.path, contents)?;
+        Ok(())
+    }
+
+    fn validate_key(key: &str) -> Result<()> {
+        let valid = key.split_once('.').is_some_and(|(section, name)| {
+            let word = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            word(section) && name.split('.').all(word)
+        });
+        if valid {
+            Ok(())
+        } else {
+            Err(BranchDBError::InvalidInput(format!("Invalid config key '{}': expected section.name", key)))
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::core::crdt::{CrdtEngine, CrdtValue};
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+pub const CONFLICTS_FILE: &str = "MERGE_CONFLICTS.json";
+
+// A row both sides of a merge changed to different values. A side that deleted the row has
+// None. A two-way merge takes `theirs` and keeps `ours` so the local version can be restored
+// with `checkout --ours`; a three-way merge stops and leaves the choice to `checkout`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MergeConflict {
+    pub table: String,
+    pub id: String,
+    pub ours: Option<CrdtValue>,
+    pub theirs: Option<CrdtValue>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MergeConflicts {
+    pub branch: String,
+    pub conflicts: Vec<MergeConflict>,
+    // Head of the merged branch while a three-way merge waits for its conflicts to be resolved;
+    // the next `commit` records it as the second parent
+    #[serde(default)]
+    pub merge_head: Option<String>,
+}
+
+impl MergeConflicts {
+    // Rows present on both sides with differing values, sorted by table then id.
+    pub(crate) fn between(branch: &str, ours: &CrdtEngine, theirs: &CrdtEngine) -> Self {
+        let mut conflicts = Vec::new();
+        for (table, rows) in &theirs.state {
+            let Some(local_rows) = ours.state.get(table) else { continue };
+            for (id, value) in rows {
+                if let Some(local) = local_rows.get(id).filter(|local| *local != value) {
+                    conflicts.push(MergeConflict {
+                        table: table.clone(),
+                        id: id.clone(),
+                        ours: Some(local.clone()),
+                        theirs: Some(value.clone()),
+                    });
+                }
+            }
+        }
+        conflicts.sort_by(|a, b| (&a.table, &a.id).cmp(&(&b.table, &b.id)));
+        Self { branch: branch.to_string(), conflicts, merge_head: None }
+    }
+
+    // Missing file means no unresolved conflicts.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    // Writes the file, or removes it once every conflict is resolved and no merge is waiting
+    // to be committed.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if self.conflicts.is_empty() && self.merge_head.is_none() {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn take(&mut self, table: &str, id: &str) -> Option<MergeConflict> {
+        let index = self.conflicts.iter().position(|c| c.table == table && c.id == id)?;
+        Some(self.conflicts.remove(index))
+    }
+}
@@ -1,10 +1,16 @@
 use serde::{Serialize, Deserialize};
 use crate::error::{BranchDBError, Result};
 use std::collections::HashMap;
+use crate::core::database::CommitStorage;
 use crate::core::models::Change;
+use crate::core::namespace::WriteBatch;
+use tracing::debug;
 
 pub type TableState = HashMap<String, CrdtValue>;
 
+// Tables flushed by `CrdtEngine::flush_evicted`, as a bincode `TableState` under the table name.
+pub const EVICTED_PREFIX: &str = "!crdt-evicted:";
+
 // A CRDT-based value that can be either a counter or a register.
 // Counter: Monotonically increasing integer (merge = max).
 // Register: Arbitrary bytes (merge = lexicographically latest).
@@ -14,19 +20,99 @@ pub enum CrdtValue {
     Register(Vec<u8>),
 }
 
+// An engine's full state serialized with bincode, as stored by `CommitStorage::save_snapshot`.
+#[derive(Debug, Clone)]
+pub struct EngineSnapshot(pub Vec<u8>);
+
+// What an engine with a size limit does when a change would take it over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    // Evict the table whose last change is the oldest
+    #[default]
+    Lru,
+    // Evict the table with the fewest changes
+    Lfu,
+    // Refuse the change
+    Error,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CrdtEngineOptions {
+    // Limit on the state's size, estimated as the serialized size of every id and value.
+    // None means unbounded.
+    pub max_state_bytes: Option<usize>,
+    pub eviction_policy: EvictionPolicy,
+}
+
+// When and how often a table was changed, for choosing what to evict.
+#[derive(Debug, Clone, Copy, Default)]
+struct TableAccess {
+    last: u64,
+    count: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct CrdtEngine {
     pub state: HashMap<String, TableState>,
+    options: CrdtEngineOptions,
+    // Estimated size of each table, only kept while there is a limit. Tables put into `state`
+    // directly are measured the first time a change reaches them.
+    sizes: HashMap<String, usize>,
+    access: HashMap<String, TableAccess>,
+    clock: u64,
+    // Tables evicted since the last `flush_evicted`
+    evicted: HashMap<String, TableState>,
+}
+
+impl Default for CrdtEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CrdtEngine {
     pub fn new() -> Self {
+        Self::new_with_options(CrdtEngineOptions::default())
+    }
+
+    pub fn new_with_options(options: CrdtEngineOptions) -> Self {
         Self {
             state: HashMap::new(),
+            options,
+            sizes: HashMap::new(),
+            access: HashMap::new(),
+            clock: 0,
+            evicted: HashMap::new(),
         }
     }
 
+    // With a size limit, tables other than the changed one are evicted, per the policy, until
+    // the change fits; a change that can't be made to fit is refused and nothing is evicted.
+    // A table that was evicted and not yet flushed is brought back before it is changed, and
+    // stays evicted if the change is refused.
     pub fn apply_change(&mut self, change: &Change) -> Result<()> {
+        let restored = self.evicted.remove(change.table());
+        let was_evicted = restored.is_some();
+        if let Some(rows) = restored {
+            self.state.insert(change.table().to_string(), rows);
+        }
+        if let Some(limit) = self.options.max_state_bytes {
+            if let Err(e) = self.make_room(change, limit) {
+                if was_evicted {
+                    if let Some(rows) = self.state.remove(change.table()) {
+                        self.sizes.remove(change.table());
+                        self.evicted.insert(change.table().to_string(), rows);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        let tick = self.clock;
+        self.clock += 1;
+        let access = self.access.entry(change.table().to_string()).or_default();
+        access.last = tick;
+        access.count += 1;
+
         match change {
             Change::Insert { table, id, value } |
             Change::Update { table, id, value } => {
@@ -43,6 +129,87 @@ impl CrdtEngine {
         Ok(())
     }
 
+    // Evicts tables until `change` fits under `limit`, or fails without evicting anything.
+    fn make_room(&mut self, change: &Change, limit: usize) -> Result<()> {
+        let table = change.table();
+        let current = self.table_size(table);
+        let old_entry = self.state.get(table)
+            .and_then(|rows| rows.get(change.id()))
+            .map_or(0, |value| entry_size(change.id(), value));
+        let new_entry = match change {
+            Change::Insert { value, .. } | Change::Update { value, .. } => {
+                entry_size(change.id(), &bincode::deserialize(value)?)
+            }
+            Change::Delete { .. } => 0,
+        };
+        let changed = current.saturating_sub(old_entry) + new_entry;
+        self.sizes.insert(table.to_string(), changed);
+
+        let names: Vec<String> = self.state.keys().filter(|name| name.as_str() != table).cloned().collect();
+        let mut others: Vec<(String, usize)> = names.into_iter()
+            .map(|name| {
+                let size = self.table_size(&name);
+                (name, size)
+            })
+            .collect();
+        let mut total = changed + others.iter().map(|(_, size)| size).sum::<usize>();
+        if total <= limit {
+            return Ok(());
+        }
+
+        // Evicting other tables can't help a table that is over the limit by itself
+        if self.options.eviction_policy == EvictionPolicy::Error || changed > limit {
+            self.sizes.insert(table.to_string(), current);
+            return Err(BranchDBError::InvalidInput("CRDT state size limit exceeded".into()));
+        }
+        let access = |name: &str| self.access.get(name).copied().unwrap_or_default();
+        match self.options.eviction_policy {
+            EvictionPolicy::Lfu => others.sort_by_key(|(name, _)| (access(name).count, access(name).last)),
+            _ => others.sort_by_key(|(name, _)| access(name).last),
+        }
+        for (name, size) in others {
+            if total <= limit {
+                break;
+            }
+            if let Some(rows) = self.state.remove(&name) {
+                debug!(table = %name, bytes = size, "evicting table from CRDT state");
+                self.evicted.insert(name.clone(), rows);
+            }
+            self.sizes.remove(&name);
+            self.access.remove(&name);
+            total -= size;
+        }
+        Ok(())
+    }
+
+    fn table_size(&mut self, table: &str) -> usize {
+        if let Some(&size) = self.sizes.get(table) {
+            return size;
+        }
+        let size = self.state.get(table)
+            .map_or(0, |rows| rows.iter().map(|(id, value)| entry_size(id, value)).sum());
+        self.sizes.insert(table.to_string(), size);
+        size
+    }
+
+    // Tables evicted since the last flush, by name.
+    pub fn evicted_tables(&self) -> Vec<&str> {
+        self.evicted.keys().map(String::as_str).collect()
+    }
+
+    // Writes the tables evicted since the last flush under EVICTED_PREFIX, replacing any copy
+    // flushed earlier, and drops them from memory. Returns how many tables were written.
+    pub fn flush_evicted(&mut self, storage: &CommitStorage) -> Result<usize> {
+        let mut batch = WriteBatch::default();
+        for (table, rows) in &self.evicted {
+            batch.put(format!("{}{}", EVICTED_PREFIX, table).as_bytes(), bincode::serialize(rows)?);
+        }
+        storage.db.write(batch)?;
+        let flushed = self.evicted.len();
+        self.evicted.clear();
+        Ok(flushed)
+    }
+
     pub fn merge(&mut self, other: &Self) -> Result<()> {
         for (table, rows) in &other.state {
             let my_rows = self.state.entry(table.clone()).or_default();
@@ -72,7 +239,89 @@ impl CrdtEngine {
         Ok(())
     }
 
+    pub fn snapshot(&self) -> Result<EngineSnapshot> {
+        Ok(EngineSnapshot(bincode::serialize(&self.state)?))
+    }
+
+    pub fn from_snapshot(snapshot: &EngineSnapshot) -> Result<Self> {
+        let mut engine = Self::new();
+        engine.state = bincode::deserialize(&snapshot.0)?;
+        Ok(engine)
+    }
+
     pub fn into_data(self) -> HashMap<String, TableState> {
         self.state
     }
-}
\ No newline at end of file
+}
+
+// Estimated bytes a row takes in the engine's state: its id and its serialized value.
+fn entry_size(id: &str, value: &CrdtValue) -> usize {
+    id.len() + bincode::serialized_size(value).unwrap_or(0) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_support::temp_repo;
+
+    fn insert(table: &str, id: &str) -> Change {
+        let value = bincode::serialize(&CrdtValue::Register(b"0123456789".to_vec())).unwrap();
+        Change::Insert { table: table.to_string(), id: id.to_string(), value }
+    }
+
+    // An engine with room for three rows like the ones `insert` makes.
+    fn limited(eviction_policy: EvictionPolicy) -> CrdtEngine {
+        let row = entry_size("1", &CrdtValue::Register(b"0123456789".to_vec()));
+        CrdtEngine::new_with_options(CrdtEngineOptions { max_state_bytes: Some(3 * row), eviction_policy })
+    }
+
+    fn tables(engine: &CrdtEngine) -> Vec<&str> {
+        let mut tables: Vec<&str> = engine.state.keys().map(String::as_str).collect();
+        tables.sort_unstable();
+        tables
+    }
+
+    #[test]
+    fn lru_evicts_the_table_changed_longest_ago() {
+        let mut engine = limited(EvictionPolicy::Lru);
+        for change in [insert("a", "1"), insert("b", "1"), insert("c", "1"), insert("a", "1")] {
+            engine.apply_change(&change).unwrap();
+        }
+        assert!(engine.evicted_tables().is_empty());
+
+        engine.apply_change(&insert("d", "1")).unwrap();
+        assert_eq!(tables(&engine), ["a", "c", "d"]);
+        assert_eq!(engine.evicted_tables(), ["b"]);
+
+        // An evicted table comes back when it is changed, pushing out the next oldest
+        engine.apply_change(&insert("b", "1")).unwrap();
+        assert_eq!(tables(&engine), ["a", "b", "d"]);
+        assert_eq!(engine.evicted_tables(), ["c"]);
+
+        let (_dir, storage) = temp_repo();
+        assert_eq!(engine.flush_evicted(&storage).unwrap(), 1);
+        assert!(engine.evicted_tables().is_empty());
+        let flushed = storage.db.get(format!("{}c", EVICTED_PREFIX).as_bytes()).unwrap().unwrap();
+        let rows: TableState = bincode::deserialize(&flushed).unwrap();
+        assert!(rows.contains_key("1"));
+    }
+
+    #[test]
+    fn lfu_evicts_the_table_changed_least_and_error_refuses() {
+        let mut engine = limited(EvictionPolicy::Lfu);
+        for change in [insert("a", "1"), insert("b", "1"), insert("b", "1"), insert("c", "1"), insert("c", "1")] {
+            engine.apply_change(&change).unwrap();
+        }
+        engine.apply_change(&insert("d", "1")).unwrap();
+        assert_eq!(engine.evicted_tables(), ["a"]);
+
+        let mut engine = limited(EvictionPolicy::Error);
+        for change in [insert("a", "1"), insert("b", "1"), insert("c", "1")] {
+            engine.apply_change(&change).unwrap();
+        }
+        let refused = engine.apply_change(&insert("d", "1"));
+        assert!(matches!(refused, Err(BranchDBError::InvalidInput(message)) if message == "CRDT state size limit exceeded"));
+        assert_eq!(tables(&engine), ["a", "b", "c"]);
+        assert!(engine.evicted_tables().is_empty());
+    }
+}
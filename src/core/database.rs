@@ -1,30 +1,552 @@
 use rocksdb::{DB, Options};
 use blake3;
-use std::time::{SystemTime, UNIX_EPOCH};
-use crate::core::models::{Commit, Change};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::core::models::{Commit, Change, LegacyCommit, UnauditedCommit};
 use crate::error::{BranchDBError, Result};
-use std::sync::Arc;
-use std::collections::HashMap;
-use crate::core::crdt::{CrdtEngine, CrdtValue};
+use crate::metrics::{self, Counter, Histogram};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use crate::core::branch::BranchManager;
+use crate::core::config::{Config, INITIAL_BRANCH_KEY};
+use crate::core::conflict::{MergeConflict, MergeConflicts, CONFLICTS_FILE};
+use crate::core::encryption;
+use crate::core::format;
+use crate::core::audit::CommitContext;
+use crate::core::crdt::{CrdtEngine, CrdtValue, EngineSnapshot, TableState};
+use crate::core::hooks::HookRunner;
+use crate::core::lock::RepoLock;
+use crate::core::namespace::{self, KeyValue, NamespacedDb, WriteBatch};
+use crate::core::merge::{load_state, rebase_conflicts, RebaseResult};
+use crate::core::phonetic;
+use crate::core::row::{self, Row};
+use crate::core::schema::{compare_schemas, SchemaChange};
+use crate::core::transaction::Transaction;
+use tracing::{debug, info, info_span, warn};
+
+// `!` can't start a SQL identifier, so registry keys never collide with `table:id` rows.
+pub(crate) const TABLE_REGISTRY_PREFIX: &str = "!table:";
+// Commit objects, keyed by the raw 32-byte hash after the prefix. Only the helpers below build
+// these keys.
+pub(crate) const COMMIT_PREFIX: &str = "!commit:";
+// Full engine state as of a commit, and for every commit the number of first-parent commits
+// since the last snapshot.
+const SNAPSHOT_PREFIX: &str = "!snapshot:";
+const SNAPSHOT_AGE_PREFIX: &str = "!snapshot-age:";
+// u64, little-endian: the sequence number of the last commit written
+const COMMIT_SEQUENCE_KEY: &[u8] = b"!commit-sequence";
+
+pub struct DiffOptions {
+    pub include_schema: bool,
+    pub include_data: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            include_schema: true,
+            include_data: true,
+        }
+    }
+}
+
+// How SQL writes treat values that don't match their column's schema type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoercionPolicy {
+    // Reject the write with `BranchDBError::TypeMismatch`
+    #[default]
+    Strict,
+    // Convert the value to the column type, failing only if it can't be converted
+    Coerce,
+    // Store the value as given
+    Silent,
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageOptions {
+    pub coercion: CoercionPolicy,
+    // Commits allowed in flight at once through a `CommitStoragePool`
+    pub max_concurrent_writes: usize,
+    // A state snapshot is stored every this many first-parent commits; 0 disables snapshots
+    pub snapshot_interval: usize,
+    // Rows per commit for CSV imports and COPY FROM stdin, unless they run in bulk
+    pub import_batch_size: usize,
+    // Refresh the materialized views reading a changed table after every commit
+    pub incremental_view_maintenance: bool,
+    // How long a writable open waits for another process to release the repository
+    pub lock_timeout: Duration,
+    // Refuse commits that would leave a foreign key pointing at a missing row
+    pub enforce_fk: bool,
+    // A CAST in a query that can't convert its value fails the query instead of giving NULL
+    pub strict_cast: bool,
+    // Times a commit is rebuilt on the new HEAD after another thread moved HEAD first; the
+    // attempt after the last retry holds off other writers, so it can't lose again
+    pub max_write_retries: usize,
+    // What every commit records about who made it
+    pub commit_context: CommitContext,
+    // Keeps every key under `<namespace>:`; empty for the repository outside any namespace
+    pub namespace: String,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            coercion: CoercionPolicy::default(),
+            max_concurrent_writes: 1,
+            snapshot_interval: 100,
+            import_batch_size: 100,
+            incremental_view_maintenance: false,
+            lock_timeout: Duration::from_secs(5),
+            enforce_fk: false,
+            strict_cast: false,
+            max_write_retries: 3,
+            commit_context: CommitContext::default(),
+            namespace: String::new(),
+        }
+    }
+}
+
+// Whether write_commit moves HEAD to the new commit.
+enum HeadUpdate<'a> {
+    Keep,
+    // Only if HEAD is still `expected`, None for a repository without commits. `guard` is the
+    // `head_writes` lock when the caller already holds it.
+    From { expected: Option<[u8; 32]>, guard: Option<MutexGuard<'a, ()>> },
+}
+
+fn snapshot_key(prefix: &str, hash: &[u8]) -> Vec<u8> {
+    format!("{}{}", prefix, hex::encode(hash)).into_bytes()
+}
+
+pub(crate) fn commit_key(hash: &[u8]) -> Vec<u8> {
+    let mut key = COMMIT_PREFIX.as_bytes().to_vec();
+    key.extend_from_slice(hash);
+    key
+}
+
+// Live rows are keyed `<table>:<id>`. Both parts are escaped so that neither can contain the
+// `:` between them: `%`, `:` and control characters become `%XX`. Names without those are
+// stored as they are. Only the helpers below build or split these keys.
+pub(crate) fn encode_row_key(table: &str, id: &str) -> String {
+    format!("{}{}", table_key_prefix(table), escape_key_part(id))
+}
+
+// The prefix shared by every live row of `table`, for prefix scans.
+pub(crate) fn table_key_prefix(table: &str) -> String {
+    format!("{}:", escape_key_part(table))
+}
+
+// The table and id of a live row key, or None if it isn't one.
+pub(crate) fn decode_row_key(key: &[u8]) -> Option<(String, String)> {
+    let key = std::str::from_utf8(key).ok()?;
+    let (table, id) = key.split_once(':')?;
+    Some((unescape_key_part(table)?, unescape_key_part(id)?))
+}
+
+pub(crate) fn escape_key_part(part: &str) -> String {
+    let mut escaped = String::with_capacity(part.len());
+    for c in part.chars() {
+        if c == '%' || c == ':' || c.is_ascii_control() {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+pub(crate) fn unescape_key_part(part: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(part.len());
+    let mut rest = part.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// A stored commit: the bincode `Commit` followed by the blake3 checksum of those bytes.
+pub(crate) fn get_commit_raw(db: &NamespacedDb, hash: &[u8]) -> Result<Option<Vec<u8>>> {
+    db.get(commit_key(hash))
+}
+
+// A stored commit, decoded only once its checksum holds, so a damaged object fails as corrupt
+// data naming the commit instead of as a decoding error from half-garbage bytes.
+pub(crate) fn read_commit(db: &NamespacedDb, hash: &[u8]) -> Result<Option<Commit>> {
+    let Some(raw) = get_commit_raw(db, hash)? else { return Ok(None) };
+    verify_commit(hash, &raw)?;
+    Ok(Some(decode_commit(&raw)?))
+}
+
+// Decodes a stored commit. Older commits end early and fail to decode in the current layout:
+// those without audit metadata are read as `UnauditedCommit`, and those written before format
+// version 4, which also end before the sequence number, as `LegacyCommit`.
+pub(crate) fn decode_commit(raw: &[u8]) -> Result<Commit> {
+    let body = &raw[..raw.len().saturating_sub(32)];
+    match bincode::deserialize::<Commit>(body) {
+        Ok(commit) => Ok(commit),
+        Err(e) => bincode::deserialize::<UnauditedCommit>(body).map(Commit::from)
+            .or_else(|_| bincode::deserialize::<LegacyCommit>(body).map(Commit::from))
+            .map_err(|_| e.into()),
+    }
+}
+
+// The checksum is the last 32 bytes and must be both the blake3 hash of the bytes before it
+// and the key the commit is stored under.
+pub(crate) fn verify_commit(hash: &[u8], raw: &[u8]) -> Result<()> {
+    let offset = raw.len().saturating_sub(32);
+    let (body, checksum) = raw.split_at(offset);
+    if raw.len() > 32 && blake3::hash(body).as_bytes() == checksum && checksum == hash {
+        return Ok(());
+    }
+    Err(BranchDBError::CorruptData(format!(
+        "commit {} fails its checksum (checksum at byte offset {} of {})", hex::encode(hash), offset, raw.len()
+    )))
+}
+
+pub(crate) fn put_commit_raw(batch: &mut WriteBatch, hash: &[u8], value: &[u8]) {
+    batch.put(commit_key(hash), value);
+}
+
+// The engine state saved at a commit, if it has a snapshot. History walks stop at the first
+// snapshot they reach and replay only the commits after it.
+pub(crate) fn load_snapshot(db: &NamespacedDb, hash: &[u8]) -> Result<Option<CrdtEngine>> {
+    match db.get(snapshot_key(SNAPSHOT_PREFIX, hash))? {
+        Some(raw) => Ok(Some(CrdtEngine::from_snapshot(&EngineSnapshot(raw))?)),
+        None => Ok(None),
+    }
+}
+
+// Mirrors a committed change onto its live `table:id` key. Rows hold the bincode `CrdtValue`;
+// `!schema` holds plain JSON, which is what `get_table_schema` reads for the current state.
+pub(crate) fn apply_to_live(batch: &mut WriteBatch, change: &Change) -> Result<()> {
+    let key = encode_row_key(change.table(), change.id());
+    match change {
+        Change::Insert { id, value, .. } | Change::Update { id, value, .. } if id == "!schema" => {
+            if let CrdtValue::Register(data) = bincode::deserialize(value)? {
+                batch.put(key.as_bytes(), data);
+            }
+        }
+        Change::Insert { value, .. } | Change::Update { value, .. } => batch.put(key.as_bytes(), value),
+        Change::Delete { .. } => batch.delete(key.as_bytes()),
+    }
+    Ok(())
+}
+
+// Changes to `table` that turn the rows `from` into the rows `to`: inserts and updates in id
+// order, then deletes in id order.
+fn diff_states(table: &str, from: &TableState, to: &TableState) -> Result<Vec<Change>> {
+    let mut diffs = Vec::new();
+    let mut to_ids: Vec<&String> = to.keys().collect();
+    to_ids.sort();
+    for id in to_ids {
+        let to_val = &to[id];
+        match from.get(id) {
+            Some(from_val) if from_val != to_val => {
+                diffs.push(Change::Update {
+                    table: table.to_string(),
+                    id: id.clone(),
+                    value: bincode::serialize(to_val)?,
+                });
+            }
+            None => {
+                diffs.push(Change::Insert {
+                    table: table.to_string(),
+                    id: id.clone(),
+                    value: bincode::serialize(to_val)?,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let mut deleted: Vec<&String> = from.keys().filter(|id| !to.contains_key(*id)).collect();
+    deleted.sort();
+    for id in deleted {
+        diffs.push(Change::Delete {
+            table: table.to_string(),
+            id: id.clone(),
+        });
+    }
+    Ok(diffs)
+}
+
+// Hash of a table's rows, in id order, as recorded in a commit's tree.
+fn table_hash(rows: &TableState) -> Result<[u8; 32]> {
+    let mut ids: Vec<&String> = rows.keys().collect();
+    ids.sort();
+    let mut hasher = blake3::Hasher::new();
+    for id in ids {
+        hasher.update(id.as_bytes());
+        hasher.update(&bincode::serialize(&rows[id])?);
+    }
+    Ok(*hasher.finalize().as_bytes())
+}
+
+// A table's state as of a commit: the nearest snapshot on the first-parent chain, with the
+// table's changes from the commits after it replayed on top. Only that table's changes are
+// kept while walking back.
+pub(crate) fn replay_table(db: &NamespacedDb, table: &str, commit_hash: &[u8; 32]) -> Result<TableState> {
+    let _span = info_span!("history_walk", table, commit = %hex::encode(commit_hash)).entered();
+    let mut history: Vec<Vec<Change>> = Vec::new();
+    let mut engine = CrdtEngine::new();
+    let mut current_hash = Some(*commit_hash);
+    while let Some(hash) = current_hash {
+        if let Some(snapshot) = load_snapshot(db, &hash)? {
+            engine = snapshot;
+            break;
+        }
+        let commit = read_commit(db, &hash)?
+            .ok_or_else(|| BranchDBError::CommitNotFound { hash: hex::encode(hash) })?;
+        current_hash = commit.parents.first().cloned();
+        history.push(commit.changes.into_iter().filter(|c| c.table() == table).collect());
+    }
+    metrics::observe(Histogram::HistoryWalkDepth, history.len() as f64);
+
+    for changes in history.iter().rev() {
+        for change in changes {
+            engine.apply_change(change)?;
+        }
+    }
+
+    let rows = engine.into_data().remove(table).unwrap_or_default();
+    metrics::increment(Counter::RowsRead, rows.len() as u64);
+    Ok(rows)
+}
+
+pub(crate) type TableRows<'a> = Box<dyn Iterator<Item = Result<(String, CrdtValue)>> + 'a>;
+
+// Rows of a table as of a commit, one at a time: metadata keys such as `!schema` first, then
+// the rows. At HEAD they are read lazily from the live `table:id` keys, which hold HEAD's
+// state, in key order; for any other commit the table is replayed with `CrdtReplayIterator`.
+pub(crate) fn table_rows<'a>(
+    db: &'a NamespacedDb,
+    table: &str,
+    commit_hash: &[u8; 32],
+) -> Result<TableRows<'a>> {
+    if db.get(b"HEAD")?.as_deref() == Some(&commit_hash[..]) {
+        return Ok(Box::new(live_table_rows(db, table)));
+    }
+    Ok(Box::new(CrdtReplayIterator::new(db, table, commit_hash)?))
+}
+
+// The live keys under `<table>:!` first, then the others under `<table>:`, so metadata comes
+// before rows whatever their ids sort after. Nothing is read ahead of the caller.
+fn live_table_rows<'a>(db: &'a NamespacedDb, table: &str) -> impl Iterator<Item = Result<(String, CrdtValue)>> + 'a {
+    let prefix = table_key_prefix(table).into_bytes();
+    let metadata_prefix = [&prefix[..], b"!"].concat();
+    let metadata = live_keys_under(db, metadata_prefix.clone());
+    let rows = live_keys_under(db, prefix).filter(move |item| !matches!(item, Ok((key, _)) if key.starts_with(&metadata_prefix)));
+    metadata.chain(rows).map(decode_live_row)
+}
+
+// The live metadata keys of `table`, then the live rows with the given ids, in that order, for
+// lookups that already know which rows they want. Ids without a live row are skipped.
+pub(crate) fn live_rows_by_id<'a>(db: &'a NamespacedDb, table: &str, ids: Vec<String>) -> TableRows<'a> {
+    let metadata = live_keys_under(db, [table_key_prefix(table).as_bytes(), b"!"].concat());
+    let table = table.to_string();
+    let rows = ids.into_iter().filter_map(move |id| {
+        let key = encode_row_key(&table, &id).into_bytes();
+        db.get(&key).map(|value| value.map(|value| (key.into_boxed_slice(), value.into_boxed_slice()))).transpose()
+    });
+    Box::new(metadata.chain(rows).map(decode_live_row))
+}
+
+fn live_keys_under(db: &NamespacedDb, prefix: Vec<u8>) -> impl Iterator<Item = Result<KeyValue>> + '_ {
+    db.prefix_iterator(prefix.clone())
+        .take_while(move |item| !matches!(item, Ok((key, _)) if !key.starts_with(&prefix)))
+}
+
+fn decode_live_row(item: Result<KeyValue>) -> Result<(String, CrdtValue)> {
+    let (key, value) = item?;
+    let (_, id) = decode_row_key(&key)
+        .ok_or_else(|| BranchDBError::CorruptData(format!("Unreadable row key {}", String::from_utf8_lossy(&key))))?;
+    // `!schema` is kept as plain JSON (see `apply_to_live`); history has it in a register
+    let value = match id.as_str() {
+        "!schema" => CrdtValue::Register(value.into_vec()),
+        _ => bincode::deserialize(&value)?,
+    };
+    Ok((id, value))
+}
+
+// Iterates the rows of a table as of a commit: metadata keys such as `!schema` first, then
+// rows in id order. Committed rows aren't stored under keys of their own (live `table:id` keys
+// only hold HEAD's state), so the table is replayed from the nearest snapshot first; rows are
+// then handed out one at a time instead of being decoded into a caller-side map.
+pub struct CrdtReplayIterator {
+    rows: std::iter::Chain<btree_map::IntoIter<String, CrdtValue>, btree_map::IntoIter<String, CrdtValue>>,
+}
+
+impl CrdtReplayIterator {
+    pub fn new(db: &NamespacedDb, table: &str, commit_hash: &[u8; 32]) -> Result<Self> {
+        let (metadata, rows): (BTreeMap<_, _>, BTreeMap<_, _>) = replay_table(db, table, commit_hash)?
+            .into_iter()
+            .partition(|(id, _)| id.starts_with('!'));
+        Ok(Self { rows: metadata.into_iter().chain(rows) })
+    }
+}
+
+impl Iterator for CrdtReplayIterator {
+    type Item = Result<(String, CrdtValue)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rows.next().map(Ok)
+    }
+}
 
 pub struct CommitStorage {
-    pub db: Arc<DB>,
+    pub db: Arc<NamespacedDb>,
+    path: PathBuf,
+    options: StorageOptions,
+    // Held while a commit checks that HEAD is still its parent and moves it
+    head_writes: Mutex<()>,
+    // Keeps other processes from writing while this one has the repository open, shared by
+    // the storages of its namespaces; None for read-only opens
+    _lock: Option<Arc<RepoLock>>,
 }
 
 impl CommitStorage {
     pub fn open(path: &str) -> Result<Self> {
+        Self::open_with_options(path, StorageOptions::default())
+    }
+
+    pub fn open_with_options(path: &str, options: StorageOptions) -> Result<Self> {
+        let lock = RepoLock::acquire(Path::new(path), options.lock_timeout)?;
         let mut opts = Options::default();
         opts.create_if_missing(true);
-        let db = DB::open(&opts, path)?;
+        // The database as a whole first, so a new one is stamped before a namespace's keys make
+        // it look like a repository from before format versions
+        let root = NamespacedDb::new(Arc::new(DB::open(&opts, path)?), "");
+        format::check(&root, true)?;
+        if !options.namespace.is_empty() {
+            namespace::check_exists(&root, &options.namespace)?;
+        }
+        let db = root.namespaced(&options.namespace);
+        format::check(&db, true)?;
+        let db = db.with_encryption(encryption::load(&db, Path::new(path))?);
+        let storage = Self {
+            db: Arc::new(db),
+            path: PathBuf::from(path),
+            options,
+            head_writes: Mutex::new(()),
+            _lock: Some(Arc::new(lock)),
+        };
+        storage.warn_if_inconsistent();
+        Ok(storage)
+    }
+
+    // A repository kept under `<namespace>:` in the database at `path`, which
+    // `create_namespace` must have started. One process opens the directory at a time; to serve several
+    // namespaces from it, open one and use `with_namespace` for the others.
+    pub fn open_namespaced(path: &str, namespace: &str) -> Result<Self> {
+        Self::open_with_options(path, StorageOptions { namespace: namespace.to_string(), ..StorageOptions::default() })
+    }
+
+    // This storage's settings and lock over another view of its database.
+    pub(crate) fn sibling(&self, db: NamespacedDb) -> Result<Self> {
+        format::check(&db, self._lock.is_some())?;
+        let db = db.with_encryption(encryption::load(&db, &self.path)?);
+        let options = StorageOptions { namespace: db.namespace().to_string(), ..self.options.clone() };
+        Ok(Self {
+            db: Arc::new(db),
+            path: self.path.clone(),
+            options,
+            head_writes: Mutex::new(()),
+            _lock: self._lock.clone(),
+        })
+    }
+
+    // For tooling that must not take the write lock or create a repository, like shell completion.
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        let db = NamespacedDb::new(Arc::new(DB::open_for_read_only(&Options::default(), path, false)?), "");
+        format::check(&db, false)?;
+        let db = db.with_encryption(encryption::load(&db, Path::new(path))?);
         Ok(Self {
-            db: Arc::new(db)
+            db: Arc::new(db),
+            path: PathBuf::from(path),
+            options: StorageOptions::default(),
+            head_writes: Mutex::new(()),
+            _lock: None,
         })
     }
+
+    pub fn options(&self) -> &StorageOptions {
+        &self.options
+    }
+
+    pub fn hooks(&self) -> HookRunner {
+        HookRunner::new(&self.path)
+    }
+
+    // Unresolved conflicts from the last merge live next to the database files, with the
+    // namespace in front of the name in a namespaced repository.
+    pub fn conflicts_path(&self) -> PathBuf {
+        match self.db.namespace() {
+            "" => self.path.join(CONFLICTS_FILE),
+            namespace => self.path.join(format!("{}.{}", namespace, CONFLICTS_FILE)),
+        }
+    }
+
+    // Changes waiting for the next `commit`, in the order they were staged.
+    pub fn staged_changes(&self) -> Result<Vec<Change>> {
+        match self.db.get(b"STAGED")? {
+            Some(raw) => Ok(bincode::deserialize(&raw)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn stage_change(&self, change: Change) -> Result<()> {
+        let mut staged = self.staged_changes()?;
+        staged.push(change);
+        self.db.put(b"STAGED", bincode::serialize(&staged)?)?;
+        Ok(())
+    }
+
+    pub fn clear_staged(&self) -> Result<()> {
+        self.db.delete(b"STAGED")?;
+        Ok(())
+    }
+
+    // Restores the local side of a merge conflict.
+    pub fn checkout_ours(&self, table: &str, id: &str) -> Result<()> {
+        self.resolve_conflict(table, id, |conflict| conflict.ours)
+    }
+
+    // Takes the merged branch's side of a merge conflict.
+    pub fn checkout_theirs(&self, table: &str, id: &str) -> Result<()> {
+        self.resolve_conflict(table, id, |conflict| conflict.theirs)
+    }
+
+    // Writes the chosen value to the live row and stages it; nothing is committed here. A side
+    // without a value deletes the row.
+    fn resolve_conflict(&self, table: &str, id: &str, pick: impl FnOnce(MergeConflict) -> Option<CrdtValue>) -> Result<()> {
+        let path = self.conflicts_path();
+        let mut conflicts = MergeConflicts::load(&path)?;
+        let conflict = conflicts.take(table, id).ok_or_else(|| {
+            BranchDBError::InvalidInput(format!("No merge conflict recorded for {}:{}", table, id))
+        })?;
+
+        let change = match pick(conflict) {
+            Some(value) => Change::Update { table: table.to_string(), id: id.to_string(), value: bincode::serialize(&value)? },
+            None => Change::Delete { table: table.to_string(), id: id.to_string() },
+        };
+        let mut batch = WriteBatch::default();
+        apply_to_live(&mut batch, &change)?;
+        phonetic::update_indexes(&self.db, &mut batch, std::slice::from_ref(&change))?;
+        self.db.write(batch)?;
+        self.stage_change(change)?;
+        conflicts.save(&path)
+    }
     
     pub fn get_commit_by_hash(&self, hash: &[u8; 32]) -> Result<Commit> {
-        let raw = self.db.get(hash)?
-            .ok_or_else(|| BranchDBError::InvalidInput("Commit not found".into()))?;
-        bincode::deserialize(&raw).map_err(Into::into)
+        read_commit(&self.db, hash)?
+            .ok_or_else(|| BranchDBError::CommitNotFound { hash: hex::encode(hash) })
     }
 
     pub fn get_head(&self) -> Result<Option<[u8; 32]>> {
@@ -40,21 +562,191 @@ impl CommitStorage {
     }
 
     pub fn create_commit(&self, message: &str, changes: Vec<Change>) -> Result<[u8; 32]> {
-        let parent = self.get_head()?;
-        let mut tree = HashMap::new(); // Now defaults to HashMap<String, [u8; 32]>
+        self.create_commit_with_parents(message, changes, &[])
+    }
 
-        // Calculate content hashes for all tables
-        for change in &changes {
-            let table_hash = self.calculate_table_hash(change.table())?;
-            tree.insert(change.table().to_string(), table_hash); // Convert &str to String
+    // Records a merge: HEAD stays the first parent and the merged head becomes the second.
+    pub fn create_merge_commit(&self, message: &str, changes: Vec<Change>, merged: &[u8; 32]) -> Result<[u8; 32]> {
+        self.create_commit_with_parents(message, changes, &[*merged])
+    }
+
+    fn create_commit_with_parents(&self, message: &str, changes: Vec<Change>, extra_parents: &[[u8; 32]]) -> Result<[u8; 32]> {
+        if self.options.enforce_fk {
+            self.check_foreign_keys(&changes)?;
+        }
+        let hash = self.commit_on_head(message, changes, extra_parents)?;
+        if self.options.incremental_view_maintenance {
+            self.maintain_views()?;
+        }
+        Ok(hash)
+    }
+
+    // A view refresh is itself a commit; going through here keeps it from triggering another
+    // round of maintenance.
+    pub(crate) fn commit_without_view_maintenance(&self, message: &str, changes: Vec<Change>) -> Result<[u8; 32]> {
+        self.commit_on_head(message, changes, &[])
+    }
+
+    // Builds the commit on the HEAD it reads and moves HEAD only if it is still there, like a
+    // compare-and-swap. When another thread committed in between, the commit is rebuilt on the
+    // new HEAD, up to `max_write_retries` times, and then once more while holding off every
+    // other writer, so no commit is lost.
+    fn commit_on_head(&self, message: &str, changes: Vec<Change>, extra_parents: &[[u8; 32]]) -> Result<[u8; 32]> {
+        let mut attempt = 0;
+        loop {
+            let guard = (attempt >= self.options.max_write_retries).then(|| self.lock_head_writes());
+            let head = self.get_head()?;
+            let parents = head.into_iter().chain(extra_parents.iter().copied()).collect();
+            let update = HeadUpdate::From { expected: head, guard };
+            if let Some(hash) = self.write_commit(message, changes.clone(), parents, update)? {
+                if head.is_none() {
+                    self.create_initial_branch(&hash)?;
+                }
+                return Ok(hash);
+            }
+            attempt += 1;
+            debug!(attempt, "HEAD moved while committing; rebuilding the commit on the new HEAD");
+        }
+    }
+
+    // Holds off every other writer while `build` turns HEAD into the changes to commit on it,
+    // so HEAD can't move between what `build` read and the write. When `build` returns None
+    // nothing is committed.
+    pub(crate) fn commit_on_locked_head(
+        &self,
+        message: &str,
+        build: impl FnOnce(Option<[u8; 32]>) -> Result<Option<Vec<Change>>>,
+    ) -> Result<Option<[u8; 32]>> {
+        let guard = self.lock_head_writes();
+        let head = self.get_head()?;
+        let Some(changes) = build(head)? else { return Ok(None) };
+        if self.options.enforce_fk {
+            self.check_foreign_keys(&changes)?;
         }
+        let update = HeadUpdate::From { expected: head, guard: Some(guard) };
+        let hash = self.write_commit(message, changes, head.into_iter().collect(), update)?
+            .ok_or_else(|| BranchDBError::ConcurrentModification("HEAD moved while it was locked".into()))?;
+        if head.is_none() {
+            self.create_initial_branch(&hash)?;
+        }
+        if self.options.incremental_view_maintenance {
+            self.maintain_views()?;
+        }
+        Ok(Some(hash))
+    }
+
+    fn lock_head_writes(&self) -> MutexGuard<'_, ()> {
+        self.head_writes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The first commit of a repository initialized with `--initial-branch` gets that branch.
+    fn create_initial_branch(&self, hash: &[u8; 32]) -> Result<()> {
+        let config = Config::load(&self.path)?;
+        let Some(name) = config.get(INITIAL_BRANCH_KEY) else { return Ok(()) };
+        let key = format!("branch:{}", name);
+        if self.db.get(key.as_bytes())?.is_none() {
+            self.db.put(key.as_bytes(), hash)?;
+            info!(branch = name, "created initial branch");
+        }
+        Ok(())
+    }
+
+    // Collapses repeated changes to the same row into the one change with the same effect,
+    // kept at the row's first position:
+    // - Insert then Update becomes an Insert carrying the updated value
+    // - Update then Update becomes the last Update
+    // - anything followed by Delete becomes the Delete, and Delete then Insert becomes the Insert
+    // An Insert followed by a Delete is kept as a Delete rather than dropped, because the Insert
+    // may have overwritten a row that existed before this commit.
+    pub fn compact_changes(changes: Vec<Change>) -> Vec<Change> {
+        let mut compacted: Vec<Change> = Vec::with_capacity(changes.len());
+        let mut positions: HashMap<(String, String), usize> = HashMap::new();
+        for change in changes {
+            let key = (change.table().to_string(), change.id().to_string());
+            let Some(&index) = positions.get(&key) else {
+                positions.insert(key, compacted.len());
+                compacted.push(change);
+                continue;
+            };
+            compacted[index] = match (&compacted[index], change) {
+                (Change::Insert { .. } | Change::Delete { .. }, Change::Update { table, id, value }) => {
+                    Change::Insert { table, id, value }
+                }
+                (_, later) => later,
+            };
+        }
+        compacted
+    }
+
+    // Stores a new commit. With `move_head`, HEAD is pointed at it and its changes are applied to
+    // the live rows in the same write, so the live state always matches HEAD; otherwise neither
+    // HEAD nor any branch is touched.
+    // Returns None, having written nothing, when HEAD is no longer what `head` expects.
+    fn write_commit(&self, message: &str, changes: Vec<Change>, parents: Vec<[u8; 32]>, head: HeadUpdate) -> Result<Option<[u8; 32]>> {
+        let changes = Self::compact_changes(changes);
+        if let Some(change) = changes.iter().find(|c| c.table().is_empty() || c.id().is_empty()) {
+            return Err(BranchDBError::InvalidInput(match change.table() {
+                "" => "Table names can't be empty".to_string(),
+                table => format!("Row ids can't be empty (table '{}')", table),
+            }));
+        }
+        if let Some(change) = changes.iter().find(|c| namespace::is_reserved_table(c.table())) {
+            return Err(BranchDBError::InvalidInput(format!(
+                "'{}' is reserved for the repository's own keys; pick another table name", change.table()
+            )));
+        }
+        let span = info_span!("create_commit", commit_message = message, changes = changes.len(), hash = tracing::field::Empty);
+        let _guard = span.enter();
+        let started = Instant::now();
+        let row_count = changes.len() as u64;
+        // Every table that exists after this commit, with the content hash of its rows: the
+        // first parent's tree, with each changed table rehashed as it stands at the first parent
+        // with this commit's changes applied on top, and removed once nothing of it is left.
+        // The live rows can't be hashed instead, since they only catch up once the commit is
+        // written.
+        let mut tree = match parents.first() {
+            Some(parent) => self.get_commit_by_hash(parent)?.tree,
+            None => HashMap::new(),
+        };
+        let mut touched: Vec<&str> = changes.iter().map(Change::table).collect();
+        touched.sort();
+        touched.dedup();
+        for &table in &touched {
+            let mut engine = CrdtEngine::new();
+            if let Some(parent) = parents.first() {
+                engine.state.insert(table.to_string(), replay_table(&self.db, table, parent)?);
+            }
+            for pending in changes.iter().filter(|c| c.table() == table) {
+                engine.apply_change(pending)?;
+            }
+            let rows = engine.into_data().remove(table).unwrap_or_default();
+            if rows.is_empty() {
+                tree.remove(table);
+            } else {
+                tree.insert(table.to_string(), table_hash(&rows)?);
+            }
+        }
+        // Outside any namespace, a table's rows would land in the namespace of the same name
+        for &table in touched.iter().filter(|_| self.db.namespace().is_empty()) {
+            if namespace::is_namespace(&self.db, table)? {
+                return Err(BranchDBError::InvalidInput(format!(
+                    "'{}' is a namespace of this database; tables outside namespaces can't share its name", table
+                )));
+            }
+        }
+        let registered: Vec<String> = touched.into_iter()
+            .filter(|table| tree.contains_key(*table))
+            .map(str::to_string)
+            .collect();
 
         let commit = Commit {
-            parents: parent.into_iter().collect(),
+            parents,
             message: message.to_string(),
-            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
             changes,
-            tree, // Now correctly HashMap<String, [u8; 32]>
+            tree,
+            sequence: self.last_sequence()? + 1,
+            audit: self.options.commit_context.audit(),
         };
 
         let serialized = bincode::serialize(&commit)?;
@@ -71,225 +763,575 @@ impl CommitStorage {
         let mut protected_value = serialized.clone();
         protected_value.extend_from_slice(checksum.as_bytes());
 
-        // Store commit
-        self.db.put(&hash_bytes, &protected_value)?;
-        
-        // Update HEAD
-        self.update_head(&hash_bytes)?;
-        
-        Ok(hash_bytes)
+        // Store commit, registering its tables so they can be listed without walking history
+        let mut batch = WriteBatch::default();
+        put_commit_raw(&mut batch, &hash_bytes, &protected_value);
+        self.set_last_sequence(&mut batch, commit.sequence);
+        for table in &registered {
+            batch.put(format!("{}{}", TABLE_REGISTRY_PREFIX, table).as_bytes(), []);
+        }
+        // The check and the write happen under the lock, so no other commit moves HEAD between them
+        let _guard = match head {
+            HeadUpdate::Keep => None,
+            HeadUpdate::From { expected, guard } => {
+                let guard = guard.unwrap_or_else(|| self.lock_head_writes());
+                if self.get_head()? != expected {
+                    return Ok(None);
+                }
+                for change in &commit.changes {
+                    apply_to_live(&mut batch, change)?;
+                }
+                phonetic::update_indexes(&self.db, &mut batch, &commit.changes)?;
+                batch.put(b"HEAD", hash_bytes);
+                Some(guard)
+            }
+        };
+        self.db.write(batch)?;
+        self.track_snapshot(&hash_bytes, commit.parents.first())?;
+
+        metrics::increment(Counter::CommitsCreated, 1);
+        metrics::increment(Counter::RowsWritten, row_count);
+        metrics::observe(Histogram::CommitLatencySeconds, started.elapsed().as_secs_f64());
+
+        span.record("hash", tracing::field::display(hex::encode(hash_bytes)));
+        debug!(elapsed_ms = started.elapsed().as_millis() as u64, "commit created");
+        Ok(Some(hash_bytes))
+    }
+
+    // Sequence number of the newest commit, or 0 before the first one. Writers hold the
+    // repository lock, so the next commit can take the number after it.
+    pub fn last_sequence(&self) -> Result<u64> {
+        match self.db.get(COMMIT_SEQUENCE_KEY)? {
+            Some(raw) => {
+                let bytes: [u8; 8] = raw.try_into()
+                    .map_err(|_| BranchDBError::CorruptData("commit sequence is not a u64".into()))?;
+                Ok(u64::from_le_bytes(bytes))
+            }
+            None => Ok(0),
+        }
     }
 
-    pub fn revert_to_commit(&self, commit_hash: &[u8; 32]) -> Result<()> {
+    pub(crate) fn set_last_sequence(&self, batch: &mut WriteBatch, sequence: u64) {
+        batch.put(COMMIT_SEQUENCE_KEY, sequence.to_le_bytes());
+    }
+
+    pub fn save_snapshot(&self, hash: &[u8; 32], engine: &CrdtEngine) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.put(snapshot_key(SNAPSHOT_PREFIX, hash), engine.snapshot()?.0);
+        batch.put(snapshot_key(SNAPSHOT_AGE_PREFIX, hash), 0u64.to_le_bytes());
+        self.db.write(batch)?;
+        debug!(commit = %hex::encode(hash), "saved state snapshot");
+        Ok(())
+    }
+
+    // Counts first-parent commits since the last snapshot and takes a new one once the count
+    // reaches `snapshot_interval`. Commits from before snapshots existed count as age 0.
+    pub(crate) fn track_snapshot(&self, hash: &[u8; 32], first_parent: Option<&[u8; 32]>) -> Result<()> {
+        let interval = self.options.snapshot_interval as u64;
+        if interval == 0 {
+            return Ok(());
+        }
+        let parent_age = match first_parent {
+            Some(parent) => self.db.get(snapshot_key(SNAPSHOT_AGE_PREFIX, parent))?
+                .and_then(|raw| raw.try_into().ok())
+                .map(u64::from_le_bytes)
+                .unwrap_or(0),
+            None => 0,
+        };
+        if parent_age + 1 >= interval {
+            let engine = load_state(self, hash.to_vec())?;
+            self.save_snapshot(hash, &engine)
+        } else {
+            self.db.put(snapshot_key(SNAPSHOT_AGE_PREFIX, hash), (parent_age + 1).to_le_bytes())?;
+            Ok(())
+        }
+    }
+
+    // Replaces the history up to `before_hash` with a single parentless commit that inserts every
+    // row of every table as of that commit. HEAD and branches pointing into the squashed history
+    // are moved to the new root; descendants of `before_hash` are left as they are.
+    pub fn squash_to_single(&self, before_hash: &[u8; 32], message: &str) -> Result<[u8; 32]> {
+        let _span = info_span!("squash", before = %hex::encode(before_hash)).entered();
+
+        let mut changes = Vec::new();
+        let mut tables = self.tables_at(before_hash)?;
+        tables.sort();
+        for table in tables {
+            let mut rows: Vec<(String, CrdtValue)> = self.table_state_at(&table, before_hash)?.into_iter().collect();
+            rows.sort_by(|a, b| a.0.cmp(&b.0));
+            for (id, value) in rows {
+                changes.push(Change::Insert {
+                    table: table.clone(),
+                    id,
+                    value: bincode::serialize(&value)?,
+                });
+            }
+        }
+
+        // Everything reachable from `before_hash`, following all parents
+        let mut squashed = HashSet::new();
+        let mut pending = vec![*before_hash];
+        while let Some(hash) = pending.pop() {
+            if squashed.insert(hash) {
+                pending.extend(self.get_commit_by_hash(&hash)?.parents);
+            }
+        }
+
+        // Moving a branch onto the new root rewrites its history, so protected branches refuse it
+        let branch_mgr = BranchManager::new(self.db.clone());
+        let mut moved = Vec::new();
+        for branch in branch_mgr.list_branches()? {
+            let Some(raw) = branch_mgr.get_branch_head(&branch)? else { continue };
+            if <[u8; 32]>::try_from(raw.as_slice()).is_ok_and(|hash| squashed.contains(&hash)) {
+                branch_mgr.check_force_update(&branch)?;
+                moved.push(branch);
+            }
+        }
+
+        let root = self.write_commit(message, changes, Vec::new(), HeadUpdate::Keep)?
+            .expect("a commit that leaves HEAD alone is always written");
+
+        if self.get_head()?.is_some_and(|head| squashed.contains(&head)) {
+            self.move_head(&root)?;
+        }
+        for branch in moved {
+            self.db.put(format!("branch:{}", branch).as_bytes(), root)?;
+            info!(branch, "moved branch to squashed root");
+        }
+
+        Ok(root)
+    }
+
+    // Recommits HEAD's commits that `onto` doesn't have on top of it, one new commit for each
+    // with the same message, and moves HEAD to the last one. The commits are HEAD's
+    // first-parent chain back to the first one in `onto`'s history; a merge commit among them
+    // becomes a plain commit of what it changed relative to its first parent. Stops before
+    // writing anything when a row was changed on both sides, since replaying would silently
+    // overwrite `onto`'s version. Branches don't move.
+    pub fn rebase_onto(&self, onto: &[u8; 32]) -> Result<RebaseResult> {
+        let head = self.get_head()?
+            .ok_or_else(|| BranchDBError::InvalidInput("HEAD not found".into()))?;
+        if MergeConflicts::load(&self.conflicts_path())?.merge_head.is_some() {
+            return Err(BranchDBError::InvalidInput("A merge is in progress; finish it with `commit` first".into()));
+        }
+        let staged = self.staged_changes()?.len();
+        if staged > 0 {
+            return Err(BranchDBError::WouldDiscardWork(format!("{} staged change(s); run `commit` first", staged)));
+        }
+        if self.is_ancestor(onto, &head)? {
+            return Ok(RebaseResult::UpToDate);
+        }
+        if self.is_ancestor(&head, onto)? {
+            self.move_head(onto)?;
+            return Ok(RebaseResult::FastForward(*onto));
+        }
+        let base = self.get_common_ancestor(&head, onto)?
+            .ok_or_else(|| BranchDBError::InvalidInput(format!("HEAD and {} share no history", hex::encode(onto))))?;
+
+        let conflicts = rebase_conflicts(
+            &load_state(self, head.to_vec())?,
+            &load_state(self, onto.to_vec())?,
+            &load_state(self, base.to_vec())?,
+        );
+        if !conflicts.is_empty() {
+            return Ok(RebaseResult::Conflict(conflicts));
+        }
+
+        // Newest first, up to the first commit `onto` already has
+        let mut replayed = Vec::new();
+        let mut hash = head;
+        while !self.is_ancestor(&hash, onto)? {
+            let commit = self.get_commit_by_hash(&hash)?;
+            let parent = *commit.parents.first()
+                .ok_or_else(|| BranchDBError::InvalidInput(format!("HEAD and {} share no history", hex::encode(onto))))?;
+            replayed.push((commit.message, parent, hash));
+            hash = parent;
+        }
+
+        let mut tip = *onto;
+        let mut commits = Vec::new();
+        for (message, parent, hash) in replayed.into_iter().rev() {
+            let changes = self.get_commit_diffs(&parent, &hash, &DiffOptions::default())?;
+            tip = self.write_commit(&message, changes, vec![tip], HeadUpdate::Keep)?
+                .expect("a commit that leaves HEAD alone is always written");
+            commits.push(tip);
+        }
+
+        let _guard = self.lock_head_writes();
+        if self.get_head()? != Some(head) {
+            return Err(BranchDBError::ConcurrentModification("HEAD moved while rebasing".into()));
+        }
+        self.move_head(&tip)?;
+        Ok(RebaseResult::Rebased { head: tip, commits })
+    }
+
+    // Returns the hash of the revert commit.
+    pub fn revert_to_commit(&self, commit_hash: &[u8; 32]) -> Result<[u8; 32]> {
         // Verify commit exists
-        let target_commit = self.get_commit_by_hash(commit_hash)?;
+        self.get_commit_by_hash(commit_hash)?;
+        let hooks = self.hooks();
+        let mut context = vec![("BRANCHDB_REVERT_TARGET_HASH", hex::encode(commit_hash))];
+        hooks.run_pre("pre-revert", "revert", &context)?;
         
-        // Create a new CRDT engine to build the target state
-        let mut target_engine = CrdtEngine::new();
-        
-        // Apply all changes from the commit's history
+        // The revert commit carries the changes from HEAD's state to the target's, so replaying
+        // history and the live rows both end up at the target state
+        let changes = match self.get_head()? {
+            Some(head) => self.get_commit_diffs(&head, commit_hash, &DiffOptions::default())?,
+            None => Vec::new(),
+        };
+        let new_hash = self.create_commit(&format!("Revert to {}", hex::encode(commit_hash)), changes)?;
+
+        context.push(("BRANCHDB_REVERT_NEW_HASH", hex::encode(new_hash)));
+        hooks.run("post-revert", &context)?;
+        Ok(new_hash)
+    }
+
+    // Row changes turning the state at `from` into the state at `to`, across every table known at
+    // either commit. A table dropped in between comes out as a delete of each of its rows and of
+    // its schema. Trees of older commits only cover the tables those commits changed, so they
+    // can't be used to decide which tables differ.
+    pub fn get_commit_diffs(&self, from: &[u8; 32], to: &[u8; 32], options: &DiffOptions) -> Result<Vec<Change>> {
+        let mut tables = self.tables_at(from)?;
+        for table in self.tables_at(to)? {
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
+        tables.sort();
+
+        let mut diffs = Vec::new();
+        for table in tables {
+            diffs.extend(self.get_table_diffs(&table, from, to)?.into_iter().filter(|change| {
+                if change.id() == "!schema" { options.include_schema } else { options.include_data }
+            }));
+        }
+        Ok(diffs)
+    }
+
+    // Nearest commit reachable from both `a` and `b`, following all parents, or None when the
+    // histories are unrelated.
+    pub fn get_common_ancestor(&self, a: &[u8; 32], b: &[u8; 32]) -> Result<Option<[u8; 32]>> {
+        let mut ancestors_of_a = HashSet::new();
+        let mut pending = vec![*a];
+        while let Some(hash) = pending.pop() {
+            if ancestors_of_a.insert(hash) {
+                pending.extend(self.get_commit_by_hash(&hash)?.parents);
+            }
+        }
+
+        // Breadth-first from `b`, so the first shared commit is the closest one to `b`
+        let mut queue = std::collections::VecDeque::from([*b]);
+        let mut seen = HashSet::new();
+        while let Some(hash) = queue.pop_front() {
+            if ancestors_of_a.contains(&hash) {
+                return Ok(Some(hash));
+            }
+            if seen.insert(hash) {
+                queue.extend(self.get_commit_by_hash(&hash)?.parents);
+            }
+        }
+        Ok(None)
+    }
+
+    // Whether `ancestor` is reachable from `descendant` through any parents. A commit counts as
+    // its own ancestor. Timestamps come from the clocks of whoever committed, so they can't be
+    // used to stop the walk early: every commit behind `descendant` may have to be read.
+    pub fn is_ancestor(&self, ancestor: &[u8; 32], descendant: &[u8; 32]) -> Result<bool> {
+        self.get_commit_by_hash(ancestor)?;
+        let mut pending = vec![*descendant];
+        let mut seen = HashSet::new();
+        while let Some(hash) = pending.pop() {
+            if hash == *ancestor {
+                return Ok(true);
+            }
+            if !seen.insert(hash) {
+                continue;
+            }
+            pending.extend(self.get_commit_by_hash(&hash)?.parents);
+        }
+        Ok(false)
+    }
+
+    // Whether a commit is in the history of any branch. Each branch is checked with
+    // `is_ancestor`, and the first hit ends the search.
+    pub fn is_on_branch(&self, hash: &[u8; 32]) -> Result<bool> {
+        let branch_mgr = BranchManager::new(self.db.clone());
+        for branch in branch_mgr.list_branches()? {
+            let Some(head) = branch_mgr.get_branch_head(&branch)? else { continue };
+            let Ok(head) = <[u8; 32]>::try_from(head) else { continue };
+            if self.is_ancestor(hash, &head)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // What `b` changed since it diverged from `a` (`git diff a...b`).
+    pub fn diff_from_merge_base(&self, a: &[u8; 32], b: &[u8; 32]) -> Result<Vec<Change>> {
+        let ancestor = self.get_common_ancestor(a, b)?.ok_or_else(|| BranchDBError::InvalidInput(
+            format!("{} and {} have no common ancestor", hex::encode(a), hex::encode(b))
+        ))?;
+        self.get_commit_diffs(&ancestor, b, &DiffOptions::default())
+    }
+
+    // Lists every table touched anywhere in the first-parent history of a commit.
+    fn tables_at(&self, commit_hash: &[u8; 32]) -> Result<Vec<String>> {
+        let mut tables = Vec::new();
         let mut current_hash = Some(*commit_hash);
-        let mut commits_to_apply = Vec::new();
-        
-        // Walk the commit history
         while let Some(hash) = current_hash {
             let commit = self.get_commit_by_hash(&hash)?;
-            commits_to_apply.push(commit.clone());
-            current_hash = commit.parents.get(0).cloned();
-        }
-        
-        // Apply changes in reverse order (oldest first)
-        for commit in commits_to_apply.into_iter().rev() {
             for change in &commit.changes {
-                target_engine.apply_change(change)?;
+                if !tables.iter().any(|t| t == change.table()) {
+                    tables.push(change.table().to_string());
+                }
             }
+            current_hash = commit.parents.first().cloned();
         }
-        
-        // Clear ALL existing data for tables in the target commit
-        let mut batch = rocksdb::WriteBatch::default();
-        for table in target_commit.tree.keys() {
-            let prefix = format!("{}:", table);
-            let iter = self.db.prefix_iterator(prefix.as_bytes());
-            for item in iter {
-                let (key, _) = item?;
-                batch.delete(key);
+        Ok(tables)
+    }
+
+    // Tables that exist as of a commit, meaning they have rows or a schema there. The commit's
+    // tree lists them, but commits written before trees were carried forward only list the
+    // tables they changed, so a table missing from the tree is replayed to make sure.
+    pub fn list_tables_at(&self, commit_hash: &[u8; 32]) -> Result<Vec<String>> {
+        let tree = self.get_commit_by_hash(commit_hash)?.tree;
+        let mut tables = Vec::new();
+        for table in self.tables_at(commit_hash)? {
+            if tree.contains_key(&table) || !self.table_state_at(&table, commit_hash)?.is_empty() {
+                tables.push(table);
             }
         }
-        
-        // Write the new state
-        for (table, rows) in target_engine.into_data() {
-            for (id, value) in rows {
-                let key = format!("{}:{}", table, id);
-                let serialized = bincode::serialize(&value)?;
-                batch.put(key.as_bytes(), serialized);
+        tables.sort();
+        Ok(tables)
+    }
+
+    // Copies every row of `src`, its schema included, to the new table `dst` in one commit on
+    // HEAD. The rows are read as of `commit_hash`, or HEAD. Fails if `dst` already exists.
+    pub fn clone_table(&self, src: &str, dst: &str, commit_hash: Option<&[u8; 32]>) -> Result<[u8; 32]> {
+        self.copy_table(src, dst, commit_hash, false)
+    }
+
+    // Like `clone_table`, but an existing `dst` is replaced: its rows missing from `src` are
+    // deleted in the same commit.
+    pub fn clone_table_replacing(&self, src: &str, dst: &str, commit_hash: Option<&[u8; 32]>) -> Result<[u8; 32]> {
+        self.copy_table(src, dst, commit_hash, true)
+    }
+
+    fn copy_table(&self, src: &str, dst: &str, commit_hash: Option<&[u8; 32]>, replace: bool) -> Result<[u8; 32]> {
+        if src == dst {
+            return Err(BranchDBError::InvalidInput(format!("Can't clone table '{}' onto itself", src)));
+        }
+        let head = self.get_head()?;
+        let source = commit_hash.copied().or(head)
+            .ok_or_else(|| BranchDBError::TableNotFound { name: src.to_string() })?;
+        let rows = self.table_state_at(src, &source)?;
+        if rows.is_empty() {
+            return Err(BranchDBError::TableNotFound { name: src.to_string() });
+        }
+
+        let mut changes = Vec::new();
+        if let Some(head) = head {
+            let existing = self.table_state_at(dst, &head)?;
+            if !existing.is_empty() && !replace {
+                return Err(BranchDBError::InvalidInput(format!("Table '{}' already exists", dst)));
             }
+            let mut stale: Vec<String> = existing.into_keys().filter(|id| !rows.contains_key(id)).collect();
+            stale.sort();
+            changes.extend(stale.into_iter().map(|id| Change::Delete { table: dst.to_string(), id }));
         }
-        
-        // Create a revert commit
-        let changes = target_commit.changes.iter()
-            .map(|c| match c {
-                Change::Insert { table, id, .. } => Change::Delete {
-                    table: table.clone(),
-                    id: id.clone(),
-                },
-                _ => c.clone(),
-            })
-            .collect();
-        
-        self.db.write(batch)?;
-        self.create_commit(&format!("Revert to {}", hex::encode(commit_hash)), changes)?;
-        
-        Ok(())
+        let mut rows: Vec<(String, CrdtValue)> = rows.into_iter().collect();
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        for (id, value) in rows {
+            changes.push(Change::Insert { table: dst.to_string(), id, value: bincode::serialize(&value)? });
+        }
+        self.create_commit(&format!("Clone table {} -> {}", src, dst), changes)
     }
 
-    fn calculate_table_hash(&self, table: &str) -> Result<[u8; 32]> {
-        let mut hasher = blake3::Hasher::new();
-        let mut rows = Vec::new();
-        
-        let iter = self.db.prefix_iterator(table.as_bytes());
-        for result in iter {
-            let (key, value) = result?;
-            rows.push((key.to_vec(), value.to_vec()));
+    // Row differences across every table known at either commit, without writing anything.
+    // Used to preview what moving between the two states would change.
+    pub fn get_state_diffs(&self, from: &[u8; 32], to: &[u8; 32]) -> Result<Vec<Change>> {
+        self.get_commit_diffs(from, to, &DiffOptions { include_schema: false, include_data: true })
+    }
+
+    // The most recent commit on the first-parent chain of `commit_hash` that touched a row.
+    pub fn row_last_modified(&self, table: &str, id: &str, commit_hash: &[u8; 32]) -> Result<Option<([u8; 32], Commit)>> {
+        let mut current_hash = Some(*commit_hash);
+        while let Some(hash) = current_hash {
+            let commit = self.get_commit_by_hash(&hash)?;
+            if commit.changes.iter().any(|c| c.table() == table && c.id() == id) {
+                return Ok(Some((hash, commit)));
+            }
+            current_hash = commit.parents.first().cloned();
         }
-        
-        // Fix sorting with explicit types
-        rows.sort_by(|a: &(Vec<u8>, Vec<u8>), b: &(Vec<u8>, Vec<u8>)| a.0.cmp(&b.0));
-        
-        for (key, value) in rows {
-            hasher.update(&key);
-            hasher.update(&value);
+        Ok(None)
+    }
+
+    // Every table ever committed, from the registry kept by `write_commit`. Repositories created
+    // before the registry existed fall back to the tables changed by the HEAD commit.
+    pub fn list_tables(&self) -> Result<Vec<String>> {
+        let mut tables = Vec::new();
+        for item in self.db.prefix_iterator(TABLE_REGISTRY_PREFIX) {
+            let (key, _) = item?;
+            let Some(name) = key.strip_prefix(TABLE_REGISTRY_PREFIX.as_bytes()) else { break };
+            tables.push(String::from_utf8_lossy(name).into_owned());
         }
-        
-        Ok(*hasher.finalize().as_bytes())
+        if tables.is_empty() {
+            if let Some(head) = self.get_head()? {
+                tables.extend(self.get_commit_by_hash(&head)?.tree.into_keys());
+            }
+        }
+        tables.sort();
+        tables.dedup();
+        Ok(tables)
     }
 
-    pub fn get_commit_diffs(&self, from: &[u8; 32], to: &[u8; 32]) -> Result<Vec<Change>> {
-        let from_commit = self.get_commit_by_hash(from)?;
-        let to_commit = self.get_commit_by_hash(to)?;
-        
+    // Column-level schema differences for every table whose schema differs between two commits.
+    pub fn get_schema_diffs(&self, from: &[u8; 32], to: &[u8; 32]) -> Result<Vec<(String, Vec<SchemaChange>)>> {
+        let mut tables = self.tables_at(from)?;
+        for table in self.tables_at(to)? {
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
+        tables.sort();
+
         let mut diffs = Vec::new();
-        
-        // Compare tables
-        for (table, to_hash) in &to_commit.tree {
-            if let Some(from_hash) = from_commit.tree.get(table) {
-                if from_hash != to_hash {
-                    // Get all changes for this table between commits
-                    let table_diffs = self.get_table_diffs(table, from, to)?;
-                    diffs.extend(table_diffs);
-                }
-            } else {
-                // Table was added
-                diffs.push(Change::Insert {
-                    table: table.clone(),
-                    id: "!schema".to_string(),
-                    value: vec![],
-                });
+        for table in tables {
+            let old_schema = self.get_table_schema(&table, Some(from))?;
+            let new_schema = self.get_table_schema(&table, Some(to))?;
+            let changes = compare_schemas(&old_schema, &new_schema);
+            if !changes.is_empty() {
+                diffs.push((table, changes));
             }
         }
         Ok(diffs)
     }
 
-    fn update_head(&self, hash: &[u8; 32]) -> Result<()> {
-        self.db.put(b"HEAD", hash)?;
+    // Points HEAD at another commit. The live rows are updated by the difference between the
+    // two states in the same write.
+    pub fn move_head(&self, to: &[u8; 32]) -> Result<()> {
+        let changes = match self.get_head()? {
+            Some(head) => self.get_commit_diffs(&head, to, &DiffOptions::default())?,
+            None => {
+                let mut changes = Vec::new();
+                for table in self.tables_at(to)? {
+                    for (id, value) in self.table_state_at(&table, to)? {
+                        changes.push(Change::Insert { table: table.clone(), id, value: bincode::serialize(&value)? });
+                    }
+                }
+                changes
+            }
+        };
+        let mut batch = WriteBatch::default();
+        for change in &changes {
+            apply_to_live(&mut batch, change)?;
+        }
+        phonetic::update_indexes(&self.db, &mut batch, &changes)?;
+        batch.put(b"HEAD", to);
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    // Deletes HEAD and every live row, as in a repository with no commits; commits and branches
+    // stay. The next `move_head` then writes the full state of its target.
+    pub(crate) fn clear_live_state(&self) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        for item in self.db.prefix_iterator(TABLE_REGISTRY_PREFIX) {
+            let (key, _) = item?;
+            let Some(table) = key.strip_prefix(TABLE_REGISTRY_PREFIX.as_bytes()) else { break };
+            let prefix = table_key_prefix(&String::from_utf8_lossy(table));
+            for row in self.db.prefix_iterator(prefix.as_bytes()) {
+                let (row_key, _) = row?;
+                if !row_key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                batch.delete(&row_key);
+            }
+        }
+        phonetic::clear_entries(&self.db, &mut batch)?;
+        batch.delete(b"HEAD");
+        self.db.write(batch)?;
         Ok(())
     }
 
     pub fn get_commit_history(&self) -> Result<Vec<Commit>> {
         let mut history = Vec::new();
         let mut current_hash = self.get_head()?;
+        let _span = info_span!("history_walk", head = current_hash.map(hex::encode).as_deref()).entered();
 
         while let Some(hash) = current_hash {
             let commit = self.get_commit_by_hash(&hash)?;
             history.push(commit.clone());
-            current_hash = commit.parents.get(0).cloned();
+            current_hash = commit.parents.first().cloned();
         }
 
         Ok(history)
     }
 
-    pub fn get_table_diffs(&self, table: &str, from: &[u8; 32], to: &[u8; 32]) -> Result<Vec<Change>> {
-        let from_commit = self.get_commit_by_hash(from)?;
-        let to_commit = self.get_commit_by_hash(to)?;
-    
-        // Get the state at each commit
-        let mut from_engine = CrdtEngine::new();
-        let mut to_engine = CrdtEngine::new();
-    
-        // Apply all changes up to 'from' commit
-        let mut current_hash = from_commit.parents.get(0).cloned();
-        while let Some(hash) = current_hash {
-            let commit = self.get_commit_by_hash(&hash)?;
-            for change in &commit.changes {
-                if change.table() == table {
-                    from_engine.apply_change(change)?;
-                }
-            }
-            current_hash = commit.parents.get(0).cloned();
-        }
-    
-        // Apply all changes up to 'to' commit
-        let mut current_hash = to_commit.parents.get(0).cloned();
+    // Commits on the first-parent chain from HEAD with `after <= timestamp < before`, in
+    // milliseconds.
+    pub fn get_commit_history_in_range(&self, after: Option<u64>, before: Option<u64>) -> Result<Vec<Commit>> {
+        let mut history = Vec::new();
+        let mut current_hash = self.get_head()?;
+        let _span = info_span!("history_walk", head = current_hash.map(hex::encode).as_deref()).entered();
+
         while let Some(hash) = current_hash {
             let commit = self.get_commit_by_hash(&hash)?;
-            for change in &commit.changes {
-                if change.table() == table {
-                    to_engine.apply_change(change)?;
-                }
-            }
-            current_hash = commit.parents.get(0).cloned();
-        }
-    
-        // Compare the states
-        let mut diffs = Vec::new();
-        let from_rows = from_engine.state.get(table).cloned().unwrap_or_default();
-        let to_rows = to_engine.state.get(table).cloned().unwrap_or_default();
-    
-        // Find added/modified rows
-        for (id, to_val) in &to_rows {
-            match from_rows.get(id) {
-                Some(from_val) if from_val != to_val => {
-                    diffs.push(Change::Update {
-                        table: table.to_string(),
-                        id: id.clone(),
-                        value: bincode::serialize(to_val)?,
-                    });
-                }
-                None => {
-                    diffs.push(Change::Insert {
-                        table: table.to_string(),
-                        id: id.clone(),
-                        value: bincode::serialize(to_val)?,
-                    });
-                }
-                _ => {}
+            current_hash = commit.parents.first().cloned();
+
+            let in_range = after.is_none_or(|a| commit.timestamp >= a)
+                && before.is_none_or(|b| commit.timestamp < b);
+            if in_range {
+                history.push(commit);
             }
         }
-    
-        // Find deleted rows
-        for (id, _) in from_rows {
-            if !to_rows.contains_key(&id) {
-                diffs.push(Change::Delete {
-                    table: table.to_string(),
-                    id,
-                });
+
+        Ok(history)
+    }
+
+    pub fn get_table_diffs(&self, table: &str, from: &[u8; 32], to: &[u8; 32]) -> Result<Vec<Change>> {
+        // Compare the states as of each commit, including that commit's own changes
+        let from_rows = self.table_state_at(table, from)?;
+        let to_rows = self.table_state_at(table, to)?;
+        diff_states(table, &from_rows, &to_rows)
+    }
+
+    // Row changes that would turn `table_a` into `table_b`, both as of `commit_hash` (HEAD by
+    // default), matching rows by id. Meant for tables of the same schema, like a staging copy
+    // of a production table; the schemas themselves aren't compared. The changes are against
+    // `table_a`.
+    pub fn diff_tables(&self, table_a: &str, table_b: &str, commit_hash: Option<&[u8; 32]>) -> Result<Vec<Change>> {
+        let commit = match commit_hash {
+            Some(hash) => *hash,
+            None => self.get_head()?.ok_or_else(|| BranchDBError::InvalidInput("No HEAD commit".into()))?,
+        };
+        let mut rows = Vec::new();
+        for table in [table_a, table_b] {
+            // Only a table with neither a schema nor rows at the commit is missing
+            let mut state = self.table_state_at(table, &commit)?;
+            if state.is_empty() {
+                return Err(BranchDBError::TableNotFound { name: table.to_string() });
             }
+            state.retain(|id, _| !id.starts_with('!'));
+            rows.push(state);
         }
-    
-        Ok(diffs)
+        diff_states(table_a, &rows[0], &rows[1])
     }
 
     pub fn debug_commit(&self, hash: &str) -> Result<()> {
         let hash_bytes = hex::decode(hash)?;
-        match self.db.get(&hash_bytes)? {
+        match get_commit_raw(&self.db, &hash_bytes)? {
             Some(data) => {
-                println!("Commit data ({} bytes):", data.len());
-                println!("Hex: {}", hex::encode(&data));
-                match bincode::deserialize::<Commit>(&data) {
-                    Ok(commit) => println!("Valid commit: {:?}", commit),
-                    Err(e) => println!("Deserialization failed: {}", e),
+                debug!(commit = hash, bytes = data.len(), raw = %hex::encode(&data), "commit data");
+                if let Err(e) = verify_commit(&hash_bytes, &data) {
+                    warn!(commit = hash, error = %e, "commit checksum mismatch");
+                }
+                match decode_commit(&data) {
+                    Ok(commit) => debug!(commit = hash, ?commit, "valid commit"),
+                    Err(e) => warn!(commit = hash, error = %e, "commit deserialization failed"),
                 }
             }
-            None => println!("Commit not found"),
+            None => warn!(commit = hash, "commit not found"),
         }
         Ok(())
     }
@@ -297,7 +1339,7 @@ impl CommitStorage {
     pub fn get_table_schema(&self, table: &str, commit_hash: Option<&[u8]>) -> Result<serde_json::Value> {
         // If no specific commit hash is provided, use the current state
         if commit_hash.is_none() {
-            let key = format!("{}:!schema", table);
+            let key = encode_row_key(table, "!schema");
             if let Some(data) = self.db.get(key.as_bytes())? {
                 return serde_json::from_slice(&data).map_err(Into::into);
             }
@@ -326,15 +1368,589 @@ impl CommitStorage {
                 }
             }
             
-            current_hash = commit.parents.get(0).map(|p| p.to_vec()).unwrap_or_default();
+            current_hash = commit.parents.first().map(|p| p.to_vec()).unwrap_or_default();
         }
 
         Ok(serde_json::json!({}))
     }
     
     pub fn update_table_schema(&self, table: &str, schema: &serde_json::Value) -> Result<()> {
-        let key = format!("{}:!schema", table);
+        let key = encode_row_key(table, "!schema");
         self.db.put(key.as_bytes(), serde_json::to_vec(schema)?)?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    // Rebuilds the rows of a table as of the given commit by replaying its history oldest first.
+    pub(crate) fn table_state_at(&self, table: &str, commit_hash: &[u8; 32]) -> Result<TableState> {
+        replay_table(&self.db, table, commit_hash)
+    }
+
+    // Rows of a table as of a commit, `!schema` included, one at a time; see `table_rows`.
+    pub fn iter_table<'a>(
+        &'a self,
+        table: &str,
+        commit_hash: &[u8; 32],
+    ) -> Result<impl Iterator<Item = Result<(String, CrdtValue)>> + 'a> {
+        table_rows(&self.db, table, commit_hash)
+    }
+
+    fn decode_row<T: Row>(value: &CrdtValue, schema: &serde_json::Value) -> Result<T> {
+        let CrdtValue::Register(data) = value else {
+            return Err(BranchDBError::TypeMismatch(
+                format!("Expected Register value for row in table '{}'", T::table())
+            ));
+        };
+        let stored: serde_json::Value = serde_json::from_slice(data)?;
+        T::from_value(row::row_to_object(stored, schema))
+    }
+
+    pub fn begin(&self) -> Result<Transaction<'_>> {
+        Transaction::new(self)
+    }
+
+    // Sets a counter to `new_value` in a new commit if it currently holds `expected`, and
+    // returns whether the swap happened. A missing row counts as 0, so swapping from 0 creates
+    // it. The read, the comparison and the commit all happen under the HEAD lock, so no other
+    // write can change the counter in between.
+    pub fn compare_and_swap_counter(&self, table: &str, id: &str, expected: u64, new_value: u64) -> Result<bool> {
+        let message = format!("Set counter {}:{} from {} to {}", table, id, expected, new_value);
+        Ok(self.swap_counter(table, id, expected, new_value, &message)?.is_some())
+    }
+
+    // `compare_and_swap_counter` with a commit message, returning the commit if it swapped.
+    pub(crate) fn swap_counter(
+        &self,
+        table: &str,
+        id: &str,
+        expected: u64,
+        new_value: u64,
+        message: &str,
+    ) -> Result<Option<[u8; 32]>> {
+        self.commit_on_locked_head(message, |head| {
+            let current = match head {
+                Some(head) => self.table_state_at(table, &head)?.remove(id),
+                None => None,
+            };
+            let exists = current.is_some();
+            let current = match current {
+                Some(CrdtValue::Counter(value)) => value,
+                Some(CrdtValue::Register(_)) => return Err(BranchDBError::TypeMismatch(
+                    format!("Row '{}' in table '{}' is not a counter", id, table)
+                )),
+                None => 0,
+            };
+            if current != expected {
+                debug!(table, id, expected, current, "counter swap skipped");
+                return Ok(None);
+            }
+            let (table, id) = (table.to_string(), id.to_string());
+            let value = bincode::serialize(&CrdtValue::Counter(new_value))?;
+            Ok(Some(vec![match exists {
+                true => Change::Update { table, id, value },
+                false => Change::Insert { table, id, value },
+            }]))
+        })
+    }
+
+    pub fn insert_row<T: Row>(&self, row: &T) -> Result<[u8; 32]> {
+        let table = T::table();
+        let id = row.id();
+        if id.is_empty() || id.starts_with('!') {
+            return Err(BranchDBError::InvalidInput(format!("Invalid row id '{}'", id)));
+        }
+
+        let head = self.get_head()?;
+        let schema = self.get_table_schema(table, head.as_ref().map(|h| &h[..]))?;
+        let value = row.to_value()?;
+        row::check_row_against_schema(table, &value, &schema)?;
+
+        let exists = match &head {
+            Some(hash) => self.table_state_at(table, hash)?.contains_key(&id),
+            None => false,
+        };
+
+        let encoded = bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&value)?))?;
+        let change = if exists {
+            Change::Update { table: table.to_string(), id: id.clone(), value: encoded }
+        } else {
+            Change::Insert { table: table.to_string(), id: id.clone(), value: encoded }
+        };
+
+        self.create_commit(&format!("Write row {} in {}", id, table), vec![change])
+    }
+
+    pub fn get_row<T: Row>(&self, id: &str, commit: Option<&[u8; 32]>) -> Result<Option<T>> {
+        let hash = match commit {
+            Some(hash) => *hash,
+            None => match self.get_head()? {
+                Some(hash) => hash,
+                None => return Ok(None),
+            },
+        };
+
+        let schema = self.get_table_schema(T::table(), Some(&hash))?;
+        let rows = self.table_state_at(T::table(), &hash)?;
+        rows.get(id)
+            .map(|value| Self::decode_row(value, &schema))
+            .transpose()
+    }
+
+    pub fn query_rows<T: Row>(&self, filter: impl Fn(&T) -> bool) -> Result<Vec<T>> {
+        let Some(head) = self.get_head()? else {
+            return Ok(Vec::new());
+        };
+
+        let schema = self.get_table_schema(T::table(), Some(&head))?;
+        let rows = self.table_state_at(T::table(), &head)?;
+
+        let mut ids: Vec<&String> = rows.keys().filter(|id| !id.starts_with('!')).collect();
+        ids.sort();
+
+        let mut matched = Vec::new();
+        for id in ids {
+            let row: T = Self::decode_row(&rows[id], &schema)?;
+            if filter(&row) {
+                matched.push(row);
+            }
+        }
+        Ok(matched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::merge::MergeResult;
+    use crate::core::sql_executor::SqlExecutor;
+    use crate::core::test_support::{head, live_rows, replayed_rows, rows, run, temp_repo};
+
+    #[test]
+    fn revert_commit_replays_to_the_live_rows() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let target = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+        run(&storage, "UPDATE users SET name = 'anne' WHERE id = '1'");
+        run(&storage, "ALTER TABLE users SET TTL '7d'");
+
+        let reverted = storage.revert_to_commit(&target).unwrap();
+        let live = live_rows(&storage, "users");
+        assert_eq!(replayed_rows(&storage, "users", &reverted), live);
+        assert_eq!(replayed_rows(&storage, "users", &target), live);
+        assert!(live.contains_key("!schema") && live.contains_key("1") && !live.contains_key("2"));
+    }
+
+    #[test]
+    fn iter_table_at_head_reads_the_live_rows_metadata_first() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        for id in ["b", " a", "c:d", "50%"] {
+            run(&storage, &format!("INSERT INTO users VALUES ('{}', 'x')", id));
+        }
+        let before = head(&storage);
+        run(&storage, "DELETE FROM users WHERE id = 'b'");
+
+        let rows: Vec<(String, CrdtValue)> = storage.iter_table("users", &head(&storage)).unwrap().map(Result::unwrap).collect();
+        assert_eq!(rows[0].0, "!schema");
+        assert_eq!(rows.iter().map(|(id, _)| id.as_str()).filter(|id| !id.starts_with('!')).count(), 3);
+        assert_eq!(rows.into_iter().collect::<BTreeMap<_, _>>(), replay_table(&storage.db, "users", &head(&storage)).unwrap().into_iter().collect());
+        // Older commits are replayed from history
+        assert!(replayed_rows(&storage, "users", &before).contains_key("b"));
+    }
+
+    #[test]
+    fn commit_trees_hash_each_table_with_the_commits_changes() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let first = head(&storage);
+        run(&storage, "UPDATE users SET name = 'anne' WHERE id = '1'");
+        let second = head(&storage);
+
+        let tree = |hash: &[u8; 32]| storage.get_commit_by_hash(hash).unwrap().tree["users"];
+        assert_ne!(tree(&first), tree(&second));
+        for hash in [first, second] {
+            assert_eq!(tree(&hash), table_hash(&replay_table(&storage.db, "users", &hash).unwrap()).unwrap());
+        }
+        let diffs = storage.get_commit_diffs(&first, &second, &DiffOptions::default()).unwrap();
+        assert!(matches!(diffs.as_slice(), [Change::Update { table, id, .. }] if table == "users" && id == "1"), "{:?}", diffs);
+    }
+
+    #[test]
+    fn one_of_concurrent_counter_swaps_from_the_same_value_succeeds() {
+        let (_dir, storage) = temp_repo();
+        assert!(storage.compare_and_swap_counter("visits", "home", 0, 5).unwrap());
+        let swapped: Vec<u64> = std::thread::scope(|scope| {
+            let swaps: Vec<_> = (0..10u64)
+                .map(|i| {
+                    let storage = &storage;
+                    scope.spawn(move || (i, storage.compare_and_swap_counter("visits", "home", 5, 100 + i).unwrap()))
+                })
+                .collect();
+            swaps.into_iter().map(|swap| swap.join().unwrap()).filter(|&(_, ok)| ok).map(|(i, _)| i).collect()
+        });
+        assert_eq!(swapped.len(), 1);
+        assert_eq!(live_rows(&storage, "visits")["home"], CrdtValue::Counter(100 + swapped[0]));
+        assert!(!storage.compare_and_swap_counter("visits", "home", 5, 6).unwrap());
+    }
+
+    #[test]
+    fn concurrent_create_commits_all_land_in_history() {
+        let (_dir, storage) = temp_repo();
+        let start = std::sync::Barrier::new(10);
+        std::thread::scope(|scope| {
+            for i in 0..10u64 {
+                let (storage, start) = (&storage, &start);
+                scope.spawn(move || {
+                    let value = bincode::serialize(&CrdtValue::Counter(i)).unwrap();
+                    let change = Change::Insert { table: "hits".to_string(), id: i.to_string(), value };
+                    start.wait();
+                    storage.create_commit(&format!("hit {}", i), vec![change]).unwrap();
+                });
+            }
+        });
+
+        let history = storage.get_commit_history().unwrap();
+        assert_eq!(history.len(), 10);
+        let mut messages: Vec<_> = history.iter().map(|commit| commit.message.clone()).collect();
+        messages.sort();
+        assert_eq!(messages, (0..10).map(|i| format!("hit {}", i)).collect::<Vec<_>>());
+        // A single chain: every commit but the first has exactly one parent
+        assert_eq!(history.iter().filter(|commit| commit.parents.len() == 1).count(), 9);
+        assert_eq!(live_rows(&storage, "hits").len(), 10);
+    }
+
+    #[test]
+    fn compacted_changes_replay_like_the_changes_one_by_one() {
+        let counter = |n: u64| bincode::serialize(&CrdtValue::Counter(n)).unwrap();
+        let (table, id) = ("hits".to_string(), "a".to_string());
+        let insert = |n| Change::Insert { table: table.clone(), id: id.clone(), value: counter(n) };
+        let update = |n| Change::Update { table: table.clone(), id: id.clone(), value: counter(n) };
+        let delete = || Change::Delete { table: table.clone(), id: id.clone() };
+        let changes = vec![
+            update(1), update(2), delete(), insert(3), update(4),
+            update(5), delete(), insert(6), update(7), update(8),
+        ];
+        let is_final_insert = |changes: &[Change]| matches!(changes, [Change::Insert { id, value, .. }] if id == "a" && *value == counter(8));
+        assert!(is_final_insert(&CommitStorage::compact_changes(changes.clone())));
+
+        // The same row already there in both repositories, then the changes in one commit or ten
+        let (_dir, compacted) = temp_repo();
+        let (_other, one_by_one) = temp_repo();
+        for storage in [&compacted, &one_by_one] {
+            storage.create_commit("start", vec![insert(0)]).unwrap();
+        }
+        let hash = compacted.create_commit("all at once", changes.clone()).unwrap();
+        assert!(is_final_insert(&compacted.get_commit_by_hash(&hash).unwrap().changes));
+        for change in changes {
+            one_by_one.create_commit("one", vec![change]).unwrap();
+        }
+        assert_eq!(live_rows(&compacted, "hits"), live_rows(&one_by_one, "hits"));
+        assert_eq!(replayed_rows(&compacted, "hits", &hash), replayed_rows(&one_by_one, "hits", &head(&one_by_one)));
+        assert_eq!(live_rows(&compacted, "hits")["a"], CrdtValue::Counter(8));
+    }
+
+    #[test]
+    fn squashing_a_hundred_commits_leaves_one_root_with_every_row() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        for i in 0..88 {
+            run(&storage, &format!("INSERT INTO users VALUES ('{}', 'user {}')", i, i));
+        }
+        for i in 0..5 {
+            run(&storage, &format!("UPDATE users SET name = 'renamed' WHERE id = '{}'", i));
+            run(&storage, &format!("DELETE FROM users WHERE id = '{}'", 80 + i));
+        }
+        run(&storage, "CREATE TABLE teams (id TEXT, name TEXT)");
+        let tip = head(&storage);
+        assert_eq!(storage.get_commit_history().unwrap().len(), 100);
+        BranchManager::new(storage.db.clone()).create_branch("main").unwrap();
+        let before = live_rows(&storage, "users");
+
+        let root = storage.squash_to_single(&tip, "Squashed").unwrap();
+        let history = storage.get_commit_history().unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].parents.is_empty());
+        assert_eq!((head(&storage), history[0].message.as_str()), (root, "Squashed"));
+        assert_eq!(BranchManager::new(storage.db.clone()).get_branch_head("main").unwrap(), Some(root.to_vec()));
+
+        assert_eq!(before.len(), 1 + 83);
+        assert_eq!(replayed_rows(&storage, "users", &root), before);
+        assert_eq!(live_rows(&storage, "users"), before);
+        assert_eq!(rows(&storage, "SELECT name FROM users WHERE id = '3'"), vec![vec![serde_json::json!("renamed")]]);
+        assert!(replayed_rows(&storage, "teams", &root).contains_key("!schema"));
+    }
+
+    #[test]
+    fn cloned_tables_are_copies_the_original_doesnt_share() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT, age INTEGER)");
+        for i in 0..50 {
+            run(&storage, &format!("INSERT INTO users VALUES ('{}', 'user {}', {})", i, i, 20 + i));
+        }
+        let original = live_rows(&storage, "users");
+
+        let clone = storage.clone_table("users", "copy", None).unwrap();
+        assert_eq!(storage.get_commit_by_hash(&clone).unwrap().message, "Clone table users -> copy");
+        assert_eq!(live_rows(&storage, "copy"), original);
+        assert_eq!(rows(&storage, "SELECT id FROM copy").len(), 50);
+        assert_eq!(storage.get_table_schema("copy", None).unwrap(), storage.get_table_schema("users", None).unwrap());
+
+        run(&storage, "UPDATE copy SET name = 'changed' WHERE id = '1'");
+        run(&storage, "DELETE FROM copy WHERE id = '2'");
+        run(&storage, "INSERT INTO copy VALUES ('50', 'new', 70)");
+        assert_eq!(live_rows(&storage, "users"), original);
+        assert_eq!(rows(&storage, "SELECT name FROM users WHERE id = '1'"), vec![vec![serde_json::json!("user 1")]]);
+
+        // An existing table is only replaced on request, and then loses the rows users lacks
+        assert!(matches!(storage.clone_table("users", "copy", None), Err(BranchDBError::InvalidInput(_))));
+        storage.clone_table_replacing("users", "copy", Some(&clone)).unwrap();
+        assert_eq!(live_rows(&storage, "copy"), original);
+        assert!(matches!(storage.clone_table("missing", "other", None), Err(BranchDBError::TableNotFound { .. })));
+    }
+
+    // A table at `base`, then '2' inserted on one side and '3' on the other: (base, ours, theirs)
+    fn diverged(storage: &CommitStorage) -> ([u8; 32], [u8; 32], [u8; 32]) {
+        run(storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let base = head(storage);
+        run(storage, "INSERT INTO users VALUES ('2', 'bob')");
+        let ours = head(storage);
+        storage.move_head(&base).unwrap();
+        run(storage, "INSERT INTO users VALUES ('3', 'cy')");
+        let theirs = head(storage);
+        storage.move_head(&ours).unwrap();
+        (base, ours, theirs)
+    }
+
+    #[test]
+    fn is_ancestor_follows_every_parent() {
+        let (_dir, storage) = temp_repo();
+        let (base, ours, theirs) = diverged(&storage);
+        assert!(storage.is_ancestor(&base, &ours).unwrap());
+        assert!(storage.is_ancestor(&ours, &ours).unwrap());
+        assert!(!storage.is_ancestor(&ours, &base).unwrap());
+        assert!(!storage.is_ancestor(&theirs, &ours).unwrap());
+
+        let merge = storage.create_merge_commit("Merge", Vec::new(), &theirs).unwrap();
+        assert!(storage.is_ancestor(&theirs, &merge).unwrap());
+        assert!(storage.is_ancestor(&base, &merge).unwrap());
+        assert!(!storage.is_ancestor(&merge, &theirs).unwrap());
+    }
+
+    #[test]
+    fn rebase_recommits_heads_commits_on_the_target() {
+        let (_dir, storage) = temp_repo();
+        let (base, ours, theirs) = diverged(&storage);
+        run(&storage, "UPDATE users SET name = 'bo' WHERE id = '2'");
+        let update = head(&storage);
+
+        let RebaseResult::Rebased { head: tip, commits } = storage.rebase_onto(&theirs).unwrap() else { panic!("expected a rebase") };
+        assert_eq!(head(&storage), tip);
+        assert_eq!(commits.len(), 2);
+        assert_eq!(storage.get_commit_by_hash(&commits[0]).unwrap().parents, vec![theirs]);
+        assert_eq!(storage.get_commit_by_hash(&commits[1]).unwrap().parents, vec![commits[0]]);
+        assert_eq!(storage.get_commit_by_hash(&tip).unwrap().message, storage.get_commit_by_hash(&update).unwrap().message);
+        assert!(!storage.is_ancestor(&ours, &tip).unwrap());
+        let mut users = rows(&storage, "SELECT id, name FROM users");
+        users.sort_by_key(|row| row[0].to_string());
+        assert_eq!(users, [["1", "ann"], ["2", "bo"], ["3", "cy"]].map(|row| row.map(serde_json::Value::from).to_vec()).to_vec());
+        assert_eq!(live_rows(&storage, "users"), replayed_rows(&storage, "users", &tip));
+
+        // The target is now in HEAD's history
+        assert!(matches!(storage.rebase_onto(&theirs).unwrap(), RebaseResult::UpToDate));
+        assert!(matches!(storage.rebase_onto(&base).unwrap(), RebaseResult::UpToDate));
+        assert_eq!(head(&storage), tip);
+    }
+
+    #[test]
+    fn ancestry_reaches_through_a_merges_second_parent() {
+        let (_dir, storage) = temp_repo();
+        let (_, ours, theirs) = diverged(&storage);
+        storage.move_head(&theirs).unwrap();
+        run(&storage, "INSERT INTO users VALUES ('4', 'di')");
+        let side = head(&storage);
+        storage.move_head(&ours).unwrap();
+        let merge = storage.create_merge_commit("Merge", Vec::new(), &side).unwrap();
+        run(&storage, "INSERT INTO users VALUES ('5', 'ed')");
+        run(&storage, "INSERT INTO users VALUES ('6', 'flo')");
+        let tip = head(&storage);
+
+        // Two commits down the second parent's side, under two commits on top of the merge
+        for ancestor in [theirs, side, merge, ours] {
+            assert!(storage.is_ancestor(&ancestor, &tip).unwrap());
+        }
+        assert!(!storage.is_ancestor(&tip, &theirs).unwrap());
+        assert!(!storage.is_ancestor(&merge, &side).unwrap());
+        assert!(!storage.is_on_branch(&theirs).unwrap());
+        BranchManager::new(storage.db.clone()).create_branch("main").unwrap();
+        assert!(storage.is_on_branch(&theirs).unwrap());
+    }
+
+    #[test]
+    fn rebase_replays_a_merge_commit_as_what_it_changed() {
+        let (_dir, storage) = temp_repo();
+        let (_, ours, theirs) = diverged(&storage);
+        run(&storage, "INSERT INTO users VALUES ('4', 'di')");
+        storage.db.put(b"branch:side", head(&storage)).unwrap();
+        storage.move_head(&ours).unwrap();
+        run(&storage, "INSERT INTO users VALUES ('5', 'ed')");
+        let MergeResult::Merged { hash: merge, .. } = storage.merge_with_ancestor("side", |_| Ok("Merge side".into())).unwrap() else {
+            panic!("expected a merge commit")
+        };
+
+        let RebaseResult::Rebased { head: tip, commits } = storage.rebase_onto(&theirs).unwrap() else { panic!("expected a rebase") };
+        assert_eq!(commits.len(), 3);
+        let replayed = storage.get_commit_by_hash(&tip).unwrap();
+        assert_eq!((replayed.message.as_str(), replayed.parents.clone()), ("Merge side", vec![commits[1]]));
+        assert_eq!(replayed.changes.iter().map(|c| c.id()).collect::<Vec<_>>(), ["4"]);
+        assert!(!storage.is_ancestor(&merge, &tip).unwrap());
+        let mut ids: Vec<_> = rows(&storage, "SELECT id FROM users").into_iter().map(|row| row[0].to_string()).collect();
+        ids.sort();
+        assert_eq!(ids, ["\"1\"", "\"2\"", "\"3\"", "\"4\"", "\"5\""]);
+        assert_eq!(live_rows(&storage, "users"), replayed_rows(&storage, "users", &tip));
+    }
+
+    #[test]
+    fn rebase_fast_forwards_and_stops_on_conflicts() {
+        let (_dir, storage) = temp_repo();
+        let (base, ours, theirs) = diverged(&storage);
+        storage.move_head(&base).unwrap();
+        assert!(matches!(storage.rebase_onto(&theirs).unwrap(), RebaseResult::FastForward(hash) if hash == theirs));
+        assert_eq!(head(&storage), theirs);
+
+        run(&storage, "UPDATE users SET name = 'anne' WHERE id = '1'");
+        let updated = head(&storage);
+        storage.move_head(&ours).unwrap();
+        run(&storage, "UPDATE users SET name = 'annie' WHERE id = '1'");
+        let before = head(&storage);
+        let RebaseResult::Conflict(conflicts) = storage.rebase_onto(&updated).unwrap() else { panic!("expected a conflict") };
+        assert_eq!(conflicts.iter().map(|c| (c.table.as_str(), c.id.as_str())).collect::<Vec<_>>(), [("users", "1")]);
+        assert_eq!(head(&storage), before);
+    }
+
+    #[test]
+    fn row_keys_round_trip_through_escaping() {
+        let parts = ["2024:03:15", "50%", "%3A", "ü", "日本:語", "tab\tnew\nline", "a::b:", ":", ""];
+        for table in ["users", "a:b", "ü%"] {
+            for id in parts {
+                let key = encode_row_key(table, id);
+                assert_eq!(key.matches(':').count(), 1, "{:?}", key);
+                assert!(key.starts_with(&table_key_prefix(table)));
+                assert_eq!(decode_row_key(key.as_bytes()), Some((table.to_string(), id.to_string())));
+            }
+        }
+        // Plain names keep the keys they had before escaping
+        assert_eq!(encode_row_key("users", "ann"), "users:ann");
+        // Another table's prefix never matches
+        assert!(!encode_row_key("a:b", "c").starts_with(&table_key_prefix("a")));
+        assert_eq!(decode_row_key(b"users:50%2"), None);
+    }
+
+    #[test]
+    fn ids_with_separators_and_unicode_are_stored_and_read_back() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        let ids = ["2024:03:15", "50%", "ü", "日本:語"];
+        for id in ids {
+            run(&storage, &format!("INSERT INTO users VALUES ('{}', 'x')", id));
+        }
+        run(&storage, "UPDATE users SET name = 'y' WHERE id = '2024:03:15'");
+
+        let live = live_rows(&storage, "users");
+        assert_eq!(live.keys().filter(|id| !id.starts_with('!')).cloned().collect::<Vec<_>>(), {
+            let mut sorted = ids.map(String::from).to_vec();
+            sorted.sort();
+            sorted
+        });
+        assert_eq!(live, replayed_rows(&storage, "users", &head(&storage)));
+        assert_eq!(rows(&storage, "SELECT name FROM users WHERE id = '2024:03:15'"), vec![vec![serde_json::json!("y")]]);
+    }
+
+    #[test]
+    fn empty_ids_and_table_names_are_rejected_when_writing() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        let before = head(&storage);
+        let insert = |table: &str, id: &str| storage.create_commit("insert", vec![Change::Insert {
+            table: table.to_string(),
+            id: id.to_string(),
+            value: bincode::serialize(&CrdtValue::Register(b"{}".to_vec())).unwrap(),
+        }]);
+        assert!(matches!(insert("users", ""), Err(BranchDBError::InvalidInput(_))));
+        assert!(matches!(insert("", "1"), Err(BranchDBError::InvalidInput(_))));
+        assert!(SqlExecutor::execute(&storage, "INSERT INTO users VALUES ('', 'x')").is_err());
+        assert_eq!(head(&storage), before);
+        assert!(!live_rows(&storage, "users").contains_key(""));
+    }
+
+    #[test]
+    fn diff_tables_turns_the_first_table_into_the_second() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE staging (id TEXT, name TEXT)");
+        run(&storage, "CREATE TABLE production (id TEXT, name TEXT)");
+        // Eight of ten rows are the same in both
+        for i in 1..=10 {
+            run(&storage, &format!("INSERT INTO staging VALUES ('{}', 'row {}')", i, i));
+        }
+        for i in 1..=8 {
+            run(&storage, &format!("INSERT INTO production VALUES ('{}', 'row {}')", i, i));
+        }
+        let before = head(&storage);
+        run(&storage, "INSERT INTO production VALUES ('9', 'changed')");
+        run(&storage, "INSERT INTO production VALUES ('11', 'row 11')");
+
+        let mut changes: Vec<(&str, String, String)> = Vec::new();
+        let diff = storage.diff_tables("staging", "production", None).unwrap();
+        for change in &diff {
+            let kind = match change {
+                Change::Insert { .. } => "insert",
+                Change::Update { .. } => "update",
+                Change::Delete { .. } => "delete",
+            };
+            changes.push((kind, change.table().to_string(), change.id().to_string()));
+        }
+        changes.sort();
+        let change = |kind, id: &str| (kind, "staging".to_string(), id.to_string());
+        assert_eq!(changes, [change("delete", "10"), change("insert", "11"), change("update", "9")]);
+        let updated = diff.iter().find(|c| c.id() == "9").unwrap();
+        let Change::Update { value, .. } = updated else { unreachable!() };
+        assert_eq!(Some(&bincode::deserialize::<CrdtValue>(value).unwrap()), live_rows(&storage, "production").get("9"));
+
+        assert_eq!(storage.diff_tables("staging", "production", Some(&before)).unwrap().len(), 2);
+        assert!(storage.diff_tables("production", "production", None).unwrap().is_empty());
+        assert!(matches!(storage.diff_tables("staging", "nothing", None), Err(BranchDBError::TableNotFound { .. })));
+    }
+
+    #[test]
+    fn a_dropped_table_leaves_the_tree_and_diffs_as_deleted() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "CREATE TABLE orders (id TEXT, item TEXT)");
+        run(&storage, "INSERT INTO orders VALUES ('a', 'pen')");
+        run(&storage, "INSERT INTO orders VALUES ('b', 'ink')");
+        let before = head(&storage);
+        run(&storage, "DROP TABLE orders");
+        let after = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+
+        assert!(storage.get_commit_by_hash(&before).unwrap().tree.contains_key("orders"));
+        assert!(!storage.get_commit_by_hash(&after).unwrap().tree.contains_key("orders"));
+        assert!(!storage.get_commit_by_hash(&head(&storage)).unwrap().tree.contains_key("orders"));
+        assert_eq!(storage.list_tables_at(&before).unwrap(), ["orders", "users"]);
+        assert_eq!(storage.list_tables_at(&after).unwrap(), ["users"]);
+        assert_eq!(storage.list_tables_at(&head(&storage)).unwrap(), ["users"]);
+
+        let dropped = storage.get_commit_diffs(&before, &after, &DiffOptions::default()).unwrap();
+        let mut ids: Vec<(&str, &str)> = dropped.iter()
+            .inspect(|c| assert!(matches!(c, Change::Delete { .. }), "{:?}", c))
+            .map(|c| (c.table(), c.id()))
+            .collect();
+        ids.sort();
+        assert_eq!(ids, [("orders", "!schema"), ("orders", "a"), ("orders", "b")]);
+        let restored = storage.get_commit_diffs(&after, &before, &DiffOptions::default()).unwrap();
+        assert_eq!(restored.len(), 3);
+        assert!(restored.iter().all(|c| matches!(c, Change::Insert { .. }) && c.table() == "orders"));
+    }
+}
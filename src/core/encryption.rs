@@ -0,0 +1,329 @@
+use crate::core::config::{Config, KEY_FILE_KEY};
+use crate::core::database::CommitStorage;
+use crate::core::format::FORMAT_VERSION_KEY;
+use crate::core::namespace::{is_namespace, NamespacedDb, WriteBatch};
+use crate::core::phonetic;
+use crate::error::{BranchDBError, Result};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::{env, fs};
+
+// An encrypted repository keeps its values AES-256-GCM encrypted on disk: rows, commits,
+// snapshots and staged changes alike. Keys stay plaintext, so hashes, table names and row ids
+// can be listed, and so do the values structural operations read without the key: refs, the
+// table registry and format bookkeeping. A commit is hashed before it is encrypted, so
+// rekeying never changes history.
+//
+// The key is derived with Argon2id from a secret in `BRANCHDB_KEY`, or in the file
+// `core.key-file` names, and a random salt, and never stored. `!encryption` holds the salt and
+// a known text encrypted with the key, to tell a wrong key from damaged data. Each value is
+// authenticated together with its key, so a value copied under another key doesn't decrypt.
+pub const KEY_ENV: &str = "BRANCHDB_KEY";
+const MARKER_KEY: &[u8] = b"!encryption";
+const ALGORITHM: &str = "AES-256-GCM";
+const CHECK_TEXT: &[u8] = b"BranchDB encryption check";
+const KDF: &str = "argon2id";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+// Values of these keys stay plaintext, as do those of keys starting with a prefix below
+const PLAINTEXT_KEYS: [&[u8]; 4] = [b"HEAD", FORMAT_VERSION_KEY, b"!commit-sequence", MARKER_KEY];
+const PLAINTEXT_PREFIXES: [&[u8]; 3] = [b"branch:", b"protected:", b"!table:"];
+
+// What `!encryption` holds, as JSON.
+#[derive(Serialize, Deserialize)]
+struct Marker {
+    algorithm: String,
+    kdf: String,
+    // The key derivation's salt, in hex
+    salt: String,
+    // CHECK_TEXT encrypted with the key, in hex
+    check: String,
+}
+
+pub struct ValueCipher {
+    cipher: Aes256Gcm,
+    salt: [u8; SALT_LEN],
+}
+
+impl ValueCipher {
+    // A new key, with a salt of its own.
+    pub fn from_secret(secret: &str) -> Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::with_salt(secret, salt)
+    }
+
+    // Surrounding whitespace, such as a key file's trailing newline, isn't part of the secret.
+    fn with_salt(secret: &str, salt: [u8; SALT_LEN]) -> Result<Self> {
+        let secret = secret.trim();
+        if secret.is_empty() {
+            return Err(BranchDBError::EncryptionError("the key is empty".into()));
+        }
+        let mut key = [0; 32];
+        Argon2::default().hash_password_into(secret.as_bytes(), &salt, &mut key)
+            .map_err(|e| BranchDBError::EncryptionError(format!("the key could not be derived: {}", e)))?;
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| BranchDBError::EncryptionError("invalid key length".into()))?;
+        Ok(Self { cipher, salt })
+    }
+
+    // The nonce followed by the ciphertext and its tag, authenticated with `key`.
+    fn encrypt(&self, key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, Payload { msg: plaintext, aad: key })
+            .map_err(|_| BranchDBError::EncryptionError("a value could not be encrypted".into()))?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn decrypt(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let undecryptable = || BranchDBError::EncryptionError(
+            "a stored value doesn't decrypt with this key; it is damaged or was written with another key".into()
+        );
+        if value.len() < NONCE_LEN {
+            return Err(undecryptable());
+        }
+        let (nonce, ciphertext) = value.split_at(NONCE_LEN);
+        self.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: key }).map_err(|_| undecryptable())
+    }
+
+    fn marker(&self) -> Result<Vec<u8>> {
+        let marker = Marker {
+            algorithm: ALGORITHM.to_string(),
+            kdf: KDF.to_string(),
+            salt: hex::encode(self.salt),
+            check: hex::encode(self.encrypt(MARKER_KEY, CHECK_TEXT)?),
+        };
+        Ok(serde_json::to_vec(&marker)?)
+    }
+
+    // The cipher `secret` derives with the marker's salt, if it is the repository's key.
+    fn unlock(marker: &Marker, secret: &str) -> Result<Self> {
+        let salt = hex::decode(&marker.salt).ok().and_then(|salt| <[u8; SALT_LEN]>::try_from(salt).ok())
+            .ok_or_else(|| BranchDBError::CorruptData("encryption salt is not 16 bytes of hex".into()))?;
+        let cipher = Self::with_salt(secret, salt)?;
+        let check = hex::decode(&marker.check).map_err(|_| BranchDBError::CorruptData("encryption check is not hex".into()))?;
+        match cipher.decrypt(MARKER_KEY, &check) {
+            Ok(text) if text == CHECK_TEXT => Ok(cipher),
+            _ => Err(BranchDBError::EncryptionError("the key is wrong for this repository".into())),
+        }
+    }
+}
+
+// How a repository's values are stored.
+#[derive(Clone, Default)]
+pub enum Encryption {
+    #[default]
+    Off,
+    // Encrypted, but no key was given: only plaintext values can be read or written
+    Locked,
+    On(Arc<ValueCipher>),
+}
+
+impl Encryption {
+    pub fn is_encrypted(&self) -> bool {
+        !matches!(self, Encryption::Off)
+    }
+
+    fn cipher(&self, key: &[u8]) -> Result<Option<&ValueCipher>> {
+        let plaintext = PLAINTEXT_KEYS.contains(&key) || PLAINTEXT_PREFIXES.iter().any(|prefix| key.starts_with(prefix));
+        match self {
+            _ if plaintext => Ok(None),
+            Encryption::Off => Ok(None),
+            Encryption::Locked => Err(BranchDBError::EncryptionError(format!(
+                "the repository is encrypted and no key was given in {} or {}", KEY_ENV, KEY_FILE_KEY
+            ))),
+            Encryption::On(cipher) => Ok(Some(cipher)),
+        }
+    }
+
+    // The value as stored under `key`.
+    pub(crate) fn seal(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        match self.cipher(key)? {
+            Some(cipher) => cipher.encrypt(key, value),
+            None => Ok(value.to_vec()),
+        }
+    }
+
+    // The value stored under `key` as it was written.
+    pub(crate) fn open(&self, key: &[u8], stored: Vec<u8>) -> Result<Vec<u8>> {
+        match self.cipher(key)? {
+            Some(cipher) => cipher.decrypt(key, &stored),
+            None => Ok(stored),
+        }
+    }
+}
+
+// The secret from `BRANCHDB_KEY`, or else from the file `core.key-file` names, which is read
+// relative to the data directory.
+pub fn secret(data_dir: &Path) -> Result<Option<String>> {
+    if let Ok(secret) = env::var(KEY_ENV) {
+        return Ok(Some(secret));
+    }
+    let config = Config::load(data_dir)?;
+    let Some(file) = config.get(KEY_FILE_KEY) else { return Ok(None) };
+    let secret = fs::read_to_string(data_dir.join(file)).map_err(|e| BranchDBError::EncryptionError(format!(
+        "could not read the key file {} set in {}: {}", file, KEY_FILE_KEY, e
+    )))?;
+    Ok(Some(secret))
+}
+
+// How the repository in `db` stores its values, checking the key when one is given.
+pub(crate) fn load(db: &NamespacedDb, data_dir: &Path) -> Result<Encryption> {
+    let Some(raw) = db.get(MARKER_KEY)? else { return Ok(Encryption::Off) };
+    let marker: Marker = serde_json::from_slice(&raw)?;
+    if marker.algorithm != ALGORITHM {
+        return Err(BranchDBError::EncryptionError(format!("unsupported algorithm {}", marker.algorithm)));
+    }
+    if marker.kdf != KDF {
+        return Err(BranchDBError::EncryptionError(format!("unsupported key derivation {}", marker.kdf)));
+    }
+    let Some(secret) = secret(data_dir)? else { return Ok(Encryption::Locked) };
+    Ok(Encryption::On(Arc::new(ValueCipher::unlock(&marker, &secret)?)))
+}
+
+// Whether a key outside any namespace belongs to one, remembering the names looked up.
+fn in_namespace(db: &NamespacedDb, key: &[u8], seen: &mut HashMap<Vec<u8>, bool>) -> Result<bool> {
+    let Some(end) = key.iter().position(|b| *b == b':') else { return Ok(false) };
+    if let Some(found) = seen.get(&key[..end]) {
+        return Ok(*found);
+    }
+    let found = is_namespace(db, &String::from_utf8_lossy(&key[..end]))?;
+    seen.insert(key[..end].to_vec(), found);
+    Ok(found)
+}
+
+impl CommitStorage {
+    // Turns encryption on for a repository that has nothing to encrypt yet.
+    pub fn enable_encryption(&self, cipher: ValueCipher) -> Result<()> {
+        if self.db.encryption().is_encrypted() {
+            return Err(BranchDBError::InvalidInput("The repository is already encrypted; use `rekey` to change the key".into()));
+        }
+        if self.get_head()?.is_some() {
+            return Err(BranchDBError::InvalidInput("The repository has commits; use `rekey` to encrypt them".into()));
+        }
+        self.db.put(MARKER_KEY, cipher.marker()?)?;
+        Ok(())
+    }
+
+    // Re-encrypts every value of the repository with `cipher`, encrypting a plaintext
+    // repository, and returns how many values there were. In place, everything is written at
+    // once, so an interrupted rekey leaves the old key working, and then compacted, so the old
+    // values don't linger in the database files. This storage still has the old key afterwards
+    // and has to be reopened. With `into`, the values are written to that
+    // database instead, which must hold nothing but its format version. Namespaces have keys
+    // of their own and are left alone.
+    pub fn rekey(&self, cipher: ValueCipher, into: Option<&CommitStorage>, mut progress: impl FnMut(usize)) -> Result<usize> {
+        if phonetic::has_indexes(&self.db)? {
+            return Err(BranchDBError::InvalidInput(
+                "Phonetic index keys hold codes of the values and would stay readable; drop the indexes with DROP PHONETIC INDEX first".into()
+            ));
+        }
+        let target = into.map_or(&*self.db, |storage| &*storage.db);
+        if into.is_some() {
+            for item in target.namespaced(target.namespace()).iterator() {
+                if &*item?.0 != FORMAT_VERSION_KEY {
+                    return Err(BranchDBError::InvalidInput("The repository to rekey into isn't empty".into()));
+                }
+            }
+        }
+        let marker = cipher.marker()?;
+        let target = target.with_encryption(Encryption::On(Arc::new(cipher)));
+
+        // Values as stored, so that keys of namespaces are skipped before anything decrypts them
+        let stored = self.db.namespaced(self.db.namespace());
+        let mut namespaces = HashMap::new();
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+        for item in stored.iterator() {
+            let (key, value) = item?;
+            if &*key == MARKER_KEY {
+                continue;
+            }
+            if self.db.namespace().is_empty() && in_namespace(&stored, &key, &mut namespaces)? {
+                continue;
+            }
+            batch.put(&key, self.db.encryption().open(&key, value.into_vec())?);
+            count += 1;
+            progress(count);
+        }
+        batch.put(MARKER_KEY, marker);
+        target.write(batch)?;
+        if into.is_none() {
+            self.db.compact();
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::models::Change;
+    use crate::core::sql_executor::SqlExecutor;
+    use crate::core::test_support::{data_path, rows, run};
+    use serde_json::json;
+
+    fn marker(cipher: &ValueCipher) -> Marker {
+        serde_json::from_slice(&cipher.marker().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn values_round_trip_and_are_bound_to_their_key() {
+        let encryption = Encryption::On(Arc::new(ValueCipher::from_secret("secret\n").unwrap()));
+        let stored = encryption.seal(b"users:1", b"{\"name\":\"ann\"}").unwrap();
+        assert!(!stored.windows(3).any(|window| window == b"ann"));
+        assert_eq!(encryption.open(b"users:1", stored.clone()).unwrap(), b"{\"name\":\"ann\"}");
+        // Moved under another key, the value no longer authenticates
+        assert!(matches!(encryption.open(b"users:2", stored), Err(BranchDBError::EncryptionError(_))));
+        // Refs stay readable
+        assert_eq!(encryption.seal(b"branch:main", b"abc").unwrap(), b"abc");
+    }
+
+    #[test]
+    fn only_the_same_secret_and_salt_unlock_a_repository() {
+        let cipher = ValueCipher::from_secret("secret").unwrap();
+        let stored = cipher.encrypt(b"users:1", b"ann").unwrap();
+        let unlocked = ValueCipher::unlock(&marker(&cipher), " secret ").unwrap();
+        assert_eq!(unlocked.decrypt(b"users:1", &stored).unwrap(), b"ann");
+
+        assert!(matches!(ValueCipher::unlock(&marker(&cipher), "other"), Err(BranchDBError::EncryptionError(_))));
+        // A new key from the same secret gets its own salt
+        let again = ValueCipher::from_secret("secret").unwrap();
+        assert_ne!(again.salt, cipher.salt);
+        assert!(again.decrypt(b"users:1", &stored).is_err());
+    }
+
+    #[test]
+    fn an_encrypted_repository_never_stores_rows_in_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = data_path(&dir);
+        CommitStorage::open(&path).unwrap().enable_encryption(ValueCipher::from_secret("secret").unwrap()).unwrap();
+        fs::write(dir.path().join("data").join("key"), "secret").unwrap();
+        let mut config = Config::load(Path::new(&path)).unwrap();
+        config.set(KEY_FILE_KEY, "key").unwrap();
+        config.save().unwrap();
+
+        let storage = CommitStorage::open(&path).unwrap();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'Ann Plaintext')");
+        // Tables named after key families that stay plaintext are refused
+        for table in ["branch", "protected", "search"] {
+            let create = format!("CREATE TABLE {} (id TEXT, name TEXT)", table);
+            assert!(matches!(SqlExecutor::execute(&storage, &create), Err(BranchDBError::InvalidInput(_))), "{}", table);
+        }
+        let change = Change::Insert { table: "!table".into(), id: "users".into(), value: b"Ann Plaintext".to_vec() };
+        assert!(matches!(storage.create_commit("sneak", vec![change]), Err(BranchDBError::InvalidInput(_))));
+
+        let raw = storage.db.with_encryption(Encryption::Off);
+        for item in raw.iterator() {
+            let (key, value) = item.unwrap();
+            assert!(!value.windows(9).any(|window| window == b"Plaintext"), "{}", String::from_utf8_lossy(&key));
+        }
+        assert_eq!(rows(&storage, "SELECT name FROM users"), vec![vec![json!("Ann Plaintext")]]);
+    }
+}
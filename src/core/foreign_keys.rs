@@ -0,0 +1,246 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::database::{decode_row_key, encode_row_key, table_key_prefix, CommitStorage};
+use crate::core::models::Change;
+use crate::core::row;
+use crate::error::{BranchDBError, Result};
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+// A row whose foreign key names a row that doesn't exist.
+#[derive(Debug, Clone, Serialize)]
+pub struct FkViolation {
+    pub table: String,
+    pub id: String,
+    pub fk_column: String,
+    pub referenced_table: String,
+    pub missing_id: String,
+}
+
+impl fmt::Display for FkViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}: {} references missing {} '{}'", self.table, self.id, self.fk_column, self.referenced_table, self.missing_id)
+    }
+}
+
+impl CommitStorage {
+    // Checks the foreign keys declared in the schema of `table`, or of every table, against
+    // HEAD. A NULL or missing key references nothing.
+    pub fn verify_foreign_keys(&self, table: Option<&str>) -> Result<Vec<FkViolation>> {
+        LiveState::new(self, &[]).violations(&|name, _| table.is_none_or(|table| table == name))
+    }
+
+    // Refuses `changes` if committing them on HEAD would add a dangling foreign key. Only the
+    // tables they touch and the tables referencing those are checked, and violations already
+    // in HEAD don't count against them.
+    pub(crate) fn check_foreign_keys(&self, changes: &[Change]) -> Result<()> {
+        let touched: HashSet<&str> = changes.iter().map(Change::table).collect();
+        let affected = |table: &str, targets: &[&str]| {
+            touched.contains(table) || targets.iter().any(|target| touched.contains(target))
+        };
+        let existing: HashSet<(String, String, String)> = LiveState::new(self, &[]).violations(&affected)?
+            .into_iter()
+            .map(|v| (v.table, v.id, v.fk_column))
+            .collect();
+        let added: Vec<FkViolation> = LiveState::new(self, changes).violations(&affected)?
+            .into_iter()
+            .filter(|v| !existing.contains(&(v.table.clone(), v.id.clone(), v.fk_column.clone())))
+            .collect();
+        match added.first() {
+            None => Ok(()),
+            Some(first) => Err(BranchDBError::InvalidInput(format!(
+                "{} foreign key violation(s), the first being {}", added.len(), first
+            ))),
+        }
+    }
+}
+
+// The live rows with `pending` changes laid over them, as they would be after committing.
+struct LiveState<'a> {
+    storage: &'a CommitStorage,
+    // The last change to each row; None once deleted
+    pending: HashMap<(&'a str, &'a str), Option<&'a [u8]>>,
+}
+
+impl<'a> LiveState<'a> {
+    fn new(storage: &'a CommitStorage, changes: &'a [Change]) -> Self {
+        let pending = changes.iter()
+            .map(|change| {
+                let value = match change {
+                    Change::Insert { value, .. } | Change::Update { value, .. } => Some(value.as_slice()),
+                    Change::Delete { .. } => None,
+                };
+                ((change.table(), change.id()), value)
+            })
+            .collect();
+        Self { storage, pending }
+    }
+
+    // Checks the tables that `include` accepts, given each table's name and the tables its
+    // foreign keys reference.
+    fn violations(&self, include: &dyn Fn(&str, &[&str]) -> bool) -> Result<Vec<FkViolation>> {
+        let mut tables: BTreeSet<String> = self.storage.list_tables()?.into_iter().collect();
+        tables.extend(self.pending.keys().map(|(table, _)| table.to_string()));
+
+        // Values of each referenced (table, column) other than the row id
+        let mut referenced: HashMap<(String, String), HashSet<String>> = HashMap::new();
+        let mut violations = Vec::new();
+        for table in &tables {
+            let schema = self.schema(table)?;
+            let Some(foreign_keys) = schema.get("foreign_keys").and_then(|f| f.as_object()) else { continue };
+            let targets: Vec<(&String, &str, &str)> = foreign_keys.iter()
+                .filter_map(|(column, fk)| Some((column, fk.get("table")?.as_str()?, fk.get("column")?.as_str()?)))
+                .collect();
+            let target_tables: Vec<&str> = targets.iter().map(|&(_, table, _)| table).collect();
+            if !include(table, &target_tables) {
+                continue;
+            }
+
+            for (id, value) in self.rows(table)? {
+                let object = decode(&value, &schema)?;
+                for &(column, target_table, target_column) in &targets {
+                    let Some(key) = key_text(object.get(column)) else { continue };
+                    let found = if target_column == "id" {
+                        self.exists(target_table, &key)?
+                    } else {
+                        let values = match referenced.entry((target_table.to_string(), target_column.to_string())) {
+                            Entry::Occupied(entry) => entry.into_mut(),
+                            Entry::Vacant(entry) => entry.insert(self.column_values(target_table, target_column)?),
+                        };
+                        values.contains(&key)
+                    };
+                    if !found {
+                        violations.push(FkViolation {
+                            table: table.clone(),
+                            id: id.clone(),
+                            fk_column: column.clone(),
+                            referenced_table: target_table.to_string(),
+                            missing_id: key,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(violations)
+    }
+
+    fn schema(&self, table: &str) -> Result<serde_json::Value> {
+        match self.pending.get(&(table, "!schema")) {
+            Some(Some(value)) => match bincode::deserialize(value)? {
+                CrdtValue::Register(data) => Ok(serde_json::from_slice(&data)?),
+                CrdtValue::Counter(_) => Ok(serde_json::json!({})),
+            },
+            Some(None) => Ok(serde_json::json!({})),
+            None => self.storage.get_table_schema(table, None),
+        }
+    }
+
+    fn rows(&self, table: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+        let prefix = table_key_prefix(table);
+        let mut rows = BTreeMap::new();
+        for item in self.storage.db.prefix_iterator(prefix.as_bytes()) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let Some((_, id)) = decode_row_key(&key) else { continue };
+            if !id.starts_with('!') {
+                rows.insert(id, value.to_vec());
+            }
+        }
+        for (&(pending_table, id), value) in &self.pending {
+            if pending_table != table || id.starts_with('!') {
+                continue;
+            }
+            match value {
+                Some(value) => rows.insert(id.to_string(), value.to_vec()),
+                None => rows.remove(id),
+            };
+        }
+        Ok(rows)
+    }
+
+    fn exists(&self, table: &str, id: &str) -> Result<bool> {
+        match self.pending.get(&(table, id)) {
+            Some(value) => Ok(value.is_some()),
+            None => Ok(self.storage.db.get(encode_row_key(table, id).as_bytes())?.is_some()),
+        }
+    }
+
+    fn column_values(&self, table: &str, column: &str) -> Result<HashSet<String>> {
+        let schema = self.schema(table)?;
+        let mut values = HashSet::new();
+        for value in self.rows(table)?.values() {
+            values.extend(key_text(decode(value, &schema)?.get(column)));
+        }
+        Ok(values)
+    }
+}
+
+// A stored row as an object named after the schema's columns.
+fn decode(value: &[u8], schema: &serde_json::Value) -> Result<serde_json::Value> {
+    let json = match bincode::deserialize(value)? {
+        CrdtValue::Register(data) => serde_json::from_slice(&data).unwrap_or(serde_json::Value::Null),
+        CrdtValue::Counter(count) => serde_json::Value::from(count),
+    };
+    Ok(row::row_to_object(json, schema))
+}
+
+// A key as compared against row ids: strings as they are, other values in their JSON form.
+// NULL references nothing.
+fn key_text(value: Option<&serde_json::Value>) -> Option<String> {
+    match value? {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::database::StorageOptions;
+    use crate::core::sql_executor::SqlExecutor;
+    use crate::core::test_support::{data_path, rows, run, temp_repo};
+
+    fn schema(storage: &CommitStorage) {
+        run(storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(storage, "CREATE TABLE orders (id TEXT, user_id TEXT REFERENCES users(id), item TEXT)");
+        run(storage, "INSERT INTO users VALUES ('1', 'ann')");
+        run(storage, "INSERT INTO orders VALUES ('a', '1', 'pen')");
+    }
+
+    #[test]
+    fn a_dangling_foreign_key_is_the_one_violation() {
+        let (_dir, storage) = temp_repo();
+        schema(&storage);
+        assert!(storage.verify_foreign_keys(None).unwrap().is_empty());
+
+        run(&storage, "INSERT INTO orders VALUES ('b', '9', 'ink')");
+        let violations = storage.verify_foreign_keys(None).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].to_string(), "orders/b: user_id references missing users '9'");
+        assert_eq!(storage.verify_foreign_keys(Some("orders")).unwrap().len(), 1);
+        assert!(storage.verify_foreign_keys(Some("users")).unwrap().is_empty());
+
+        // Deleting the referenced row leaves the other order dangling too
+        run(&storage, "DELETE FROM users WHERE id = '1'");
+        assert_eq!(storage.verify_foreign_keys(None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn enforced_foreign_keys_refuse_the_commit_that_would_dangle() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions { enforce_fk: true, ..StorageOptions::default() };
+        let storage = CommitStorage::open_with_options(&data_path(&dir), options).unwrap();
+        schema(&storage);
+
+        for statement in ["INSERT INTO orders VALUES ('b', '9', 'ink')", "DELETE FROM users WHERE id = '1'"] {
+            let err = SqlExecutor::execute(&storage, statement).unwrap_err();
+            assert!(matches!(&err, BranchDBError::InvalidInput(message) if message.starts_with("1 foreign key violation(s)")), "{}", err);
+        }
+        assert_eq!(rows(&storage, "SELECT id FROM orders"), vec![vec![serde_json::json!("a")]]);
+        assert!(storage.verify_foreign_keys(None).unwrap().is_empty());
+    }
+}
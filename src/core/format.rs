@@ -0,0 +1,266 @@
+use crate::error::{BranchDBError, Result};
+use crate::core::database::{apply_to_live, commit_key, encode_row_key, replay_table, table_key_prefix, TABLE_REGISTRY_PREFIX};
+use crate::core::models::Change;
+use crate::core::namespace::{NamespacedDb, WriteBatch};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+// Version of the on-disk layout written by this build: commit encoding, key layout and
+// `CrdtValue` encoding. A change to any of them bumps it and adds a step to `MIGRATIONS`.
+pub const FORMAT_VERSION: u32 = 5;
+
+// u32, little-endian. Repositories created before versioning have no key and are version 0.
+pub(crate) const FORMAT_VERSION_KEY: &[u8] = b"!format-version";
+
+// One upgrade step, taking a repository from `version - 1` to `version`. Steps must be
+// idempotent: a step interrupted before the version was bumped runs again on the next migrate.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    run: fn(&NamespacedDb) -> Result<()>,
+}
+
+// Every step, oldest first; the last one's version is FORMAT_VERSION.
+const MIGRATIONS: &[Migration] = &[
+    // Version 1 only starts recording the version; the layout is unchanged
+    Migration { version: 1, description: "record the format version", run: |_| Ok(()) },
+    Migration { version: 2, description: "move commit objects under the !commit: prefix", run: namespace_commits },
+    Migration { version: 3, description: "escape ':' and '%' in live row keys", run: escape_row_keys },
+    // Older commits are read in their own layout, so none are rewritten; the version only
+    // keeps older builds from reading millisecond timestamps as seconds
+    Migration { version: 4, description: "record commit times in milliseconds with a sequence number", run: |_| Ok(()) },
+    Migration { version: 5, description: "write HEAD's rows to the live row keys", run: materialize_head },
+];
+
+// Commits used to be stored under their bare 32-byte hash. A commit's value is its bincode
+// encoding followed by the blake3 hash of that encoding, which is also its key, so a key is
+// only moved when its value ends in the key and hashes to it.
+fn namespace_commits(db: &NamespacedDb) -> Result<()> {
+    let mut batch = WriteBatch::default();
+    let mut moved = 0;
+    for item in db.iterator() {
+        let (key, value) = item?;
+        if key.len() != 32 || value.len() <= 32 {
+            continue;
+        }
+        let (body, checksum) = value.split_at(value.len() - 32);
+        if checksum == &key[..] && blake3::hash(body).as_bytes() == &key[..] {
+            batch.put(commit_key(&key), &value);
+            batch.delete(&key);
+            moved += 1;
+        }
+    }
+    db.write(batch)?;
+    info!(commits = moved, "moved commit objects");
+    Ok(())
+}
+
+// Live rows used to be keyed by the raw table name and id. A key is attributed to the longest
+// registered table it starts with, and rewritten only if escaping changes it. Escaping isn't
+// idempotent, so the new version is stored in the same write as the keys.
+fn escape_row_keys(db: &NamespacedDb) -> Result<()> {
+    let mut tables = registered_tables(db)?;
+    tables.sort_by_key(|table| std::cmp::Reverse(table.len()));
+
+    let mut batch = WriteBatch::default();
+    let mut moved = 0;
+    for item in db.iterator() {
+        let (key, value) = item?;
+        let Ok(key) = std::str::from_utf8(&key) else { continue };
+        let Some((table, id)) = tables.iter()
+            .find_map(|table| Some((table, key.strip_prefix(table.as_str())?.strip_prefix(':')?))) else { continue };
+        let escaped = encode_row_key(table, id);
+        if escaped != key {
+            batch.put(escaped.as_bytes(), &value);
+            batch.delete(key.as_bytes());
+            moved += 1;
+        }
+    }
+    batch.put(FORMAT_VERSION_KEY, 3u32.to_le_bytes());
+    db.write(batch)?;
+    info!(rows = moved, "escaped row keys");
+    Ok(())
+}
+
+// Commits used to leave the live `table:id` keys alone, so repositories from then have none,
+// or only those written since, while reads at HEAD go to them. Every registered table's live
+// keys are replaced with its rows replayed at HEAD, which a second run writes again unchanged.
+fn materialize_head(db: &NamespacedDb) -> Result<()> {
+    let Some(head) = db.get(b"HEAD")? else { return Ok(()) };
+    let head: [u8; 32] = head.try_into()
+        .map_err(|_| BranchDBError::CorruptData("HEAD is not a commit hash".into()))?;
+    let mut batch = WriteBatch::default();
+    let mut rows = 0;
+    for table in registered_tables(db)? {
+        let prefix = table_key_prefix(&table);
+        for item in db.prefix_iterator(prefix.as_bytes()) {
+            let (key, _) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            batch.delete(&key);
+        }
+        for (id, value) in replay_table(db, &table, &head)? {
+            apply_to_live(&mut batch, &Change::Insert { table: table.clone(), id, value: bincode::serialize(&value)? })?;
+            rows += 1;
+        }
+    }
+    db.write(batch)?;
+    info!(rows, "wrote live rows");
+    Ok(())
+}
+
+fn registered_tables(db: &NamespacedDb) -> Result<Vec<String>> {
+    let mut tables = Vec::new();
+    for item in db.prefix_iterator(TABLE_REGISTRY_PREFIX) {
+        let (key, _) = item?;
+        let Some(name) = key.strip_prefix(TABLE_REGISTRY_PREFIX.as_bytes()) else { break };
+        tables.push(String::from_utf8_lossy(name).into_owned());
+    }
+    Ok(tables)
+}
+
+pub fn stored_version(db: &NamespacedDb) -> Result<u32> {
+    match db.get(FORMAT_VERSION_KEY)? {
+        Some(raw) => {
+            let bytes: [u8; 4] = raw.try_into()
+                .map_err(|_| BranchDBError::CorruptData("format version is not a u32".into()))?;
+            Ok(u32::from_le_bytes(bytes))
+        }
+        None => Ok(0),
+    }
+}
+
+fn write_version(db: &NamespacedDb, version: u32) -> Result<()> {
+    db.put(FORMAT_VERSION_KEY, version.to_le_bytes())?;
+    Ok(())
+}
+
+// Called on every open. A brand-new, empty database is stamped with the current version; a
+// repository written by a newer build, or one that still needs `migrate`, is refused before
+// anything tries to decode it.
+pub fn check(db: &NamespacedDb, writable: bool) -> Result<()> {
+    if writable && db.get(FORMAT_VERSION_KEY)?.is_none() && db.iterator().next().is_none() {
+        return write_version(db, FORMAT_VERSION);
+    }
+    let found = stored_version(db)?;
+    if found > FORMAT_VERSION {
+        return Err(BranchDBError::FormatTooNew { found, supported: FORMAT_VERSION });
+    }
+    if found < FORMAT_VERSION {
+        return Err(BranchDBError::MigrationRequired { found, current: FORMAT_VERSION });
+    }
+    Ok(())
+}
+
+// Steps still to run on a repository at `version`.
+pub fn pending(version: u32) -> Vec<&'static Migration> {
+    MIGRATIONS.iter().filter(|m| m.version > version).collect()
+}
+
+// Runs the pending steps in order, storing the new version after each one.
+pub fn migrate(db: &NamespacedDb, mut on_step: impl FnMut(&Migration)) -> Result<u32> {
+    let mut version = stored_version(db)?;
+    if version > FORMAT_VERSION {
+        return Err(BranchDBError::FormatTooNew { found: version, supported: FORMAT_VERSION });
+    }
+    for step in pending(version) {
+        info!(from = version, to = step.version, step = step.description, "migrating");
+        (step.run)(db)?;
+        write_version(db, step.version)?;
+        version = step.version;
+        on_step(step);
+    }
+    Ok(version)
+}
+
+// Copies the closed repository to `<data dir>.backup-v<version>-<unix time>` next to it.
+pub fn backup(data_dir: &Path, version: u32) -> Result<PathBuf> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let target = PathBuf::from(format!("{}.backup-v{}-{}", data_dir.display(), version, timestamp));
+    copy_dir(data_dir, &target)?;
+    Ok(target)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::database::CommitStorage;
+    use crate::core::test_support::{data_path, head, live_rows, replayed_rows, rows};
+    use rocksdb::{Options, DB};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    // Every key and value, hex-encoded, of a repository made by the last build at each older
+    // version: `init`, `CREATE TABLE users (id TEXT, name TEXT)`, inserts of '1' and
+    // '2024:03:15', then an update of '1' to 'anne', which builds from before live rows refused.
+    const FIXTURES: [(u32, &str); 4] = [
+        (0, include_str!("../../tests/fixtures/format-v0.json")),
+        (1, include_str!("../../tests/fixtures/format-v1.json")),
+        (2, include_str!("../../tests/fixtures/format-v2.json")),
+        (3, include_str!("../../tests/fixtures/format-v3.json")),
+    ];
+
+    fn load_fixture(path: &str, fixture: &str) -> NamespacedDb {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        let db = NamespacedDb::new(Arc::new(DB::open(&options, path).unwrap()), "");
+        let pairs: Vec<(String, String)> = serde_json::from_str(fixture).unwrap();
+        let mut batch = WriteBatch::default();
+        for (key, value) in pairs {
+            batch.put(hex::decode(key).unwrap(), hex::decode(value).unwrap());
+        }
+        db.write(batch).unwrap();
+        db
+    }
+
+    #[test]
+    fn fixtures_at_every_older_version_migrate_to_the_current_one() {
+        for (version, fixture) in FIXTURES {
+            let dir = tempfile::tempdir().unwrap();
+            let db = load_fixture(&data_path(&dir), fixture);
+            assert!(matches!(check(&db, true), Err(BranchDBError::MigrationRequired { found, .. }) if found == version));
+
+            let mut steps = Vec::new();
+            assert_eq!(migrate(&db, |step| steps.push(step.version)).unwrap(), FORMAT_VERSION);
+            assert_eq!(steps, ((version + 1)..=FORMAT_VERSION).collect::<Vec<_>>(), "from version {}", version);
+            // Migrating again finds nothing to do
+            assert_eq!(migrate(&db, |_| panic!("migrated version {} twice", version)).unwrap(), FORMAT_VERSION);
+            assert!(db.get(b"users:2024:03:15").unwrap().is_none());
+            drop(db);
+
+            let storage = CommitStorage::open(&data_path(&dir)).unwrap();
+            let mut users = rows(&storage, "SELECT id, name FROM users");
+            users.sort_by_key(|row| row[0].to_string());
+            let first = if version == 0 { "ann" } else { "anne" };
+            assert_eq!(users, vec![vec![json!("1"), json!(first)], vec![json!("2024:03:15"), json!("bob")]], "from version {}", version);
+            assert_eq!(live_rows(&storage, "users"), replayed_rows(&storage, "users", &head(&storage)), "from version {}", version);
+            assert_eq!(storage.get_commit_history().unwrap().len(), if version == 0 { 3 } else { 4 });
+        }
+    }
+
+    #[test]
+    fn newer_repositories_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = load_fixture(&data_path(&dir), FIXTURES[3].1);
+        write_version(&db, FORMAT_VERSION + 1).unwrap();
+        assert!(matches!(check(&db, true), Err(BranchDBError::FormatTooNew { .. })));
+        assert!(matches!(migrate(&db, |_| {}), Err(BranchDBError::FormatTooNew { .. })));
+    }
+}
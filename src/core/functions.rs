@@ -0,0 +1,246 @@
+use crate::core::database::CommitStorage;
+use crate::core::join::QueryRow;
+use crate::core::phonetic::Algorithm;
+use crate::core::query::{eval_expr, ScalarFunctionEvaluator, TypeCoercer};
+use crate::core::views::strip_keywords;
+use crate::error::{BranchDBError, Result};
+use crate::core::namespace::NamespacedDb;
+use serde::{Deserialize, Serialize};
+use sqlparser::ast::{Expr, SelectItem, SetExpr, Statement};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+// `func:<name>` holds a stored function's definition as JSON, under its lowercased name since
+// function names are case-insensitive. Like view definitions, functions aren't versioned: one
+// created on a branch is callable from every branch and commit.
+const FUNCTION_PREFIX: &str = "func:";
+
+// Calls nested deeper than this fail, so a function that ends up calling itself errors out
+// instead of overflowing the stack.
+const MAX_CALL_DEPTH: usize = 32;
+
+const USAGE: &str = "Expected CREATE FUNCTION <name>(<params>) RETURNS <type> AS '<expression>'";
+
+// A function defined by `CREATE FUNCTION`. Only the number of parameters matters; the body
+// reads the arguments as `$1`, `$2`, ...
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredFunction {
+    pub name: String,
+    pub params: Vec<String>,
+    pub returns: String,
+    pub body: String,
+}
+
+// A stored function with its parsed body, or why the body doesn't parse.
+type LoadedFunction = (StoredFunction, std::result::Result<Expr, String>);
+
+thread_local! {
+    // Stored functions callable by the query running on this thread. Set by QueryProcessor::query
+    // for the same reason as STRICT_CAST: eval_expr has no database to read them from.
+    static FUNCTIONS: RefCell<HashMap<String, LoadedFunction>> = RefCell::new(HashMap::new());
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// `CREATE FUNCTION <name>(<params>) RETURNS <type> AS '<expression>'`. Quotes in the body are
+// doubled, or the body is dollar-quoted as `$$...$$` to leave them as they are.
+pub(crate) fn parse_create_function(command: &str) -> Option<Result<StoredFunction>> {
+    let rest = strip_keywords(command, &["CREATE", "FUNCTION"])?;
+    Some(parse_definition(rest).ok_or_else(|| BranchDBError::InvalidInput(USAGE.into())))
+}
+
+fn parse_definition(rest: &str) -> Option<StoredFunction> {
+    let (name, rest) = rest.split_once('(')?;
+    let (params, rest) = rest.split_once(')')?;
+    let (returns, body) = split_at_keyword(strip_keywords(rest, &["RETURNS"])?, "AS")?;
+    let body = body.trim_end_matches(';').trim_end();
+    let body = match body.strip_prefix("$$").and_then(|b| b.strip_suffix("$$")) {
+        Some(inner) => inner.trim().to_string(),
+        None => body.strip_prefix('\'')?.strip_suffix('\'')?.replace("''", "'"),
+    };
+    if returns.is_empty() || body.is_empty() {
+        return None;
+    }
+    Some(StoredFunction {
+        name: name.trim().to_lowercase(),
+        params: params.split(',').map(str::trim).filter(|p| !p.is_empty()).map(str::to_string).collect(),
+        returns: returns.to_string(),
+        body,
+    })
+}
+
+// Splits `text` around the first whitespace-separated word equal to `keyword`.
+fn split_at_keyword<'a>(text: &'a str, keyword: &str) -> Option<(&'a str, &'a str)> {
+    let mut start = 0;
+    for word in text.split_whitespace() {
+        let offset = start + text[start..].find(word)?;
+        if word.eq_ignore_ascii_case(keyword) {
+            return Some((text[..offset].trim(), text[offset + word.len()..].trim()));
+        }
+        start = offset + word.len();
+    }
+    None
+}
+
+// `DROP FUNCTION [IF EXISTS] <name>`: the name and whether IF EXISTS was given. A parameter
+// list after the name is ignored.
+pub(crate) fn parse_drop_function(command: &str) -> Option<(String, bool)> {
+    let rest = strip_keywords(command, &["DROP", "FUNCTION"])?;
+    let (rest, if_exists) = match strip_keywords(rest, &["IF", "EXISTS"]) {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+    let name = rest.split('(').next().unwrap_or_default().trim_end_matches(';').trim();
+    Some((name.to_lowercase(), if_exists))
+}
+
+// The body as an expression: it has to parse as the only column of a SELECT without FROM.
+fn parse_body(body: &str) -> std::result::Result<Expr, String> {
+    let ast = Parser::parse_sql(&GenericDialect, &format!("SELECT {}", body)).map_err(|e| e.to_string())?;
+    if let [Statement::Query(query)] = ast.as_slice() {
+        if let SetExpr::Select(select) = &*query.body {
+            if let ([SelectItem::UnnamedExpr(expr)], true) = (select.projection.as_slice(), select.from.is_empty()) {
+                return Ok(expr.clone());
+            }
+        }
+    }
+    Err(format!("'{}' is not a single expression", body))
+}
+
+fn is_builtin(name: &str) -> bool {
+    ScalarFunctionEvaluator::handles(name) || name.eq_ignore_ascii_case("count") || Algorithm::from_function(name).is_some()
+}
+
+// Every stored function, keyed by name.
+pub(crate) fn stored_functions(db: &NamespacedDb) -> Result<HashMap<String, StoredFunction>> {
+    let mut functions = HashMap::new();
+    for item in db.prefix_iterator(FUNCTION_PREFIX) {
+        let (key, value) = item?;
+        let Some(name) = key.strip_prefix(FUNCTION_PREFIX.as_bytes()) else { break };
+        functions.insert(String::from_utf8_lossy(name).into_owned(), serde_json::from_slice(&value)?);
+    }
+    Ok(functions)
+}
+
+// Makes the stored functions in `db` callable from eval_expr while `run` runs on this thread.
+pub(crate) fn with_functions<T>(db: &NamespacedDb, run: impl FnOnce() -> Result<T>) -> Result<T> {
+    let loaded = stored_functions(db)?.into_iter()
+        .map(|(name, function)| {
+            let body = parse_body(&function.body);
+            (name, (function, body))
+        })
+        .collect();
+    let outer = FUNCTIONS.with(|functions| functions.replace(loaded));
+    let result = run();
+    FUNCTIONS.with(|functions| functions.replace(outer));
+    result
+}
+
+impl CommitStorage {
+    // Stores a function after checking that its body parses. The name may not be a built-in
+    // function or an existing stored one.
+    pub fn create_function(&self, function: &StoredFunction) -> Result<()> {
+        let valid_name = !function.name.is_empty()
+            && function.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(BranchDBError::InvalidInput(format!("Invalid function name '{}'", function.name)));
+        }
+        if is_builtin(&function.name) {
+            return Err(BranchDBError::InvalidInput(format!("'{}' is a built-in function", function.name)));
+        }
+        let key = format!("{}{}", FUNCTION_PREFIX, function.name);
+        if self.db.get(key.as_bytes())?.is_some() {
+            return Err(BranchDBError::InvalidInput(format!("Function '{}' already exists", function.name)));
+        }
+        parse_body(&function.body).map_err(|e| BranchDBError::InvalidInput(format!(
+            "Body of function '{}' doesn't parse: {}", function.name, e
+        )))?;
+        self.db.put(key.as_bytes(), serde_json::to_vec(function)?)?;
+        Ok(())
+    }
+
+    // Returns whether the function existed. A missing one is an error unless `if_exists`.
+    pub fn drop_function(&self, name: &str, if_exists: bool) -> Result<bool> {
+        let key = format!("{}{}", FUNCTION_PREFIX, name.to_lowercase());
+        if self.db.get(key.as_bytes())?.is_none() {
+            if if_exists {
+                return Ok(false);
+            }
+            return Err(BranchDBError::InvalidInput(format!("No function named '{}'", name)));
+        }
+        self.db.delete(key.as_bytes())?;
+        Ok(true)
+    }
+}
+
+// Calls stored functions from queries.
+pub struct FunctionEvaluator;
+
+impl FunctionEvaluator {
+    pub fn defines(name: &str) -> bool {
+        FUNCTIONS.with(|functions| functions.borrow().contains_key(&name.to_lowercase()))
+    }
+
+    // Binds the arguments to `$1`, `$2`, ... and evaluates the body against the row, so the
+    // body may also read the row's columns. The result is CAST to the declared return type.
+    pub fn call(name: &str, args: &[serde_json::Value], row: &QueryRow) -> Result<serde_json::Value> {
+        let (function, body) = FUNCTIONS.with(|functions| functions.borrow().get(&name.to_lowercase()).cloned())
+            .ok_or_else(|| BranchDBError::InvalidInput(format!("Unknown function: {}", name)))?;
+        if args.len() != function.params.len() {
+            return Err(BranchDBError::InvalidInput(format!(
+                "{} expects {} arguments, got {}", function.name, function.params.len(), args.len()
+            )));
+        }
+        let body = body.map_err(|e| BranchDBError::InvalidInput(format!(
+            "Body of function '{}' doesn't parse: {}", function.name, e
+        )))?;
+
+        let mut scope = row.clone();
+        for (position, arg) in args.iter().enumerate() {
+            scope.insert(format!("${}", position + 1), arg.clone());
+        }
+        let depth = CALL_DEPTH.with(|depth| depth.replace(depth.get() + 1));
+        let result = match depth < MAX_CALL_DEPTH {
+            true => eval_expr(&body, &scope),
+            false => Err(BranchDBError::InvalidInput(format!(
+                "Function '{}' nests calls more than {} deep", function.name, MAX_CALL_DEPTH
+            ))),
+        };
+        CALL_DEPTH.with(|d| d.set(depth));
+        TypeCoercer::cast_in_query(&result?, &function.returns, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::sql_executor::{SqlExecutor, SqlOutcome};
+    use crate::core::test_support::{rows, run, temp_repo};
+    use crate::error::BranchDBError;
+    use serde_json::json;
+
+    #[test]
+    fn a_stored_function_computes_a_value_per_row() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE people (id TEXT, first TEXT, last TEXT)");
+        run(&storage, "INSERT INTO people VALUES ('1', 'Ada', 'Lovelace')");
+        run(&storage, "INSERT INTO people VALUES ('2', 'Alan', 'Turing')");
+        let created = run(&storage, "CREATE FUNCTION full_name(first TEXT, last TEXT) RETURNS TEXT AS '$1 || '' '' || $2'");
+        assert!(matches!(created, SqlOutcome::Function { dropped: false, .. }));
+
+        assert_eq!(rows(&storage, "SELECT full_name(first, last) FROM people ORDER BY id"),
+            vec![vec![json!("Ada Lovelace")], vec![json!("Alan Turing")]]);
+        assert_eq!(rows(&storage, "SELECT id FROM people WHERE full_name(first, last) = 'Alan Turing'"), vec![vec![json!("2")]]);
+        // Names are case-insensitive, and a wrong number of arguments is an error
+        assert_eq!(rows(&storage, "SELECT FULL_NAME(last, first) FROM people WHERE id = '1'"), vec![vec![json!("Lovelace Ada")]]);
+        let one_argument = SqlExecutor::execute(&storage, "SELECT full_name(first) FROM people");
+        assert!(matches!(one_argument, Err(BranchDBError::InvalidInput(_))));
+        let again = SqlExecutor::execute(&storage, "CREATE FUNCTION full_name(a TEXT) RETURNS TEXT AS '$1'");
+        assert!(matches!(again, Err(BranchDBError::InvalidInput(_))));
+
+        run(&storage, "DROP FUNCTION full_name");
+        let dropped = SqlExecutor::execute(&storage, "SELECT full_name(first, last) FROM people");
+        assert!(matches!(dropped, Err(BranchDBError::InvalidInput(_))), "{:?}", dropped);
+        assert!(matches!(run(&storage, "DROP FUNCTION IF EXISTS full_name"), SqlOutcome::Function { existed: false, .. }));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::core::branch::BranchManager;
+use crate::core::crdt::{CrdtValue, TableState};
+use crate::core::database::CommitStorage;
+use crate::core::row;
+use crate::error::{BranchDBError, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// BranchDB commits record no author, so every exported commit gets this identity.
+const GIT_NAME: &str = "BranchDB";
+const GIT_EMAIL: &str = "branchdb@localhost";
+
+impl CommitStorage {
+    // Writes the history reachable from HEAD and the branches to a new bare git repository at
+    // `dest_path`. Every commit becomes a git commit with the same parents, message and
+    // timestamp, whose tree holds one `<table>.json` file per table with its rows as of that
+    // commit. Branches become `refs/heads/<name>` and git's HEAD points where ours does. Needs
+    // `git` on the PATH. Returns the number of commits exported.
+    pub fn export_git(&self, dest_path: &str) -> Result<usize> {
+        let dest = Path::new(dest_path);
+        if dest.exists() && dest.read_dir()?.next().is_some() {
+            return Err(BranchDBError::InvalidInput(format!("{} exists and is not empty", dest_path)));
+        }
+        git(None, &["init", "--bare", "--quiet", dest_path], None, None)?;
+
+        let branch_mgr = BranchManager::new(self.db.clone());
+        let mut branches = Vec::new();
+        for name in branch_mgr.list_branches()? {
+            let Some(head) = branch_mgr.get_branch_head(&name)? else { continue };
+            let hash: [u8; 32] = head.try_into()
+                .map_err(|_| BranchDBError::CorruptData(format!("branch '{}' points to an invalid commit", name)))?;
+            branches.push((name, hash));
+        }
+        let head = self.get_head()?;
+        let order = self.parents_first(head.iter().chain(branches.iter().map(|(_, hash)| hash)).copied())?;
+
+        // The git commit made for each of ours, and the blob of each table as of it
+        let mut exported: HashMap<[u8; 32], String> = HashMap::new();
+        let mut files: HashMap<[u8; 32], BTreeMap<String, String>> = HashMap::new();
+        for hash in &order {
+            let commit = self.get_commit_by_hash(hash)?;
+            // Tables this commit didn't change are the same blobs as in its first parent
+            let mut tables = commit.parents.first().and_then(|p| files.get(p)).cloned().unwrap_or_default();
+            let changed: BTreeSet<&str> = commit.changes.iter().map(|c| c.table()).collect();
+            for table in changed {
+                let json = table_json(&self.table_state_at(table, hash)?)?;
+                let blob = git(Some(dest), &["hash-object", "-w", "--stdin"], Some(&json), None)?;
+                tables.insert(table.to_string(), blob);
+            }
+
+            let listing: String = tables.iter()
+                .map(|(table, blob)| format!("100644 blob {}\t{}.json\n", blob, table))
+                .collect();
+            let tree = git(Some(dest), &["mktree"], Some(listing.as_bytes()), None)?;
+            let mut args = vec!["commit-tree", tree.as_str()];
+            for parent in &commit.parents {
+                args.extend(["-p", exported[parent].as_str()]);
+            }
+            let id = git(Some(dest), &args, Some(commit.message.as_bytes()), Some(commit.timestamp_secs()))?;
+            exported.insert(*hash, id);
+            files.insert(*hash, tables);
+        }
+
+        for (name, hash) in &branches {
+            git(Some(dest), &["update-ref", &format!("refs/heads/{}", name), &exported[hash]], None, None)?;
+        }
+        if let Some(head) = head {
+            // HEAD follows a branch that points at it, and is detached otherwise
+            match branches.iter().find(|(_, hash)| *hash == head) {
+                Some((name, _)) => git(Some(dest), &["symbolic-ref", "HEAD", &format!("refs/heads/{}", name)], None, None)?,
+                None => git(Some(dest), &["update-ref", "--no-deref", "HEAD", &exported[&head]], None, None)?,
+            };
+        }
+        Ok(order.len())
+    }
+
+    // Every commit reachable from `starts`, each one after all of its parents.
+    fn parents_first(&self, starts: impl Iterator<Item = [u8; 32]>) -> Result<Vec<[u8; 32]>> {
+        let mut order = Vec::new();
+        let mut seen = HashSet::new();
+        // (commit, whether its parents have been pushed already)
+        let mut stack: Vec<([u8; 32], bool)> = starts.map(|hash| (hash, false)).collect();
+        while let Some((hash, expanded)) = stack.pop() {
+            if expanded {
+                order.push(hash);
+                continue;
+            }
+            if !seen.insert(hash) {
+                continue;
+            }
+            stack.push((hash, true));
+            for parent in self.get_commit_by_hash(&hash)?.parents.iter().rev() {
+                if !seen.contains(parent) {
+                    stack.push((*parent, false));
+                }
+            }
+        }
+        Ok(order)
+    }
+}
+
+// A table's rows keyed by id, each decoded to an object named after the schema's columns.
+fn table_json(state: &TableState) -> Result<Vec<u8>> {
+    let schema = match state.get("!schema") {
+        Some(CrdtValue::Register(data)) => serde_json::from_slice(data).unwrap_or_default(),
+        _ => serde_json::Value::Null,
+    };
+    let rows: BTreeMap<&String, serde_json::Value> = state.iter()
+        .filter(|(id, _)| !id.starts_with('!'))
+        .map(|(id, value)| {
+            let json = match value {
+                CrdtValue::Register(data) => serde_json::from_slice(data)
+                    .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(data).into_owned())),
+                CrdtValue::Counter(count) => serde_json::Value::from(*count),
+            };
+            (id, row::row_to_object(json, &schema))
+        })
+        .collect();
+    let mut json = serde_json::to_vec_pretty(&rows)?;
+    json.push(b'\n');
+    Ok(json)
+}
+
+// Runs git, against `git_dir` when given, feeding it `input` and returning its trimmed stdout.
+// With `timestamp`, the author and committer dates are set to it.
+fn git(git_dir: Option<&Path>, args: &[&str], input: Option<&[u8]>, timestamp: Option<u64>) -> Result<String> {
+    let mut command = Command::new("git");
+    if let Some(dir) = git_dir {
+        command.arg("--git-dir").arg(dir);
+    }
+    command.args(args)
+        .env("GIT_AUTHOR_NAME", GIT_NAME)
+        .env("GIT_AUTHOR_EMAIL", GIT_EMAIL)
+        .env("GIT_COMMITTER_NAME", GIT_NAME)
+        .env("GIT_COMMITTER_EMAIL", GIT_EMAIL)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(timestamp) = timestamp {
+        let date = format!("{} +0000", timestamp);
+        command.env("GIT_AUTHOR_DATE", &date).env("GIT_COMMITTER_DATE", &date);
+    }
+
+    let mut child = command.spawn()?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(BranchDBError::GitCommand {
+            command: args.first().copied().unwrap_or_default().to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_support::{run, temp_repo};
+
+    #[test]
+    fn an_exported_history_shows_up_in_git_log() {
+        let (dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        run(&storage, "UPDATE users SET name = 'anne' WHERE id = '1'");
+        BranchManager::new(storage.db.clone()).create_branch("main").unwrap();
+
+        let dest = dir.path().join("export.git");
+        assert_eq!(storage.export_git(dest.to_str().unwrap()).unwrap(), 3);
+        let log = git(Some(&dest), &["log", "--format=%s"], None, None).unwrap();
+        assert_eq!(log.lines().collect::<Vec<_>>(), [
+            "SQL: UPDATE users SET name = 'anne' WHERE id = '1'",
+            "SQL: INSERT INTO users VALUES ('1', 'ann')",
+            "SQL: CREATE TABLE users (id TEXT, name TEXT)",
+        ]);
+        assert_eq!(git(Some(&dest), &["symbolic-ref", "HEAD"], None, None).unwrap(), "refs/heads/main");
+        let users: serde_json::Value = serde_json::from_str(&git(Some(&dest), &["show", "HEAD:users.json"], None, None).unwrap()).unwrap();
+        assert_eq!(users, serde_json::json!({"1": {"id": "1", "name": "anne"}}));
+
+        let again = storage.export_git(dest.to_str().unwrap());
+        assert!(matches!(again, Err(BranchDBError::InvalidInput(_))));
+    }
+}
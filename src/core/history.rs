@@ -0,0 +1,250 @@
+use crate::core::branch::BranchManager;
+use crate::core::database::CommitStorage;
+use crate::core::models::Commit;
+use crate::error::{BranchDBError, Result};
+use serde::{Serialize, Serializer};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use tracing::warn;
+
+// Ordered by (timestamp, commit sequence, insertion order), so commits without a sequence
+// number and equal timestamps come out in the order they were found.
+type QueueEntry = ((u64, u64), std::cmp::Reverse<usize>, [u8; 32]);
+
+// Iterates every commit reachable from a starting point through all parents, newest first,
+// visiting each commit once. Commits are loaded as they are reached, so callers can stream the
+// output of a long history instead of collecting it first.
+pub struct HistoryWalk<'a> {
+    storage: &'a CommitStorage,
+    queue: BinaryHeap<QueueEntry>,
+    // Commits in the queue, loaded once when first reached
+    queued: HashMap<[u8; 32], Commit>,
+    seen: HashSet<[u8; 32]>,
+    sequence: usize,
+    // Skip commits that are missing or fail their checksum, with a warning, instead of failing
+    best_effort: bool,
+}
+
+impl<'a> HistoryWalk<'a> {
+    pub fn new(storage: &'a CommitStorage, start: Option<[u8; 32]>) -> Result<Self> {
+        Self::from_tips(storage, start)
+    }
+
+    // Walks from several starting points at once; history they share is visited once.
+    pub fn from_tips(storage: &'a CommitStorage, tips: impl IntoIterator<Item = [u8; 32]>) -> Result<Self> {
+        Self::walk(storage, tips, false)
+    }
+
+    // Like `from_tips`, but an unreadable commit (quarantined, missing or corrupt) is skipped
+    // with a warning, along with whatever history is only reachable through it.
+    pub fn best_effort(storage: &'a CommitStorage, tips: impl IntoIterator<Item = [u8; 32]>) -> Result<Self> {
+        Self::walk(storage, tips, true)
+    }
+
+    fn walk(storage: &'a CommitStorage, tips: impl IntoIterator<Item = [u8; 32]>, best_effort: bool) -> Result<Self> {
+        let mut walk = Self {
+            storage,
+            queue: BinaryHeap::new(),
+            queued: HashMap::new(),
+            seen: HashSet::new(),
+            sequence: 0,
+            best_effort,
+        };
+        for hash in tips {
+            walk.push(hash)?;
+        }
+        Ok(walk)
+    }
+
+    pub fn from_head(storage: &'a CommitStorage) -> Result<Self> {
+        Self::new(storage, storage.get_head()?)
+    }
+
+    fn push(&mut self, hash: [u8; 32]) -> Result<()> {
+        if self.seen.insert(hash) {
+            let commit = match self.storage.get_commit_by_hash(&hash) {
+                Err(e @ (BranchDBError::CommitNotFound { .. } | BranchDBError::CorruptData(_))) if self.best_effort => {
+                    warn!(commit = %hex::encode(hash), error = %e, "skipping unreadable commit");
+                    return Ok(());
+                }
+                result => result?,
+            };
+            self.queue.push((commit.order_key(), std::cmp::Reverse(self.sequence), hash));
+            self.queued.insert(hash, commit);
+            self.sequence += 1;
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<Option<([u8; 32], Commit)>> {
+        let Some((_, _, hash)) = self.queue.pop() else { return Ok(None) };
+        let commit = self.queued.remove(&hash).expect("queued commits are loaded");
+        for parent in &commit.parents {
+            self.push(*parent)?;
+        }
+        Ok(Some((hash, commit)))
+    }
+}
+
+// One commit of the DAG with its edges in both directions. Hashes serialize as hex.
+#[derive(Debug, Clone, Serialize)]
+pub struct DagNode {
+    #[serde(serialize_with = "hex_hash")]
+    pub hash: [u8; 32],
+    pub message: String,
+    // Unix time in milliseconds
+    pub timestamp: u64,
+    #[serde(serialize_with = "hex_hashes")]
+    pub parents: Vec<[u8; 32]>,
+    // Only children reachable from HEAD or a branch; newest first
+    #[serde(serialize_with = "hex_hashes")]
+    pub children: Vec<[u8; 32]>,
+    // Branches whose head is this commit
+    pub branch_refs: Vec<String>,
+    // There are no tags yet, so this is always empty; it is part of the format so consumers
+    // won't break when they arrive
+    pub tag_refs: Vec<String>,
+}
+
+// Every commit reachable from HEAD or any branch, newest first, and the ones without parents.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommitDag {
+    pub commits: Vec<DagNode>,
+    #[serde(serialize_with = "hex_hashes")]
+    pub root_hashes: Vec<[u8; 32]>,
+}
+
+fn hex_hash<S: Serializer>(hash: &[u8; 32], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(hash))
+}
+
+fn hex_hashes<S: Serializer>(hashes: &[[u8; 32]], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(hashes.iter().map(hex::encode))
+}
+
+impl CommitStorage {
+    // The whole history as a graph: every commit reachable from HEAD or a branch, with its
+    // parents, its children found by inverting the parent edges, and the branches pointing at it.
+    pub fn get_commit_dag(&self) -> Result<CommitDag> {
+        let branch_mgr = BranchManager::new(self.db.clone());
+        let mut branch_refs: HashMap<[u8; 32], Vec<String>> = HashMap::new();
+        for branch in branch_mgr.list_branches()? {
+            let Some(raw) = branch_mgr.get_branch_head(&branch)? else { continue };
+            let Ok(hash) = <[u8; 32]>::try_from(raw.as_slice()) else { continue };
+            branch_refs.entry(hash).or_default().push(branch);
+        }
+
+        let mut commits = Vec::new();
+        let mut positions = HashMap::new();
+        for entry in HistoryWalk::from_tips(self, self.all_tips()?)? {
+            let (hash, commit) = entry?;
+            positions.insert(hash, commits.len());
+            commits.push(DagNode {
+                hash,
+                message: commit.message,
+                timestamp: commit.timestamp,
+                parents: commit.parents,
+                children: Vec::new(),
+                branch_refs: branch_refs.remove(&hash).unwrap_or_default(),
+                tag_refs: Vec::new(),
+            });
+        }
+        for child in 0..commits.len() {
+            for parent in commits[child].parents.clone() {
+                if let Some(&position) = positions.get(&parent) {
+                    let hash = commits[child].hash;
+                    commits[position].children.push(hash);
+                }
+            }
+        }
+
+        let root_hashes = commits.iter().filter(|node| node.parents.is_empty()).map(|node| node.hash).collect();
+        Ok(CommitDag { commits, root_hashes })
+    }
+
+    // Every commit reachable from HEAD or any branch, once each, newest first.
+    pub fn get_all_commits(&self) -> Result<Vec<(Commit, [u8; 32])>> {
+        HistoryWalk::from_tips(self, self.all_tips()?)?
+            .map(|entry| entry.map(|(hash, commit)| (commit, hash)))
+            .collect()
+    }
+
+    // Commits reachable from HEAD or any branch with a sequence number above `after`, oldest
+    // first. The walk stops at the first commit at or below it, so only the new end of the
+    // history is read; unreadable commits are skipped.
+    pub fn commits_after(&self, after: u64) -> Result<Vec<([u8; 32], Commit)>> {
+        let mut commits = Vec::new();
+        for entry in HistoryWalk::best_effort(self, self.all_tips()?)? {
+            let (hash, commit) = entry?;
+            if commit.sequence <= after {
+                break;
+            }
+            commits.push((hash, commit));
+        }
+        commits.reverse();
+        Ok(commits)
+    }
+
+    // HEAD, then the head of every branch that isn't HEAD.
+    pub fn all_tips(&self) -> Result<Vec<[u8; 32]>> {
+        let branch_mgr = BranchManager::new(self.db.clone());
+        let mut tips: Vec<[u8; 32]> = self.get_head()?.into_iter().collect();
+        for branch in branch_mgr.list_branches()? {
+            let Some(raw) = branch_mgr.get_branch_head(&branch)? else { continue };
+            let Ok(hash) = <[u8; 32]>::try_from(raw.as_slice()) else { continue };
+            if !tips.contains(&hash) {
+                tips.push(hash);
+            }
+        }
+        Ok(tips)
+    }
+}
+
+impl Iterator for HistoryWalk<'_> {
+    type Item = Result<([u8; 32], Commit)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_support::{head, run, temp_repo};
+
+    #[test]
+    fn the_dag_links_a_fork_and_its_merge_both_ways() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        let root = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let fork = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+        let side = head(&storage);
+        BranchManager::new(storage.db.clone()).create_branch("feature").unwrap();
+        storage.move_head(&fork).unwrap();
+        run(&storage, "INSERT INTO users VALUES ('3', 'cy')");
+        let main = head(&storage);
+        let merge = storage.create_merge_commit("Merge feature", Vec::new(), &side).unwrap();
+
+        let dag = storage.get_commit_dag().unwrap();
+        assert_eq!(dag.commits.len(), 5);
+        assert_eq!(dag.root_hashes, vec![root]);
+        let node = |hash| dag.commits.iter().find(|node| node.hash == hash).unwrap();
+        assert_eq!(node(merge).parents, vec![main, side]);
+        assert!(node(merge).children.is_empty());
+        let mut children = node(fork).children.clone();
+        children.sort();
+        let mut expected = vec![main, side];
+        expected.sort();
+        assert_eq!(children, expected);
+        assert_eq!(node(side).children, vec![merge]);
+        assert_eq!(node(main).children, vec![merge]);
+        assert_eq!(node(side).branch_refs, vec!["feature".to_string()]);
+
+        let json = serde_json::to_value(&dag).unwrap();
+        let merge_json = json["commits"].as_array().unwrap().iter().find(|node| node["hash"] == hex::encode(merge)).unwrap();
+        assert_eq!(merge_json["parents"], serde_json::json!([hex::encode(main), hex::encode(side)]));
+        assert_eq!(json["root_hashes"], serde_json::json!([hex::encode(root)]));
+    }
+}
@@ -0,0 +1,52 @@
+use crate::error::{BranchDBError, Result};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{debug, warn};
+
+pub const HOOKS_DIR: &str = "hooks";
+
+// Environment variables passed to a hook, in addition to `BRANCHDB_DIR`.
+pub type HookContext = Vec<(&'static str, String)>;
+
+// Runs the executables in `<data dir>/hooks/`, named after the event (`pre-revert`,
+// `post-merge`, ...). A missing hook counts as success. Hook output goes to stderr so it
+// never mixes with command output on stdout.
+pub struct HookRunner {
+    dir: PathBuf,
+    data_dir: PathBuf,
+}
+
+impl HookRunner {
+    pub fn new(data_dir: &Path) -> Self {
+        Self { dir: data_dir.join(HOOKS_DIR), data_dir: data_dir.to_path_buf() }
+    }
+
+    // Returns whether the hook exited successfully.
+    pub fn run(&self, name: &str, context: &HookContext) -> Result<bool> {
+        let path = self.dir.join(name);
+        if !path.is_file() {
+            return Ok(true);
+        }
+
+        debug!(hook = name, path = %path.display(), "running hook");
+        let status = Command::new(&path)
+            .env("BRANCHDB_DIR", &self.data_dir)
+            .envs(context.iter().map(|(key, value)| (*key, value)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(std::io::stderr()))
+            .status()?;
+        if !status.success() {
+            warn!(hook = name, ?status, "hook failed");
+        }
+        Ok(status.success())
+    }
+
+    // For `pre-*` hooks: a nonzero exit rejects the operation.
+    pub fn run_pre(&self, name: &str, operation: &str, context: &HookContext) -> Result<()> {
+        if self.run(name, context)? {
+            Ok(())
+        } else {
+            Err(BranchDBError::InvalidInput(format!("{} hook rejected the {}", name, operation)))
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::core::query::TypeCoercer;
+use crate::error::{BranchDBError, Result};
+use csv::StringRecord;
+use serde_json::{json, Map, Value};
+
+// Rows `import-csv --infer-types` reads before settling on the column types.
+pub const INFER_SAMPLE_ROWS: usize = 100;
+
+// Guesses a table schema from the first rows of a CSV. A column is INTEGER, FLOAT or BOOLEAN
+// when every non-empty field of the sample parses as one, in that order, and TEXT otherwise.
+// Empty fields are nulls and don't count; a column with nothing else is TEXT.
+pub struct SchemaInferencer;
+
+impl SchemaInferencer {
+    pub fn infer(headers: &StringRecord, sample: &[StringRecord]) -> Value {
+        let columns: Map<String, Value> = headers.iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let fields: Vec<&str> = sample.iter()
+                    .filter_map(|record| record.get(i))
+                    .map(str::trim)
+                    .filter(|field| !field.is_empty())
+                    .collect();
+                (name.to_string(), Value::String(column_type(&fields).to_string()))
+            })
+            .collect();
+        json!({ "columns": columns })
+    }
+}
+
+fn column_type(fields: &[&str]) -> &'static str {
+    let all = |parses: fn(&str) -> bool| !fields.is_empty() && fields.iter().all(|field| parses(field));
+    if all(|field| field.parse::<i64>().is_ok()) {
+        "INTEGER"
+    } else if all(|field| field.parse::<f64>().is_ok()) {
+        "FLOAT"
+    } else if all(|field| field.eq_ignore_ascii_case("true") || field.eq_ignore_ascii_case("false")) {
+        "BOOLEAN"
+    } else {
+        "TEXT"
+    }
+}
+
+// Turns CSV records into JSON objects typed by a table schema: empty fields become null, and
+// fields of numeric and boolean columns become JSON numbers and booleans. A field that doesn't
+// parse as its column's type is kept as a string, or fails the row with `strict`.
+pub struct TypedRowBuilder {
+    pub headers: StringRecord,
+    pub strict: bool,
+}
+
+impl TypedRowBuilder {
+    pub fn build(&self, record: &StringRecord, schema: &Value) -> Result<Value> {
+        let columns = schema.get("columns").and_then(|c| c.as_object());
+        let mut row = Map::new();
+        for (i, field) in record.iter().enumerate() {
+            let name = self.headers.get(i).map_or_else(|| i.to_string(), str::to_string);
+            let kind = columns.and_then(|c| c.get(&name)).and_then(|kind| kind.as_str()).unwrap_or("TEXT");
+            let value = match field {
+                "" => Value::Null,
+                field => match TypeCoercer::coerce(&Value::String(field.to_string()), kind) {
+                    Ok(value) => value,
+                    Err(BranchDBError::TypeMismatch(message)) if self.strict => {
+                        let line = record.position().map_or_else(String::new, |p| format!("line {}, ", p.line()));
+                        return Err(BranchDBError::TypeMismatch(format!("{}column '{}': {}", line, name, message)));
+                    }
+                    Err(_) => Value::String(field.to_string()),
+                },
+            };
+            row.insert(name, value);
+        }
+        Ok(Value::Object(row))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(fields: &[&str]) -> StringRecord {
+        StringRecord::from(fields.to_vec())
+    }
+
+    #[test]
+    fn infers_each_column_type_from_the_sample() {
+        let headers = record(&["count", "price", "active", "name", "note", "mixed"]);
+        let sample = [
+            record(&["1", "2.5", "true", "ann", "", "1"]),
+            record(&["-7", "3", "FALSE", "bob", "", "1.5"]),
+            record(&["", "", "", "", "", ""]),
+        ];
+        let columns = SchemaInferencer::infer(&headers, &sample)["columns"].clone();
+        assert_eq!(columns, json!({
+            "count": "INTEGER",
+            "price": "FLOAT",
+            "active": "BOOLEAN",
+            "name": "TEXT",
+            "note": "TEXT",
+            "mixed": "FLOAT",
+        }));
+    }
+
+    #[test]
+    fn builds_typed_rows_with_nulls_for_empty_fields() {
+        let schema = json!({ "columns": { "id": "TEXT", "age": "INTEGER", "score": "FLOAT", "active": "BOOLEAN" } });
+        let builder = TypedRowBuilder { headers: record(&["id", "age", "score", "active"]), strict: false };
+        assert_eq!(
+            builder.build(&record(&["1", "42", "", "true"]), &schema).unwrap(),
+            json!({ "id": "1", "age": 42, "score": null, "active": true })
+        );
+        // Without strict, a field that doesn't parse stays text
+        assert_eq!(builder.build(&record(&["2", "old", "1.5", "no"]), &schema).unwrap()["age"], json!("old"));
+    }
+
+    #[test]
+    fn strict_rows_fail_on_a_mismatched_field() {
+        let schema = json!({ "columns": { "id": "TEXT", "age": "INTEGER" } });
+        let builder = TypedRowBuilder { headers: record(&["id", "age"]), strict: true };
+        match builder.build(&record(&["1", "old"]), &schema) {
+            Err(BranchDBError::TypeMismatch(message)) => assert!(message.contains("column 'age'"), "{}", message),
+            other => panic!("expected TypeMismatch, got {:?}", other),
+        }
+    }
+}
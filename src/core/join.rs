@@ -0,0 +1,160 @@
+use crate::error::Result;
+
+pub type QueryRow = serde_json::Map<String, serde_json::Value>;
+
+// A row produced by a join: the fields of both sides merged into one JSON object.
+// Keys are qualified with the table name or alias (`users.id`) so both sides can coexist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinedRow(pub QueryRow);
+
+impl JoinedRow {
+    pub fn merge(left: &QueryRow, right: &QueryRow) -> Self {
+        let mut row = left.clone();
+        for (key, value) in right {
+            row.insert(key.clone(), value.clone());
+        }
+        JoinedRow(row)
+    }
+
+    // Fills the given columns with NULL, for the side of an outer join that had no match.
+    pub fn null_pad(mut self, columns: &[String]) -> Self {
+        for column in columns {
+            self.0.entry(column.clone()).or_insert(serde_json::Value::Null);
+        }
+        self
+    }
+
+    pub fn into_row(self) -> QueryRow {
+        self.0
+    }
+}
+
+pub struct JoinEvaluator;
+
+impl JoinEvaluator {
+    pub fn inner_join<F>(left_rows: &[QueryRow], right_rows: &[QueryRow], predicate: F) -> Result<Vec<JoinedRow>>
+    where
+        F: Fn(&JoinedRow) -> Result<bool>,
+    {
+        let mut joined = Vec::new();
+        for left in left_rows {
+            for right in right_rows {
+                let candidate = JoinedRow::merge(left, right);
+                if predicate(&candidate)? {
+                    joined.push(candidate);
+                }
+            }
+        }
+        Ok(joined)
+    }
+
+    // Every left row appears at least once; unmatched ones get NULL for `right_columns`.
+    pub fn left_join<F>(left_rows: &[QueryRow], right_rows: &[QueryRow], right_columns: &[String], predicate: F) -> Result<Vec<JoinedRow>>
+    where
+        F: Fn(&JoinedRow) -> Result<bool>,
+    {
+        let mut joined = Vec::new();
+        for left in left_rows {
+            let mut matched = false;
+            for right in right_rows {
+                let candidate = JoinedRow::merge(left, right);
+                if predicate(&candidate)? {
+                    joined.push(candidate);
+                    matched = true;
+                }
+            }
+            if !matched {
+                joined.push(JoinedRow(left.clone()).null_pad(right_columns));
+            }
+        }
+        Ok(joined)
+    }
+
+    // LEFT JOIN with the sides swapped; left-side fields still come first in each row.
+    pub fn right_join<F>(left_rows: &[QueryRow], right_rows: &[QueryRow], left_columns: &[String], predicate: F) -> Result<Vec<JoinedRow>>
+    where
+        F: Fn(&JoinedRow) -> Result<bool>,
+    {
+        let mut joined = Vec::new();
+        for right in right_rows {
+            let mut matched = false;
+            for left in left_rows {
+                let candidate = JoinedRow::merge(left, right);
+                if predicate(&candidate)? {
+                    joined.push(candidate);
+                    matched = true;
+                }
+            }
+            if !matched {
+                let padded = JoinedRow(QueryRow::new()).null_pad(left_columns);
+                joined.push(JoinedRow::merge(&padded.0, right));
+            }
+        }
+        Ok(joined)
+    }
+
+    // Matched pairs, plus unmatched rows from either side padded with NULLs.
+    pub fn full_outer_join<F>(
+        left_rows: &[QueryRow],
+        right_rows: &[QueryRow],
+        left_columns: &[String],
+        right_columns: &[String],
+        predicate: F,
+    ) -> Result<Vec<JoinedRow>>
+    where
+        F: Fn(&JoinedRow) -> Result<bool>,
+    {
+        let mut joined = Vec::new();
+        let mut right_matched = vec![false; right_rows.len()];
+        for left in left_rows {
+            let mut matched = false;
+            for (i, right) in right_rows.iter().enumerate() {
+                let candidate = JoinedRow::merge(left, right);
+                if predicate(&candidate)? {
+                    joined.push(candidate);
+                    matched = true;
+                    right_matched[i] = true;
+                }
+            }
+            if !matched {
+                joined.push(JoinedRow(left.clone()).null_pad(right_columns));
+            }
+        }
+        for (right, matched) in right_rows.iter().zip(right_matched) {
+            if !matched {
+                let padded = JoinedRow(QueryRow::new()).null_pad(left_columns);
+                joined.push(JoinedRow::merge(&padded.0, right));
+            }
+        }
+        Ok(joined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::test_support::{rows, run, temp_repo};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn outer_joins_pad_the_side_without_a_match_with_nulls() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "CREATE TABLE orders (id TEXT, user_id TEXT, item TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+        run(&storage, "INSERT INTO orders VALUES ('a', '1', 'pen')");
+        run(&storage, "INSERT INTO orders VALUES ('b', '3', 'ink')");
+        let join = |kind: &str| rows(&storage, &format!(
+            "SELECT u.name, o.item FROM users u {} JOIN orders o ON u.id = o.user_id ORDER BY u.name, o.item", kind));
+        let pair = |name: Value, item: Value| vec![name, item];
+
+        assert_eq!(join("INNER"), vec![pair(json!("ann"), json!("pen"))]);
+        assert_eq!(join("LEFT"), vec![pair(json!("ann"), json!("pen")), pair(json!("bob"), Value::Null)]);
+        assert_eq!(join("RIGHT"), vec![pair(Value::Null, json!("ink")), pair(json!("ann"), json!("pen"))]);
+        assert_eq!(join("FULL OUTER"), vec![
+            pair(Value::Null, json!("ink")),
+            pair(json!("ann"), json!("pen")),
+            pair(json!("bob"), Value::Null),
+        ]);
+    }
+}
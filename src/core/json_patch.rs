@@ -0,0 +1,215 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::database::{encode_row_key, CommitStorage};
+use crate::core::models::Change;
+use crate::core::row;
+use crate::error::{BranchDBError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+// Written into every patch; `apply-patch` refuses other formats and newer versions.
+pub const PATCH_FORMAT: &str = "branchdb-patch";
+pub const PATCH_VERSION: u32 = 1;
+
+// A change set as `diff --format json-patch` writes it and `apply-patch` reads it. Values are
+// the stored JSON, not bincode, so other systems can read and write patches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonPatch {
+    pub format: String,
+    pub version: u32,
+    // Hex hashes of the commits the patch was taken between, for reference only
+    #[serde(default)]
+    pub from: Option<String>,
+    #[serde(default)]
+    pub to: Option<String>,
+    pub changes: Vec<PatchChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum PatchChange {
+    Insert {
+        table: String,
+        id: String,
+        #[serde(flatten)]
+        value: PatchValue,
+    },
+    Update {
+        table: String,
+        id: String,
+        #[serde(flatten)]
+        value: PatchValue,
+    },
+    Delete {
+        table: String,
+        id: String,
+    },
+}
+
+// `"value": <JSON>` for a register, `"counter": <n>` for a counter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchValue {
+    Value(serde_json::Value),
+    Counter(u64),
+}
+
+// A change whose precondition doesn't hold at HEAD, by its position in the patch from 1.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PatchProblem {
+    pub index: usize,
+    pub table: String,
+    pub id: String,
+    pub reason: String,
+}
+
+impl fmt::Display for PatchProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} {}/{}: {}", self.index, self.table, self.id, self.reason)
+    }
+}
+
+impl JsonPatch {
+    pub fn from_changes(from: &[u8; 32], to: &[u8; 32], changes: &[Change]) -> Result<Self> {
+        Ok(Self {
+            format: PATCH_FORMAT.to_string(),
+            version: PATCH_VERSION,
+            from: Some(hex::encode(from)),
+            to: Some(hex::encode(to)),
+            changes: changes.iter().map(PatchChange::from_change).collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let patch: Self = serde_json::from_str(text)
+            .map_err(|e| BranchDBError::InvalidInput(format!("Not a BranchDB JSON patch: {}", e)))?;
+        if patch.format != PATCH_FORMAT {
+            return Err(BranchDBError::InvalidInput(format!("Expected format '{}', got '{}'", PATCH_FORMAT, patch.format)));
+        }
+        if patch.version > PATCH_VERSION {
+            return Err(BranchDBError::InvalidInput(format!(
+                "Patch version {} is newer than this build supports (version {})", patch.version, PATCH_VERSION
+            )));
+        }
+        Ok(patch)
+    }
+}
+
+impl PatchChange {
+    fn from_change(change: &Change) -> Result<Self> {
+        let (table, id) = (change.table().to_string(), change.id().to_string());
+        Ok(match change {
+            Change::Insert { value, .. } => PatchChange::Insert { value: PatchValue::decode(value, &table, &id)?, table, id },
+            Change::Update { value, .. } => PatchChange::Update { value: PatchValue::decode(value, &table, &id)?, table, id },
+            Change::Delete { .. } => PatchChange::Delete { table, id },
+        })
+    }
+
+    pub fn table(&self) -> &str {
+        match self {
+            PatchChange::Insert { table, .. } | PatchChange::Update { table, .. } | PatchChange::Delete { table, .. } => table,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            PatchChange::Insert { id, .. } | PatchChange::Update { id, .. } | PatchChange::Delete { id, .. } => id,
+        }
+    }
+
+    pub fn to_change(&self) -> Result<Change> {
+        let (table, id) = (self.table().to_string(), self.id().to_string());
+        Ok(match self {
+            PatchChange::Insert { value, .. } => Change::Insert { table, id, value: value.encode()? },
+            PatchChange::Update { value, .. } => Change::Update { table, id, value: value.encode()? },
+            PatchChange::Delete { .. } => Change::Delete { table, id },
+        })
+    }
+}
+
+impl PatchValue {
+    fn decode(bytes: &[u8], table: &str, id: &str) -> Result<Self> {
+        match bincode::deserialize::<CrdtValue>(bytes)? {
+            CrdtValue::Counter(count) => Ok(PatchValue::Counter(count)),
+            CrdtValue::Register(data) => serde_json::from_slice(&data)
+                .map(PatchValue::Value)
+                .map_err(|_| BranchDBError::InvalidInput(format!(
+                    "Row '{}' of '{}' isn't stored as JSON and can't be written to a JSON patch", id, table
+                ))),
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let value = match self {
+            PatchValue::Value(json) => CrdtValue::Register(serde_json::to_vec(json)?),
+            PatchValue::Counter(count) => CrdtValue::Counter(*count),
+        };
+        Ok(bincode::serialize(&value)?)
+    }
+}
+
+impl CommitStorage {
+    // Checks every change of the patch against the live rows, in order, so a change sees the
+    // ones before it: updates and deletes need the row to exist, inserts need it not to, and
+    // an inserted or updated row has to fit the table's schema, a `!schema` earlier in the
+    // patch included. Returns the changes whose checks failed, nothing when all pass.
+    pub fn check_patch(&self, patch: &JsonPatch) -> Result<Vec<PatchProblem>> {
+        // Rows the patch has written or deleted so far, and schemas it has set
+        let mut exists: HashMap<(&str, &str), bool> = HashMap::new();
+        let mut schemas: HashMap<&str, serde_json::Value> = HashMap::new();
+        let mut problems = Vec::new();
+        for (index, change) in patch.changes.iter().enumerate() {
+            let (table, id) = (change.table(), change.id());
+            let present = match exists.get(&(table, id)) {
+                Some(&present) => present,
+                None => self.db.get(encode_row_key(table, id).as_bytes())?.is_some(),
+            };
+            let schema = match schemas.get(table) {
+                Some(schema) => schema.clone(),
+                None => self.get_table_schema(table, None)?,
+            };
+            let reason = match change {
+                PatchChange::Insert { .. } if present => Some("row already exists".to_string()),
+                PatchChange::Update { .. } | PatchChange::Delete { .. } if !present => Some("row doesn't exist".to_string()),
+                PatchChange::Insert { value: PatchValue::Value(value), .. } | PatchChange::Update { value: PatchValue::Value(value), .. }
+                    if !id.starts_with('!') => fits_schema(table, value, &schema).err(),
+                _ => None,
+            };
+            match reason {
+                Some(reason) => problems.push(PatchProblem { index: index + 1, table: table.to_string(), id: id.to_string(), reason }),
+                None => {
+                    exists.insert((table, id), !matches!(change, PatchChange::Delete { .. }));
+                    match change {
+                        PatchChange::Insert { value: PatchValue::Value(schema), .. }
+                        | PatchChange::Update { value: PatchValue::Value(schema), .. } if id == "!schema" => {
+                            schemas.insert(table, schema.clone());
+                        }
+                        PatchChange::Delete { .. } if id == "!schema" => {
+                            schemas.insert(table, serde_json::json!({}));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(problems)
+    }
+}
+
+// Objects need exactly the schema's columns; positional rows may not have more values than
+// it has columns. Tables without declared columns take any row.
+fn fits_schema(table: &str, value: &serde_json::Value, schema: &serde_json::Value) -> std::result::Result<(), String> {
+    let columns = row::schema_columns(schema);
+    match value {
+        _ if columns.is_empty() => Ok(()),
+        serde_json::Value::Object(_) => row::check_row_against_schema(table, value, schema).map_err(|e| match e {
+            BranchDBError::InvalidInput(message) | BranchDBError::TypeMismatch(message) => message,
+            other => other.to_string(),
+        }),
+        serde_json::Value::Array(values) if values.len() > columns.len() => Err(format!(
+            "row has {} values but '{}' has {} columns", values.len(), table, columns.len()
+        )),
+        serde_json::Value::Array(_) => Ok(()),
+        _ => Err("value is neither a row array nor an object".to_string()),
+    }
+}
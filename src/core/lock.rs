@@ -0,0 +1,110 @@
+use crate::error::{BranchDBError, Result};
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+// Next to RocksDB's own `LOCK`, which only says that some process has the files open.
+pub const LOCK_FILE: &str = "branchdb.lock";
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+// An advisory lock on a repository, held by one writing process at a time: an exclusive
+// `flock` on a file that stays in place. The operating system releases it when the owner
+// exits, crashed or not, so there is never a stale lock to reclaim. The file holds the owner's
+// pid, only to name it in errors. Released when dropped.
+#[derive(Debug)]
+pub struct RepoLock {
+    file: File,
+}
+
+impl RepoLock {
+    // Waits up to `timeout` for the lock.
+    pub fn acquire(data_dir: &Path, timeout: Duration) -> Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let started = Instant::now();
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => break,
+                Err(e) if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() => return Err(e.into()),
+                Err(_) if started.elapsed() >= timeout => {
+                    let pid = fs::read_to_string(&path).ok().and_then(|pid| pid.trim().parse().ok()).unwrap_or_default();
+                    return Err(BranchDBError::RepositoryLocked { pid });
+                }
+                Err(_) => {
+                    debug!(path = %path.display(), "waiting for the repository lock");
+                    thread::sleep(RETRY_INTERVAL);
+                }
+            }
+        }
+        file.set_len(0)?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::database::{CommitStorage, StorageOptions};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+
+    #[test]
+    fn second_writer_times_out_while_the_first_holds_the_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let held = RepoLock::acquire(dir.path(), Duration::ZERO).unwrap();
+        match RepoLock::acquire(dir.path(), Duration::from_millis(100)) {
+            Err(BranchDBError::RepositoryLocked { pid }) => assert_eq!(pid, std::process::id()),
+            other => panic!("expected RepositoryLocked, got {:?}", other),
+        }
+        drop(held);
+        assert!(dir.path().join(LOCK_FILE).exists());
+        RepoLock::acquire(dir.path(), Duration::ZERO).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writers_hold_the_lock_one_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let writers = 8;
+        let start = Arc::new(Barrier::new(writers));
+        let inside = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..writers)
+            .map(|_| {
+                let (path, start, inside) = (dir.path().to_path_buf(), start.clone(), inside.clone());
+                thread::spawn(move || {
+                    start.wait();
+                    let _lock = RepoLock::acquire(&path, Duration::from_secs(30)).unwrap();
+                    assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                    thread::sleep(Duration::from_millis(10));
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn second_storage_on_the_same_repository_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let _first = CommitStorage::open(path).unwrap();
+        let options = StorageOptions { lock_timeout: Duration::from_millis(100), ..StorageOptions::default() };
+        assert!(matches!(
+            CommitStorage::open_with_options(path, options),
+            Err(BranchDBError::RepositoryLocked { .. })
+        ));
+    }
+}
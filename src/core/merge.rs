@@ -1,8 +1,96 @@
-use crate::core::crdt::CrdtEngine;
+use crate::core::conflict::{MergeConflict, MergeConflicts};
+use crate::core::crdt::{CrdtEngine, CrdtValue};
+use crate::core::database::{apply_to_live, load_snapshot, CommitStorage};
 use crate::core::models::Change;
-use crate::error::Result;
+use crate::core::phonetic;
+use crate::error::{BranchDBError, Result};
+use crate::metrics::{self, Counter};
+use crate::core::namespace::WriteBatch;
+use std::collections::BTreeSet;
+use tracing::warn;
 
-pub fn merge_states(state1: &mut CrdtEngine, state2: &CrdtEngine) -> Result<Vec<Change>> {
+// How `merge_with_ancestor` ended.
+#[derive(Debug)]
+pub enum MergeResult {
+    // HEAD was behind the branch and was moved up to it
+    FastForward([u8; 32]),
+    // Merging changed nothing, so no commit was made
+    UpToDate,
+    // A merge commit was made. `conflicts` are only reported by the two-way fallback, which
+    // took the branch's side of each
+    Merged { hash: [u8; 32], conflicts: Vec<MergeConflict> },
+    // Rows both sides changed differently since the common ancestor. Nothing was committed:
+    // the other changes are staged, and `checkout --ours/--theirs` then `commit` finish the merge
+    Conflict(Vec<MergeConflict>),
+}
+
+// How `rebase_onto` ended.
+#[derive(Debug)]
+pub enum RebaseResult {
+    // The target was already in HEAD's history, so nothing changed
+    UpToDate,
+    // HEAD was behind the target and was moved up to it
+    FastForward([u8; 32]),
+    // HEAD's commits since the common ancestor were recommitted on the target, oldest first,
+    // and HEAD moved to the last of them
+    Rebased { head: [u8; 32], commits: Vec<[u8; 32]> },
+    // Rows HEAD's commits and the target changed differently since the common ancestor.
+    // Nothing was written
+    Conflict(Vec<MergeConflict>),
+}
+
+// Rows `ours` and `theirs` both changed since `base`, to different values. Unlike a merge,
+// counters conflict too, since replaying a commit writes its values as they are.
+pub(crate) fn rebase_conflicts(ours: &CrdtEngine, theirs: &CrdtEngine, base: &CrdtEngine) -> Vec<MergeConflict> {
+    let mut rows: BTreeSet<(String, String)> = BTreeSet::new();
+    for engine in [ours, theirs] {
+        for (table, table_rows) in &engine.state {
+            rows.extend(table_rows.keys().map(|id| (table.clone(), id.clone())));
+        }
+    }
+    for (table, table_rows) in &base.state {
+        rows.extend(table_rows.keys().map(|id| (table.clone(), id.clone())));
+    }
+
+    let mut conflicts = Vec::new();
+    for (table, id) in rows {
+        let get = |engine: &CrdtEngine| engine.state.get(&table).and_then(|rows| rows.get(&id)).cloned();
+        let (mine, their, original) = (get(ours), get(theirs), get(base));
+        if mine != original && their != original && mine != their {
+            conflicts.push(MergeConflict { table, id, ours: mine, theirs: their });
+        }
+    }
+    conflicts
+}
+
+// Replays the first-parent chain ending at `hash` into a fresh engine.
+pub(crate) fn load_state(storage: &CommitStorage, mut hash: Vec<u8>) -> Result<CrdtEngine> {
+    let mut history = Vec::new();
+    let mut engine = CrdtEngine::new();
+    while !hash.is_empty() {
+        if let Some(snapshot) = load_snapshot(&storage.db, &hash)? {
+            engine = snapshot;
+            break;
+        }
+        // Convert Vec<u8> to [u8; 32]
+        let hash_array: [u8; 32] = hash.as_slice().try_into()
+            .map_err(|_| BranchDBError::InvalidInput("Invalid commit hash length".into()))?;
+
+        let commit = storage.get_commit_by_hash(&hash_array)?;
+        hash = commit.parents.first().map(|p| p.to_vec()).unwrap_or_default();
+        history.push(commit);
+    }
+
+    // Oldest first so later changes win
+    for commit in history.iter().rev() {
+        for change in &commit.changes {
+            engine.apply_change(change)?;
+        }
+    }
+    Ok(engine)
+}
+
+pub(crate) fn merge_states(state1: &mut CrdtEngine, state2: &CrdtEngine) -> Result<Vec<Change>> {
     let mut changes = Vec::new();
 
     for (table, rows) in state2.state.iter() {
@@ -12,6 +100,7 @@ pub fn merge_states(state1: &mut CrdtEngine, state2: &CrdtEngine) -> Result<Vec<
             match local_rows.get(id) {
                 Some(local_val) => {
                     if local_val != value {
+                        metrics::increment(Counter::MergeConflicts, 1);
                         local_rows.insert(id.clone(), value.clone());
                         changes.push(Change::Update {
                             table: table.clone(),
@@ -33,4 +122,156 @@ pub fn merge_states(state1: &mut CrdtEngine, state2: &CrdtEngine) -> Result<Vec<
     }
 
     Ok(changes)
-}
\ No newline at end of file
+}
+// Three-way merge of `theirs` into `ours` against their common ancestor `base`. A row changed
+// on one side only takes that side's value, deletions included; a row both sides changed to
+// different values is a conflict and keeps ours. Counters add up what each side added since
+// the ancestor, so they never conflict. Returns the changes applied to `ours` and the conflicts,
+// sorted by table then id.
+pub(crate) fn merge_states_three_way(
+    ours: &mut CrdtEngine,
+    theirs: &CrdtEngine,
+    base: &CrdtEngine,
+) -> Result<(Vec<Change>, Vec<MergeConflict>)> {
+    let mut rows: BTreeSet<(String, String)> = BTreeSet::new();
+    for engine in [&*ours, theirs, base] {
+        for (table, table_rows) in &engine.state {
+            rows.extend(table_rows.keys().map(|id| (table.clone(), id.clone())));
+        }
+    }
+
+    let mut changes = Vec::new();
+    let mut conflicts = Vec::new();
+    for (table, id) in rows {
+        let get = |engine: &CrdtEngine| engine.state.get(&table).and_then(|rows| rows.get(&id)).cloned();
+        let (mine, their, original) = (get(ours), get(theirs), get(base));
+        let merged = match (&mine, &their) {
+            _ if mine == their || their == original => continue,
+            _ if mine == original => their.clone(),
+            (Some(CrdtValue::Counter(a)), Some(CrdtValue::Counter(b))) => {
+                let start = match original {
+                    Some(CrdtValue::Counter(start)) => start,
+                    _ => 0,
+                };
+                Some(CrdtValue::Counter((a + b).saturating_sub(start)))
+            }
+            _ => {
+                metrics::increment(Counter::MergeConflicts, 1);
+                conflicts.push(MergeConflict { table, id, ours: mine, theirs: their });
+                continue;
+            }
+        };
+
+        let local_rows = ours.state.entry(table.clone()).or_default();
+        match merged {
+            Some(value) => {
+                let serialized = bincode::serialize(&value)?;
+                let existed = local_rows.insert(id.clone(), value).is_some();
+                changes.push(match existed {
+                    true => Change::Update { table, id, value: serialized },
+                    false => Change::Insert { table, id, value: serialized },
+                });
+            }
+            None => {
+                local_rows.remove(&id);
+                changes.push(Change::Delete { table, id });
+            }
+        }
+    }
+    Ok((changes, conflicts))
+}
+
+impl CommitStorage {
+    // Merges a branch into HEAD. With a common ancestor this is a three-way merge that stops on
+    // conflicts, writing them to MERGE_CONFLICTS.json and staging everything else. Histories
+    // that share no commit fall back to the two-way merge, where the branch wins every
+    // conflict, with a warning. `message` is asked for the merge commit's message once its
+    // changes are known, so a fast-forward or a merge stopped by conflicts never asks.
+    pub fn merge_with_ancestor(&self, branch_name: &str, message: impl FnOnce(&[Change]) -> Result<String>) -> Result<MergeResult> {
+        let branch_head = self.db.get(format!("branch:{}", branch_name).as_bytes())?
+            .ok_or_else(|| BranchDBError::BranchNotFound { name: branch_name.to_string() })?;
+        let branch_hash: [u8; 32] = branch_head.as_slice().try_into()
+            .map_err(|_| BranchDBError::InvalidInput(format!("Branch '{}' points to an invalid commit", branch_name)))?;
+        let current_hash = self.get_head()?
+            .ok_or_else(|| BranchDBError::InvalidInput("HEAD not found".into()))?;
+
+        let path = self.conflicts_path();
+        let pending = MergeConflicts::load(&path)?;
+        if pending.merge_head.is_some() {
+            return Err(BranchDBError::InvalidInput(format!(
+                "A merge of '{}' is in progress; resolve its conflicts with `checkout --ours/--theirs` and `commit` first",
+                pending.branch
+            )));
+        }
+        if self.is_ancestor(&branch_hash, &current_hash)? {
+            return Err(BranchDBError::InvalidInput("Already up to date".into()));
+        }
+        if self.is_ancestor(&current_hash, &branch_hash)? {
+            self.move_head(&branch_hash)?;
+            return Ok(MergeResult::FastForward(branch_hash));
+        }
+
+        let mut ours = load_state(self, current_hash.to_vec())?;
+        let theirs = load_state(self, branch_hash.to_vec())?;
+        let Some(ancestor) = self.get_common_ancestor(&current_hash, &branch_hash)? else {
+            warn!(branch = branch_name, "no common ancestor; falling back to a two-way merge where the branch wins conflicts");
+            let conflicts = MergeConflicts::between(branch_name, &ours, &theirs);
+            conflicts.save(&path)?;
+            let changes = merge_states(&mut ours, &theirs)?;
+            if changes.is_empty() {
+                return Ok(MergeResult::UpToDate);
+            }
+            let hash = self.create_merge_commit(&message(&changes)?, changes, &branch_hash)?;
+            return Ok(MergeResult::Merged { hash, conflicts: conflicts.conflicts });
+        };
+
+        let base = load_state(self, ancestor.to_vec())?;
+        let (changes, conflicts) = merge_states_three_way(&mut ours, &theirs, &base)?;
+        if !conflicts.is_empty() {
+            let mut batch = WriteBatch::default();
+            for change in &changes {
+                apply_to_live(&mut batch, change)?;
+            }
+            phonetic::update_indexes(&self.db, &mut batch, &changes)?;
+            self.db.write(batch)?;
+            for change in changes {
+                self.stage_change(change)?;
+            }
+            MergeConflicts {
+                branch: branch_name.to_string(),
+                conflicts: conflicts.clone(),
+                merge_head: Some(hex::encode(branch_hash)),
+            }.save(&path)?;
+            return Ok(MergeResult::Conflict(conflicts));
+        }
+
+        // Clears conflicts left by an earlier two-way merge, as that merge's own save would
+        MergeConflicts::default().save(&path)?;
+        if changes.is_empty() {
+            return Ok(MergeResult::UpToDate);
+        }
+        let hash = self.create_merge_commit(&message(&changes)?, changes, &branch_hash)?;
+        Ok(MergeResult::Merged { hash, conflicts: Vec::new() })
+    }
+
+    // The branch head a three-way merge stopped at for conflicts, once they are all resolved.
+    // Errors while conflicts remain, since committing then would record a half-merged state.
+    pub fn pending_merge_head(&self) -> Result<Option<[u8; 32]>> {
+        let pending = MergeConflicts::load(&self.conflicts_path())?;
+        let Some(head) = pending.merge_head else { return Ok(None) };
+        if !pending.conflicts.is_empty() {
+            return Err(BranchDBError::InvalidInput(format!(
+                "{} merge conflict(s) with '{}' are unresolved; use `checkout --ours/--theirs table:id` first",
+                pending.conflicts.len(), pending.branch
+            )));
+        }
+        let hash = hex::decode(&head).ok().and_then(|raw| <[u8; 32]>::try_from(raw.as_slice()).ok())
+            .ok_or_else(|| BranchDBError::CorruptData(format!("{} names an invalid merge head", self.conflicts_path().display())))?;
+        Ok(Some(hash))
+    }
+
+    // Forgets a finished merge once its commit is made.
+    pub fn clear_pending_merge(&self) -> Result<()> {
+        MergeConflicts::default().save(&self.conflicts_path())
+    }
+}
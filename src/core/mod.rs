@@ -1,6 +1,41 @@
 pub mod models;
+pub mod attach;
+pub mod audit;
+pub mod batch;
 pub mod database;
+pub mod encryption;
+pub mod config;
+pub mod conflict;
 pub mod crdt;
+pub mod foreign_keys;
+pub mod format;
+pub mod functions;
+pub mod git_export;
+pub mod history;
+pub mod hooks;
+pub mod import;
+pub mod join;
+pub mod json_patch;
+pub mod lock;
 pub mod branch;
 pub mod merge;
-pub mod query;
\ No newline at end of file
+pub mod namespace;
+pub mod patch;
+pub mod phonetic;
+pub mod pool;
+pub mod query;
+pub mod recovery;
+pub mod refs;
+pub mod row;
+pub mod schema;
+pub mod sql;
+pub mod sql_executor;
+pub mod table_stats;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod transaction;
+pub mod trash;
+pub mod ttl;
+pub mod upsert;
+pub mod verify;
+pub mod views;
\ No newline at end of file
@@ -18,9 +18,96 @@ pub enum CrdtValue {
 pub struct Commit {
     pub parents: Vec<[u8; 32]>,
     pub message: String,
+    // Unix time in milliseconds
     pub timestamp: u64,
     pub changes: Vec<Change>,
+    // Content hash of every table that exists after the commit. Older commits only list the
+    // tables they changed.
     pub tree: HashMap<String, [u8; 32]>,
+    // Position among the repository's commits, counting up from 1, so commits made in the same
+    // millisecond are still ordered. 0 for commits written before it was recorded.
+    pub sequence: u64,
+    // Who made the commit and how. None for commits made with collection turned off, and for
+    // commits written before it was recorded.
+    #[serde(default)]
+    pub audit: Option<CommitAudit>,
+}
+
+// Where a commit came from, as far as the process making it could tell.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommitAudit {
+    // The operating system user
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    // The command line that made the commit, or the tag a library caller gave instead
+    #[serde(default)]
+    pub command: Option<String>,
+    // Free-form, such as a ticket or the job that ran the command
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+impl Commit {
+    // Sorts commits oldest first.
+    pub fn order_key(&self) -> (u64, u64) {
+        (self.timestamp, self.sequence)
+    }
+
+    pub fn timestamp_secs(&self) -> u64 {
+        self.timestamp / 1000
+    }
+}
+
+// A commit as stored before audit metadata was recorded: the current layout up to the sequence
+// number. Builds from before then read newer commits in this layout, ignoring what follows.
+#[derive(Deserialize)]
+pub(crate) struct UnauditedCommit {
+    parents: Vec<[u8; 32]>,
+    message: String,
+    timestamp: u64,
+    changes: Vec<Change>,
+    tree: HashMap<String, [u8; 32]>,
+    sequence: u64,
+}
+
+impl From<UnauditedCommit> for Commit {
+    fn from(unaudited: UnauditedCommit) -> Self {
+        Commit {
+            parents: unaudited.parents,
+            message: unaudited.message,
+            timestamp: unaudited.timestamp,
+            changes: unaudited.changes,
+            tree: unaudited.tree,
+            sequence: unaudited.sequence,
+            audit: None,
+        }
+    }
+}
+
+// A commit as stored before format version 4: no sequence number, and a timestamp in seconds.
+#[derive(Deserialize)]
+pub(crate) struct LegacyCommit {
+    parents: Vec<[u8; 32]>,
+    message: String,
+    timestamp: u64,
+    changes: Vec<Change>,
+    tree: HashMap<String, [u8; 32]>,
+}
+
+impl From<LegacyCommit> for Commit {
+    fn from(legacy: LegacyCommit) -> Self {
+        Commit {
+            parents: legacy.parents,
+            message: legacy.message,
+            timestamp: legacy.timestamp.saturating_mul(1000),
+            changes: legacy.changes,
+            tree: legacy.tree,
+            sequence: 0,
+            audit: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,4 +136,12 @@ impl Change {
             Change::Delete { table, .. } => table,
         }
     }
+
+    pub fn id(&self) -> &str {
+        match self {
+            Change::Insert { id, .. } => id,
+            Change::Update { id, .. } => id,
+            Change::Delete { id, .. } => id,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,325 @@
+use crate::core::database::{CommitStorage, TABLE_REGISTRY_PREFIX};
+use crate::core::encryption::Encryption;
+use crate::core::format;
+use crate::error::{BranchDBError, Result};
+use rocksdb::{Direction, IteratorMode, DB};
+use std::sync::Arc;
+
+// A RocksDB database as one repository sees it. A namespaced repository keeps every key under
+// `<namespace>:`, so tenants can share a database directory without seeing each other's
+// commits, branches or rows: keys are prefixed on the way in, and iterators stop at the end of
+// the namespace and hand keys back without the prefix. The repository outside any namespace
+// sees the database as it is.
+//
+// Namespace names can't contain ':', so no namespace's keys fall inside another's. Rows of the
+// repository outside any namespace are keyed `<table>:<id>` as well, so a namespace and one of
+// its tables can't share a name; whichever comes second is refused.
+//
+// Values are encrypted on the way in and decrypted on the way out when the repository is
+// encrypted; see `encryption`.
+pub struct NamespacedDb {
+    db: Arc<DB>,
+    prefix: Vec<u8>,
+    encryption: Encryption,
+}
+
+pub type KeyValue = (Box<[u8]>, Box<[u8]>);
+
+impl NamespacedDb {
+    pub fn new(db: Arc<DB>, namespace: &str) -> Self {
+        let prefix = match namespace {
+            "" => Vec::new(),
+            namespace => format!("{}:", namespace).into_bytes(),
+        };
+        Self { db, prefix, encryption: Encryption::Off }
+    }
+
+    // The same database under another namespace, with values as stored.
+    pub fn namespaced(&self, namespace: &str) -> Self {
+        Self::new(self.db.clone(), namespace)
+    }
+
+    // The same namespace, storing values as `encryption` says.
+    pub fn with_encryption(&self, encryption: Encryption) -> Self {
+        Self { db: self.db.clone(), prefix: self.prefix.clone(), encryption }
+    }
+
+    pub fn encryption(&self) -> &Encryption {
+        &self.encryption
+    }
+
+    pub fn namespace(&self) -> &str {
+        std::str::from_utf8(self.prefix.strip_suffix(b":").unwrap_or_default()).unwrap_or_default()
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        [&self.prefix[..], key].concat()
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        match self.db.get(self.key(key))? {
+            Some(stored) => Ok(Some(self.encryption.open(key, stored)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
+        let key = key.as_ref();
+        self.db.put(self.key(key), self.encryption.seal(key, value.as_ref())?)?;
+        Ok(())
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        self.db.delete(self.key(key.as_ref()))?;
+        Ok(())
+    }
+
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut prefixed = rocksdb::WriteBatch::default();
+        for (key, value) in batch.ops {
+            match value {
+                Some(value) => prefixed.put(self.key(&key), self.encryption.seal(&key, &value)?),
+                None => prefixed.delete(self.key(&key)),
+            }
+        }
+        self.db.write(prefixed)?;
+        Ok(())
+    }
+
+    // Rewrites the namespace's part of the database files, dropping overwritten and deleted
+    // values for good.
+    pub fn compact(&self) {
+        match self.prefix.split_last() {
+            // ';' follows ':', so the range ends right after the namespace
+            Some((_, name)) => self.db.compact_range(Some(&self.prefix), Some([name, b";"].concat())),
+            None => self.db.compact_range::<&[u8], &[u8]>(None, None),
+        }
+    }
+
+    // Every key of the namespace, in order.
+    pub fn iterator(&self) -> Box<dyn Iterator<Item = Result<KeyValue>> + '_> {
+        self.prefix_iterator([])
+    }
+
+    // Keys from `prefix` onwards to the end of the namespace. Like RocksDB's own, the iterator
+    // doesn't stop once keys no longer start with `prefix`. Only values under `prefix` are
+    // decrypted: callers stop at the first key past it, which outside any namespace may
+    // belong to a namespace with another key.
+    pub fn prefix_iterator<P: AsRef<[u8]>>(&self, prefix: P) -> Box<dyn Iterator<Item = Result<KeyValue>> + '_> {
+        let prefix = prefix.as_ref().to_vec();
+        let start = self.key(&prefix);
+        let items = self.db.iterator(IteratorMode::From(&start, Direction::Forward))
+            .map_while(move |item| match item {
+                Ok((key, value)) => {
+                    let key = key.strip_prefix(&self.prefix[..])?;
+                    let value = match key.starts_with(&prefix) {
+                        true => self.encryption.open(key, value.into_vec()).map(Vec::into_boxed_slice),
+                        false => Ok(value),
+                    };
+                    Some(value.map(|value| (key.into(), value)))
+                }
+                Err(e) => Some(Err(e.into())),
+            });
+        Box::new(items)
+    }
+}
+
+// Puts and deletes written together by `NamespacedDb::write`, which prefixes their keys.
+#[derive(Default)]
+pub struct WriteBatch {
+    // A None value deletes the key
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.ops.push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.ops.push((key.as_ref().to_vec(), None));
+    }
+}
+
+// Key families the repository outside any namespace keeps at `<family>:<name>`. A namespace
+// with one of these names would have its keys read as that repository's branches, views,
+// functions, attachments and phonetic indexes, and theirs as its own.
+const RESERVED_NAMES: [&str; 6] = ["branch", "protected", "view", "func", "attach", "search"];
+
+// Whether a table called `name` would have its rows at the keys of one of the families above,
+// or of the internal `!<family>:` ones, where they'd be taken for them and, in an encrypted
+// repository, be stored in plaintext.
+pub(crate) fn is_reserved_table(name: &str) -> bool {
+    RESERVED_NAMES.contains(&name) || name.starts_with('!')
+}
+
+// Refuses a name that isn't valid, is reserved, or that a table outside any namespace
+// already has.
+pub(crate) fn check_name(db: &NamespacedDb, name: &str) -> Result<()> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(BranchDBError::InvalidInput(format!(
+            "Invalid namespace '{}'; use letters, digits, '_' and '-'", name
+        )));
+    }
+    if RESERVED_NAMES.contains(&name) {
+        return Err(BranchDBError::InvalidInput(format!(
+            "'{}' is reserved for the repository's own keys; pick another namespace name", name
+        )));
+    }
+    if db.namespaced("").get(format!("{}{}", TABLE_REGISTRY_PREFIX, name))?.is_some() {
+        return Err(BranchDBError::InvalidInput(format!(
+            "'{}' is a table of the repository outside any namespace; pick another name", name
+        )));
+    }
+    Ok(())
+}
+
+// Whether the database has a namespace called `name`. Every namespace has its format version.
+pub(crate) fn is_namespace(db: &NamespacedDb, name: &str) -> Result<bool> {
+    let key = [format!("{}:", name).as_bytes(), format::FORMAT_VERSION_KEY].concat();
+    Ok(db.namespaced("").get(key)?.is_some())
+}
+
+// Every namespace of the database, found by its format version key.
+pub(crate) fn namespaces(db: &NamespacedDb) -> Result<Vec<String>> {
+    let suffix = [b":", format::FORMAT_VERSION_KEY].concat();
+    let mut names = Vec::new();
+    for item in db.namespaced("").iterator() {
+        let (key, _) = item?;
+        let Some(name) = key.strip_suffix(&suffix[..]).and_then(|name| std::str::from_utf8(name).ok()) else { continue };
+        if !name.is_empty() && !name.contains(':') {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+// Refuses to work in a namespace nobody created, so a mistyped --namespace doesn't quietly
+// start an empty repository.
+pub(crate) fn check_exists(db: &NamespacedDb, name: &str) -> Result<()> {
+    check_name(db, name)?;
+    if !is_namespace(db, name)? {
+        return Err(BranchDBError::InvalidInput(format!(
+            "Namespace '{}' doesn't exist; create it with create-namespace {}", name, name
+        )));
+    }
+    Ok(())
+}
+
+impl CommitStorage {
+    // Another namespace of the same open database, sharing its lock, for serving several
+    // tenants from one process. The namespace must have been created with `create_namespace`.
+    pub fn with_namespace(&self, namespace: &str) -> Result<Self> {
+        if !namespace.is_empty() {
+            check_exists(&self.db, namespace)?;
+        }
+        self.sibling(self.db.namespaced(namespace))
+    }
+
+    // Starts an empty namespace in this storage's database.
+    pub fn create_namespace(&self, name: &str) -> Result<()> {
+        check_name(&self.db, name)?;
+        if is_namespace(&self.db, name)? {
+            return Err(BranchDBError::InvalidInput(format!("Namespace '{}' already exists", name)));
+        }
+        format::check(&self.db.namespaced(name), true)
+    }
+
+    // Deletes every key of the namespace, returning how many there were.
+    pub fn drop_namespace(&self, name: &str) -> Result<usize> {
+        if !is_namespace(&self.db, name)? {
+            return Err(BranchDBError::InvalidInput(format!("Namespace '{}' doesn't exist", name)));
+        }
+        let namespace = self.db.namespaced(name);
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+        for item in namespace.iterator() {
+            let (key, _) = item?;
+            batch.delete(key);
+            count += 1;
+        }
+        namespace.write(batch)?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::branch::BranchManager;
+    use crate::core::database::StorageOptions;
+    use crate::core::sql_executor::SqlExecutor;
+    use crate::core::test_support::{data_path, rows, run, temp_repo};
+    use serde_json::json;
+
+    #[test]
+    fn namespaces_see_only_their_own_rows_and_branches() {
+        let (_dir, root) = temp_repo();
+        root.create_namespace("acme").unwrap();
+        let acme = root.with_namespace("acme").unwrap();
+
+        run(&root, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&root, "INSERT INTO users VALUES ('1', 'root')");
+        assert!(acme.list_tables().unwrap().is_empty());
+
+        run(&acme, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&acme, "INSERT INTO users VALUES ('2', 'acme')");
+        assert_eq!(rows(&root, "SELECT name FROM users"), vec![vec![json!("root")]]);
+        assert_eq!(rows(&acme, "SELECT name FROM users"), vec![vec![json!("acme")]]);
+
+        BranchManager::new(acme.db.clone()).create_branch("feature").unwrap();
+        assert!(!BranchManager::new(root.db.clone()).list_branches().unwrap().contains(&"feature".to_string()));
+        assert_ne!(root.get_head().unwrap(), acme.get_head().unwrap());
+    }
+
+    #[test]
+    fn root_key_families_are_reserved() {
+        let (_dir, root) = temp_repo();
+        for name in RESERVED_NAMES {
+            assert!(matches!(root.create_namespace(name), Err(BranchDBError::InvalidInput(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn namespace_cannot_share_a_root_table_name() {
+        let (_dir, root) = temp_repo();
+        run(&root, "CREATE TABLE orders (id TEXT, total INTEGER)");
+        assert!(root.create_namespace("orders").is_err());
+
+        root.create_namespace("acme").unwrap();
+        assert!(SqlExecutor::execute(&root, "CREATE TABLE acme (id TEXT, name TEXT)").is_err());
+    }
+
+    #[test]
+    fn missing_namespace_is_not_created_on_use() {
+        let (dir, root) = temp_repo();
+        assert!(root.with_namespace("acme").is_err());
+        assert!(!is_namespace(&root.db, "acme").unwrap());
+        drop(root);
+
+        let options = StorageOptions { namespace: "acme".to_string(), ..StorageOptions::default() };
+        assert!(CommitStorage::open_with_options(&data_path(&dir), options).is_err());
+        let root = CommitStorage::open(&data_path(&dir)).unwrap();
+        assert!(!is_namespace(&root.db, "acme").unwrap());
+    }
+
+    #[test]
+    fn dropping_a_namespace_leaves_the_others() {
+        let (_dir, root) = temp_repo();
+        run(&root, "CREATE TABLE users (id TEXT, name TEXT)");
+        for name in ["acme", "globex"] {
+            root.create_namespace(name).unwrap();
+            let tenant = root.with_namespace(name).unwrap();
+            run(&tenant, "CREATE TABLE users (id TEXT, name TEXT)");
+            run(&tenant, &format!("INSERT INTO users VALUES ('1', '{}')", name));
+        }
+
+        assert!(root.drop_namespace("acme").unwrap() > 0);
+        assert!(!is_namespace(&root.db, "acme").unwrap());
+        let globex = root.with_namespace("globex").unwrap();
+        assert_eq!(rows(&globex, "SELECT name FROM users"), vec![vec![json!("globex")]]);
+        assert_eq!(root.list_tables().unwrap(), vec!["users".to_string()]);
+    }
+}
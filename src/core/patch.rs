@@ -0,0 +1,255 @@
+use crate::core::database::{decode_commit, get_commit_raw, put_commit_raw, verify_commit, CommitStorage, TABLE_REGISTRY_PREFIX};
+use crate::core::models::{Change, Commit};
+use crate::error::{BranchDBError, Result};
+use crate::core::namespace::WriteBatch;
+use serde::{Deserialize, Serialize};
+
+// A commit as a compact binary patch, for moving commits between repositories:
+//
+//   "BDBP", version: u8, commit hash: [u8; 32]
+//   parent count: u16, parents: [u8; 32] each
+//   timestamp: u64, sequence: u64, message_len: u32, message
+//   tree count: u32, then per table: name_len: u16, name, hash: [u8; 32]
+//   change count: u32, then per change:
+//     operation: u8 (0 insert, 1 update, 2 delete), table_len: u16, table,
+//     id_len: u16, id, value_len: u32, value (empty for deletes)
+//   audit_len: u32, audit (since version 2)
+//
+// Integers are little-endian. Lengths are as narrow as the values allow, where bincode spends
+// a u64 on every length and a u32 on every enum tag. The audit is the stored bytes after the
+// sequence number, copied as they are: empty for commits written before audit metadata was
+// recorded, which hash without it.
+const MAGIC: &[u8; 4] = b"BDBP";
+pub const PATCH_VERSION: u8 = 2;
+
+// Operation bytes; a value taken from a stored `CrdtValue::Register` is flagged so its bincode
+// prefix can be left out and rebuilt, since it only repeats the variant and the length.
+const INSERT: u8 = 0;
+const UPDATE: u8 = 1;
+const DELETE: u8 = 2;
+const REGISTER_VALUE: u8 = 0x80;
+// bincode's `CrdtValue::Register` variant tag
+const REGISTER_TAG: [u8; 4] = [1, 0, 0, 0];
+
+// The commit laid out the way it is stored up to the audit metadata, with the tree as a list.
+// bincode writes a map and a list of pairs alike, so this reads the tree in its stored order
+// and writes back the exact bytes the commit was hashed over, which a HashMap doesn't promise.
+#[derive(Serialize, Deserialize)]
+struct StoredCommit {
+    parents: Vec<[u8; 32]>,
+    message: String,
+    timestamp: u64,
+    changes: Vec<Change>,
+    tree: Vec<(String, [u8; 32])>,
+    sequence: u64,
+}
+
+pub struct PatchEncoder;
+
+impl PatchEncoder {
+    // Encodes a commit from its stored bytes, which carry the tree order the hash depends on.
+    pub fn encode(hash: &[u8; 32], stored: &[u8]) -> Result<Vec<u8>> {
+        verify_commit(hash, stored)?;
+        let body = &stored[..stored.len() - 32];
+        let commit: StoredCommit = bincode::deserialize(body).map_err(|_| BranchDBError::InvalidInput(format!(
+            "Commit {} predates repository format version 4 and can't be written as a patch", hex::encode(hash)
+        )))?;
+        let audit = &body[bincode::serialized_size(&commit)? as usize..];
+
+        let mut out = Vec::with_capacity(body.len() / 2);
+        out.extend_from_slice(MAGIC);
+        out.push(PATCH_VERSION);
+        out.extend_from_slice(hash);
+        put_len(&mut out, commit.parents.len(), 2, "parents")?;
+        for parent in &commit.parents {
+            out.extend_from_slice(parent);
+        }
+        out.extend_from_slice(&commit.timestamp.to_le_bytes());
+        out.extend_from_slice(&commit.sequence.to_le_bytes());
+        put_bytes(&mut out, commit.message.as_bytes(), 4, "message")?;
+        put_len(&mut out, commit.tree.len(), 4, "tree")?;
+        for (table, table_hash) in &commit.tree {
+            put_bytes(&mut out, table.as_bytes(), 2, "table name")?;
+            out.extend_from_slice(table_hash);
+        }
+        put_len(&mut out, commit.changes.len(), 4, "changes")?;
+        for change in &commit.changes {
+            let (operation, value) = match change {
+                Change::Insert { value, .. } => (INSERT, value.as_slice()),
+                Change::Update { value, .. } => (UPDATE, value.as_slice()),
+                Change::Delete { .. } => (DELETE, &[][..]),
+            };
+            let (operation, value) = match register_payload(value) {
+                Some(payload) => (operation | REGISTER_VALUE, payload),
+                None => (operation, value),
+            };
+            out.push(operation);
+            put_bytes(&mut out, change.table().as_bytes(), 2, "table name")?;
+            put_bytes(&mut out, change.id().as_bytes(), 2, "row id")?;
+            put_bytes(&mut out, value, 4, "value")?;
+        }
+        put_bytes(&mut out, audit, 4, "audit")?;
+        Ok(out)
+    }
+}
+
+pub struct PatchDecoder;
+
+impl PatchDecoder {
+    pub fn decode(bytes: &[u8]) -> Result<Commit> {
+        let (_, stored) = Self::decode_stored(bytes)?;
+        decode_commit(&stored)
+    }
+
+    // The commit's hash and its bytes as they are stored, checksum included, once the rebuilt
+    // commit hashes to the hash in the header.
+    pub(crate) fn decode_stored(bytes: &[u8]) -> Result<([u8; 32], Vec<u8>)> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err(BranchDBError::InvalidInput("Not a BranchDB commit patch".into()));
+        }
+        let version = reader.take(1)?[0];
+        if version > PATCH_VERSION {
+            return Err(BranchDBError::InvalidInput(format!(
+                "Patch version {} is newer than this build supports (version {})", version, PATCH_VERSION
+            )));
+        }
+        let hash = reader.hash()?;
+        let parents = (0..reader.uint(2)?).map(|_| reader.hash()).collect::<Result<_>>()?;
+        let timestamp = reader.uint(8)?;
+        let sequence = reader.uint(8)?;
+        let message = reader.string(4)?;
+        let tree = (0..reader.uint(4)?)
+            .map(|_| Ok((reader.string(2)?, reader.hash()?)))
+            .collect::<Result<_>>()?;
+        let changes = (0..reader.uint(4)?).map(|_| reader.change()).collect::<Result<_>>()?;
+        let audit = if version >= 2 { reader.bytes(4)? } else { &[] };
+        if reader.offset != bytes.len() {
+            return Err(BranchDBError::CorruptData(format!("{} bytes after the end of the patch", bytes.len() - reader.offset)));
+        }
+
+        let commit = StoredCommit { parents, message, timestamp, changes, tree, sequence };
+        let mut stored = bincode::serialize(&commit)?;
+        stored.extend_from_slice(audit);
+        let checksum = blake3::hash(&stored);
+        if checksum.as_bytes() != &hash {
+            return Err(BranchDBError::CorruptData(format!("patch of commit {} doesn't match its hash", hex::encode(hash))));
+        }
+        stored.extend_from_slice(checksum.as_bytes());
+        Ok((hash, stored))
+    }
+}
+
+impl CommitStorage {
+    pub fn export_patch(&self, hash: &[u8; 32]) -> Result<Vec<u8>> {
+        let stored = get_commit_raw(&self.db, hash)?
+            .ok_or_else(|| BranchDBError::CommitNotFound { hash: hex::encode(hash) })?;
+        PatchEncoder::encode(hash, &stored)
+    }
+
+    // Stores the commit of a patch, like `git fetch` stores objects: neither HEAD, a branch nor
+    // the live rows move. Its parents have to be here already, so history arrives oldest
+    // first. Importing a commit that is already here does nothing.
+    pub fn import_patch(&self, bytes: &[u8]) -> Result<[u8; 32]> {
+        let (hash, stored) = PatchDecoder::decode_stored(bytes)?;
+        if get_commit_raw(&self.db, &hash)?.is_some() {
+            return Ok(hash);
+        }
+        let commit = decode_commit(&stored)?;
+        for parent in &commit.parents {
+            if get_commit_raw(&self.db, parent)?.is_none() {
+                return Err(BranchDBError::InvalidInput(format!(
+                    "Parent {} of commit {} is missing; import it first", hex::encode(parent), hex::encode(hash)
+                )));
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        put_commit_raw(&mut batch, &hash, &stored);
+        for table in commit.tree.keys() {
+            batch.put(format!("{}{}", TABLE_REGISTRY_PREFIX, table).as_bytes(), []);
+        }
+        // Commits made here afterwards still order after the imported one
+        if commit.sequence > self.last_sequence()? {
+            self.set_last_sequence(&mut batch, commit.sequence);
+        }
+        self.db.write(batch)?;
+        self.track_snapshot(&hash, commit.parents.first())?;
+        Ok(hash)
+    }
+}
+
+// The JSON of a stored `CrdtValue::Register`, if `value` is exactly one.
+fn register_payload(value: &[u8]) -> Option<&[u8]> {
+    let payload = value.strip_prefix(&REGISTER_TAG)?.get(8..)?;
+    let len = u64::from_le_bytes(value[4..12].try_into().ok()?);
+    (len == payload.len() as u64).then_some(payload)
+}
+
+fn put_len(out: &mut Vec<u8>, len: usize, width: usize, what: &str) -> Result<()> {
+    if width < 8 && len >> (width * 8) != 0 {
+        return Err(BranchDBError::InvalidInput(format!("{} is too long for a patch ({} bytes)", what, len)));
+    }
+    out.extend_from_slice(&(len as u64).to_le_bytes()[..width]);
+    Ok(())
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8], width: usize, what: &str) -> Result<()> {
+    put_len(out, bytes.len(), width, what)?;
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.offset.checked_add(len).filter(|&end| end <= self.bytes.len()).ok_or_else(|| {
+            BranchDBError::CorruptData(format!("patch ends early, at byte {} of {}", self.offset, self.bytes.len()))
+        })?;
+        let taken = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(taken)
+    }
+
+    // A little-endian unsigned integer `width` bytes wide.
+    fn uint(&mut self, width: usize) -> Result<u64> {
+        let mut buf = [0; 8];
+        buf[..width].copy_from_slice(self.take(width)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn hash(&mut self) -> Result<[u8; 32]> {
+        Ok(self.take(32)?.try_into().expect("took 32 bytes"))
+    }
+
+    fn bytes(&mut self, width: usize) -> Result<&'a [u8]> {
+        let len = self.uint(width)? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self, width: usize) -> Result<String> {
+        String::from_utf8(self.bytes(width)?.to_vec())
+            .map_err(|_| BranchDBError::CorruptData(format!("text at byte {} of the patch isn't UTF-8", self.offset)))
+    }
+
+    fn change(&mut self) -> Result<Change> {
+        let operation = self.take(1)?[0];
+        let table = self.string(2)?;
+        let id = self.string(2)?;
+        let raw = self.bytes(4)?;
+        let value = match operation & REGISTER_VALUE {
+            0 => raw.to_vec(),
+            _ => [&REGISTER_TAG[..], &(raw.len() as u64).to_le_bytes(), raw].concat(),
+        };
+        match operation & !REGISTER_VALUE {
+            INSERT => Ok(Change::Insert { table, id, value }),
+            UPDATE => Ok(Change::Update { table, id, value }),
+            DELETE => Ok(Change::Delete { table, id }),
+            other => Err(BranchDBError::CorruptData(format!("unknown change operation {} in patch", other))),
+        }
+    }
+}
@@ -0,0 +1,521 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::database::{
+    encode_row_key, escape_key_part, live_rows_by_id, table_key_prefix, table_rows, unescape_key_part, CommitStorage, TableRows,
+};
+use crate::core::models::Change;
+use crate::core::namespace::{NamespacedDb, WriteBatch};
+use crate::core::row;
+use crate::core::views::strip_keywords;
+use crate::error::{BranchDBError, Result};
+use sqlparser::ast::{BinaryOperator, Expr, Function, FunctionArg, FunctionArgExpr, Value};
+use std::collections::HashMap;
+use tracing::info;
+
+// `search:index:<table>:<column>` marks a phonetic index, with the table and column escaped
+// like a live row key. Every live row whose column holds text with letters then has an empty
+// `search:phonetic:<table>:<column>:<soundex>:<id>` key and a `search:metaphone:...` one. The
+// entries are kept in step with the live rows, so they only describe HEAD.
+const INDEX_PREFIX: &str = "search:index:";
+
+// How SOUNDEX_MATCH and METAPHONE_MATCH compare names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Soundex,
+    Metaphone,
+}
+
+const ALGORITHMS: [Algorithm; 2] = [Algorithm::Soundex, Algorithm::Metaphone];
+
+impl Algorithm {
+    // The algorithm a `<NAME>_MATCH` function compares with.
+    pub fn from_function(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("soundex_match") {
+            Some(Algorithm::Soundex)
+        } else if name.eq_ignore_ascii_case("metaphone_match") {
+            Some(Algorithm::Metaphone)
+        } else {
+            None
+        }
+    }
+
+    pub fn code(self, value: &str) -> Option<String> {
+        match self {
+            Algorithm::Soundex => soundex(value),
+            Algorithm::Metaphone => metaphone(value),
+        }
+    }
+
+    fn entry_prefix(self) -> &'static str {
+        match self {
+            Algorithm::Soundex => "search:phonetic:",
+            Algorithm::Metaphone => "search:metaphone:",
+        }
+    }
+}
+
+// American Soundex: the first letter followed by three digits for the consonant sounds after
+// it, so "Smith", "Smyth" and "Smithe" all become S530. Letters outside A-Z are ignored;
+// a value without any letters has no code.
+pub fn soundex(value: &str) -> Option<String> {
+    let mut letters = value.chars().filter(char::is_ascii_alphabetic).map(|c| c.to_ascii_uppercase());
+    let first = letters.next()?;
+    let mut code = first.to_string();
+    let mut previous = digit(first);
+    for letter in letters {
+        let current = digit(letter);
+        match current {
+            // H and W don't separate consonants with the same code
+            None if matches!(letter, 'H' | 'W') => continue,
+            Some(d) if current != previous => {
+                code.push(d);
+                if code.len() == 4 {
+                    break;
+                }
+            }
+            _ => {}
+        }
+        previous = current;
+    }
+    while code.len() < 4 {
+        code.push('0');
+    }
+    Some(code)
+}
+
+fn digit(letter: char) -> Option<char> {
+    match letter {
+        'B' | 'F' | 'P' | 'V' => Some('1'),
+        'C' | 'G' | 'J' | 'K' | 'Q' | 'S' | 'X' | 'Z' => Some('2'),
+        'D' | 'T' => Some('3'),
+        'L' => Some('4'),
+        'M' | 'N' => Some('5'),
+        'R' => Some('6'),
+        _ => None,
+    }
+}
+
+// Philips' original Metaphone, which spells out how a name is pronounced rather than grouping
+// consonants, so "Catherine" and "Kathryn" are both K0RN while "Smith" is SM0 (`0` is "th").
+// Vowels only count as the first letter. Letters outside A-Z are ignored; a value without any
+// letters has no code.
+pub fn metaphone(value: &str) -> Option<String> {
+    let word: Vec<char> = value.chars().filter(char::is_ascii_alphabetic).map(|c| c.to_ascii_uppercase()).collect();
+    let mut code = String::new();
+    // Initial letters that are silent or said differently
+    let start = match (*word.first()?, word.get(1)) {
+        ('A', Some('E')) | ('G' | 'K' | 'P', Some('N')) | ('W', Some('R')) => 1,
+        ('X', _) => {
+            code.push('S');
+            1
+        }
+        ('W', Some('H')) => {
+            code.push('W');
+            2
+        }
+        _ => 0,
+    };
+    let vowel = |c: Option<char>| matches!(c, Some('A' | 'E' | 'I' | 'O' | 'U'));
+    for i in start..word.len() {
+        let letter = word[i];
+        let prev = i.checked_sub(1).map(|p| word[p]);
+        let (next, after) = (word.get(i + 1).copied(), word.get(i + 2).copied());
+        let last = i + 1 == word.len();
+        if prev == Some(letter) && letter != 'C' {
+            continue;
+        }
+        match letter {
+            'A' | 'E' | 'I' | 'O' | 'U' if code.is_empty() && i == start => code.push(letter),
+            'A' | 'E' | 'I' | 'O' | 'U' => {}
+            // MB at the end, as in "Lamb"
+            'B' if prev == Some('M') && last => {}
+            'C' if next == Some('H') && prev == Some('S') => code.push('K'),
+            'C' if next == Some('H') || (next == Some('I') && after == Some('A')) => code.push('X'),
+            'C' if matches!(next, Some('I' | 'E' | 'Y')) => {
+                if prev != Some('S') {
+                    code.push('S');
+                }
+            }
+            'C' => code.push('K'),
+            'D' if next == Some('G') && matches!(after, Some('E' | 'I' | 'Y')) => code.push('J'),
+            'D' => code.push('T'),
+            'G' if next == Some('H') && i + 2 < word.len() && !vowel(after) => {}
+            // GN and GNED at the end, as in "Sign"
+            'G' if next == Some('N') && matches!(word[i + 2..], [] | ['E', 'D']) => {}
+            'G' if matches!(next, Some('I' | 'E' | 'Y')) => {
+                // DGE is already a J
+                if prev != Some('D') {
+                    code.push('J');
+                }
+            }
+            'G' => code.push('K'),
+            'H' if (vowel(prev) && !vowel(next)) || matches!(prev, Some('C' | 'S' | 'P' | 'T' | 'G')) => {}
+            'K' if prev == Some('C') => {}
+            'P' if next == Some('H') => code.push('F'),
+            'Q' => code.push('K'),
+            'S' if next == Some('H') || (next == Some('I') && matches!(after, Some('O' | 'A'))) => code.push('X'),
+            'T' if next == Some('I') && matches!(after, Some('O' | 'A')) => code.push('X'),
+            'T' if next == Some('H') => code.push('0'),
+            'T' if next == Some('C') && after == Some('H') => {}
+            'V' => code.push('F'),
+            'W' | 'Y' if !vowel(next) => {}
+            'X' => code.push_str("KS"),
+            'Z' => code.push('S'),
+            _ => code.push(letter),
+        }
+    }
+    Some(code)
+}
+
+// `CREATE PHONETIC INDEX ON <table>(<column>)`: the table and column.
+pub(crate) fn parse_create_index(command: &str) -> Option<Result<(String, String)>> {
+    parse_index_target(strip_keywords(command, &["CREATE", "PHONETIC", "INDEX", "ON"])?, "CREATE")
+}
+
+// `DROP PHONETIC INDEX ON <table>(<column>)`: the table and column.
+pub(crate) fn parse_drop_index(command: &str) -> Option<Result<(String, String)>> {
+    parse_index_target(strip_keywords(command, &["DROP", "PHONETIC", "INDEX", "ON"])?, "DROP")
+}
+
+fn parse_index_target(rest: &str, verb: &str) -> Option<Result<(String, String)>> {
+    let target = rest.trim_end_matches(';').trim_end();
+    let parsed = target.split_once('(')
+        .and_then(|(table, column)| Some((table.trim(), column.strip_suffix(')')?.trim())))
+        .filter(|(table, column)| !table.is_empty() && !column.is_empty() && !column.contains(','));
+    Some(parsed.map(|(table, column)| (table.to_string(), column.to_string()))
+        .ok_or_else(|| BranchDBError::InvalidInput(format!("Expected {} PHONETIC INDEX ON <table>(<column>)", verb))))
+}
+
+fn index_key(table: &str, column: &str) -> String {
+    format!("{}{}", INDEX_PREFIX, encode_row_key(table, column))
+}
+
+fn entry_prefix(algorithm: Algorithm, table: &str, column: &str) -> String {
+    format!("{}{}:", algorithm.entry_prefix(), encode_row_key(table, column))
+}
+
+// Columns of `table` with a phonetic index.
+fn indexed_columns(db: &NamespacedDb, table: &str) -> Result<Vec<String>> {
+    let prefix = format!("{}{}", INDEX_PREFIX, table_key_prefix(table));
+    let mut columns = Vec::new();
+    for item in db.prefix_iterator(prefix.as_bytes()) {
+        let (key, _) = item?;
+        let Some(column) = key.strip_prefix(prefix.as_bytes()) else { break };
+        let column = std::str::from_utf8(column).ok().and_then(unescape_key_part)
+            .ok_or_else(|| BranchDBError::CorruptData(format!("Unreadable index key {}", String::from_utf8_lossy(&key))))?;
+        columns.push(column);
+    }
+    Ok(columns)
+}
+
+fn live_schema(db: &NamespacedDb, table: &str) -> Result<serde_json::Value> {
+    match db.get(encode_row_key(table, "!schema"))? {
+        Some(raw) => Ok(serde_json::from_slice(&raw)?),
+        None => Ok(serde_json::Value::Null),
+    }
+}
+
+pub(crate) fn has_indexes(db: &NamespacedDb) -> Result<bool> {
+    match db.prefix_iterator(INDEX_PREFIX.as_bytes()).next() {
+        Some(item) => Ok(item?.0.starts_with(INDEX_PREFIX.as_bytes())),
+        None => Ok(false),
+    }
+}
+
+// The text a row holds in `column`, if any.
+fn column_text(value: &CrdtValue, schema: &serde_json::Value, column: &str) -> Option<String> {
+    let CrdtValue::Register(data) = value else { return None };
+    let row = row::row_to_object(serde_json::from_slice(data).ok()?, schema);
+    row.get(column)?.as_str().map(str::to_string)
+}
+
+fn write_entries(batch: &mut WriteBatch, table: &str, column: &str, id: &str, text: &str, put: bool) {
+    for algorithm in ALGORITHMS {
+        if let Some(code) = algorithm.code(text) {
+            let key = format!("{}{}:{}", entry_prefix(algorithm, table, column), code, escape_key_part(id));
+            if put {
+                batch.put(key.as_bytes(), []);
+            } else {
+                batch.delete(key.as_bytes());
+            }
+        }
+    }
+}
+
+// Adds the index entries that `changes` to the live rows call for to `batch`, which has to be
+// written together with those live rows. Reads the rows as they are before the batch, to
+// remove their old entries.
+pub(crate) fn update_indexes(db: &NamespacedDb, batch: &mut WriteBatch, changes: &[Change]) -> Result<()> {
+    // Per table: its indexed columns, and its schema before and after the changes
+    let mut tables: HashMap<&str, (Vec<String>, serde_json::Value, serde_json::Value)> = HashMap::new();
+    for change in changes {
+        let (table, id) = (change.table(), change.id());
+        if id.starts_with('!') {
+            continue;
+        }
+        if !tables.contains_key(table) {
+            let columns = indexed_columns(db, table)?;
+            let (before, after) = match columns.is_empty() {
+                true => Default::default(),
+                false => {
+                    let before = live_schema(db, table)?;
+                    let after = match changes.iter().rfind(|c| c.table() == table && c.id() == "!schema") {
+                        Some(Change::Insert { value, .. } | Change::Update { value, .. }) => match bincode::deserialize(value)? {
+                            CrdtValue::Register(data) => serde_json::from_slice(&data)?,
+                            CrdtValue::Counter(_) => serde_json::Value::Null,
+                        },
+                        Some(Change::Delete { .. }) => serde_json::Value::Null,
+                        None => before.clone(),
+                    };
+                    (before, after)
+                }
+            };
+            tables.insert(table, (columns, before, after));
+        }
+        let (columns, before, after) = &tables[table];
+        if columns.is_empty() {
+            continue;
+        }
+        let old: Option<CrdtValue> = db.get(encode_row_key(table, id))?.map(|raw| bincode::deserialize(&raw)).transpose()?;
+        for column in columns {
+            if let Some(text) = old.as_ref().and_then(|value| column_text(value, before, column)) {
+                write_entries(batch, table, column, id, &text, false);
+            }
+            if let Change::Insert { value, .. } | Change::Update { value, .. } = change {
+                if let Some(text) = column_text(&bincode::deserialize(value)?, after, column) {
+                    write_entries(batch, table, column, id, &text, true);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Deletes every index entry, keeping the indexes, for when the live rows are all removed.
+pub(crate) fn clear_entries(db: &NamespacedDb, batch: &mut WriteBatch) -> Result<()> {
+    for algorithm in ALGORITHMS {
+        let prefix = algorithm.entry_prefix().as_bytes();
+        for item in db.prefix_iterator(prefix) {
+            let (key, _) = item?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            batch.delete(&key);
+        }
+    }
+    Ok(())
+}
+
+fn delete_entries(db: &NamespacedDb, batch: &mut WriteBatch, table: &str, column: &str) -> Result<()> {
+    for algorithm in ALGORITHMS {
+        let prefix = entry_prefix(algorithm, table, column);
+        for item in db.prefix_iterator(prefix.as_bytes()) {
+            let (key, _) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            batch.delete(&key);
+        }
+    }
+    Ok(())
+}
+
+// The first `SOUNDEX_MATCH(column, '<text>')` or `METAPHONE_MATCH(...)` that the whole of
+// `selection` depends on, looking through ANDs: the algorithm, column and text.
+fn index_condition(selection: &Expr) -> Option<(Algorithm, String, String)> {
+    match selection {
+        Expr::Nested(inner) => index_condition(inner),
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => index_condition(left).or_else(|| index_condition(right)),
+        Expr::Function(Function { name, args, .. }) => {
+            let algorithm = Algorithm::from_function(&name.to_string())?;
+            match args.as_slice() {
+                [FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Identifier(column))), FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Value(Value::SingleQuotedString(text))))] => {
+                    Some((algorithm, column.value.clone(), text.clone()))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// The rows of `table` at `commit_hash`, in the form `table_rows` gives them, narrowed to those
+// a phonetic index lists for a match in `selection`. None when there is no such match, no index
+// on its column, or the commit isn't HEAD; the caller then reads the whole table. The rows
+// still have to be checked against `selection`.
+pub(crate) fn indexed_rows<'a>(db: &'a NamespacedDb, table: &str, commit_hash: &[u8; 32], selection: Option<&Expr>) -> Result<Option<TableRows<'a>>> {
+    let Some((algorithm, column, text)) = selection.and_then(index_condition) else { return Ok(None) };
+    if db.get(b"HEAD")?.as_deref() != Some(&commit_hash[..]) || db.get(index_key(table, &column))?.is_none() {
+        return Ok(None);
+    }
+    let mut ids = Vec::new();
+    if let Some(code) = algorithm.code(&text) {
+        let prefix = format!("{}{}:", entry_prefix(algorithm, table, &column), code);
+        for item in db.prefix_iterator(prefix.as_bytes()) {
+            let (key, _) = item?;
+            let Some(id) = key.strip_prefix(prefix.as_bytes()) else { break };
+            ids.push(std::str::from_utf8(id).ok().and_then(unescape_key_part)
+                .ok_or_else(|| BranchDBError::CorruptData(format!("Unreadable index key {}", String::from_utf8_lossy(&key))))?);
+        }
+    }
+    Ok(Some(live_rows_by_id(db, table, ids)))
+}
+
+impl CommitStorage {
+    // Indexes a column of a table at HEAD for SOUNDEX_MATCH and METAPHONE_MATCH, and returns how
+    // many rows were indexed. Indexes aren't versioned; they follow HEAD. Encrypted repositories
+    // refuse them, since index keys hold codes derived from values and keys are never encrypted.
+    pub fn create_phonetic_index(&self, table: &str, column: &str) -> Result<usize> {
+        if self.db.encryption().is_encrypted() {
+            return Err(BranchDBError::InvalidInput(
+                "Phonetic indexes aren't available in encrypted repositories: their keys would show codes of the values".into()
+            ));
+        }
+        let key = index_key(table, column);
+        if self.db.get(key.as_bytes())?.is_some() {
+            return Err(BranchDBError::InvalidInput(format!("{}({}) already has a phonetic index", table, column)));
+        }
+        let head = self.get_head()?.ok_or_else(|| BranchDBError::TableNotFound { name: table.to_string() })?;
+        let mut rows = table_rows(&self.db, table, &head)?.peekable();
+        if rows.peek().is_none() {
+            return Err(BranchDBError::TableNotFound { name: table.to_string() });
+        }
+
+        let mut batch = WriteBatch::default();
+        let mut schema = serde_json::Value::Null;
+        let mut indexed = 0;
+        for row in rows {
+            let (id, value) = row?;
+            if id == "!schema" {
+                if let CrdtValue::Register(data) = &value {
+                    schema = serde_json::from_slice(data)?;
+                }
+            } else if !id.starts_with('!') {
+                if let Some(text) = column_text(&value, &schema, column) {
+                    write_entries(&mut batch, table, column, &id, &text, true);
+                    indexed += 1;
+                }
+            }
+        }
+        batch.put(key.as_bytes(), []);
+        self.db.write(batch)?;
+        info!(table, column, rows = indexed, "created phonetic index");
+        Ok(indexed)
+    }
+
+    pub fn drop_phonetic_index(&self, table: &str, column: &str) -> Result<()> {
+        let key = index_key(table, column);
+        if self.db.get(key.as_bytes())?.is_none() {
+            return Err(BranchDBError::InvalidInput(format!("{}({}) has no phonetic index", table, column)));
+        }
+        let mut batch = WriteBatch::default();
+        delete_entries(&self.db, &mut batch, table, column)?;
+        batch.delete(key.as_bytes());
+        self.db.write(batch)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::sql_executor::SqlOutcome;
+    use crate::core::test_support::{head, rows, run, temp_repo};
+    use serde_json::json;
+
+    fn names(storage: &CommitStorage, query: &str) -> Vec<String> {
+        let mut names: Vec<String> = rows(storage, query).into_iter().map(|row| row[0].as_str().unwrap().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    fn entries(storage: &CommitStorage) -> usize {
+        ALGORITHMS.iter()
+            .map(|algorithm| {
+                let prefix = algorithm.entry_prefix().as_bytes();
+                storage.db.prefix_iterator(prefix).take_while(|item| item.as_ref().is_ok_and(|(key, _)| key.starts_with(prefix))).count()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn codes_group_names_that_sound_alike() {
+        for name in ["Smith", "Smyth", "Smithe"] {
+            assert_eq!(soundex(name).as_deref(), Some("S530"));
+            assert_eq!(metaphone(name).as_deref(), Some("SM0"));
+        }
+        for (name, code) in [("Catherine", "K0RN"), ("Kathryn", "K0RN"), ("Knight", "NT"), ("Wright", "RT"), ("Thumb", "0M"), ("Xavier", "SFR")] {
+            assert_eq!(metaphone(name).as_deref(), Some(code), "{}", name);
+        }
+        assert_eq!(soundex("Jones").as_deref(), Some("J520"));
+        assert_eq!(metaphone("123"), None);
+    }
+
+    #[test]
+    fn index_statements_parse() {
+        let parsed = parse_create_index("CREATE PHONETIC INDEX ON users (name);").unwrap().unwrap();
+        assert_eq!(parsed, ("users".to_string(), "name".to_string()));
+        assert!(parse_drop_index("drop phonetic index on users(name)").unwrap().is_ok());
+        assert!(parse_create_index("CREATE PHONETIC INDEX ON users").unwrap().is_err());
+        assert!(parse_create_index("CREATE INDEX ON users(name)").is_none());
+    }
+
+    #[test]
+    fn indexed_matches_read_the_rows_the_index_lists() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        for (id, name) in [("1", "Smith"), ("2", "Smyth"), ("3", "Smithe"), ("4", "Jones")] {
+            run(&storage, &format!("INSERT INTO users VALUES ('{}', '{}')", id, name));
+        }
+        let before = head(&storage);
+        let query = "SELECT name FROM users WHERE SOUNDEX_MATCH(name, 'Smith')";
+        let unindexed = names(&storage, query);
+        assert_eq!(unindexed, ["Smith", "Smithe", "Smyth"]);
+
+        assert!(matches!(run(&storage, "CREATE PHONETIC INDEX ON users(name)"), SqlOutcome::PhoneticIndex { rows: 4, .. }));
+        assert_eq!(entries(&storage), 8);
+        assert_eq!(names(&storage, query), unindexed);
+        assert_eq!(names(&storage, "SELECT name FROM users WHERE METAPHONE_MATCH(name, 'Smith') AND id <> '1'"), ["Smithe", "Smyth"]);
+
+        // Writes keep the entries in step with the live rows
+        run(&storage, "UPDATE users SET name = 'Jonas' WHERE id = '2'");
+        run(&storage, "DELETE FROM users WHERE id = '3'");
+        run(&storage, "INSERT INTO users VALUES ('5', 'Smitt')");
+        assert_eq!(names(&storage, query), ["Smith", "Smitt"]);
+        assert_eq!(names(&storage, "SELECT name FROM users WHERE SOUNDEX_MATCH(name, 'Jones')"), ["Jonas", "Jones"]);
+        assert_eq!(entries(&storage), 8);
+        storage.move_head(&before).unwrap();
+        assert_eq!(names(&storage, query), unindexed);
+
+        // Only the listed rows are read: an entry removed behind the index's back goes unmatched
+        let key = format!("{}S530:{}", entry_prefix(Algorithm::Soundex, "users", "name"), escape_key_part("2"));
+        storage.db.delete(key.as_bytes()).unwrap();
+        assert_eq!(names(&storage, query), ["Smith", "Smithe"]);
+
+        storage.drop_phonetic_index("users", "name").unwrap();
+        assert_eq!(entries(&storage), 0);
+        assert_eq!(names(&storage, query), unindexed);
+        assert!(storage.drop_phonetic_index("users", "name").is_err());
+        assert!(matches!(storage.create_phonetic_index("missing", "name"), Err(BranchDBError::TableNotFound { .. })));
+        assert_eq!(rows(&storage, "SELECT COUNT(*) FROM users WHERE SOUNDEX_MATCH(name, 'Smith')"), vec![vec![json!(3)]]);
+    }
+
+    #[test]
+    fn updating_the_indexed_column_moves_its_entries() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT, city TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'Smith', 'Leeds')");
+        run(&storage, "INSERT INTO users VALUES ('2', 'Jones', 'York')");
+        run(&storage, "CREATE PHONETIC INDEX ON users(name)");
+
+        run(&storage, "UPDATE users SET name = 'Catherine' WHERE id = '1'");
+        assert!(names(&storage, "SELECT name FROM users WHERE SOUNDEX_MATCH(name, 'Smith')").is_empty());
+        assert_eq!(names(&storage, "SELECT name FROM users WHERE METAPHONE_MATCH(name, 'Kathryn')"), ["Catherine"]);
+        let old = format!("{}S530:{}", entry_prefix(Algorithm::Soundex, "users", "name"), escape_key_part("1"));
+        assert!(storage.db.get(old.as_bytes()).unwrap().is_none());
+
+        // A change to another column leaves the entries alone
+        run(&storage, "UPDATE users SET city = 'Hull' WHERE id = '2'");
+        assert_eq!(names(&storage, "SELECT name FROM users WHERE SOUNDEX_MATCH(name, 'Jonas')"), ["Jones"]);
+        assert_eq!(entries(&storage), 4);
+    }
+}
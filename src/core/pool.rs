@@ -0,0 +1,82 @@
+use crate::core::database::{CommitStorage, StorageOptions};
+use crate::core::models::Change;
+use crate::error::Result;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex};
+use tracing::debug;
+
+// Shares one open repository between threads. RocksDB handles concurrent access itself, so
+// every `PooledStorage` points at the same `CommitStorage`; reads run fully in parallel while
+// commits take one of `max_concurrent_writes` write permits.
+#[derive(Clone)]
+pub struct CommitStoragePool {
+    storage: Arc<CommitStorage>,
+    writes: Arc<WritePermits>,
+}
+
+impl CommitStoragePool {
+    pub fn open(path: &str, options: StorageOptions) -> Result<Self> {
+        Ok(Self::new(CommitStorage::open_with_options(path, options)?))
+    }
+
+    pub fn new(storage: CommitStorage) -> Self {
+        let permits = storage.options().max_concurrent_writes.max(1);
+        Self {
+            storage: Arc::new(storage),
+            writes: Arc::new(WritePermits { available: Mutex::new(permits), released: Condvar::new() }),
+        }
+    }
+
+    pub fn acquire(&self) -> Result<PooledStorage> {
+        Ok(PooledStorage { storage: self.storage.clone(), writes: self.writes.clone() })
+    }
+}
+
+// A handle to the pool's storage that can be moved to another thread.
+pub struct PooledStorage {
+    storage: Arc<CommitStorage>,
+    writes: Arc<WritePermits>,
+}
+
+impl PooledStorage {
+    // Blocks until a write permit is free. Hold the guard for the whole read-modify-write.
+    pub fn write(&self) -> WriteGuard<'_> {
+        let mut available = self.writes.available.lock().unwrap_or_else(|e| e.into_inner());
+        while *available == 0 {
+            debug!("waiting for a write permit");
+            available = self.writes.released.wait(available).unwrap_or_else(|e| e.into_inner());
+        }
+        *available -= 1;
+        WriteGuard { writes: &self.writes }
+    }
+
+    // `create_commit` under a write permit.
+    pub fn commit(&self, message: &str, changes: Vec<Change>) -> Result<[u8; 32]> {
+        let _guard = self.write();
+        self.storage.create_commit(message, changes)
+    }
+}
+
+impl Deref for PooledStorage {
+    type Target = CommitStorage;
+
+    fn deref(&self) -> &CommitStorage {
+        &self.storage
+    }
+}
+
+struct WritePermits {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+pub struct WriteGuard<'a> {
+    writes: &'a WritePermits,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        *self.writes.available.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.writes.released.notify_one();
+    }
+}
@@ -1,23 +1,300 @@
 use crate::core::models::Commit;
 use crate::core::crdt::CrdtEngine;
+use crate::core::database::{load_snapshot, read_commit, table_rows};
+use crate::core::attach;
+use crate::core::functions::{self, FunctionEvaluator};
+use crate::core::trash::{self, TrashEntry, DELETED_AT_COLUMN};
+use crate::core::{phonetic, row};
 use crate::error::{BranchDBError, Result};
-use rocksdb::DB;
+use crate::metrics::{self, Counter, Histogram};
+use crate::core::namespace::NamespacedDb;
 use sqlparser::dialect::GenericDialect;
 use sqlparser::parser::Parser;
-use sqlparser::ast::{Statement, Query, SetExpr};
-use std::collections::HashMap;
+use sqlparser::ast::{
+    BinaryOperator, Expr, Function, FunctionArg, FunctionArgExpr, JoinConstraint, JoinOperator,
+    OrderByExpr, Query, Select, SelectItem, SetExpr, Statement, TableFactor, TableWithJoins,
+    UnaryOperator, Value,
+};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
 use crate::core::crdt::CrdtValue;
+use crate::core::join::{JoinEvaluator, JoinedRow, QueryRow};
+use tracing::{info_span, warn};
+
+// Rows produced by a SELECT, in output order.
+#[derive(Debug, Clone)]
+pub struct QueryResult {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+impl QueryResult {
+    // A `col | col` header followed by one line per row. An empty table still gets its header.
+    pub fn to_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if !self.columns.is_empty() {
+            lines.push(self.columns.join(" | "));
+        }
+        if self.rows.is_empty() {
+            lines.push(format!("No rows found for table '{}'.", self.table));
+        }
+        for values in &self.rows {
+            let rendered: Vec<String> = values.iter().map(render_value).collect();
+            lines.push(rendered.join(" | "));
+        }
+        lines
+    }
+
+    // One JSON object per row, keyed by output column.
+    pub fn to_json(&self) -> serde_json::Value {
+        let rows = self.rows.iter()
+            .map(|values| {
+                let row: QueryRow = self.columns.iter().cloned().zip(values.iter().cloned()).collect();
+                serde_json::Value::Object(row)
+            })
+            .collect();
+        serde_json::Value::Array(rows)
+    }
+}
+
+// Splits a trailing `WITH <commit_hash>` off a query, as in `SELECT * FROM users WITH ab12...`.
+pub(crate) fn split_commit_suffix(sql: &str) -> (&str, Option<&str>) {
+    let trimmed = sql.trim_end().trim_end_matches(';').trim_end();
+    let mut words = trimmed.rsplitn(3, char::is_whitespace);
+    if let (Some(hash), Some(with), Some(rest)) = (words.next(), words.next(), words.next()) {
+        if with.eq_ignore_ascii_case("WITH") && hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return (rest, Some(hash));
+        }
+    }
+    (sql, None)
+}
+
+// Declared schema type of each column, keyed the same way as the row keys
+type ColumnTypes = HashMap<String, String>;
+
+thread_local! {
+    // Whether a CAST that fails is an error rather than NULL, for the query running on this
+    // thread. eval_expr is also reached from joins, views and upserts, which don't carry the
+    // processor, so QueryProcessor::query sets it for the duration of the statement.
+    static STRICT_CAST: Cell<bool> = const { Cell::new(false) };
+}
+
+// Converts values to the JSON representation of a schema column type.
+pub struct TypeCoercer;
+
+impl TypeCoercer {
+    // Strings are parsed into the target type; values already of that type pass through and
+    // TEXT-like or unknown types accept anything. NULL stays NULL.
+    pub fn coerce(value: &serde_json::Value, target_type: &str) -> Result<serde_json::Value> {
+        use serde_json::Value as J;
+        let mismatch = |expected: &str| {
+            BranchDBError::TypeMismatch(format!("Expected {}, got {}", expected, render_value(value)))
+        };
+        if value.is_null() {
+            return Ok(J::Null);
+        }
+
+        let base = target_type.split('(').next().unwrap_or_default().trim().to_uppercase();
+        match base.as_str() {
+            "INTEGER" | "INT" | "BIGINT" | "SMALLINT" => match value {
+                J::Number(n) if n.is_i64() || n.is_u64() => Ok(value.clone()),
+                J::String(s) => s.trim().parse::<i64>().map(J::from).map_err(|_| mismatch("integer")),
+                _ => Err(mismatch("integer")),
+            },
+            "FLOAT" | "REAL" | "DOUBLE" | "NUMERIC" | "DECIMAL" => match value {
+                J::Number(_) => Ok(value.clone()),
+                J::String(s) => s.trim().parse::<f64>().ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(J::Number)
+                    .ok_or_else(|| mismatch("float")),
+                _ => Err(mismatch("float")),
+            },
+            "BOOLEAN" | "BOOL" => match value {
+                J::Bool(_) => Ok(value.clone()),
+                J::String(s) if s.eq_ignore_ascii_case("true") || s == "1" => Ok(J::Bool(true)),
+                J::String(s) if s.eq_ignore_ascii_case("false") || s == "0" => Ok(J::Bool(false)),
+                _ => Err(mismatch("boolean")),
+            },
+            _ => Ok(value.clone()),
+        }
+    }
+
+    // `CAST(value AS type)`: `coerce`, except that text types render any value as a string and
+    // TIMESTAMP reads a date or date-time as an RFC 3339 UTC timestamp. NULL stays NULL.
+    pub fn cast(value: &serde_json::Value, target_type: &str) -> Result<serde_json::Value> {
+        if value.is_null() {
+            return Ok(serde_json::Value::Null);
+        }
+        let base = target_type.split('(').next().unwrap_or_default().trim().to_uppercase();
+        match base.as_str() {
+            "TEXT" | "VARCHAR" | "CHAR" | "STRING" | "CHARACTER VARYING" => Ok(serde_json::Value::String(render_value(value))),
+            "TIMESTAMP" | "DATETIME" => {
+                let text = value.as_str().map(str::trim).unwrap_or_default();
+                let parsed = chrono::DateTime::parse_from_rfc3339(text).map(|dt| dt.naive_utc())
+                    .or_else(|_| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f"))
+                    .or_else(|_| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f"))
+                    .or_else(|_| chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d").map(|d| d.and_time(chrono::NaiveTime::MIN)));
+                match parsed {
+                    Ok(timestamp) => Ok(serde_json::Value::String(
+                        timestamp.and_utc().to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+                    )),
+                    Err(_) => Err(BranchDBError::TypeMismatch(format!("Expected timestamp, got {}", render_value(value)))),
+                }
+            }
+            _ => Self::coerce(value, target_type),
+        }
+    }
+
+    // A CAST in a query: a value that doesn't convert is NULL, or an error under a strict
+    // QueryProcessor. `TRY_CAST` is always NULL.
+    pub(crate) fn cast_in_query(value: &serde_json::Value, target_type: &str, may_fail: bool) -> Result<serde_json::Value> {
+        match Self::cast(value, target_type) {
+            Err(e) if may_fail && STRICT_CAST.with(Cell::get) => Err(e),
+            Err(_) => Ok(serde_json::Value::Null),
+            converted => converted,
+        }
+    }
+}
+
+// Evaluates CASE expressions against a row.
+pub struct CaseEvaluator;
+
+impl CaseEvaluator {
+    // `CASE WHEN cond THEN val ... ELSE val END` returns the value of the first true condition;
+    // `CASE operand WHEN val THEN ...` the first one equal to the operand, so a NULL operand
+    // matches nothing. Without a match the result is the ELSE value, or NULL if there is none.
+    pub fn eval(case_expr: &Expr, row: &QueryRow) -> Result<serde_json::Value> {
+        let Expr::Case { operand, conditions, results, else_result, .. } = case_expr else {
+            return Err(BranchDBError::InvalidInput(format!("Not a CASE expression: {}", case_expr)));
+        };
+        let operand = operand.as_ref().map(|o| eval_expr(o, row)).transpose()?;
+        for (condition, result) in conditions.iter().zip(results) {
+            let matched = match &operand {
+                Some(value) => compare_values(value, &eval_expr(condition, row)?) == Some(Ordering::Equal),
+                None => is_true(&eval_expr(condition, row)?),
+            };
+            if matched {
+                return eval_expr(result, row);
+            }
+        }
+        match else_result {
+            Some(otherwise) => eval_expr(otherwise, row),
+            None => Ok(serde_json::Value::Null),
+        }
+    }
+}
+
+// Evaluates the scalar functions for NULL handling against a row.
+pub struct ScalarFunctionEvaluator;
+
+impl ScalarFunctionEvaluator {
+    pub fn handles(func_name: &str) -> bool {
+        ["COALESCE", "NULLIF", "IFNULL", "IIF", "TO_INTEGER", "TO_TEXT", "TO_FLOAT"].iter()
+            .any(|f| f.eq_ignore_ascii_case(func_name))
+    }
+
+    // `COALESCE(a, b, ...)`: the first argument that isn't NULL; `IFNULL(a, b)` is COALESCE with
+    // two. `NULLIF(a, b)`: NULL if a equals b, otherwise a. `IIF(cond, a, b)`: a if cond is
+    // true, otherwise b. `TO_INTEGER(a)`, `TO_TEXT(a)` and `TO_FLOAT(a)` are CASTs to those
+    // types. Arguments are only evaluated when their value is needed.
+    pub fn eval(func_name: &str, args: &[FunctionArg], row: &QueryRow) -> Result<serde_json::Value> {
+        let name = func_name.to_uppercase();
+        let args = args.iter()
+            .map(|arg| match arg {
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => Ok(expr),
+                other => Err(BranchDBError::InvalidInput(format!("Unsupported {} argument: {}", name, other))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let arity = |expected: usize| -> Result<()> {
+            if args.len() == expected {
+                return Ok(());
+            }
+            Err(BranchDBError::InvalidInput(format!("{} expects {} arguments, got {}", name, expected, args.len())))
+        };
+
+        match name.as_str() {
+            "COALESCE" | "IFNULL" => {
+                if name == "IFNULL" {
+                    arity(2)?;
+                }
+                for arg in args {
+                    let value = eval_expr(arg, row)?;
+                    if !value.is_null() {
+                        return Ok(value);
+                    }
+                }
+                Ok(serde_json::Value::Null)
+            }
+            "NULLIF" => {
+                arity(2)?;
+                let value = eval_expr(args[0], row)?;
+                if compare_values(&value, &eval_expr(args[1], row)?) == Some(Ordering::Equal) {
+                    return Ok(serde_json::Value::Null);
+                }
+                Ok(value)
+            }
+            "IIF" => {
+                arity(3)?;
+                let chosen = if is_true(&eval_expr(args[0], row)?) { args[1] } else { args[2] };
+                eval_expr(chosen, row)
+            }
+            "TO_INTEGER" | "TO_TEXT" | "TO_FLOAT" => {
+                arity(1)?;
+                let target_type = name.trim_start_matches("TO_");
+                TypeCoercer::cast_in_query(&eval_expr(args[0], row)?, target_type, true)
+            }
+            _ => Err(BranchDBError::InvalidInput(format!("Unknown function: {}", func_name))),
+        }
+    }
+}
 
 pub struct QueryProcessor<'a> {
-    db: &'a DB
+    db: &'a NamespacedDb,
+    strict_cast: bool,
+    // Whether tables read their trashed rows too, for `INCLUDING DELETED`
+    include_deleted: bool,
 }
 
 impl<'a> QueryProcessor<'a> {
-    pub fn new(db: &'a DB) -> Self {
-        QueryProcessor { db }
+    pub fn new(db: &'a NamespacedDb) -> Self {
+        QueryProcessor { db, strict_cast: false, include_deleted: false }
+    }
+
+    // Makes a CAST that fails an error instead of NULL.
+    pub fn with_strict_cast(mut self, strict_cast: bool) -> Self {
+        self.strict_cast = strict_cast;
+        self
     }
 
     pub fn execute(&self, sql: &str) -> Result<()> {
+        for line in self.query(sql)?.to_lines() {
+            println!("{}", line);
+        }
+        Ok(())
+    }
+
+    // Runs a SELECT and returns its rows. The commit comes from a trailing `WITH <hash>`, or a
+    // CTE named after the hash (`WITH "<hash>" AS (...) SELECT ...`), and defaults to HEAD. Before
+    // the first commit there is no HEAD and the result is empty. A table exists at a commit if
+    // it has a schema or rows there, so an empty table gives its columns and no rows, and a
+    // table that doesn't exist is an error.
+    pub fn query(&self, sql: &str) -> Result<QueryResult> {
+        // A query nested in a strict one, such as a view it reads, stays strict
+        let outer = STRICT_CAST.with(|strict| strict.replace(strict.get() || self.strict_cast));
+        let result = functions::with_functions(self.db, || self.run_query(sql));
+        STRICT_CAST.with(|strict| strict.set(outer));
+        result
+    }
+
+    fn run_query(&self, sql: &str) -> Result<QueryResult> {
+        // Applies to the tables of this statement, not to the views it reads
+        let (sql, include_deleted) = trash::split_including_deleted(sql);
+        if include_deleted && !self.include_deleted {
+            return QueryProcessor { include_deleted, ..*self }.run_query(sql);
+        }
+        let (sql, suffix_hash) = split_commit_suffix(sql);
         let dialect = GenericDialect;
         let ast = Parser::parse_sql(&dialect, sql)
             .map_err(|e| BranchDBError::InvalidInput(format!("SQL parse error: {}", e)))?;
@@ -30,55 +307,313 @@ impl<'a> QueryProcessor<'a> {
             return Err(BranchDBError::InvalidInput("Only SELECT queries are supported".into()));
         };
 
-        let (table, commit_hash) = Self::extract_table_and_commit(query)?;
-        let commit = self.get_commit_by_hash(&commit_hash)?;
+        let (table, cte_hash) = Self::extract_table_and_commit(query)?;
+        let hash_bytes = match suffix_hash.map(str::to_string).or(cte_hash) {
+            Some(commit_hash) => hex::decode(&commit_hash)
+                .map_err(|_| BranchDBError::InvalidInput("Invalid hex string for commit hash".into()))?,
+            None => match self.db.get(b"HEAD")? {
+                Some(head) => head,
+                // Nothing committed yet, so every table is empty
+                None => return Ok(QueryResult { table, columns: Vec::new(), rows: Vec::new() }),
+            },
+        };
+        let SetExpr::Select(select) = &*query.body else {
+            return Err(BranchDBError::InvalidInput("Expected SELECT statement".into()));
+        };
+
+        // A plain table scan without DISTINCT or ORDER BY filters rows as they are read, so rows
+        // that don't match the WHERE clause are never held in memory
+        let from = &select.from[0];
+        let streamed = from.joins.is_empty()
+            && matches!(from.relation, TableFactor::Table { .. })
+            && !select.distinct
+            && query.order_by.is_empty();
+        if streamed {
+            let (columns, filtered) = self.scan_table(&table, &hash_bytes, select.selection.as_ref())?;
+            let (columns, rows) = Self::finish_select(select, &query.order_by, &columns, filtered)?;
+            return Ok(QueryResult { table, columns, rows });
+        }
 
-        let mut engine = CrdtEngine::new();
-        for change in &commit.changes {
-            engine.apply_change(change)?;
+        let (columns, types, rows) = self.load_from(from, &hash_bytes, false)?;
+        let (columns, rows) = Self::run_select(select, &query.order_by, &columns, &types, rows)?;
+        Ok(QueryResult { table, columns, rows })
+    }
+
+    // Evaluates a single-table SELECT: WHERE, then projection, then DISTINCT, then ORDER BY.
+    fn run_select(
+        select: &Select,
+        order_by: &[OrderByExpr],
+        columns: &[String],
+        types: &ColumnTypes,
+        rows: Vec<QueryRow>,
+    ) -> Result<(Vec<String>, Vec<Vec<serde_json::Value>>)> {
+        let mut filtered = Vec::new();
+        for row in rows {
+            if matches_selection(select.selection.as_ref(), &row, types)? {
+                filtered.push(row);
+            }
+        }
+        Self::finish_select(select, order_by, columns, filtered)
+    }
+
+    // Everything after WHERE: projection, DISTINCT and ORDER BY over the rows that matched.
+    // `columns` are the source's columns, which `*` expands to.
+    fn finish_select(
+        select: &Select,
+        order_by: &[OrderByExpr],
+        columns: &[String],
+        filtered: Vec<QueryRow>,
+    ) -> Result<(Vec<String>, Vec<Vec<serde_json::Value>>)> {
+        // Aggregate queries collapse to a single row; DISTINCT has no effect on them
+        if let Some(columns) = count_projection(&select.projection) {
+            let count = serde_json::Value::from(filtered.len());
+            let values = vec![count; columns.len()];
+            return Ok((columns, vec![values]));
         }
 
-        if let Some(rows) = engine.into_data().remove(&table) {
-            for (id, value) in rows {
-                println!("{:?}: {:?}", id, value);
+        let columns = projection_columns(&select.projection, columns, &filtered)?;
+        let mut projected: Vec<(QueryRow, Vec<serde_json::Value>)> = Vec::new();
+        for row in filtered {
+            let values = project_row(&select.projection, &columns, &row)?;
+            projected.push((row, values));
+        }
+
+        if select.distinct {
+            let mut seen = BTreeSet::new();
+            projected.retain(|(_, values)| seen.insert(serde_json::Value::Array(values.clone()).to_string()));
+        }
+
+        if !order_by.is_empty() {
+            let mut keyed = Vec::new();
+            for (row, values) in projected {
+                // ORDER BY may name either a source column or an output alias
+                let mut scope = row.clone();
+                for (name, value) in columns.iter().zip(&values) {
+                    scope.insert(name.clone(), value.clone());
+                }
+                let keys = order_by.iter()
+                    .map(|o| eval_expr(&o.expr, &scope))
+                    .collect::<Result<Vec<_>>>()?;
+                keyed.push((keys, row, values));
             }
-        } else {
-            println!("No rows found for table '{}'.", table);
+            keyed.sort_by(|(a, _, _), (b, _, _)| {
+                for ((x, y), o) in a.iter().zip(b).zip(order_by) {
+                    let ord = compare_values(x, y).unwrap_or_else(|| null_order(x, y));
+                    let ord = if o.asc == Some(false) { ord.reverse() } else { ord };
+                    if ord != Ordering::Equal {
+                        return ord;
+                    }
+                }
+                Ordering::Equal
+            });
+            projected = keyed.into_iter().map(|(_, row, values)| (row, values)).collect();
         }
 
-        Ok(())
+        Ok((columns, projected.into_iter().map(|(_, values)| values).collect()))
     }
 
-    fn extract_table_and_commit(query: &Query) -> Result<(String, String)> {
+    // Loads the FROM clause with its columns. A lone table keeps plain column names; joined
+    // tables have every key qualified with the table name or alias. `nested` is set while
+    // loading the FROM of a derived table, which may not contain another one.
+    fn load_from(&self, from: &TableWithJoins, commit_hash: &[u8], nested: bool) -> Result<(Vec<String>, ColumnTypes, Vec<QueryRow>)> {
+        let (qualifier, columns, types, rows) = self.load_relation(&from.relation, commit_hash, nested)?;
+        if from.joins.is_empty() {
+            return Ok((columns, types, rows));
+        }
+
+        let mut acc_types = qualify_types(types, &qualifier);
+        let mut acc_columns = qualify_columns(&columns, &qualifier);
+        let mut acc = qualify_rows(rows, &qualifier);
+        for join in &from.joins {
+            let (qualifier, columns, types, rows) = self.load_relation(&join.relation, commit_hash, nested)?;
+            acc_types.extend(qualify_types(types, &qualifier));
+            let right_columns = qualify_columns(&columns, &qualifier);
+            let right = qualify_rows(rows, &qualifier);
+
+            let joined = match &join.join_operator {
+                JoinOperator::Inner(constraint) => {
+                    let on = join_predicate(constraint)?;
+                    JoinEvaluator::inner_join(&acc, &right, |row| matches_on(on, row))?
+                }
+                JoinOperator::LeftOuter(constraint) => {
+                    let on = join_predicate(constraint)?;
+                    JoinEvaluator::left_join(&acc, &right, &right_columns, |row| matches_on(on, row))?
+                }
+                JoinOperator::RightOuter(constraint) => {
+                    let on = join_predicate(constraint)?;
+                    JoinEvaluator::right_join(&acc, &right, &acc_columns, |row| matches_on(on, row))?
+                }
+                JoinOperator::FullOuter(constraint) => {
+                    let on = join_predicate(constraint)?;
+                    JoinEvaluator::full_outer_join(&acc, &right, &acc_columns, &right_columns, |row| matches_on(on, row))?
+                }
+                JoinOperator::CrossJoin => JoinEvaluator::inner_join(&acc, &right, |_| Ok(true))?,
+                _ => return Err(BranchDBError::InvalidInput("Unsupported join type".into())),
+            };
+
+            acc = joined.into_iter().map(JoinedRow::into_row).collect();
+            acc_columns.extend(right_columns);
+        }
+        Ok((acc_columns, acc_types, acc))
+    }
+
+    // Loads one FROM item along with the name it is qualified by. A derived table
+    // (`(SELECT ...) AS a`) is evaluated at the same commit and its result rows are used as an
+    // in-memory table named after its alias.
+    fn load_relation(
+        &self,
+        factor: &TableFactor,
+        commit_hash: &[u8],
+        nested: bool,
+    ) -> Result<(String, Vec<String>, ColumnTypes, Vec<QueryRow>)> {
+        let TableFactor::Derived { subquery, .. } = factor else {
+            let (table, qualifier) = table_ref(factor)?;
+            let (columns, types, rows) = self.load_table(&table, commit_hash)?;
+            return Ok((qualifier, columns, types, rows));
+        };
+        if nested {
+            return Err(BranchDBError::InvalidInput("Subqueries in FROM cannot be nested".into()));
+        }
+
+        let (_, qualifier) = table_ref(factor)?;
+        let SetExpr::Select(select) = &*subquery.body else {
+            return Err(BranchDBError::InvalidInput("Expected SELECT statement in subquery".into()));
+        };
+        let from = select.from.first()
+            .ok_or_else(|| BranchDBError::InvalidInput("Missing FROM clause in subquery".into()))?;
+        let (columns, types, rows) = self.load_from(from, commit_hash, true)?;
+        let (columns, values) = Self::run_select(select, &subquery.order_by, &columns, &types, rows)?;
+        let types = types.into_iter().filter(|(name, _)| columns.contains(name)).collect();
+        let rows = values.into_iter()
+            .map(|values| columns.iter().cloned().zip(values).collect())
+            .collect();
+        Ok((qualifier, columns, types, rows))
+    }
+
+    // Loads the data rows of a table at a commit as JSON objects, ordered by row id, along with
+    // the table's columns (declared schema columns first, then any others found in the rows)
+    // and the declared type of each schema column.
+    fn load_table(&self, table: &str, commit_hash: &[u8]) -> Result<(Vec<String>, ColumnTypes, Vec<QueryRow>)> {
+        // `<alias>.<table>` of an attached repository is read there, at its own commit
+        if let Some((attachment, table)) = attach::attached_table(self.db, table)? {
+            let repo = attachment.open()?;
+            return QueryProcessor::new(&repo.storage.db).load_table(&table, &repo.commit);
+        }
+        let state = self.get_table_at_commit(table, commit_hash)?;
+        if state.is_empty() {
+            return Err(BranchDBError::TableNotFound { name: table.to_string() });
+        }
+        let (schema, types) = table_schema(state.get("!schema"));
+        let mut columns = row::schema_columns(&schema);
+
+        let mut ids: Vec<&String> = state.keys().filter(|id| !id.starts_with('!')).collect();
+        ids.sort();
+
+        let mut rows = Vec::new();
+        for id in ids {
+            let row = decode_row(&state[id], &schema)?;
+            for key in row.keys() {
+                if !columns.contains(key) {
+                    columns.push(key.clone());
+                }
+            }
+            rows.push(row);
+        }
+        if self.include_deleted {
+            let mut trashed: Vec<(String, TrashEntry)> = state.iter().filter_map(|(id, value)| trash::trash_entry(id, value)).collect();
+            trashed.sort_by(|a, b| a.0.cmp(&b.0));
+            rows.extend(trashed.into_iter().map(|(_, entry)| trashed_row(entry, &schema)));
+            columns.push(DELETED_AT_COLUMN.to_string());
+        }
+        Ok((columns, types, rows))
+    }
+
+    // Reads a table at a commit one row at a time through `table_rows`, keeping only
+    // the rows that match `selection`. Returns the table's columns, as `load_table` does, and
+    // the matching rows ordered by row id.
+    fn scan_table(&self, table: &str, commit_hash: &[u8], selection: Option<&Expr>) -> Result<(Vec<String>, Vec<QueryRow>)> {
+        if let Some((attachment, table)) = attach::attached_table(self.db, table)? {
+            let repo = attachment.open()?;
+            return QueryProcessor::new(&repo.storage.db).scan_table(&table, &repo.commit, selection);
+        }
+        let hash: [u8; 32] = commit_hash.try_into()
+            .map_err(|_| BranchDBError::InvalidInput("Invalid commit hash length".into()))?;
+        // A phonetic index narrows a SOUNDEX_MATCH or METAPHONE_MATCH at HEAD to the rows it lists
+        let entries = match phonetic::indexed_rows(self.db, table, &hash, selection)? {
+            Some(rows) => rows,
+            None => table_rows(self.db, table, &hash)?,
+        };
+        let mut entries = entries.peekable();
+        if entries.peek().is_none() {
+            return Err(BranchDBError::TableNotFound { name: table.to_string() });
+        }
+
+        // Metadata keys come first, so the schema is known before any row is decoded
+        let mut schema_value = None;
+        let mut trashed = Vec::new();
+        while let Some(entry) = entries.next_if(|entry| matches!(entry, Ok((id, _)) if id.starts_with('!'))) {
+            let (id, value) = entry?;
+            if id == "!schema" {
+                schema_value = Some(value);
+            } else if let Some((_, entry)) = trash::trash_entry(&id, &value).filter(|_| self.include_deleted) {
+                trashed.push(entry);
+            }
+        }
+        let (schema, types) = table_schema(schema_value.as_ref());
+        let mut columns = row::schema_columns(&schema);
+
+        let mut matched = Vec::new();
+        for entry in entries {
+            let (_, value) = entry?;
+            let row = decode_row(&value, &schema)?;
+            for key in row.keys() {
+                if !columns.contains(key) {
+                    columns.push(key.clone());
+                }
+            }
+            if matches_selection(selection, &row, &types)? {
+                matched.push(row);
+            }
+        }
+        if self.include_deleted {
+            for entry in trashed {
+                let row = trashed_row(entry, &schema);
+                if matches_selection(selection, &row, &types)? {
+                    matched.push(row);
+                }
+            }
+            columns.push(DELETED_AT_COLUMN.to_string());
+        }
+        Ok((columns, matched))
+    }
+
+    fn extract_table_and_commit(query: &Query) -> Result<(String, Option<String>)> {
         let SetExpr::Select(select) = &*query.body else {
             return Err(BranchDBError::InvalidInput("Expected SELECT statement".into()));
         };
 
-        let from = select.from.get(0)
+        let from = select.from.first()
             .ok_or_else(|| BranchDBError::InvalidInput("Missing FROM clause".into()))?;
 
-        let table_name = from.relation.to_string();
+        let (table_name, _) = table_ref(&from.relation)?;
 
         let Some(with) = &query.with else {
-            return Err(BranchDBError::InvalidInput("Missing WITH clause".into()));
+            return Ok((table_name, None));
         };
 
-        let cte = with.cte_tables.get(0)
+        let cte = with.cte_tables.first()
             .ok_or_else(|| BranchDBError::InvalidInput("Missing CTE in WITH clause".into()))?;
 
-        let commit_hash = cte.alias.name.to_string();
-        Ok((table_name, commit_hash))
+        let commit_hash = cte.alias.name.value.clone();
+        Ok((table_name, Some(commit_hash)))
     }
 
     fn get_commit_by_hash(&self, hex_hash: &str) -> Result<Commit> {
         let hash_bytes = hex::decode(hex_hash)
             .map_err(|_| BranchDBError::InvalidInput("Invalid hex string for commit hash".into()))?;
 
-        let raw = self.db.get(&hash_bytes).map_err(|e| BranchDBError::StorageError(e.to_string()))?
-            .ok_or_else(|| BranchDBError::InvalidInput("Commit not found".into()))?;
-
-        let commit: Commit = bincode::deserialize(&raw)?;
-        Ok(commit)
+        read_commit(self.db, &hash_bytes)?
+            .ok_or_else(|| BranchDBError::CommitNotFound { hash: hex_hash.to_string() })
     }
 
     pub fn get_table_at_commit(&self, table: &str, commit_hash: &[u8]) -> Result<HashMap<String, CrdtValue>> {
@@ -86,36 +621,616 @@ impl<'a> QueryProcessor<'a> {
         if commit_hash.is_empty() {
             return Err(BranchDBError::InvalidInput("Empty commit hash".into()));
         }
-    
+
+        let _span = info_span!("history_walk", table, commit = %hex::encode(commit_hash)).entered();
         let mut engine = CrdtEngine::new();
         let mut current_hash = commit_hash.to_vec();
-        
+        let mut history = Vec::new();
+
         while !current_hash.is_empty() {
+            match load_snapshot(self.db, &current_hash) {
+                Ok(Some(snapshot)) => {
+                    engine = snapshot;
+                    break;
+                }
+                Ok(None) => {}
+                // Without the key nothing else would load either
+                Err(e @ BranchDBError::EncryptionError(_)) => return Err(e),
+                Err(e) => warn!(commit = %hex::encode(&current_hash), error = %e, "failed to load snapshot, replaying history"),
+            }
             let commit = match self.get_commit_by_hash(&hex::encode(&current_hash)) {
                 Ok(c) => c,
+                Err(e @ BranchDBError::EncryptionError(_)) => return Err(e),
                 Err(e) => {
-                    eprintln!("Failed to load commit {}: {}", hex::encode(&current_hash), e);
+                    warn!(commit = %hex::encode(&current_hash), error = %e, "failed to load commit, history truncated");
                     break;
                 }
             };
-            
-            for change in commit.changes.iter().rev() {
+            current_hash = commit.parents.first().map(|p| p.to_vec()).unwrap_or_default();
+            history.push(commit);
+        }
+
+        metrics::observe(Histogram::HistoryWalkDepth, history.len() as f64);
+
+        // Replay oldest first so later changes win
+        for commit in history.iter().rev() {
+            for change in &commit.changes {
                 if change.table() == table {
                     if let Err(e) = engine.apply_change(change) {
-                        eprintln!("Warning: Failed to apply change: {}", e);
+                        warn!(error = %e, "failed to apply change");
                     }
                 }
             }
-            
-            current_hash = commit.parents.get(0).map(|p| p.to_vec()).unwrap_or_default();
         }
-        
-        Ok(engine.state.get(table).cloned().unwrap_or_default())
+
+        let rows = engine.state.get(table).cloned().unwrap_or_default();
+        metrics::increment(Counter::RowsRead, rows.len() as u64);
+        Ok(rows)
     }
 
     pub fn get_head_hash(&self) -> Result<Vec<u8>> {
-        self.db.get(b"HEAD")
-            .map_err(|e| BranchDBError::StorageError(e.to_string()))?  // Convert error to string
+        self.db.get(b"HEAD")?
             .ok_or_else(|| BranchDBError::InvalidInput("No HEAD commit".into()))
     }
-}
\ No newline at end of file
+}
+
+// Output column names for a projection. `*` expands to the source's columns, then any other
+// column seen in the rows, in first-seen order.
+fn projection_columns(projection: &[SelectItem], source: &[String], rows: &[QueryRow]) -> Result<Vec<String>> {
+    let mut columns = Vec::new();
+    for item in projection {
+        match item {
+            SelectItem::Wildcard { .. } | SelectItem::QualifiedWildcard { .. } => {
+                for key in source {
+                    if !columns.contains(key) {
+                        columns.push(key.clone());
+                    }
+                }
+                for row in rows {
+                    for key in row.keys() {
+                        if !columns.contains(key) {
+                            columns.push(key.clone());
+                        }
+                    }
+                }
+            }
+            SelectItem::UnnamedExpr(expr) => columns.push(expr_name(expr)),
+            SelectItem::ExprWithAlias { alias, .. } => columns.push(alias.value.clone()),
+        }
+    }
+    Ok(columns)
+}
+
+fn project_row(projection: &[SelectItem], columns: &[String], row: &QueryRow) -> Result<Vec<serde_json::Value>> {
+    let mut values = Vec::with_capacity(columns.len());
+    for item in projection {
+        match item {
+            SelectItem::Wildcard { .. } | SelectItem::QualifiedWildcard { .. } => {
+                // Wildcards cover every column that isn't produced by another item
+                let named = projection.iter().filter(|i| !matches!(i, SelectItem::Wildcard { .. } | SelectItem::QualifiedWildcard { .. })).count();
+                for column in columns.iter().take(columns.len() - named) {
+                    values.push(row.get(column).cloned().unwrap_or(serde_json::Value::Null));
+                }
+            }
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                values.push(eval_expr(expr, row)?);
+            }
+        }
+    }
+    Ok(values)
+}
+
+// Recognizes projections made only of COUNT(*) / COUNT(col) and returns their output names.
+fn count_projection(projection: &[SelectItem]) -> Option<Vec<String>> {
+    let mut names = Vec::new();
+    for item in projection {
+        let (expr, alias) = match item {
+            SelectItem::UnnamedExpr(expr) => (expr, None),
+            SelectItem::ExprWithAlias { expr, alias } => (expr, Some(alias.value.clone())),
+            _ => return None,
+        };
+        let Expr::Function(Function { name, args, .. }) = expr else {
+            return None;
+        };
+        if !name.to_string().eq_ignore_ascii_case("count") || args.len() != 1 {
+            return None;
+        }
+        names.push(alias.unwrap_or_else(|| expr.to_string()));
+    }
+    if names.is_empty() { None } else { Some(names) }
+}
+
+// Table name and the qualifier its columns are known by: the alias if one was given, else the
+// name without the attachment it is read from, so `ref.countries` is qualified `countries`.
+fn table_ref(factor: &TableFactor) -> Result<(String, String)> {
+    match factor {
+        TableFactor::Table { name, alias, .. } => {
+            let table = name.to_string();
+            let qualifier = match (alias, name.0.last()) {
+                (Some(alias), _) => alias.name.value.clone(),
+                (None, Some(last)) => last.value.clone(),
+                (None, None) => table.clone(),
+            };
+            Ok((table, qualifier))
+        }
+        // A derived table is only known by its alias
+        TableFactor::Derived { alias, .. } => {
+            let alias = alias.as_ref()
+                .ok_or_else(|| BranchDBError::InvalidInput("Subquery in FROM must have an alias".into()))?;
+            Ok((alias.name.value.clone(), alias.name.value.clone()))
+        }
+        other => Err(BranchDBError::InvalidInput(format!("Unsupported table reference: {}", other))),
+    }
+}
+
+fn qualify_columns(columns: &[String], qualifier: &str) -> Vec<String> {
+    columns.iter().map(|c| format!("{}.{}", qualifier, c)).collect()
+}
+
+fn qualify_types(types: ColumnTypes, qualifier: &str) -> ColumnTypes {
+    types.into_iter().map(|(k, v)| (format!("{}.{}", qualifier, k), v)).collect()
+}
+
+fn qualify_rows(rows: Vec<QueryRow>, qualifier: &str) -> Vec<QueryRow> {
+    rows.into_iter()
+        .map(|row| row.into_iter().map(|(k, v)| (format!("{}.{}", qualifier, k), v)).collect())
+        .collect()
+}
+
+fn join_predicate(constraint: &JoinConstraint) -> Result<Option<&Expr>> {
+    match constraint {
+        JoinConstraint::On(expr) => Ok(Some(expr)),
+        JoinConstraint::None => Ok(None),
+        _ => Err(BranchDBError::InvalidInput("Only JOIN ... ON is supported".into())),
+    }
+}
+
+fn matches_on(on: Option<&Expr>, row: &JoinedRow) -> Result<bool> {
+    match on {
+        Some(expr) => Ok(is_true(&eval_expr(expr, &row.0)?)),
+        None => Ok(true),
+    }
+}
+
+// Resolves a column reference. Exact keys win; otherwise an unqualified name matches the
+// single qualified key ending in `.name`, and a qualified name falls back to its last part.
+fn lookup_column(row: &QueryRow, parts: &[String]) -> serde_json::Value {
+    let full = parts.join(".");
+    if let Some(value) = row.get(&full) {
+        return value.clone();
+    }
+    let Some(last) = parts.last() else {
+        return serde_json::Value::Null;
+    };
+    if parts.len() > 1 {
+        return row.get(last).cloned().unwrap_or(serde_json::Value::Null);
+    }
+    let suffix = format!(".{}", last);
+    let mut matches = row.iter().filter(|(k, _)| k.ends_with(&suffix));
+    match (matches.next(), matches.next()) {
+        (Some((_, value)), None) => value.clone(),
+        _ => serde_json::Value::Null,
+    }
+}
+
+// Schema type of a column reference, resolved like `lookup_column`.
+fn column_type<'t>(types: &'t ColumnTypes, expr: &Expr) -> Option<&'t str> {
+    let parts: Vec<String> = match expr {
+        Expr::Identifier(ident) => vec![ident.value.clone()],
+        Expr::CompoundIdentifier(parts) => parts.iter().map(|p| p.value.clone()).collect(),
+        _ => return None,
+    };
+    if let Some(ty) = types.get(&parts.join(".")) {
+        return Some(ty);
+    }
+    let last = parts.last()?;
+    if parts.len() > 1 {
+        return types.get(last).map(String::as_str);
+    }
+    let suffix = format!(".{}", last);
+    let mut matches = types.iter().filter(|(k, _)| k.ends_with(&suffix));
+    match (matches.next(), matches.next()) {
+        (Some((_, ty)), None) => Some(ty),
+        _ => None,
+    }
+}
+
+// WHERE evaluation: like `eval_expr`, except that string operands of a comparison involving a
+// typed column are coerced to that column's schema type first, so `age = '42'` matches 42.
+// Strings that don't convert are compared as they are.
+fn eval_predicate(expr: &Expr, row: &QueryRow, types: &ColumnTypes) -> Result<serde_json::Value> {
+    match expr {
+        Expr::Nested(inner) => eval_predicate(inner, row, types),
+        Expr::UnaryOp { op: UnaryOperator::Not, expr } => {
+            Ok(serde_json::Value::Bool(!is_true(&eval_predicate(expr, row, types)?)))
+        }
+        Expr::BinaryOp { left, op: op @ (BinaryOperator::And | BinaryOperator::Or), right } => {
+            eval_binary(&eval_predicate(left, row, types)?, op, &eval_predicate(right, row, types)?)
+        }
+        Expr::BinaryOp { left: l, op, right: r } => {
+            let mut left = eval_expr(l, row)?;
+            let mut right = eval_expr(r, row)?;
+            if let Some(ty) = column_type(types, l).or_else(|| column_type(types, r)) {
+                for value in [&mut left, &mut right] {
+                    if value.is_string() {
+                        if let Ok(coerced) = TypeCoercer::coerce(value, ty) {
+                            *value = coerced;
+                        }
+                    }
+                }
+            }
+            eval_binary(&left, op, &right)
+        }
+        other => eval_expr(other, row),
+    }
+}
+
+fn expr_name(expr: &Expr) -> String {
+    match expr {
+        Expr::Identifier(ident) => ident.value.clone(),
+        Expr::CompoundIdentifier(parts) => parts.last().map(|p| p.value.clone()).unwrap_or_default(),
+        other => other.to_string(),
+    }
+}
+
+pub(crate) fn eval_expr(expr: &Expr, row: &QueryRow) -> Result<serde_json::Value> {
+    match expr {
+        Expr::Identifier(ident) => Ok(lookup_column(row, std::slice::from_ref(&ident.value))),
+        Expr::CompoundIdentifier(parts) => {
+            let parts: Vec<String> = parts.iter().map(|p| p.value.clone()).collect();
+            Ok(lookup_column(row, &parts))
+        }
+        // Outside a stored function's body nothing is bound to `$1`, `$2`, ...
+        Expr::Value(Value::Placeholder(name)) => row.get(name).cloned()
+            .ok_or_else(|| BranchDBError::InvalidInput(format!("Parameter {} has no argument", name))),
+        Expr::Value(value) => literal_value(value),
+        Expr::Nested(inner) => eval_expr(inner, row),
+        Expr::IsNull(inner) => Ok(serde_json::Value::Bool(eval_expr(inner, row)?.is_null())),
+        Expr::IsNotNull(inner) => Ok(serde_json::Value::Bool(!eval_expr(inner, row)?.is_null())),
+        Expr::UnaryOp { op: UnaryOperator::Not, expr } => {
+            Ok(serde_json::Value::Bool(!is_true(&eval_expr(expr, row)?)))
+        }
+        Expr::UnaryOp { op: UnaryOperator::Minus, expr } => {
+            match eval_expr(expr, row)?.as_f64() {
+                Some(n) => Ok(number_value(-n)),
+                None => Err(BranchDBError::TypeMismatch(format!("Cannot negate {}", expr))),
+            }
+        }
+        Expr::BinaryOp { left, op, right } => {
+            let left = eval_expr(left, row)?;
+            let right = eval_expr(right, row)?;
+            eval_binary(&left, op, &right)
+        }
+        Expr::Case { .. } => CaseEvaluator::eval(expr, row),
+        Expr::Cast { expr: inner, data_type, .. } => {
+            TypeCoercer::cast_in_query(&eval_expr(inner, row)?, &data_type.to_string(), true)
+        }
+        Expr::TryCast { expr: inner, data_type, .. } => {
+            TypeCoercer::cast_in_query(&eval_expr(inner, row)?, &data_type.to_string(), false)
+        }
+        Expr::Function(Function { name, args, .. }) if ScalarFunctionEvaluator::handles(&name.to_string()) => {
+            ScalarFunctionEvaluator::eval(&name.to_string(), args, row)
+        }
+        Expr::Function(Function { name, args, .. }) if name.to_string().eq_ignore_ascii_case("count") => {
+            // COUNT outside of a pure aggregate projection counts the current row
+            let counted = match args.first() {
+                Some(FunctionArg::Unnamed(FunctionArgExpr::Expr(e))) => !eval_expr(e, row)?.is_null(),
+                _ => true,
+            };
+            Ok(serde_json::Value::from(counted as u64))
+        }
+        Expr::Function(Function { name, args, .. }) if phonetic::Algorithm::from_function(&name.to_string()).is_some() => {
+            // SOUNDEX_MATCH(col, 'name') and METAPHONE_MATCH(col, 'name') are true when both
+            // sound alike; NULLs and values without letters never match
+            let algorithm = phonetic::Algorithm::from_function(&name.to_string()).expect("checked by the guard");
+            let [FunctionArg::Unnamed(FunctionArgExpr::Expr(left)), FunctionArg::Unnamed(FunctionArgExpr::Expr(right))] = args.as_slice() else {
+                return Err(BranchDBError::InvalidInput(format!("{} expects two arguments", name.to_string().to_uppercase())));
+            };
+            let code = |value: serde_json::Value| match value {
+                serde_json::Value::String(s) => algorithm.code(&s),
+                _ => None,
+            };
+            let matched = match (code(eval_expr(left, row)?), code(eval_expr(right, row)?)) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            };
+            Ok(serde_json::Value::Bool(matched))
+        }
+        Expr::Function(Function { name, args, .. }) if FunctionEvaluator::defines(&name.to_string()) => {
+            let args = args.iter()
+                .map(|arg| match arg {
+                    FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => eval_expr(expr, row),
+                    other => Err(BranchDBError::InvalidInput(format!("Unsupported {} argument: {}", name, other))),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            FunctionEvaluator::call(&name.to_string(), &args, row)
+        }
+        Expr::Function(Function { name, .. }) => Err(BranchDBError::InvalidInput(format!("Unknown function: {}", name))),
+        other => Err(BranchDBError::InvalidInput(format!("Unsupported expression: {}", other))),
+    }
+}
+
+fn eval_binary(left: &serde_json::Value, op: &BinaryOperator, right: &serde_json::Value) -> Result<serde_json::Value> {
+    let result = match op {
+        BinaryOperator::And => is_true(left) && is_true(right),
+        BinaryOperator::Or => is_true(left) || is_true(right),
+        BinaryOperator::Eq => compare_values(left, right) == Some(Ordering::Equal),
+        BinaryOperator::NotEq => matches!(compare_values(left, right), Some(o) if o != Ordering::Equal),
+        BinaryOperator::Lt => compare_values(left, right) == Some(Ordering::Less),
+        BinaryOperator::LtEq => matches!(compare_values(left, right), Some(Ordering::Less | Ordering::Equal)),
+        BinaryOperator::Gt => compare_values(left, right) == Some(Ordering::Greater),
+        BinaryOperator::GtEq => matches!(compare_values(left, right), Some(Ordering::Greater | Ordering::Equal)),
+        // `a || b` joins the text of both values; NULL on either side gives NULL
+        BinaryOperator::StringConcat if left.is_null() || right.is_null() => return Ok(serde_json::Value::Null),
+        BinaryOperator::StringConcat => {
+            return Ok(serde_json::Value::String(render_value(left) + &render_value(right)));
+        }
+        other => return Err(BranchDBError::InvalidInput(format!("Unsupported operator: {}", other))),
+    };
+    Ok(serde_json::Value::Bool(result))
+}
+
+fn literal_value(value: &Value) -> Result<serde_json::Value> {
+    match value {
+        Value::Number(n, _) => n.parse::<f64>()
+            .map(number_value)
+            .map_err(|_| BranchDBError::InvalidInput(format!("Invalid number literal: {}", n))),
+        Value::SingleQuotedString(s) | Value::DoubleQuotedString(s) => Ok(serde_json::Value::String(s.clone())),
+        Value::Boolean(b) => Ok(serde_json::Value::Bool(*b)),
+        Value::Null => Ok(serde_json::Value::Null),
+        other => Err(BranchDBError::InvalidInput(format!("Unsupported literal: {}", other))),
+    }
+}
+
+fn number_value(n: f64) -> serde_json::Value {
+    if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+        serde_json::Value::from(n as i64)
+    } else {
+        serde_json::Value::from(n)
+    }
+}
+
+fn as_number(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+// Compares two values, treating numeric strings as numbers. NULL compares as unknown.
+fn compare_values(left: &serde_json::Value, right: &serde_json::Value) -> Option<Ordering> {
+    use serde_json::Value as J;
+    match (left, right) {
+        (J::Null, _) | (_, J::Null) => None,
+        (J::Bool(a), J::Bool(b)) => Some(a.cmp(b)),
+        (J::String(a), J::String(b)) => match (as_number(left), as_number(right)) {
+            (Some(x), Some(y)) => x.partial_cmp(&y),
+            _ => Some(a.cmp(b)),
+        },
+        _ => match (as_number(left), as_number(right)) {
+            (Some(x), Some(y)) => x.partial_cmp(&y),
+            _ => Some(render_value(left).cmp(&render_value(right))),
+        },
+    }
+}
+
+// NULLs sort first when ordering.
+fn null_order(left: &serde_json::Value, right: &serde_json::Value) -> Ordering {
+    match (left.is_null(), right.is_null()) {
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        _ => Ordering::Equal,
+    }
+}
+
+// A table's declared schema, or `{}` without one, and the declared type of each of its columns.
+fn table_schema(value: Option<&CrdtValue>) -> (serde_json::Value, ColumnTypes) {
+    let schema = match value {
+        Some(CrdtValue::Register(data)) => serde_json::from_slice(data).unwrap_or_else(|_| serde_json::json!({})),
+        _ => serde_json::json!({}),
+    };
+    let types: ColumnTypes = schema.get("columns")
+        .and_then(|c| c.as_object())
+        .map(|cols| cols.iter()
+            .filter_map(|(name, ty)| Some((name.clone(), ty.as_str()?.to_string())))
+            .collect())
+        .unwrap_or_default();
+    (schema, types)
+}
+
+// A stored row as a JSON object. Values that aren't objects, and counters, become a single
+// `value` column.
+// A trashed row as `INCLUDING DELETED` reads it, after the live rows and with the time it
+// was deleted in `deleted_at`, which is NULL for live rows.
+fn trashed_row(entry: TrashEntry, schema: &serde_json::Value) -> QueryRow {
+    let mut row = match row::row_to_object(entry.row, schema) {
+        serde_json::Value::Object(map) => map,
+        other => QueryRow::from_iter([("value".to_string(), other)]),
+    };
+    row.insert(DELETED_AT_COLUMN.to_string(), trash::deleted_time(entry.deleted_at).into());
+    row
+}
+
+fn decode_row(value: &CrdtValue, schema: &serde_json::Value) -> Result<QueryRow> {
+    let row = match value {
+        CrdtValue::Register(data) => {
+            let value: serde_json::Value = serde_json::from_slice(data)?;
+            match row::row_to_object(value, schema) {
+                serde_json::Value::Object(map) => map,
+                other => {
+                    let mut map = QueryRow::new();
+                    map.insert("value".to_string(), other);
+                    map
+                }
+            }
+        }
+        CrdtValue::Counter(count) => {
+            let mut map = QueryRow::new();
+            map.insert("value".to_string(), serde_json::Value::from(*count));
+            map
+        }
+    };
+    Ok(row)
+}
+
+fn matches_selection(selection: Option<&Expr>, row: &QueryRow, types: &ColumnTypes) -> Result<bool> {
+    match selection {
+        Some(predicate) => Ok(is_true(&eval_predicate(predicate, row, types)?)),
+        None => Ok(true),
+    }
+}
+
+pub(crate) fn is_true(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Bool(b) => *b,
+        serde_json::Value::Null => false,
+        serde_json::Value::Number(n) => n.as_f64() != Some(0.0),
+        serde_json::Value::String(s) => !s.is_empty(),
+        _ => true,
+    }
+}
+
+fn render_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "NULL".to_string(),
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryProcessor;
+    use crate::core::database::{CoercionPolicy, CommitStorage, StorageOptions};
+    use crate::core::sql_executor::SqlExecutor;
+    use crate::core::test_support::{data_path, head, rows, run, temp_repo};
+    use crate::error::BranchDBError;
+    use serde_json::json;
+
+    #[test]
+    fn distinct_drops_repeated_rows_after_where_and_before_order_by() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE people (id TEXT, city TEXT, team TEXT)");
+        for (id, city, team) in [("1", "Oslo", "a"), ("2", "Bern", "b"), ("3", "Oslo", "a"), ("4", "Oslo", "b"), ("5", "Bern", "b"), ("6", "Rome", "a")] {
+            run(&storage, &format!("INSERT INTO people VALUES ('{}', '{}', '{}')", id, city, team));
+        }
+
+        assert_eq!(rows(&storage, "SELECT DISTINCT city FROM people ORDER BY city"),
+            vec![vec![json!("Bern")], vec![json!("Oslo")], vec![json!("Rome")]]);
+        assert_eq!(rows(&storage, "SELECT DISTINCT city FROM people WHERE team = 'a' ORDER BY city DESC"),
+            vec![vec![json!("Rome")], vec![json!("Oslo")]]);
+        assert_eq!(rows(&storage, "SELECT DISTINCT city, team FROM people ORDER BY city, team"), vec![
+            vec![json!("Bern"), json!("b")],
+            vec![json!("Oslo"), json!("a")],
+            vec![json!("Oslo"), json!("b")],
+            vec![json!("Rome"), json!("a")],
+        ]);
+        // Every row has its own id, so DISTINCT * keeps them all
+        assert_eq!(rows(&storage, "SELECT DISTINCT * FROM people").len(), 6);
+        assert_eq!(rows(&storage, "SELECT DISTINCT COUNT(*) FROM people"), vec![vec![json!(6)]]);
+    }
+
+    #[test]
+    fn a_derived_table_feeds_its_rows_to_the_outer_query() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT, age INTEGER)");
+        for (id, name, age) in [("1", "ann", "17"), ("2", "bob", "34"), ("3", "cy", "19"), ("4", "di", "12")] {
+            run(&storage, &format!("INSERT INTO users VALUES ('{}', '{}', '{}')", id, name, age));
+        }
+        let old = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('5', 'ed', '40')");
+
+        assert_eq!(rows(&storage, "SELECT a.name FROM (SELECT * FROM users WHERE age > 18) AS a ORDER BY a.name"),
+            vec![vec![json!("bob")], vec![json!("cy")], vec![json!("ed")]]);
+        assert_eq!(rows(&storage, "SELECT a.name FROM (SELECT * FROM users WHERE age > 18) AS a WHERE a.name <> 'cy' ORDER BY a.name"),
+            vec![vec![json!("bob")], vec![json!("ed")]]);
+        // The inner query reads the outer query's commit
+        assert_eq!(rows(&storage, &format!("SELECT a.name FROM (SELECT * FROM users WHERE age > 18) AS a ORDER BY a.name WITH {}", hex::encode(old))),
+            vec![vec![json!("bob")], vec![json!("cy")]]);
+
+        let nested = "SELECT b.name FROM (SELECT * FROM (SELECT * FROM users) AS a) AS b";
+        assert!(matches!(SqlExecutor::execute(&storage, nested), Err(BranchDBError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn a_select_without_a_commit_reads_head() {
+        let (_dir, storage) = temp_repo();
+        // Nothing committed yet: an empty result rather than an error
+        assert!(rows(&storage, "SELECT * FROM users").is_empty());
+
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let first = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+        let names = |sql: &str| rows(&storage, sql).into_iter().map(|row| row[0].clone()).collect::<Vec<_>>();
+        assert_eq!(names("SELECT name FROM users ORDER BY id"), [json!("ann"), json!("bob")]);
+        assert_eq!(names(&format!("SELECT name FROM users WITH {}", hex::encode(first))), [json!("ann")]);
+
+        // A detached HEAD is read where it is
+        storage.move_head(&first).unwrap();
+        assert_eq!(names("SELECT name FROM users ORDER BY id"), [json!("ann")]);
+        let missing = SqlExecutor::execute(&storage, "SELECT * FROM orders");
+        assert!(matches!(missing, Err(BranchDBError::TableNotFound { .. })), "{:?}", missing);
+    }
+
+    #[test]
+    fn null_handling_functions_with_null_and_non_null_arguments() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT, nick TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann', NULL)");
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob', 'bo')");
+        let column = |expr: &str| -> Vec<serde_json::Value> {
+            rows(&storage, &format!("SELECT {} FROM users ORDER BY id", expr)).into_iter().map(|row| row[0].clone()).collect()
+        };
+
+        assert_eq!(column("COALESCE(nick, name, 'x')"), [json!("ann"), json!("bo")]);
+        assert_eq!(column("COALESCE(nick)"), [json!(null), json!("bo")]);
+        assert_eq!(column("COALESCE(NULL, NULL, 3)"), [json!(3), json!(3)]);
+        assert_eq!(column("IFNULL(nick, 'none')"), [json!("none"), json!("bo")]);
+        assert_eq!(column("NULLIF(name, 'ann')"), [json!(null), json!("bob")]);
+        assert_eq!(column("NULLIF(nick, 'x')"), [json!(null), json!("bo")]);
+        assert_eq!(column("IIF(nick IS NULL, 'no nick', nick)"), [json!("no nick"), json!("bo")]);
+        assert_eq!(column("IIF(NULL, 'yes', 'no')"), [json!("no"), json!("no")]);
+
+        assert_eq!(rows(&storage, "SELECT name FROM users WHERE COALESCE(nick, '') = ''"), vec![vec![json!("ann")]]);
+        assert_eq!(rows(&storage, "SELECT name FROM users WHERE NULLIF(name, 'ann') IS NOT NULL"), vec![vec![json!("bob")]]);
+        for wrong in ["IFNULL(nick)", "NULLIF(name, nick, 'x')", "IIF(nick, name)"] {
+            let result = SqlExecutor::execute(&storage, &format!("SELECT {} FROM users", wrong));
+            assert!(matches!(result, Err(BranchDBError::InvalidInput(_))), "{}", wrong);
+        }
+    }
+
+    #[test]
+    fn cast_converts_what_it_can_and_gives_null_for_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        // Coerced writes store numbers and silent ones the strings as given, so the column
+        // ends up holding both
+        for (coercion, id, value) in [(CoercionPolicy::Coerce, "1", "42"), (CoercionPolicy::Silent, "2", "17"), (CoercionPolicy::Silent, "3", "abc")] {
+            let options = StorageOptions { coercion, ..StorageOptions::default() };
+            let storage = CommitStorage::open_with_options(&data_path(&dir), options).unwrap();
+            if id == "1" {
+                run(&storage, "CREATE TABLE readings (id TEXT, value INTEGER)");
+            }
+            run(&storage, &format!("INSERT INTO readings VALUES ('{}', '{}')", id, value));
+        }
+        let storage = CommitStorage::open(&data_path(&dir)).unwrap();
+        let column = |expr: &str| -> Vec<serde_json::Value> {
+            rows(&storage, &format!("SELECT {} FROM readings ORDER BY id", expr)).into_iter().map(|row| row[0].clone()).collect()
+        };
+        assert_eq!(column("value"), [json!(42), json!("17"), json!("abc")]);
+
+        assert_eq!(column("CAST(value AS INTEGER)"), [json!(42), json!(17), json!(null)]);
+        assert_eq!(column("TO_INTEGER(value)"), column("CAST(value AS INTEGER)"));
+        assert_eq!(column("CAST(value AS TEXT)"), [json!("42"), json!("17"), json!("abc")]);
+        assert_eq!(column("TO_FLOAT(value)"), [json!(42), json!(17.0), json!(null)]);
+        assert_eq!(column("CAST(value AS BOOLEAN)"), [json!(null), json!(null), json!(null)]);
+        assert_eq!(column("CAST('2024-03-01' AS TIMESTAMP)")[0], json!("2024-03-01T00:00:00Z"));
+        assert_eq!(rows(&storage, "SELECT id FROM readings WHERE CAST(value AS INTEGER) > 20"), vec![vec![json!("1")]]);
+
+        // With --strict-cast the unconvertible value fails the query instead
+        let strict = QueryProcessor::new(&storage.db).with_strict_cast(true);
+        assert!(matches!(strict.query("SELECT CAST(value AS INTEGER) FROM readings"), Err(BranchDBError::TypeMismatch(_))));
+        assert!(strict.query("SELECT CAST(value AS INTEGER) FROM readings WHERE id <> '3'").is_ok());
+    }
+}
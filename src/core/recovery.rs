@@ -0,0 +1,239 @@
+use crate::core::branch::BranchManager;
+use crate::core::conflict::MergeConflicts;
+use crate::core::database::{commit_key, decode_commit, get_commit_raw, verify_commit, CommitStorage, COMMIT_PREFIX};
+use crate::core::models::Change;
+use crate::error::Result;
+use crate::core::namespace::{NamespacedDb, WriteBatch};
+use serde::Serialize;
+use std::fmt;
+use tracing::{info, warn};
+
+// Commit objects that failed their checksum, moved out of the way by `quarantine_corrupt`
+// under the same raw hash. Nothing reads them back; they are kept for inspection.
+pub const QUARANTINE_PREFIX: &str = "!corrupt:";
+
+// State left behind by an operation that didn't finish, found by `check_consistency`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Inconsistency {
+    // HEAD isn't a commit hash at all
+    HeadInvalid,
+    // HEAD names a commit that isn't stored, or whose checksum doesn't match
+    HeadMissing { hash: String },
+    HeadCorrupt { hash: String },
+    // A branch ref naming a commit that isn't stored or doesn't check out
+    BranchDangling { branch: String, hash: String },
+    // Staged changes or merge conflicts that can't be read back
+    StagedUnreadable,
+    ConflictsUnreadable,
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inconsistency::HeadInvalid => write!(f, "HEAD is not a commit hash"),
+            Inconsistency::HeadMissing { hash } => write!(f, "HEAD points to missing commit {}", hash),
+            Inconsistency::HeadCorrupt { hash } => write!(f, "HEAD points to commit {} whose checksum doesn't match", hash),
+            Inconsistency::BranchDangling { branch, hash } => write!(f, "branch '{}' points to missing or damaged commit {}", branch, hash),
+            Inconsistency::StagedUnreadable => write!(f, "staged changes can't be read"),
+            Inconsistency::ConflictsUnreadable => write!(f, "merge conflicts file can't be read"),
+        }
+    }
+}
+
+impl Inconsistency {
+    // What `abort_pending` does about it.
+    pub fn repair(&self) -> &'static str {
+        match self {
+            Inconsistency::HeadInvalid | Inconsistency::HeadMissing { .. } | Inconsistency::HeadCorrupt { .. } => {
+                "clear HEAD and the live rows; check out a branch or commit afterwards"
+            }
+            Inconsistency::BranchDangling { .. } => "delete the branch",
+            Inconsistency::StagedUnreadable => "discard the staged changes",
+            Inconsistency::ConflictsUnreadable => "discard the recorded merge conflicts",
+        }
+    }
+}
+
+// How far a damaged commit's body may end before the end of its stored value: a checksum cut
+// short or missing, one written twice, or stray bytes after it.
+const CHECKSUM_SEARCH_BYTES: usize = 64;
+
+// Whether a stored commit exists and its trailing checksum matches the bytes before it.
+enum CommitCheck {
+    Valid,
+    Missing,
+    Corrupt,
+}
+
+fn check_commit(db: &NamespacedDb, hash: &[u8]) -> Result<CommitCheck> {
+    let Some(raw) = get_commit_raw(db, hash)? else { return Ok(CommitCheck::Missing) };
+    Ok(match verify_commit(hash, &raw) {
+        Ok(()) => CommitCheck::Valid,
+        Err(_) => CommitCheck::Corrupt,
+    })
+}
+
+impl CommitStorage {
+    // Looks for state an interrupted operation left behind. Only HEAD, the branch refs, the
+    // staged changes and the conflicts file are read, never the history, so this runs on every
+    // open.
+    pub fn check_consistency(&self) -> Result<Vec<Inconsistency>> {
+        let mut found = Vec::new();
+        if let Some(head) = self.db.get(b"HEAD")? {
+            if head.len() != 32 {
+                found.push(Inconsistency::HeadInvalid);
+            } else {
+                match check_commit(&self.db, &head)? {
+                    CommitCheck::Valid => {}
+                    CommitCheck::Missing => found.push(Inconsistency::HeadMissing { hash: hex::encode(&head) }),
+                    CommitCheck::Corrupt => found.push(Inconsistency::HeadCorrupt { hash: hex::encode(&head) }),
+                }
+            }
+        }
+
+        let branch_mgr = BranchManager::new(self.db.clone());
+        for branch in branch_mgr.list_branches()? {
+            let Some(hash) = branch_mgr.get_branch_head(&branch)? else { continue };
+            if hash.len() != 32 || !matches!(check_commit(&self.db, &hash)?, CommitCheck::Valid) {
+                found.push(Inconsistency::BranchDangling { branch, hash: hex::encode(&hash) });
+            }
+        }
+
+        if let Some(raw) = self.db.get(b"STAGED")? {
+            if bincode::deserialize::<Vec<Change>>(&raw).is_err() {
+                found.push(Inconsistency::StagedUnreadable);
+            }
+        }
+        if MergeConflicts::load(&self.conflicts_path()).is_err() {
+            found.push(Inconsistency::ConflictsUnreadable);
+        }
+        Ok(found)
+    }
+
+    // Repairs whatever `check_consistency` finds by dropping the half-written state, and returns
+    // what was repaired. Branch protection doesn't stop a dangling branch from being deleted.
+    pub fn abort_pending(&self) -> Result<Vec<Inconsistency>> {
+        let found = self.check_consistency()?;
+        for problem in &found {
+            match problem {
+                Inconsistency::HeadInvalid | Inconsistency::HeadMissing { .. } | Inconsistency::HeadCorrupt { .. } => {
+                    self.clear_live_state()?;
+                }
+                Inconsistency::BranchDangling { branch, .. } => {
+                    self.db.delete(format!("branch:{}", branch).as_bytes())?;
+                    self.db.delete(format!("protected:{}", branch).as_bytes())?;
+                }
+                Inconsistency::StagedUnreadable => self.clear_staged()?,
+                Inconsistency::ConflictsUnreadable => std::fs::remove_file(self.conflicts_path())?,
+            }
+            info!(problem = %problem, repair = problem.repair(), "repaired");
+        }
+        Ok(found)
+    }
+
+    // Rewrites commit objects whose checksum fails but whose body is intact: some prefix of the
+    // stored value, ending at most CHECKSUM_SEARCH_BYTES before its end, still hashes to the key
+    // and decodes. The value is cut back to that body and the checksum appended once. Commits
+    // that can't be recovered this way are left for `quarantine_corrupt`. Returns the number of
+    // commits rewritten.
+    pub fn repair_checksums(&self) -> Result<usize> {
+        let mut batch = WriteBatch::default();
+        let mut repaired = 0;
+        for item in self.db.prefix_iterator(COMMIT_PREFIX) {
+            let (key, value) = item?;
+            let Some(hash) = key.strip_prefix(COMMIT_PREFIX.as_bytes()) else { break };
+            if hash.len() != 32 || verify_commit(hash, &value).is_ok() {
+                continue;
+            }
+            let shortest = value.len().saturating_sub(CHECKSUM_SEARCH_BYTES);
+            let Some(body) = (shortest..=value.len()).rev()
+                .map(|end| &value[..end])
+                .find(|body| blake3::hash(body).as_bytes() == hash) else { continue };
+            let rewritten = [body, hash].concat();
+            if decode_commit(&rewritten).is_err() {
+                continue;
+            }
+            info!(commit = %hex::encode(hash), from = value.len(), to = rewritten.len(), "repaired commit checksum");
+            batch.put(commit_key(hash), rewritten);
+            repaired += 1;
+        }
+        self.db.write(batch)?;
+        Ok(repaired)
+    }
+
+    // Checks every stored commit and moves those whose checksum fails under QUARANTINE_PREFIX,
+    // so they read as missing rather than as errors. Reads the whole history. Returns the
+    // quarantined hashes.
+    pub fn quarantine_corrupt(&self) -> Result<Vec<[u8; 32]>> {
+        let mut batch = WriteBatch::default();
+        let mut quarantined = Vec::new();
+        for item in self.db.prefix_iterator(COMMIT_PREFIX) {
+            let (key, value) = item?;
+            let Some(hash) = key.strip_prefix(COMMIT_PREFIX.as_bytes()) else { break };
+            if verify_commit(hash, &value).is_ok() {
+                continue;
+            }
+            warn!(commit = %hex::encode(hash), "quarantining corrupt commit");
+            batch.put([QUARANTINE_PREFIX.as_bytes(), hash].concat(), &value);
+            batch.delete(commit_key(hash));
+            if let Ok(hash) = <[u8; 32]>::try_from(hash) {
+                quarantined.push(hash);
+            }
+        }
+        self.db.write(batch)?;
+        Ok(quarantined)
+    }
+
+    // Called on open: the check is only reported, so `fsck` can still open the repository.
+    pub(crate) fn warn_if_inconsistent(&self) {
+        match self.check_consistency() {
+            Ok(found) if found.is_empty() => {}
+            Ok(found) => {
+                for problem in &found {
+                    warn!(%problem, "inconsistent repository state");
+                }
+                warn!("an operation was interrupted; run `branchdb fsck` to inspect it or `branchdb fsck --abort-pending` to repair it");
+            }
+            Err(e) => warn!(error = %e, "could not check the repository for interrupted operations"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::history::HistoryWalk;
+    use crate::core::test_support::{head, run, temp_repo};
+    use crate::error::BranchDBError;
+
+    #[test]
+    fn a_corrupt_commit_is_reported_and_quarantined() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let damaged = head(&storage);
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+        let tip = head(&storage);
+        // A byte flipped in the middle of the body, which `repair_checksums` can't undo
+        let mut raw = storage.db.get(commit_key(&damaged)).unwrap().unwrap();
+        let middle = raw.len() / 2;
+        raw[middle] ^= 0xff;
+        storage.db.put(commit_key(&damaged), &raw).unwrap();
+
+        let err = storage.get_commit_by_hash(&damaged).unwrap_err();
+        assert!(matches!(&err, BranchDBError::CorruptData(message) if message.contains(&hex::encode(damaged))), "{}", err);
+        assert!(HistoryWalk::from_tips(&storage, [tip]).unwrap().any(|entry| entry.is_err()));
+        assert_eq!(storage.repair_checksums().unwrap(), 0);
+
+        assert_eq!(storage.quarantine_corrupt().unwrap(), vec![damaged]);
+        assert!(storage.db.get(commit_key(&damaged)).unwrap().is_none());
+        assert_eq!(storage.db.get([QUARANTINE_PREFIX.as_bytes(), &damaged].concat()).unwrap().unwrap(), raw);
+        assert!(storage.quarantine_corrupt().unwrap().is_empty());
+        // The rest of the history still reads, and a best-effort walk stops at the gap
+        assert!(storage.get_commit_by_hash(&tip).is_ok());
+        let walked: Vec<_> = HistoryWalk::best_effort(&storage, [tip]).unwrap().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(walked, vec![tip]);
+        assert!(storage.check_consistency().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::core::branch::BranchManager;
+use crate::core::database::{commit_key, CommitStorage, COMMIT_PREFIX};
+use crate::error::{BranchDBError, Result};
+
+// Shortest abbreviated hash accepted, so a short word isn't taken as a hash prefix.
+const MIN_ABBREV: usize = 4;
+
+// Resolves a reference to a commit. The base is `HEAD`, a branch name, or a full or abbreviated
+// commit hash. It may be followed by any number of suffixes:
+// - `~n` is the n-th first-parent ancestor (`~` alone means `~1`)
+// - `^n` is the n-th parent of a merge (`^` alone means `^1`, `^0` is the commit itself)
+// so `main~2^2` is the second parent of the commit two steps behind `main`.
+pub fn resolve(storage: &CommitStorage, reference: &str) -> Result<[u8; 32]> {
+    // A branch whose name happens to contain `~` or `^` wins over the suffix syntax
+    if let Some(hash) = branch_head(storage, reference)? {
+        return Ok(hash);
+    }
+
+    let split = reference.find(['~', '^']).unwrap_or(reference.len());
+    let (base, mut suffix) = reference.split_at(split);
+    let mut hash = resolve_base(storage, base)?;
+    let mut steps = 0;
+    while let Some(op) = suffix.chars().next() {
+        let digits = suffix[1..].find(|c: char| !c.is_ascii_digit()).map_or(suffix.len(), |i| i + 1);
+        let count = match &suffix[1..digits] {
+            "" => 1,
+            n => n.parse::<usize>()
+                .map_err(|_| BranchDBError::InvalidInput(format!("Invalid count in '{}'", reference)))?,
+        };
+        if op == '~' {
+            for _ in 0..count {
+                let commit = storage.get_commit_by_hash(&hash)?;
+                hash = *commit.parents.first().ok_or_else(|| BranchDBError::InvalidInput(format!(
+                    "{}: only {} commits in history", reference, steps + 1
+                )))?;
+                steps += 1;
+            }
+        } else if op == '^' && count > 0 {
+            let commit = storage.get_commit_by_hash(&hash)?;
+            hash = *commit.parents.get(count - 1).ok_or_else(|| BranchDBError::InvalidInput(format!(
+                "{}: commit {} has {} parent(s)", reference, hex::encode(&hash[..8]), commit.parents.len()
+            )))?;
+            steps += 1;
+        } else if op != '^' {
+            return Err(BranchDBError::InvalidInput(format!("Invalid reference '{}'", reference)));
+        }
+        suffix = &suffix[digits..];
+    }
+    Ok(hash)
+}
+
+// Branches whose names match a glob (`*` any run of characters, `?` any one), with their heads,
+// in name order. A pattern matching no branch is reported as a missing branch.
+pub fn matching_branches(storage: &CommitStorage, pattern: &str) -> Result<Vec<(String, [u8; 32])>> {
+    let mut branches = Vec::new();
+    for name in BranchManager::new(storage.db.clone()).list_branches()? {
+        if glob_match(pattern, &name) {
+            if let Some(hash) = branch_head(storage, &name)? {
+                branches.push((name, hash));
+            }
+        }
+    }
+    if branches.is_empty() {
+        return Err(BranchDBError::BranchNotFound { name: pattern.to_string() });
+    }
+    Ok(branches)
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Position in each after the last `*`, to retry from with one more character swallowed
+    let (mut p, mut n, mut star) = (0, 0, None);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after_star, swallowed)) => {
+                    p = after_star;
+                    n = swallowed + 1;
+                    star = Some((after_star, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn branch_head(storage: &CommitStorage, name: &str) -> Result<Option<[u8; 32]>> {
+    let Some(raw) = storage.db.get(format!("branch:{}", name).as_bytes())? else { return Ok(None) };
+    let hash: [u8; 32] = raw.try_into()
+        .map_err(|_| BranchDBError::InvalidInput(format!("Branch '{}' points to an invalid commit", name)))?;
+    Ok(Some(hash))
+}
+
+// Non-hex names are taken as a branch that does not exist.
+fn resolve_base(storage: &CommitStorage, base: &str) -> Result<[u8; 32]> {
+    if base == "HEAD" {
+        return storage.get_head()?.ok_or_else(|| BranchDBError::InvalidInput("No HEAD commit".into()));
+    }
+    if let Some(hash) = branch_head(storage, base)? {
+        return Ok(hash);
+    }
+    if base.is_empty() || !base.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(BranchDBError::BranchNotFound { name: base.to_string() });
+    }
+    if base.len() == 64 {
+        let hash: [u8; 32] = hex::decode(base)?.try_into()
+            .map_err(|_| BranchDBError::CommitNotFound { hash: base.to_string() })?;
+        storage.get_commit_by_hash(&hash)?;
+        return Ok(hash);
+    }
+    if base.len() < MIN_ABBREV {
+        return Err(BranchDBError::CommitNotFound { hash: base.to_string() });
+    }
+    resolve_abbreviated(storage, base)
+}
+
+// Commits are keyed by their raw 32-byte hash, so candidates are found by seeking to the
+// prefix's whole bytes and keeping the hashes that match the odd trailing digit too.
+fn resolve_abbreviated(storage: &CommitStorage, prefix: &str) -> Result<[u8; 32]> {
+    let prefix = prefix.to_ascii_lowercase();
+    let seek = commit_key(&hex::decode(&prefix[..prefix.len() / 2 * 2])?);
+    let mut matches = Vec::new();
+    for item in storage.db.prefix_iterator(&seek) {
+        let (key, _) = item?;
+        // prefix_iterator keeps going past the prefix without a prefix extractor
+        if !key.starts_with(&seek) {
+            break;
+        }
+        let Ok(hash) = <[u8; 32]>::try_from(&key[COMMIT_PREFIX.len()..]) else { continue };
+        if hex::encode(hash).starts_with(&prefix) {
+            matches.push(hash);
+        }
+    }
+    match matches.as_slice() {
+        [hash] => Ok(*hash),
+        [] => Err(BranchDBError::CommitNotFound { hash: prefix }),
+        _ => Err(BranchDBError::InvalidInput(format!(
+            "Abbreviated hash '{}' is ambiguous ({} commits match)", prefix, matches.len()
+        ))),
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Serialize, de::DeserializeOwned};
+use crate::error::{BranchDBError, Result};
+
+// Row maps a Rust struct onto a table row. Only `table` and `id` need to be written by hand;
+// the JSON conversion is provided through serde for any Serialize + Deserialize struct.
+pub trait Row: Serialize + DeserializeOwned {
+    fn table() -> &'static str;
+
+    fn id(&self) -> String;
+
+    fn to_value(&self) -> Result<serde_json::Value> {
+        let value = serde_json::to_value(self)?;
+        if !value.is_object() {
+            return Err(BranchDBError::TypeMismatch(
+                format!("Rows for table '{}' must serialize to a JSON object", Self::table())
+            ));
+        }
+        Ok(value)
+    }
+
+    fn from_value(value: serde_json::Value) -> Result<Self> {
+        serde_json::from_value(value).map_err(|e| BranchDBError::TypeMismatch(
+            format!("Row in table '{}' does not match the requested type: {}", Self::table(), e)
+        ))
+    }
+}
+
+// Returns the column names declared in a table schema, in schema order.
+pub(crate) fn schema_columns(schema: &serde_json::Value) -> Vec<String> {
+    schema.get("columns")
+        .and_then(|c| c.as_object())
+        .map(|cols| cols.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+// Checks that the fields of a serialized row line up with the table schema.
+// Tables without declared columns accept any row.
+pub(crate) fn check_row_against_schema(table: &str, row: &serde_json::Value, schema: &serde_json::Value) -> Result<()> {
+    let columns = schema_columns(schema);
+    if columns.is_empty() {
+        return Ok(());
+    }
+
+    let fields = row.as_object()
+        .ok_or_else(|| BranchDBError::TypeMismatch("Row must be a JSON object".into()))?;
+
+    let missing: Vec<&str> = columns.iter()
+        .filter(|c| !fields.contains_key(c.as_str()))
+        .map(|c| c.as_str())
+        .collect();
+    let unexpected: Vec<&str> = fields.keys()
+        .filter(|f| !columns.contains(f))
+        .map(|f| f.as_str())
+        .collect();
+
+    if missing.is_empty() && unexpected.is_empty() {
+        return Ok(());
+    }
+
+    let mut problems = Vec::new();
+    if !missing.is_empty() {
+        problems.push(format!("missing columns: {}", missing.join(", ")));
+    }
+    if !unexpected.is_empty() {
+        problems.push(format!("columns not in schema: {}", unexpected.join(", ")));
+    }
+    Err(BranchDBError::InvalidInput(format!(
+        "Row does not match schema of table '{}' ({})", table, problems.join("; ")
+    )))
+}
+
+// Normalizes a stored row into a JSON object. Rows inserted through SQL are stored as
+// positional arrays, so they are zipped with the schema columns; values past the declared
+// columns are named `column<N>` by position.
+pub(crate) fn row_to_object(row: serde_json::Value, schema: &serde_json::Value) -> serde_json::Value {
+    match row {
+        serde_json::Value::Array(values) => {
+            let columns = schema_columns(schema);
+            let object = values.into_iter()
+                .enumerate()
+                .map(|(i, v)| (columns.get(i).cloned().unwrap_or_else(|| format!("column{}", i + 1)), v))
+                .collect::<serde_json::Map<_, _>>();
+            serde_json::Value::Object(object)
+        }
+        other => other,
+    }
+}
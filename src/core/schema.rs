@@ -0,0 +1,134 @@
+use crate::error::{BranchDBError, Result};
+use std::fmt;
+
+// Separates the column values in the row id of a table with a composite primary key.
+pub const KEY_SEPARATOR: char = '\0';
+
+// A primary key declared with `PRIMARY KEY (a, b, ...)`, stored in the schema as
+// `"primary_key": ["a", "b"]`. Rows of such a table are keyed by the values of those columns
+// joined with KEY_SEPARATOR; other tables are keyed by their first value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiKeyPrimaryKey {
+    pub columns: Vec<String>,
+}
+
+impl MultiKeyPrimaryKey {
+    pub fn from_schema(schema: &serde_json::Value) -> Option<Self> {
+        let columns: Vec<String> = schema.get("primary_key")?.as_array()?.iter()
+            .filter_map(|c| c.as_str().map(str::to_string))
+            .collect();
+        (!columns.is_empty()).then_some(Self { columns })
+    }
+
+    // The row id for the key column values `value_of` gives. Every key column needs a value,
+    // and no value may contain the separator.
+    pub fn row_id(&self, value_of: impl Fn(&str) -> Option<String>) -> Result<String> {
+        let mut parts = Vec::with_capacity(self.columns.len());
+        for column in &self.columns {
+            let value = value_of(column).ok_or_else(|| BranchDBError::InvalidInput(format!(
+                "Missing value for primary key column '{}'", column
+            )))?;
+            if value.contains(KEY_SEPARATOR) {
+                return Err(BranchDBError::InvalidInput(format!(
+                    "Primary key column '{}' can't contain a NUL character", column
+                )));
+            }
+            parts.push(value);
+        }
+        Ok(parts.join(&KEY_SEPARATOR.to_string()))
+    }
+
+    // A row id split back into the value of each key column.
+    pub fn split<'a>(&self, id: &'a str) -> Vec<&'a str> {
+        id.split(KEY_SEPARATOR).collect()
+    }
+
+    pub fn contains(&self, column: &str) -> bool {
+        self.columns.iter().any(|c| c == column)
+    }
+}
+
+// A single column-level difference between two table schemas.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaChange {
+    Added { column: String, column_type: String },
+    Removed { column: String, column_type: String },
+    TypeChanged { column: String, from: String, to: String },
+}
+
+impl fmt::Display for SchemaChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaChange::Added { column, column_type } => write!(f, "+ {} {}", column, column_type),
+            SchemaChange::Removed { column, column_type } => write!(f, "- {} {}", column, column_type),
+            SchemaChange::TypeChanged { column, from, to } => write!(f, "~ {}: {} → {}", column, from, to),
+        }
+    }
+}
+
+fn column_type(value: &serde_json::Value) -> String {
+    value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string())
+}
+
+// Compares the `columns` map of two schemas. Added and type-changed columns follow the order
+// of the new schema, removed columns follow the order of the old one.
+pub fn compare_schemas(old: &serde_json::Value, new: &serde_json::Value) -> Vec<SchemaChange> {
+    let empty = serde_json::Map::new();
+    let old_cols = old.get("columns").and_then(|c| c.as_object()).unwrap_or(&empty);
+    let new_cols = new.get("columns").and_then(|c| c.as_object()).unwrap_or(&empty);
+
+    let mut changes = Vec::new();
+    for (column, new_type) in new_cols {
+        match old_cols.get(column) {
+            None => changes.push(SchemaChange::Added {
+                column: column.clone(),
+                column_type: column_type(new_type),
+            }),
+            Some(old_type) if old_type != new_type => changes.push(SchemaChange::TypeChanged {
+                column: column.clone(),
+                from: column_type(old_type),
+                to: column_type(new_type),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (column, old_type) in old_cols {
+        if !new_cols.contains_key(column) {
+            changes.push(SchemaChange::Removed {
+                column: column.clone(),
+                column_type: column_type(old_type),
+            });
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_support::{live_rows, rows, run, temp_repo};
+    use serde_json::json;
+
+    #[test]
+    fn a_composite_key_keeps_rows_apart_that_share_one_column() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE people (first_name TEXT, last_name TEXT, city TEXT, PRIMARY KEY (first_name, last_name))");
+        run(&storage, "INSERT INTO people VALUES ('ann', 'lee', 'Oslo')");
+        run(&storage, "INSERT INTO people VALUES ('ann', 'kim', 'Rome')");
+
+        let key = MultiKeyPrimaryKey::from_schema(&storage.get_table_schema("people", None).unwrap()).unwrap();
+        assert_eq!(key.columns, ["first_name", "last_name"]);
+        let ids: Vec<String> = live_rows(&storage, "people").into_keys().filter(|id| !id.starts_with('!')).collect();
+        assert_eq!(ids, ["ann\0kim", "ann\0lee"]);
+        assert_eq!(key.split(&ids[0]), ["ann", "kim"]);
+
+        run(&storage, "UPDATE people SET city = 'Bern' WHERE first_name = 'ann' AND last_name = 'kim'");
+        assert_eq!(rows(&storage, "SELECT last_name, city FROM people ORDER BY last_name"),
+            vec![vec![json!("kim"), json!("Bern")], vec![json!("lee"), json!("Oslo")]]);
+        run(&storage, "DELETE FROM people WHERE first_name = 'ann' AND last_name = 'lee'");
+        assert_eq!(rows(&storage, "SELECT last_name FROM people"), vec![vec![json!("kim")]]);
+
+        assert!(key.row_id(|column| (column == "first_name").then(|| "ann".to_string())).is_err());
+        assert!(key.row_id(|_| Some("a\0b".to_string())).is_err());
+    }
+}
@@ -0,0 +1,953 @@
+use crate::core::database::{decode_row_key, encode_row_key, table_key_prefix, CoercionPolicy, CommitStorage};
+use crate::core::query::{QueryProcessor, TypeCoercer};
+use crate::core::models::Change;
+use crate::core::crdt::CrdtValue;
+use crate::core::row;
+use crate::core::schema::MultiKeyPrimaryKey;
+use crate::core::trash;
+use crate::core::ttl;
+use crate::core::upsert;
+use crate::error::{BranchDBError, Result};
+use serde::Serialize;
+use sqlparser::ast::{
+    Assignment, BinaryOperator, ColumnOption, Expr, Ident, ObjectName, ObjectType, SetExpr, Statement, TableConstraint, TableFactor, Value,
+};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::collections::HashMap;
+
+// Translates a single SQL write statement into the changes it would commit.
+// Nothing is written; callers decide how the changes are committed.
+pub(crate) fn statement_changes(storage: &CommitStorage, command: &str) -> Result<Vec<Change>> {
+    let cmd_upper = command.to_uppercase();
+    
+    if cmd_upper.starts_with("CREATE TABLE") || cmd_upper.starts_with("CREATE OR REPLACE TABLE") {
+        let target = create_table_target(command)?;
+        let table_name = target.table.as_str();
+
+        // IF NOT EXISTS leaves an existing table alone and commits nothing; OR REPLACE deletes
+        // its rows in the same commit that writes the new schema
+        let mut changes = Vec::new();
+        if target.if_not_exists || target.or_replace {
+            let existing = drop_table_rows(storage, table_name)?;
+            if target.if_not_exists && !existing.is_empty() {
+                return Ok(Vec::new());
+            }
+            changes = existing;
+        }
+
+        let schema = create_table_schema(&target.statement);
+        if let Some(duration) = schema.get("ttl").and_then(|ttl| ttl.as_str()) {
+            ttl::parse_duration(duration)?;
+        }
+        changes.push(Change::Insert {
+            table: table_name.to_string(),
+            id: "!schema".to_string(),
+            value: bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&schema)?))?,
+        });
+        
+        Ok(changes)
+    } 
+    else if cmd_upper.starts_with("MERGE") {
+        upsert::merge_changes(storage, command)
+    }
+    else if let Some(changes) = cmd_upper.starts_with("INSERT INTO")
+        .then(|| upsert::on_conflict_changes(storage, command))
+        .flatten()
+    {
+        changes
+    }
+    else if cmd_upper.starts_with("INSERT INTO") {
+        let table = command.split_whitespace()
+            .nth(2)
+            .ok_or_else(|| BranchDBError::InvalidInput("Missing table name".into()))?;
+        
+        let values_start = command.find("VALUES")
+            .ok_or_else(|| BranchDBError::InvalidInput("Missing VALUES clause".into()))? + 6;
+        let values_part = &command[values_start..].trim();
+        
+        let values = parse_sql_values(values_part)?;
+        if values.is_empty() {
+            return Err(BranchDBError::InvalidInput("No values provided".into()));
+        }
+        
+        // Dynamic type checking - works with any schema format
+        let policy = storage.options().coercion;
+        let mut typed: Vec<serde_json::Value> = values.iter().cloned().map(serde_json::Value::String).collect();
+        let head = storage.get_head()?;
+        let schema = storage.get_table_schema(table, head.as_ref().map(|h| &h[..])).ok();
+        if let Some(schema) = &schema {
+            if let Some(columns) = schema.get("columns") {
+                // Match values to columns by position when column names aren't specified
+                for (i, field) in values.iter().enumerate() {
+                    if let Some((_, col_type)) = columns.as_object()
+                        .and_then(|cols| cols.iter().nth(i))
+                    {
+                        typed[i] = validate_value_type(
+                            field,
+                            col_type.as_str().unwrap_or("TEXT"),
+                            policy
+                        )?;
+                    }
+                }
+            }
+        }
+
+        let json_value = serde_json::to_string(&typed)?;  
+
+        // Keyed by the first value, or by the primary key columns' values if there are several
+        let id = match schema.as_ref().and_then(MultiKeyPrimaryKey::from_schema) {
+            Some(key) => {
+                let columns = row::schema_columns(schema.as_ref().unwrap_or(&serde_json::Value::Null));
+                key.row_id(|column| columns.iter().position(|c| c == column).and_then(|i| values.get(i)).cloned())?
+            }
+            None => values[0].to_string(),
+        };
+        
+        let changes = vec![Change::Insert {
+            table: table.to_string(),
+            id,
+            value: bincode::serialize(&CrdtValue::Register(json_value.as_bytes().to_vec()))?,
+        }];
+        
+        Ok(changes)
+    }
+    
+    else if cmd_upper.starts_with("UPDATE") {
+        let table = command.split_whitespace()
+            .nth(1)
+            .ok_or_else(|| BranchDBError::InvalidInput("Missing table name".into()))?;
+
+        let set_idx = command.find("SET")
+            .ok_or_else(|| BranchDBError::InvalidInput("Missing SET clause".into()))?;
+        let where_idx = command.find("WHERE")
+            .ok_or_else(|| BranchDBError::InvalidInput("Missing WHERE clause".into()))?;
+
+        let set_clause = &command[set_idx+3..where_idx].trim();
+        let where_clause = &command[where_idx+5..].trim();
+
+        // A composite key is matched column by column; other tables by the single `=`
+        let id = if let Some(key) = MultiKeyPrimaryKey::from_schema(&storage.get_table_schema(table, None)?) {
+            for pair in set_clause.split(',') {
+                let field = pair.split('=').next().unwrap_or_default().trim();
+                if key.contains(field) {
+                    return Err(BranchDBError::InvalidInput(format!("Primary key column '{}' can't be updated", field)));
+                }
+            }
+            row_id_for(table, &key, where_clause)?
+        } else if where_clause.contains("=") {
+            let parts: Vec<&str> = where_clause.splitn(2, '=').collect();
+            if parts.len() != 2 {
+                return Err(BranchDBError::InvalidInput("Invalid WHERE clause format".into()));
+            }
+            parts[1].trim().trim_matches('\'').to_string()
+        } else {
+            return Err(BranchDBError::InvalidInput("WHERE clause must contain = operator".into()));
+        };
+        let id = id.as_str();
+
+        // Get current value
+        let key = encode_row_key(table, id);
+        let current_value = match storage.db.get(key.as_bytes())? {
+            Some(existing) => {
+                let crdt_value: CrdtValue = bincode::deserialize(&existing)?;
+                match crdt_value {
+                    CrdtValue::Register(data) => {
+                        // Parse as JSON value
+                        let mut current: serde_json::Value = serde_json::from_slice(&data)?;
+                        
+                        // Handle array format
+                        if let serde_json::Value::Array(ref mut arr) = current {
+                            // Get schema to determine field positions
+                            let schema = storage.get_table_schema(table, None)?;
+                            let columns = schema.get("columns")
+                                .and_then(|c| c.as_object())
+                                .ok_or_else(|| BranchDBError::InvalidInput("Invalid schema format".into()))?;
+                            
+                            // Process each SET clause
+                            for pair in set_clause.split(',') {
+                                let mut parts = pair.split('=').map(|s| s.trim());
+                                let field = parts.next()
+                                    .ok_or_else(|| BranchDBError::InvalidInput("Invalid SET clause".into()))?;
+                                let value = parts.next()
+                                    .ok_or_else(|| BranchDBError::InvalidInput("Invalid SET clause".into()))?
+                                    .trim_matches('\'');
+                                
+                                // Find field position in schema
+                                if let Some((pos, _)) = columns.iter().enumerate().find(|(_, (name, _))| name.as_str() == field) {
+                                    if pos < arr.len() {
+                                        // Type checking
+                                        if let Some(existing_val) = arr.get(pos) {
+                                            match existing_val {
+                                                serde_json::Value::Number(_) => {
+                                                    arr[pos] = value.parse::<f64>()
+                                                        .map(serde_json::Value::from)
+                                                        .map_err(|_| BranchDBError::TypeMismatch(
+                                                            format!("Expected number for field {}", field)
+                                                        ))?;
+                                                }
+                                                _ => arr[pos] = value.into(),
+                                            }
+                                        } else {
+                                            arr.push(value.into());
+                                        }
+                                    } else {
+                                        return Err(BranchDBError::InvalidInput(
+                                            format!("Field position {} out of bounds", pos)
+                                        ));
+                                    }
+                                } else {
+                                    return Err(BranchDBError::InvalidInput(
+                                        format!("Field '{}' not found in schema", field)
+                                    ));
+                                }
+                            }
+                        }
+                        current
+                    }
+                    _ => return Err(BranchDBError::TypeMismatch("Expected Register type".into())),
+                }
+            }
+            None => return Err(BranchDBError::RowNotFound {
+                table: table.to_string(),
+                id: id.to_string(),
+            }),
+        };
+
+        // Create and commit changes
+        let changes = vec![Change::Update {
+            table: table.to_string(),
+            id: id.to_string(),
+            value: bincode::serialize(&CrdtValue::Register(
+                serde_json::to_vec(&current_value)?
+            ))?,
+        }];
+        
+        Ok(changes)
+    }
+
+    // DELETE FROM <table> WHERE <key>: one row, by its id or by every primary key column
+    else if cmd_upper.starts_with("DELETE") {
+        let (table, selection) = delete_target(command)?;
+        let selection = selection
+            .ok_or_else(|| BranchDBError::InvalidInput("Missing WHERE clause".into()))?;
+        let schema = storage.get_table_schema(&table, None)?;
+        let values = key_values(&selection);
+        let id = match MultiKeyPrimaryKey::from_schema(&schema) {
+            Some(key) => key.row_id(|column| values.get(column).cloned())?,
+            None => row::schema_columns(&schema).first().and_then(|first| values.get(first))
+                .or_else(|| values.get("id"))
+                .cloned()
+                .ok_or_else(|| BranchDBError::InvalidInput("DELETE needs WHERE id = <value>".into()))?,
+        };
+        if storage.db.get(encode_row_key(&table, &id).as_bytes())?.is_none() {
+            return Err(BranchDBError::RowNotFound { table, id });
+        }
+        if trash::is_soft_delete(&schema) {
+            return trash::soft_delete_changes(storage, &table, &id);
+        }
+        Ok(vec![Change::Delete { table, id }])
+    }
+
+    // NEW COMMAND SUPPORT: ALTER TABLE
+    else if cmd_upper.starts_with("ALTER TABLE") {
+        let table = command.split_whitespace()
+            .nth(2)
+            .ok_or_else(|| BranchDBError::InvalidInput("Missing table name".into()))?;
+
+        // Get current schema from the commit history
+        let head = storage.get_head()?;
+        let mut schema = storage.get_table_schema(table, head.as_ref().map(|h| &h[..]))?;
+
+        // Parse ALTER command: ALTER TABLE <table> ADD|DROP COLUMN <name> [type], or SET|DROP
+        // TTL or SOFT DELETE
+        let mut rows = Vec::new();
+        if let Some(enabled) = trash::parse_alter_soft_delete(command) {
+            match enabled? {
+                true => schema["soft_delete"] = serde_json::Value::Bool(true),
+                false => {
+                    if let Some(schema) = schema.as_object_mut() {
+                        schema.remove("soft_delete");
+                    }
+                }
+            }
+        } else if let Some(duration) = ttl::parse_alter_ttl(command) {
+            match duration? {
+                Some(duration) => schema["ttl"] = serde_json::Value::String(duration),
+                None => {
+                    if let Some(schema) = schema.as_object_mut() {
+                        schema.remove("ttl");
+                    }
+                }
+            }
+        } else if cmd_upper.contains("ADD COLUMN") {
+            let column_name = command.split_whitespace()
+                .nth(5)
+                .ok_or_else(|| BranchDBError::InvalidInput("Missing column name".into()))?;
+            let column_type = command.split_whitespace()
+                .nth(6)
+                .ok_or_else(|| BranchDBError::InvalidInput("Missing column type".into()))?;
+
+            schema["columns"][column_name] = serde_json::Value::String(column_type.to_string());
+        } else if cmd_upper.contains("DROP COLUMN") {
+            let column_name = command.split_whitespace()
+                .nth(5)
+                .ok_or_else(|| BranchDBError::InvalidInput("Missing column name".into()))?;
+            if MultiKeyPrimaryKey::from_schema(&schema).is_some_and(|key| key.contains(column_name)) {
+                return Err(BranchDBError::InvalidInput(format!("Primary key column '{}' can't be dropped", column_name)));
+            }
+            let position = row::schema_columns(&schema).iter().position(|c| c == column_name);
+            schema["columns"].as_object_mut()
+                .ok_or(BranchDBError::TypeMismatch("Invalid schema format".into()))?
+                .remove(column_name);
+            if let Some(not_null) = schema.get_mut("not_null").and_then(|n| n.as_array_mut()) {
+                not_null.retain(|c| c != column_name);
+            }
+            rows = drop_column_from_rows(storage, table, column_name, position)?;
+        } else {
+            return Err(BranchDBError::InvalidInput("Unsupported ALTER TABLE operation".into()));
+        }
+
+        // Update schema
+        let mut changes = vec![Change::Update {
+            table: table.to_string(),
+            id: "!schema".to_string(),
+            value: bincode::serialize(&CrdtValue::Register(
+                serde_json::to_vec(&schema)?
+            ))?,
+        }];
+        changes.extend(rows);
+
+        Ok(changes)
+    }
+    // DROP TABLE [IF EXISTS] <table>: deletes every row and the schema
+    else if cmd_upper.starts_with("DROP TABLE") {
+        let ast = Parser::parse_sql(&GenericDialect, command)
+            .map_err(|e| BranchDBError::InvalidInput(e.to_string()))?;
+        let Some(Statement::Drop { object_type: ObjectType::Table, if_exists, names, .. }) = ast.first() else {
+            return Err(BranchDBError::InvalidInput("Expected DROP TABLE <table>".into()));
+        };
+        let mut changes = Vec::new();
+        for name in names {
+            let table = name.to_string();
+            let rows = drop_table_rows(storage, &table)?;
+            if rows.is_empty() && !if_exists {
+                return Err(BranchDBError::TableNotFound { name: table });
+            }
+            changes.extend(rows);
+        }
+        Ok(changes)
+    }
+    // TRUNCATE [TABLE] <table>: deletes every row but keeps the schema and other metadata
+    else if cmd_upper.starts_with("TRUNCATE") {
+        let rest = command.trim()["TRUNCATE".len()..].trim_start();
+        let rest = match rest.get(..6) {
+            Some(word) if word.eq_ignore_ascii_case("TABLE ") => &rest[6..],
+            _ => rest,
+        };
+        let table = rest.trim().trim_end_matches(';').trim();
+        if table.is_empty() || table.contains(char::is_whitespace) {
+            return Err(BranchDBError::InvalidInput("Expected TRUNCATE TABLE <table>".into()));
+        }
+        if storage.db.get(encode_row_key(table, "!schema"))?.is_none() {
+            return Err(BranchDBError::TableNotFound { name: table.to_string() });
+        }
+        Ok(drop_table_rows(storage, table)?.into_iter()
+            .filter(|change| !matches!(change, Change::Delete { id, .. } if id.starts_with('!')))
+            .collect())
+    }
+    else {
+        Err(BranchDBError::InvalidInput("Unsupported SQL command".into()))
+    }
+}
+
+// Statements that delete rows or values wholesale rather than naming the rows they change,
+// which the CLI confirms before committing.
+pub(crate) fn is_destructive(command: &str) -> bool {
+    let upper = command.trim_start().to_uppercase();
+    upper.starts_with("DROP TABLE")
+        || upper.starts_with("TRUNCATE")
+        || upper.starts_with("CREATE OR REPLACE TABLE")
+        || upper.starts_with("CREATE TABLE OR REPLACE")
+        || (upper.starts_with("ALTER TABLE") && upper.contains("DROP COLUMN"))
+}
+
+// Splits a script at the semicolons outside quotes and `$$` bodies. `--` comments run to the
+// end of the line and are dropped, as are empty statements.
+pub(crate) fn split_statements(script: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    // The quote character, or `$` inside a `$$` body
+    let mut quote = None;
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('$'), '$') if chars.next_if_eq(&'$').is_some() => {
+                current.push_str("$$");
+                quote = None;
+            }
+            (Some(q), _) => {
+                current.push(c);
+                // A doubled quote closes and reopens, which keeps it inside
+                if c == q && q != '$' {
+                    quote = None;
+                }
+            }
+            (None, '\'' | '"') => {
+                current.push(c);
+                quote = Some(c);
+            }
+            (None, '$') if chars.next_if_eq(&'$').is_some() => {
+                current.push_str("$$");
+                quote = Some('$');
+            }
+            (None, '-') if chars.next_if_eq(&'-').is_some() => {
+                while chars.next_if(|&next| next != '\n').is_some() {}
+            }
+            (None, ';') => {
+                if !current.trim().is_empty() {
+                    statements.push(current.trim().to_string());
+                }
+                current.clear();
+            }
+            (None, _) => current.push(c),
+        }
+    }
+    if !current.trim().is_empty() {
+        statements.push(current.trim().to_string());
+    }
+    statements
+}
+
+// A delete for every live key of a table, metadata such as `!schema` included, so nothing of
+// it is left at HEAD.
+fn drop_table_rows(storage: &CommitStorage, table: &str) -> Result<Vec<Change>> {
+    let prefix = table_key_prefix(table);
+    let mut changes = Vec::new();
+    for item in storage.db.prefix_iterator(prefix.as_bytes()) {
+        let (key, _) = item?;
+        if !key.starts_with(prefix.as_bytes()) {
+            break;
+        }
+        let Some((_, id)) = decode_row_key(&key) else { continue };
+        changes.push(Change::Delete { table: table.to_string(), id });
+    }
+    Ok(changes)
+}
+
+// Rows are stored positionally, so a dropped column's value has to leave every row with it:
+// left in place, it would be read as the next column, or as one added later. Returns an
+// update for every live row that held a value for the column; rows stored as objects lose
+// the field instead.
+fn drop_column_from_rows(storage: &CommitStorage, table: &str, column: &str, position: Option<usize>) -> Result<Vec<Change>> {
+    let prefix = table_key_prefix(table);
+    let mut changes = Vec::new();
+    for item in storage.db.prefix_iterator(prefix.as_bytes()) {
+        let (key, value) = item?;
+        if !key.starts_with(prefix.as_bytes()) {
+            break;
+        }
+        let Some((_, id)) = decode_row_key(&key) else { continue };
+        if id.starts_with('!') {
+            continue;
+        }
+        let CrdtValue::Register(data) = bincode::deserialize(&value)? else { continue };
+        let mut row: serde_json::Value = serde_json::from_slice(&data)?;
+        let changed = match (&mut row, position) {
+            (serde_json::Value::Array(values), Some(position)) if position < values.len() => {
+                values.remove(position);
+                true
+            }
+            (serde_json::Value::Object(fields), _) => fields.remove(column).is_some(),
+            _ => false,
+        };
+        if changed {
+            changes.push(Change::Update {
+                table: table.to_string(),
+                id,
+                value: bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&row)?))?,
+            });
+        }
+    }
+    Ok(changes)
+}
+
+// `UPDATE <table> SET counter = <new> WHERE id = '<id>' AND counter = <expected>`, a
+// compare-and-swap on a counter row. The two WHERE conditions may come in either order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CounterSwap {
+    pub table: String,
+    pub id: String,
+    pub expected: u64,
+    pub new_value: u64,
+}
+
+// Recognizes a counter compare-and-swap; any other statement, including other UPDATEs,
+// gives None.
+pub(crate) fn parse_counter_swap(command: &str) -> Option<CounterSwap> {
+    if !command.trim_start().to_uppercase().starts_with("UPDATE") {
+        return None;
+    }
+    let ast = Parser::parse_sql(&GenericDialect, command).ok()?;
+    let [Statement::Update { table, assignments, selection: Some(selection), .. }] = ast.as_slice() else {
+        return None;
+    };
+    let TableFactor::Table { name, .. } = &table.relation else { return None };
+    let [Assignment { id: column, value }] = assignments.as_slice() else { return None };
+    if !matches!(column.as_slice(), [c] if c.value.eq_ignore_ascii_case("counter")) {
+        return None;
+    }
+    let new_value = u64_literal(value)?;
+
+    let Expr::BinaryOp { left, op: BinaryOperator::And, right } = selection else { return None };
+    let (mut id, mut expected) = (None, None);
+    for condition in [left, right] {
+        let Expr::BinaryOp { left: column, op: BinaryOperator::Eq, right: value } = &**condition else {
+            return None;
+        };
+        let Expr::Identifier(column) = &**column else { return None };
+        match column.value.to_lowercase().as_str() {
+            "id" => match &**value {
+                Expr::Value(Value::SingleQuotedString(s)) => id = Some(s.clone()),
+                _ => return None,
+            },
+            "counter" => expected = Some(u64_literal(value)?),
+            _ => return None,
+        }
+    }
+    Some(CounterSwap { table: name.to_string(), id: id?, expected: expected?, new_value })
+}
+
+fn u64_literal(expr: &Expr) -> Option<u64> {
+    match expr {
+        Expr::Value(Value::Number(n, _)) => n.parse().ok(),
+        _ => None,
+    }
+}
+
+// Estimated cost of a write, as shown by `EXPLAIN INSERT|UPDATE|DELETE`. Rows live under
+// `table:id` keys, so the id is the only index: a WHERE that pins `id` to a value is a point
+// lookup, and anything else scans the whole table at HEAD.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DmlPlan {
+    pub operation: String,
+    pub table: String,
+    pub estimated_rows_scanned: usize,
+    pub estimated_rows_affected: usize,
+    pub index_used: Option<String>,
+}
+
+impl DmlPlan {
+    pub fn to_lines(&self) -> Vec<String> {
+        let access = match (&self.index_used, self.operation.as_str()) {
+            (_, "INSERT") => "Direct write by id".to_string(),
+            (Some(index), _) => format!("Index lookup on {} using {}", self.table, index),
+            (None, _) => format!("Full scan on {}", self.table),
+        };
+        vec![
+            format!("{} on {}", self.operation, self.table),
+            format!("  -> {}", access),
+            format!("     rows scanned: ~{}", self.estimated_rows_scanned),
+            format!("     rows affected: ~{}", self.estimated_rows_affected),
+        ]
+    }
+}
+
+// The statement after `EXPLAIN`, or None if the command isn't an EXPLAIN.
+pub(crate) fn explain_target(command: &str) -> Option<&str> {
+    let (keyword, rest) = command.trim_start().split_once(char::is_whitespace)?;
+    keyword.eq_ignore_ascii_case("EXPLAIN").then(|| rest.trim())
+}
+
+// Plans a write without running it. Counts come from the table at HEAD.
+pub(crate) fn explain_dml(storage: &CommitStorage, statement: &str) -> Result<DmlPlan> {
+    let statement = statement.trim().trim_end_matches(';');
+    let operation = statement.split_whitespace().next().unwrap_or_default().to_uppercase();
+    let invalid = |e: sqlparser::parser::ParserError| BranchDBError::InvalidInput(e.to_string());
+    let (table, selection) = match operation.as_str() {
+        "INSERT" => {
+            // A row is written straight to its key; no constraint makes it read anything first
+            let table = statement.split_whitespace().nth(2)
+                .ok_or_else(|| BranchDBError::InvalidInput("Missing table name".into()))?;
+            return Ok(DmlPlan {
+                operation,
+                table: table.split('(').next().unwrap_or(table).to_string(),
+                estimated_rows_scanned: 0,
+                estimated_rows_affected: 1,
+                index_used: None,
+            });
+        }
+        "UPDATE" => {
+            let ast = Parser::parse_sql(&GenericDialect, statement).map_err(invalid)?;
+            let [Statement::Update { table, selection, .. }] = ast.as_slice() else {
+                return Err(BranchDBError::InvalidInput("Expected a single UPDATE statement".into()));
+            };
+            let TableFactor::Table { name, .. } = &table.relation else {
+                return Err(BranchDBError::InvalidInput("UPDATE must name a table".into()));
+            };
+            (name.to_string(), selection.clone())
+        }
+        "DELETE" => delete_target(statement)?,
+        _ => return Err(BranchDBError::InvalidInput("EXPLAIN supports INSERT, UPDATE and DELETE".into())),
+    };
+
+    let count = |selection: Option<&Expr>| -> Result<usize> {
+        let sql = match selection {
+            Some(predicate) => format!("SELECT COUNT(*) FROM {} WHERE {}", table, predicate),
+            None => format!("SELECT COUNT(*) FROM {}", table),
+        };
+        let result = QueryProcessor::new(&storage.db).query(&sql)?;
+        Ok(result.rows.first().and_then(|row| row.first()).and_then(|n| n.as_u64()).unwrap_or(0) as usize)
+    };
+    let composite = MultiKeyPrimaryKey::from_schema(&storage.get_table_schema(&table, None)?);
+    let key_lookup = match (&composite, &selection) {
+        (Some(key), Some(selection)) => {
+            let values = key_values(selection);
+            key.row_id(|column| values.get(column).cloned()).ok()
+                .map(|id| (format!("primary key ({})", key.columns.join(", ")), id))
+        }
+        (None, Some(selection)) => primary_key_value(selection).map(|id| ("primary key (id)".to_string(), id)),
+        _ => None,
+    };
+    let (index_used, scanned, affected) = match key_lookup {
+        // At most the one row under that key, which is what UPDATE itself reads
+        Some((index, id)) => {
+            let found = usize::from(storage.db.get(encode_row_key(&table, &id).as_bytes())?.is_some());
+            (Some(index), found, found)
+        }
+        None => (None, count(None)?, count(selection.as_ref())?),
+    };
+    Ok(DmlPlan {
+        operation,
+        table,
+        estimated_rows_scanned: scanned,
+        estimated_rows_affected: affected,
+        index_used,
+    })
+}
+
+// The id a WHERE clause pins with `id = <literal>`, also when it is one side of an AND.
+fn primary_key_value(selection: &Expr) -> Option<String> {
+    let mut values = key_values(selection);
+    let id = values.keys().find(|column| column.eq_ignore_ascii_case("id"))?.clone();
+    values.remove(&id)
+}
+
+// Every `column = <literal>` a WHERE clause requires, looking through ANDs and parentheses.
+fn key_values(selection: &Expr) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    collect_key_values(selection, &mut values);
+    values
+}
+
+fn collect_key_values(selection: &Expr, values: &mut HashMap<String, String>) {
+    match selection {
+        Expr::Nested(inner) => collect_key_values(inner, values),
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+            collect_key_values(left, values);
+            collect_key_values(right, values);
+        }
+        Expr::BinaryOp { left, op: BinaryOperator::Eq, right } => {
+            let ((Expr::Identifier(column), Expr::Value(value)) | (Expr::Value(value), Expr::Identifier(column))) =
+                (&**left, &**right) else { return };
+            let value = match value {
+                Value::SingleQuotedString(s) => s.clone(),
+                Value::Number(n, _) => n.clone(),
+                _ => return,
+            };
+            values.entry(column.value.clone()).or_insert(value);
+        }
+        _ => {}
+    }
+}
+
+// The row of a table with a composite key that an UPDATE's WHERE clause names.
+fn row_id_for(table: &str, key: &MultiKeyPrimaryKey, where_clause: &str) -> Result<String> {
+    let query = format!("SELECT * FROM {} WHERE {}", table, where_clause);
+    let ast = Parser::parse_sql(&GenericDialect, &query)
+        .map_err(|e| BranchDBError::InvalidInput(format!("SQL parse error: {}", e)))?;
+    let selection = match ast.as_slice() {
+        [Statement::Query(query)] => match &*query.body {
+            SetExpr::Select(select) => select.selection.clone(),
+            _ => None,
+        },
+        _ => None,
+    };
+    let values = selection.as_ref().map(key_values).unwrap_or_default();
+    key.row_id(|column| values.get(column).cloned())
+}
+
+// `DELETE FROM <table> [WHERE ...]`: everything after DELETE reads as the FROM and WHERE of a
+// SELECT.
+fn delete_target(statement: &str) -> Result<(String, Option<Expr>)> {
+    let statement = statement.trim().trim_end_matches(';');
+    let query = format!("SELECT *{}", &statement["DELETE".len()..]);
+    let ast = Parser::parse_sql(&GenericDialect, &query)
+        .map_err(|e| BranchDBError::InvalidInput(e.to_string()))?;
+    let table_and_selection = match ast.as_slice() {
+        [Statement::Query(query)] => match &*query.body {
+            SetExpr::Select(select) => match select.from.as_slice() {
+                [from] if from.joins.is_empty() => match &from.relation {
+                    TableFactor::Table { name, .. } => Some((name.to_string(), select.selection.clone())),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    };
+    table_and_selection
+        .ok_or_else(|| BranchDBError::InvalidInput("Expected DELETE FROM <table> [WHERE ...]".into()))
+}
+
+// A CREATE TABLE statement's table and modifiers. `CREATE TABLE OR REPLACE` is taken as a
+// spelling of `CREATE OR REPLACE TABLE`, which `statement` is rewritten to so sqlparser can
+// read the columns.
+struct CreateTableTarget {
+    table: String,
+    if_not_exists: bool,
+    or_replace: bool,
+    statement: String,
+}
+
+fn create_table_target(command: &str) -> Result<CreateTableTarget> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let keywords_at = |at: usize, keywords: &[&str]| {
+        keywords.iter().enumerate().all(|(i, keyword)| words.get(at + i).is_some_and(|w| w.eq_ignore_ascii_case(keyword)))
+    };
+
+    // CREATE [OR REPLACE] TABLE [OR REPLACE] [IF NOT EXISTS] <name>
+    let mut at = 1;
+    let replace_before = keywords_at(at, &["OR", "REPLACE"]);
+    if replace_before {
+        at += 2;
+    }
+    at += 1;
+    let replace_after = keywords_at(at, &["OR", "REPLACE"]);
+    if replace_after {
+        at += 2;
+    }
+    let if_not_exists = keywords_at(at, &["IF", "NOT", "EXISTS"]);
+    if if_not_exists {
+        at += 3;
+    }
+    let or_replace = replace_before || replace_after;
+    if or_replace && if_not_exists {
+        return Err(BranchDBError::InvalidInput("CREATE TABLE can't use both OR REPLACE and IF NOT EXISTS".into()));
+    }
+
+    let name = words.get(at).ok_or_else(|| BranchDBError::InvalidInput("Missing table name".into()))?;
+    let table = name.split('(').next().unwrap_or(name);
+    if table.is_empty() {
+        return Err(BranchDBError::InvalidInput("Missing table name".into()));
+    }
+    let statement = if replace_after {
+        let mut rest = command.trim_start();
+        for keyword in ["CREATE", "TABLE", "OR", "REPLACE"] {
+            rest = rest[keyword.len()..].trim_start();
+        }
+        format!("CREATE OR REPLACE TABLE {}", rest)
+    } else {
+        command.to_string()
+    };
+    Ok(CreateTableTarget { table: table.to_string(), if_not_exists, or_replace, statement })
+}
+
+// Builds the schema for a CREATE TABLE statement, keeping the declared column order, and the
+// TTL of a trailing `WITH TTL '<duration>'`. Statements sqlparser can't handle still create
+// the table, just without declared columns.
+pub(crate) fn create_table_schema(command: &str) -> serde_json::Value {
+    let (statement, duration) = ttl::split_ttl_clause(command);
+    let mut schema = declared_schema(&statement);
+    if let Some(duration) = duration {
+        schema["ttl"] = serde_json::Value::String(duration);
+    }
+    schema
+}
+
+fn declared_schema(command: &str) -> serde_json::Value {
+    let Ok(ast) = Parser::parse_sql(&GenericDialect, command) else {
+        return serde_json::json!({});
+    };
+    let Some(Statement::CreateTable { columns, constraints, .. }) = ast.first() else {
+        return serde_json::json!({});
+    };
+    if columns.is_empty() {
+        return serde_json::json!({});
+    }
+
+    // `REFERENCES` on a column or a `FOREIGN KEY` constraint, each as
+    // `column -> {"table", "column"}`; a reference without a column is to the row id
+    let mut foreign_keys = serde_json::Map::new();
+    let mut reference = |column: &str, table: &ObjectName, referred: Option<&Ident>| {
+        let referred = referred.map_or("id", |c| c.value.as_str());
+        foreign_keys.insert(column.to_string(), serde_json::json!({ "table": table.to_string(), "column": referred }));
+    };
+    for column in columns {
+        for option in &column.options {
+            if let ColumnOption::ForeignKey { foreign_table, referred_columns, .. } = &option.option {
+                reference(&column.name.value, foreign_table, referred_columns.first());
+            }
+        }
+    }
+    for constraint in constraints {
+        if let TableConstraint::ForeignKey { columns, foreign_table, referred_columns, .. } = constraint {
+            for (i, column) in columns.iter().enumerate() {
+                reference(&column.value, foreign_table, referred_columns.get(i));
+            }
+        }
+    }
+
+    // `PRIMARY KEY (a, b)` keys rows by those columns; see MultiKeyPrimaryKey
+    let primary_key = constraints.iter().find_map(|constraint| match constraint {
+        TableConstraint::Unique { columns, is_primary: true, .. } => {
+            Some(columns.iter().map(|c| c.value.clone()).collect::<Vec<_>>())
+        }
+        _ => None,
+    });
+
+    // Only recorded, not enforced on write; `verify-data` reports NULLs in these columns
+    let not_null: Vec<&str> = columns.iter()
+        .filter(|c| c.options.iter().any(|o| matches!(o.option, ColumnOption::NotNull)))
+        .map(|c| c.name.value.as_str())
+        .collect();
+
+    let columns: serde_json::Map<String, serde_json::Value> = columns.iter()
+        .map(|c| (c.name.value.clone(), serde_json::Value::String(c.data_type.to_string())))
+        .collect();
+    let mut schema = serde_json::json!({ "columns": columns });
+    if !not_null.is_empty() {
+        schema["not_null"] = serde_json::json!(not_null);
+    }
+    if !foreign_keys.is_empty() {
+        schema["foreign_keys"] = serde_json::Value::Object(foreign_keys);
+    }
+    if let Some(primary_key) = primary_key {
+        schema["primary_key"] = serde_json::json!(primary_key);
+    }
+    schema
+}
+
+fn parse_sql_values(values_part: &str) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    let mut in_quotes = false;
+    let mut current = String::new();
+    let mut chars = values_part.chars().peekable();
+    
+    if values_part.starts_with('(') {
+        chars.next();
+    }
+    
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_quotes = !in_quotes;
+                if !in_quotes {
+                    values.push(current.trim().to_string());
+                    current.clear();
+                }
+            },
+            ',' if !in_quotes => {
+                // Skip commas between values
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == ',' {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+            },
+            ')' if !in_quotes => break,
+            _ => current.push(c),
+        }
+    }
+    
+    Ok(values)
+}
+
+// Checks a value against its column type and returns what should be stored, according to
+// the coercion policy: the original string, or the converted value under `Coerce`.
+pub(crate) fn validate_value_type(value: &str, expected_type: &str, policy: CoercionPolicy) -> Result<serde_json::Value> {
+    let original = serde_json::Value::String(value.to_string());
+    match policy {
+        CoercionPolicy::Coerce => return TypeCoercer::coerce(&original, expected_type),
+        CoercionPolicy::Silent => return Ok(original),
+        CoercionPolicy::Strict => {}
+    }
+
+    match expected_type.to_uppercase().as_str() {
+        "INTEGER" | "INT" => {
+            value.parse::<i64>()
+                .map_err(|_| BranchDBError::TypeMismatch(format!("Expected integer, got {}", value)))?;
+        },
+        "FLOAT" | "REAL" => {
+            value.parse::<f64>()
+                .map_err(|_| BranchDBError::TypeMismatch(format!("Expected float, got {}", value)))?;
+        },
+        "BOOLEAN" | "BOOL" if !value.eq_ignore_ascii_case("true") && !value.eq_ignore_ascii_case("false") => {
+            return Err(BranchDBError::TypeMismatch(format!("Expected boolean, got {}", value)));
+        },
+        _ => {} // No validation for TEXT/STRING, or a valid boolean
+    }
+    Ok(original)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::database::StorageOptions;
+    use crate::core::sql_executor::{SqlExecutor, SqlOutcome};
+    use crate::core::test_support::{data_path, head, rows, run, temp_repo};
+    use crate::error::BranchDBError;
+    use serde_json::json;
+
+    // Writes '42' and then 'x' into an INTEGER column under `coercion`, returning what the
+    // second write did and the values stored.
+    fn write_strings(coercion: CoercionPolicy) -> (Result<()>, Vec<Vec<serde_json::Value>>) {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions { coercion, ..StorageOptions::default() };
+        let storage = CommitStorage::open_with_options(&data_path(&dir), options).unwrap();
+        run(&storage, "CREATE TABLE counts (id TEXT, n INTEGER)");
+        run(&storage, "INSERT INTO counts VALUES ('a', '42')");
+        let second = SqlExecutor::execute(&storage, "INSERT INTO counts VALUES ('b', 'x')").map(drop);
+        (second, rows(&storage, "SELECT n FROM counts WHERE n = '42' OR id = 'b' ORDER BY id"))
+    }
+
+    #[test]
+    fn each_coercion_policy_treats_a_string_in_an_integer_column_its_own_way() {
+        let (second, stored) = write_strings(CoercionPolicy::Strict);
+        assert!(matches!(second, Err(BranchDBError::TypeMismatch(_))), "{:?}", second);
+        assert_eq!(stored, vec![vec![json!("42")]]);
+
+        let (second, stored) = write_strings(CoercionPolicy::Coerce);
+        assert!(matches!(second, Err(BranchDBError::TypeMismatch(_))), "{:?}", second);
+        assert_eq!(stored, vec![vec![json!(42)]]);
+
+        let (second, stored) = write_strings(CoercionPolicy::Silent);
+        assert!(second.is_ok(), "{:?}", second);
+        assert_eq!(stored, vec![vec![json!("42")], vec![json!("x")]]);
+    }
+
+    #[test]
+    fn create_table_if_not_exists_commits_only_once() {
+        let (_dir, storage) = temp_repo();
+        let create = "CREATE TABLE IF NOT EXISTS users (id TEXT, name TEXT)";
+        assert!(matches!(run(&storage, create), SqlOutcome::Committed { changes: 1, .. }));
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let before = head(&storage);
+        assert!(matches!(run(&storage, create), SqlOutcome::Unchanged));
+        assert_eq!(head(&storage), before);
+        assert_eq!(storage.get_commit_history().unwrap().len(), 2);
+        assert_eq!(rows(&storage, "SELECT name FROM users"), vec![vec![json!("ann")]]);
+
+        // OR REPLACE deletes the rows in the commit that writes the new schema
+        for replace in ["CREATE OR REPLACE TABLE users (id TEXT, email TEXT)", "CREATE TABLE OR REPLACE users (id TEXT, email TEXT)"] {
+            run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+            assert!(matches!(run(&storage, replace), SqlOutcome::Committed { .. }));
+            assert!(rows(&storage, "SELECT * FROM users").is_empty());
+            assert!(storage.get_table_schema("users", None).unwrap()["columns"].get("email").is_some());
+        }
+        let both = SqlExecutor::execute(&storage, "CREATE OR REPLACE TABLE IF NOT EXISTS users (id TEXT)");
+        assert!(matches!(both, Err(BranchDBError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::core::database::CommitStorage;
+use crate::core::query::{QueryProcessor, QueryResult};
+use crate::core::models::Change;
+use crate::core::{attach, functions, phonetic, sql, views};
+use crate::error::Result;
+
+// What running one statement produced: rows for a read, or the commit a write created.
+#[derive(Debug)]
+pub enum SqlOutcome {
+    Rows(QueryResult),
+    Committed { hash: [u8; 32], changes: usize },
+    // A write that changed no rows, like an upsert that did nothing; no commit is created
+    Unchanged,
+    // A counter compare-and-swap; `hash` is the new commit, or None if the counter didn't match
+    CounterSwap { hash: Option<[u8; 32]> },
+    // A materialized view was created or refreshed; `hash` is None if its rows didn't change
+    View { name: String, hash: Option<[u8; 32]> },
+    // A stored function was created, or dropped; `existed` is false for DROP IF EXISTS of one
+    // that wasn't there
+    Function { name: String, dropped: bool, existed: bool },
+    // A repository was attached, reading at `commit`, or detached
+    Attachment { alias: String, commit: Option<[u8; 32]> },
+    // A phonetic index was created over `rows` rows, or dropped
+    PhoneticIndex { table: String, column: String, dropped: bool, rows: usize },
+}
+
+// Single entry point for SQL text. Reads go to the query engine and never create a commit;
+// everything else is translated into changes and committed with an `SQL: <statement>` message,
+// so `query` and `sql` record identical commits for the same write.
+pub struct SqlExecutor;
+
+impl SqlExecutor {
+    pub fn execute(storage: &CommitStorage, sql: &str) -> Result<SqlOutcome> {
+        Self::execute_checked(storage, sql, |_| Ok(()))
+    }
+
+    // Like `execute`, but a write's changes are handed to `check` before they are committed,
+    // and an error from it commits nothing.
+    pub fn execute_checked(storage: &CommitStorage, sql: &str, check: impl FnOnce(&[Change]) -> Result<()>) -> Result<SqlOutcome> {
+        if attach::is_show_attachments(sql) {
+            return Ok(SqlOutcome::Rows(storage.attachments_result()?));
+        }
+        if Self::is_read(sql) {
+            let processor = QueryProcessor::new(&storage.db).with_strict_cast(storage.options().strict_cast);
+            return Ok(SqlOutcome::Rows(processor.query(sql)?));
+        }
+
+        if let Some(create) = views::parse_create_view(sql) {
+            let (name, query) = create?;
+            let hash = storage.create_view(&name, &query)?;
+            return Ok(SqlOutcome::View { name, hash });
+        }
+        if let Some(name) = views::parse_refresh_view(sql) {
+            let hash = storage.refresh_view(&name)?;
+            return Ok(SqlOutcome::View { name, hash });
+        }
+
+        if let Some(create) = functions::parse_create_function(sql) {
+            let function = create?;
+            storage.create_function(&function)?;
+            return Ok(SqlOutcome::Function { name: function.name, dropped: false, existed: true });
+        }
+        if let Some((name, if_exists)) = functions::parse_drop_function(sql) {
+            let existed = storage.drop_function(&name, if_exists)?;
+            return Ok(SqlOutcome::Function { name, dropped: true, existed });
+        }
+
+        if let Some(attach) = attach::parse_attach(sql) {
+            let attachment = attach?;
+            let commit = storage.attach(&attachment)?;
+            return Ok(SqlOutcome::Attachment { alias: attachment.alias, commit: Some(commit) });
+        }
+        if let Some(detach) = attach::parse_detach(sql) {
+            let alias = detach?;
+            storage.detach(&alias)?;
+            return Ok(SqlOutcome::Attachment { alias, commit: None });
+        }
+
+        if let Some(create) = phonetic::parse_create_index(sql) {
+            let (table, column) = create?;
+            let rows = storage.create_phonetic_index(&table, &column)?;
+            return Ok(SqlOutcome::PhoneticIndex { table, column, dropped: false, rows });
+        }
+        if let Some(drop) = phonetic::parse_drop_index(sql) {
+            let (table, column) = drop?;
+            storage.drop_phonetic_index(&table, &column)?;
+            return Ok(SqlOutcome::PhoneticIndex { table, column, dropped: true, rows: 0 });
+        }
+
+        if let Some(swap) = sql::parse_counter_swap(sql) {
+            storage.check_writable([swap.table.as_str()])?;
+            let message = format!("SQL: {}", sql);
+            let hash = storage.swap_counter(&swap.table, &swap.id, swap.expected, swap.new_value, &message)?;
+            return Ok(SqlOutcome::CounterSwap { hash });
+        }
+
+        let changes = sql::statement_changes(storage, sql)?;
+        storage.check_writable(changes.iter().map(Change::table))?;
+        if changes.is_empty() {
+            return Ok(SqlOutcome::Unchanged);
+        }
+        check(&changes)?;
+        let count = changes.len();
+        let hash = storage.create_commit(&format!("SQL: {}", sql), changes)?;
+        Ok(SqlOutcome::Committed { hash, changes: count })
+    }
+
+    pub fn is_read(sql: &str) -> bool {
+        let upper = sql.trim_start().to_uppercase();
+        upper.starts_with("SELECT") || upper.starts_with("WITH")
+    }
+}
@@ -0,0 +1,178 @@
+use crate::core::database::{table_key_prefix, CommitStorage};
+use crate::core::history::HistoryWalk;
+use crate::core::models::Change;
+use crate::error::{BranchDBError, Result};
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use tracing::debug;
+
+// `!table-stats:<table>` holds the last stats computed for a table. They name the HEAD they
+// were computed at and are reused until it moves.
+const STATS_CACHE_PREFIX: &str = "!table-stats:";
+
+// The span of time changes are counted over, named by its first day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Day,
+    // Weeks start on Monday
+    Week,
+    Month,
+}
+
+impl Bucket {
+    fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Bucket::Day => date,
+            Bucket::Week => date - chrono::Days::new(u64::from(date.weekday().num_days_from_monday())),
+            Bucket::Month => date.with_day(1).unwrap_or(date),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChangeCounts {
+    pub inserts: usize,
+    pub updates: usize,
+    pub deletes: usize,
+}
+
+impl ChangeCounts {
+    fn count(&mut self, change: &Change) {
+        match change {
+            Change::Insert { .. } => self.inserts += 1,
+            Change::Update { .. } => self.updates += 1,
+            Change::Delete { .. } => self.deletes += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BucketCounts {
+    // First day of the bucket, YYYY-MM-DD
+    pub start: String,
+    #[serde(flatten)]
+    pub changes: ChangeCounts,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RowUpdates {
+    pub id: String,
+    pub updates: usize,
+}
+
+// What the history reachable from HEAD did to one table, and what the table holds now.
+// Schema changes are counted apart from row changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableStats {
+    pub table: String,
+    pub head: String,
+    pub bucket: Bucket,
+    pub commits: usize,
+    pub schema_changes: usize,
+    pub changes: ChangeCounts,
+    // Oldest first; buckets without changes are left out
+    pub buckets: Vec<BucketCounts>,
+    // The `top` rows updated most often, most first, ties by id
+    pub top: usize,
+    pub most_updated: Vec<RowUpdates>,
+    pub rows: usize,
+    // Stored size of the live rows' keys and values
+    pub bytes: u64,
+}
+
+impl CommitStorage {
+    // Stats for `table` at HEAD, from the cache when they were last computed at the same HEAD
+    // with the same bucket and `top`. Walks the whole history reachable from HEAD otherwise.
+    pub fn table_stats(&self, table: &str, bucket: Bucket, top: usize) -> Result<TableStats> {
+        let head = self.get_head()?.ok_or_else(|| BranchDBError::TableNotFound { name: table.to_string() })?;
+        let cache_key = format!("{}{}", STATS_CACHE_PREFIX, table);
+        if let Some(cached) = self.db.get(cache_key.as_bytes())? {
+            // A cache entry that doesn't decode, as after the stats gain a field, is recomputed
+            if let Ok(stats) = serde_json::from_slice::<TableStats>(&cached) {
+                if stats.head == hex::encode(head) && stats.bucket == bucket && stats.top == top {
+                    debug!(table, "table stats served from cache");
+                    return Ok(stats);
+                }
+            }
+        }
+
+        let stats = self.compute_table_stats(table, &head, bucket, top)?;
+        self.db.put(cache_key.as_bytes(), serde_json::to_vec(&stats)?)?;
+        Ok(stats)
+    }
+
+    fn compute_table_stats(&self, table: &str, head: &[u8; 32], bucket: Bucket, top: usize) -> Result<TableStats> {
+        let mut commits = 0;
+        let mut schema_changes = 0;
+        let mut changes = ChangeCounts::default();
+        let mut buckets: BTreeMap<NaiveDate, ChangeCounts> = BTreeMap::new();
+        let mut updates: HashMap<String, usize> = HashMap::new();
+
+        for entry in HistoryWalk::new(self, Some(*head))? {
+            let (_, commit) = entry?;
+            let touching: Vec<&Change> = commit.changes.iter().filter(|c| c.table() == table).collect();
+            if touching.is_empty() {
+                continue;
+            }
+            commits += 1;
+            let date = chrono::DateTime::from_timestamp_millis(commit.timestamp as i64)
+                .map(|time| time.date_naive())
+                .unwrap_or_default();
+            for change in touching {
+                if change.id().starts_with('!') {
+                    schema_changes += usize::from(change.id() == "!schema");
+                    continue;
+                }
+                changes.count(change);
+                buckets.entry(bucket.start(date)).or_default().count(change);
+                if let Change::Update { id, .. } = change {
+                    *updates.entry(id.clone()).or_default() += 1;
+                }
+            }
+        }
+
+        let (rows, bytes) = self.live_size(table)?;
+        if commits == 0 && rows == 0 {
+            return Err(BranchDBError::TableNotFound { name: table.to_string() });
+        }
+        let mut most_updated: Vec<RowUpdates> = updates.into_iter().map(|(id, updates)| RowUpdates { id, updates }).collect();
+        most_updated.sort_by(|a, b| b.updates.cmp(&a.updates).then_with(|| a.id.cmp(&b.id)));
+        most_updated.truncate(top);
+
+        Ok(TableStats {
+            table: table.to_string(),
+            head: hex::encode(head),
+            bucket,
+            commits,
+            schema_changes,
+            changes,
+            buckets: buckets.into_iter()
+                .map(|(start, changes)| BucketCounts { start: start.to_string(), changes })
+                .collect(),
+            top,
+            most_updated,
+            rows,
+            bytes,
+        })
+    }
+
+    // Number of live rows, metadata keys left out, and the bytes their keys and values take.
+    fn live_size(&self, table: &str) -> Result<(usize, u64)> {
+        let prefix = table_key_prefix(table);
+        let (mut rows, mut bytes) = (0, 0);
+        for item in self.db.prefix_iterator(prefix.as_bytes()) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if key[prefix.len()..].starts_with(b"!") {
+                continue;
+            }
+            rows += 1;
+            bytes += (key.len() + value.len()) as u64;
+        }
+        Ok((rows, bytes))
+    }
+}
@@ -0,0 +1,56 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::database::{decode_row_key, table_key_prefix, CommitStorage};
+use crate::core::sql_executor::{SqlExecutor, SqlOutcome};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+// A repository in a temporary directory, removed along with it. Keep the directory alive for
+// as long as the storage is used.
+pub(crate) fn temp_repo() -> (TempDir, CommitStorage) {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = CommitStorage::open(&data_path(&dir)).unwrap();
+    (dir, storage)
+}
+
+// Where `temp_repo` keeps the database, for reopening it: a directory inside `dir`, so that
+// backups made next to it are removed with `dir` too.
+pub(crate) fn data_path(dir: &TempDir) -> String {
+    dir.path().join("data").to_str().unwrap().to_string()
+}
+
+// Runs one statement as `sql` does, panicking if it fails.
+pub(crate) fn run(storage: &CommitStorage, statement: &str) -> SqlOutcome {
+    SqlExecutor::execute(storage, statement).unwrap_or_else(|e| panic!("{}: {}", statement, e))
+}
+
+// The rows a query returns, in its order.
+pub(crate) fn rows(storage: &CommitStorage, query: &str) -> Vec<Vec<Value>> {
+    match run(storage, query) {
+        SqlOutcome::Rows(result) => result.rows,
+        other => panic!("{} returned {:?}", query, other),
+    }
+}
+
+pub(crate) fn head(storage: &CommitStorage) -> [u8; 32] {
+    storage.get_head().unwrap().expect("no commits yet")
+}
+
+// A table's live rows as the keyspace holds them, with `!schema`'s plain JSON as the register
+// history holds it in.
+pub(crate) fn live_rows(storage: &CommitStorage, table: &str) -> BTreeMap<String, CrdtValue> {
+    let prefix = table_key_prefix(table);
+    storage.db.prefix_iterator(prefix.as_bytes())
+        .map(Result::unwrap)
+        .take_while(|(key, _)| key.starts_with(prefix.as_bytes()))
+        .map(|(key, value)| match decode_row_key(&key).unwrap().1 {
+            id if id == "!schema" => (id, CrdtValue::Register(value.into_vec())),
+            id => (id, bincode::deserialize(&value).unwrap()),
+        })
+        .collect()
+}
+
+// A table's rows, `!schema` included, replayed from history at `hash`.
+pub(crate) fn replayed_rows(storage: &CommitStorage, table: &str, hash: &[u8; 32]) -> BTreeMap<String, CrdtValue> {
+    storage.iter_table(table, hash).unwrap().map(Result::unwrap).collect()
+}
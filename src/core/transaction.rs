@@ -0,0 +1,230 @@
+use crate::core::crdt::{CrdtValue, TableState};
+use crate::core::database::CommitStorage;
+use crate::core::models::Change;
+use crate::core::row;
+use crate::core::sql;
+use crate::error::{BranchDBError, Result};
+use std::collections::{HashMap, HashSet};
+
+// Transaction buffers changes against the HEAD seen at `begin` and writes them as a single
+// commit. Dropping a transaction without committing discards the buffered changes.
+pub struct Transaction<'a> {
+    storage: &'a CommitStorage,
+    base: Option<[u8; 32]>,
+    snapshot: HashMap<String, TableState>,
+    changes: Vec<Change>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(storage: &'a CommitStorage) -> Result<Self> {
+        Ok(Self {
+            storage,
+            base: storage.get_head()?,
+            snapshot: HashMap::new(),
+            changes: Vec::new(),
+        })
+    }
+
+    pub fn base(&self) -> Option<[u8; 32]> {
+        self.base
+    }
+
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    pub fn insert(&mut self, table: &str, id: &str, value: serde_json::Value) -> Result<()> {
+        let change = Change::Insert {
+            table: table.to_string(),
+            id: id.to_string(),
+            value: self.encode_row(table, &value)?,
+        };
+        self.stage(change)
+    }
+
+    pub fn update(&mut self, table: &str, id: &str, value: serde_json::Value) -> Result<()> {
+        let change = Change::Update {
+            table: table.to_string(),
+            id: id.to_string(),
+            value: self.encode_row(table, &value)?,
+        };
+        self.stage(change)
+    }
+
+    pub fn delete(&mut self, table: &str, id: &str) -> Result<()> {
+        self.stage(Change::Delete {
+            table: table.to_string(),
+            id: id.to_string(),
+        })
+    }
+
+    // Buffers the changes of a SQL write statement instead of committing them immediately.
+    pub fn sql(&mut self, command: &str) -> Result<()> {
+        for change in sql::statement_changes(self.storage, command)? {
+            self.stage(change)?;
+        }
+        Ok(())
+    }
+
+    pub fn rollback(self) {}
+
+    // Checks and writes under the storage's HEAD lock, so no commit can land on a row of this
+    // transaction between the check and the write.
+    pub fn commit(mut self, message: &str) -> Result<[u8; 32]> {
+        if self.changes.is_empty() {
+            return Err(BranchDBError::NothingToCommit);
+        }
+
+        let storage = self.storage;
+        let hash = storage.commit_on_locked_head(message, |head| {
+            if head != self.base {
+                self.check_rebase(head)?;
+            }
+            Ok(Some(std::mem::take(&mut self.changes)))
+        })?;
+        hash.ok_or(BranchDBError::NothingToCommit)
+    }
+
+    // HEAD moved since begin: the buffered changes can be replayed on top of the new HEAD only
+    // if none of the intervening commits touched the same rows.
+    fn check_rebase(&self, head: Option<[u8; 32]>) -> Result<()> {
+        let mut touched = HashSet::new();
+        let mut current_hash = head;
+        loop {
+            if current_hash == self.base {
+                break;
+            }
+            let Some(hash) = current_hash else {
+                return Err(BranchDBError::ConcurrentModification(
+                    "HEAD no longer descends from the transaction's starting commit".into()
+                ));
+            };
+            let commit = self.storage.get_commit_by_hash(&hash)?;
+            for change in &commit.changes {
+                touched.insert(row_key(change));
+            }
+            current_hash = commit.parents.first().cloned();
+        }
+
+        if let Some((table, id)) = self.changes.iter().map(row_key).find(|key| touched.contains(key)) {
+            return Err(BranchDBError::ConcurrentModification(
+                format!("Row '{}' in table '{}' was changed by another commit", id, table)
+            ));
+        }
+        Ok(())
+    }
+
+    fn stage(&mut self, change: Change) -> Result<()> {
+        let (table, id) = row_key(&change);
+        // Schema entries and other internal rows are not subject to existence checks
+        if !id.starts_with('!') {
+            let exists = self.row_exists(&table, &id)?;
+            match &change {
+                Change::Insert { .. } if exists => {
+                    return Err(BranchDBError::ConstraintViolation(
+                        format!("Row '{}' already exists in table '{}'", id, table)
+                    ));
+                }
+                Change::Update { .. } | Change::Delete { .. } if !exists => {
+                    return Err(BranchDBError::RowNotFound { table, id });
+                }
+                _ => {}
+            }
+        }
+        self.changes.push(change);
+        Ok(())
+    }
+
+    fn row_exists(&mut self, table: &str, id: &str) -> Result<bool> {
+        // The most recent buffered change for the row wins over the snapshot
+        if let Some(change) = self.changes.iter().rev().find(|c| c.table() == table && row_key(c).1 == id) {
+            return Ok(!matches!(change, Change::Delete { .. }));
+        }
+        Ok(self.table_snapshot(table)?.contains_key(id))
+    }
+
+    fn table_snapshot(&mut self, table: &str) -> Result<&TableState> {
+        if !self.snapshot.contains_key(table) {
+            let rows = match &self.base {
+                Some(hash) => self.storage.table_state_at(table, hash)?,
+                None => TableState::new(),
+            };
+            self.snapshot.insert(table.to_string(), rows);
+        }
+        Ok(&self.snapshot[table])
+    }
+
+    fn encode_row(&self, table: &str, value: &serde_json::Value) -> Result<Vec<u8>> {
+        if value.is_object() {
+            let schema = self.storage.get_table_schema(table, self.base.as_ref().map(|h| &h[..]))?;
+            row::check_row_against_schema(table, value, &schema)?;
+        }
+        Ok(bincode::serialize(&CrdtValue::Register(serde_json::to_vec(value)?))?)
+    }
+}
+
+fn row_key(change: &Change) -> (String, String) {
+    match change {
+        Change::Insert { table, id, .. }
+        | Change::Update { table, id, .. }
+        | Change::Delete { table, id } => (table.clone(), id.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_support::{rows, run, temp_repo};
+    use serde_json::json;
+    use std::sync::Barrier;
+
+    #[test]
+    fn overlapping_transactions_conflict_and_disjoint_ones_both_land() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        run(&storage, "INSERT INTO users VALUES ('2', 'bob')");
+
+        let mut first = storage.begin().unwrap();
+        let mut second = storage.begin().unwrap();
+        first.sql("UPDATE users SET name = 'amy' WHERE id = '1'").unwrap();
+        second.sql("UPDATE users SET name = 'ada' WHERE id = '1'").unwrap();
+        first.commit("first").unwrap();
+        assert!(matches!(second.commit("second"), Err(BranchDBError::ConcurrentModification(_))));
+
+        let mut first = storage.begin().unwrap();
+        let mut second = storage.begin().unwrap();
+        first.sql("UPDATE users SET name = 'ann' WHERE id = '1'").unwrap();
+        second.sql("UPDATE users SET name = 'ben' WHERE id = '2'").unwrap();
+        first.commit("first").unwrap();
+        second.commit("second").unwrap();
+        assert_eq!(rows(&storage, "SELECT name FROM users ORDER BY id"), vec![vec![json!("ann")], vec![json!("ben")]]);
+    }
+
+    #[test]
+    fn concurrent_transactions_on_one_row_commit_once() {
+        let (_dir, storage) = temp_repo();
+        run(&storage, "CREATE TABLE users (id TEXT, name TEXT)");
+        run(&storage, "INSERT INTO users VALUES ('1', 'ann')");
+        let started = Barrier::new(8);
+        let committed = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|i| {
+                    let (storage, started) = (&storage, &started);
+                    scope.spawn(move || {
+                        let mut tx = storage.begin().unwrap();
+                        tx.sql(&format!("UPDATE users SET name = 'user{}' WHERE id = '1'", i)).unwrap();
+                        started.wait();
+                        match tx.commit("rename") {
+                            Ok(_) => true,
+                            Err(BranchDBError::ConcurrentModification(_)) => false,
+                            Err(e) => panic!("{}", e),
+                        }
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).filter(|&committed| committed).count()
+        });
+        assert_eq!(committed, 1);
+    }
+}
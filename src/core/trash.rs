@@ -0,0 +1,199 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::database::{decode_row_key, encode_row_key, CommitStorage};
+use crate::core::models::Change;
+use crate::core::row;
+use crate::error::{BranchDBError, Result};
+use serde::{Deserialize, Serialize};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Tables with `"soft_delete": true` in their schema keep what `DELETE FROM` removes: the row
+// leaves its id and is written to `!trash:<id>` of the same table in the same commit. Like
+// `!schema`, trash keys are skipped by queries, and being ordinary changes they merge, diff
+// and revert like rows do.
+pub(crate) const TRASH_PREFIX: &str = "!trash:";
+// The column `INCLUDING DELETED` and `show-table --include-deleted` add
+pub const DELETED_AT_COLUMN: &str = "deleted_at";
+
+// What `!trash:<id>` holds, as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrashEntry {
+    // The row as it was stored
+    pub row: serde_json::Value,
+    // Milliseconds since the epoch
+    pub deleted_at: u64,
+}
+
+// A trashed row at HEAD.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashedRow {
+    pub table: String,
+    pub id: String,
+    pub row: serde_json::Value,
+    pub deleted_at: u64,
+}
+
+pub fn is_soft_delete(schema: &serde_json::Value) -> bool {
+    schema.get("soft_delete").and_then(|s| s.as_bool()).unwrap_or(false)
+}
+
+pub fn trash_id(id: &str) -> String {
+    format!("{}{}", TRASH_PREFIX, id)
+}
+
+// The row id and entry of a `!trash:<id>` key; None for every other key.
+pub fn trash_entry(id: &str, value: &CrdtValue) -> Option<(String, TrashEntry)> {
+    let row_id = id.strip_prefix(TRASH_PREFIX)?;
+    let CrdtValue::Register(data) = value else { return None };
+    Some((row_id.to_string(), serde_json::from_slice(data).ok()?))
+}
+
+// A deletion time as queries and `show-table --include-deleted` show it, in UTC.
+pub fn deleted_time(deleted_at: u64) -> String {
+    chrono::DateTime::from_timestamp_millis(deleted_at as i64)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| deleted_at.to_string())
+}
+
+// Puts the trashed rows of a table's state back under their ids, for `show-table
+// --include-deleted`, and adds a `deleted_at` column to the schema that only they fill.
+// Positional rows of a table without declared columns have nowhere to put it.
+pub fn reveal_trashed(rows: &mut HashMap<String, CrdtValue>, schema: &mut serde_json::Value) -> Result<()> {
+    let trashed: Vec<(String, String, TrashEntry)> = rows.iter()
+        .filter_map(|(key, value)| trash_entry(key, value).map(|(id, entry)| (key.clone(), id, entry)))
+        .collect();
+    let width = row::schema_columns(schema).len();
+    if let Some(columns) = schema.get_mut("columns").and_then(|c| c.as_object_mut()) {
+        columns.insert(DELETED_AT_COLUMN.to_string(), serde_json::Value::String("TEXT".to_string()));
+    }
+    for (key, id, entry) in trashed {
+        rows.remove(&key);
+        let deleted_at = serde_json::Value::String(deleted_time(entry.deleted_at));
+        let row = match entry.row {
+            serde_json::Value::Array(mut values) if width > 0 => {
+                values.resize(width, serde_json::Value::Null);
+                values.push(deleted_at);
+                serde_json::Value::Array(values)
+            }
+            serde_json::Value::Object(mut object) => {
+                object.insert(DELETED_AT_COLUMN.to_string(), deleted_at);
+                serde_json::Value::Object(object)
+            }
+            other => other,
+        };
+        rows.insert(id, CrdtValue::Register(serde_json::to_vec(&row)?));
+    }
+    Ok(())
+}
+
+// Splits a trailing `INCLUDING DELETED` off a SELECT.
+pub(crate) fn split_including_deleted(sql: &str) -> (&str, bool) {
+    let trimmed = sql.trim_end().trim_end_matches(';').trim_end();
+    let mut words = trimmed.rsplitn(3, char::is_whitespace);
+    match (words.next(), words.next(), words.next()) {
+        (Some(deleted), Some(including), Some(rest))
+            if deleted.eq_ignore_ascii_case("DELETED") && including.eq_ignore_ascii_case("INCLUDING") => (rest, true),
+        _ => (sql, false),
+    }
+}
+
+// `ALTER TABLE <table> SET SOFT DELETE` gives true, `ALTER TABLE <table> DROP SOFT DELETE`
+// false. Other statements aren't a soft delete change.
+pub(crate) fn parse_alter_soft_delete(command: &str) -> Option<Result<bool>> {
+    let tokens = Tokenizer::new(&GenericDialect, command).tokenize().ok()?;
+    let tokens: Vec<Token> = tokens.into_iter().filter(|t| !matches!(t, Token::Whitespace(_) | Token::SemiColon)).collect();
+    let word = |i: usize, word: &str| matches!(tokens.get(i), Some(Token::Word(w)) if w.value.eq_ignore_ascii_case(word));
+    if !word(4, "SOFT") {
+        return None;
+    }
+    Some(match tokens.len() == 6 && word(5, "DELETE") {
+        true if word(3, "SET") => Ok(true),
+        true if word(3, "DROP") => Ok(false),
+        _ => Err(BranchDBError::InvalidInput("Expected ALTER TABLE <table> SET SOFT DELETE or DROP SOFT DELETE".into())),
+    })
+}
+
+// The changes deleting a live row of a soft delete table: the row goes and its trash entry
+// is written, replacing one left by an earlier delete of the same id. Rows that aren't JSON,
+// such as counters, are deleted outright.
+pub(crate) fn soft_delete_changes(storage: &CommitStorage, table: &str, id: &str) -> Result<Vec<Change>> {
+    let delete = Change::Delete { table: table.to_string(), id: id.to_string() };
+    let Some(raw) = storage.db.get(encode_row_key(table, id).as_bytes())? else {
+        return Err(BranchDBError::RowNotFound { table: table.to_string(), id: id.to_string() });
+    };
+    let row = match bincode::deserialize(&raw)? {
+        CrdtValue::Register(data) => serde_json::from_slice(&data).ok(),
+        CrdtValue::Counter(_) => None,
+    };
+    let Some(row) = row else { return Ok(vec![delete]) };
+
+    let entry = TrashEntry { row, deleted_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64 };
+    let value = bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&entry)?))?;
+    let (table, trash_id) = (table.to_string(), trash_id(id));
+    let trashed = match storage.db.get(encode_row_key(&table, &trash_id).as_bytes())? {
+        Some(_) => Change::Update { table, id: trash_id, value },
+        None => Change::Insert { table, id: trash_id, value },
+    };
+    Ok(vec![delete, trashed])
+}
+
+impl CommitStorage {
+    // The trashed rows at HEAD, of `table` or of every table, ordered by table and id.
+    pub fn trash(&self, table: Option<&str>) -> Result<Vec<TrashedRow>> {
+        let tables = match table {
+            Some(table) => vec![table.to_string()],
+            None => self.list_tables()?,
+        };
+        let mut trashed = Vec::new();
+        for table in tables {
+            let prefix = encode_row_key(&table, TRASH_PREFIX);
+            for item in self.db.prefix_iterator(prefix.as_bytes()) {
+                let (key, value) = item?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                let Some((_, id)) = decode_row_key(&key) else { continue };
+                if let Some((id, entry)) = trash_entry(&id, &bincode::deserialize(&value)?) {
+                    trashed.push(TrashedRow { table: table.clone(), id, row: entry.row, deleted_at: entry.deleted_at });
+                }
+            }
+        }
+        Ok(trashed)
+    }
+
+    // Puts a trashed row back under its id in a new commit on HEAD, which also removes it from
+    // the trash. Fails while a live row has the id.
+    pub fn restore_row(&self, table: &str, id: &str) -> Result<[u8; 32]> {
+        let trash_id = trash_id(id);
+        let raw = self.db.get(encode_row_key(table, &trash_id).as_bytes())?.ok_or_else(|| BranchDBError::InvalidInput(format!(
+            "Row '{}' of '{}' is not in the trash", id, table
+        )))?;
+        let (_, entry) = trash_entry(&trash_id, &bincode::deserialize(&raw)?)
+            .ok_or_else(|| BranchDBError::CorruptData(format!("trash entry of row '{}' in '{}' is not JSON", id, table)))?;
+        if self.db.get(encode_row_key(table, id).as_bytes())?.is_some() {
+            return Err(BranchDBError::InvalidInput(format!(
+                "Row '{}' of '{}' exists again; delete it before restoring the trashed one", id, table
+            )));
+        }
+        let changes = vec![
+            Change::Insert {
+                table: table.to_string(),
+                id: id.to_string(),
+                value: bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&entry.row)?))?,
+            },
+            Change::Delete { table: table.to_string(), id: trash_id },
+        ];
+        self.create_commit(&format!("Restore {}/{}", table, id), changes)
+    }
+
+    // Deletes the rows' trash entries for good in one commit on HEAD.
+    pub fn empty_trash(&self, rows: &[TrashedRow]) -> Result<[u8; 32]> {
+        let changes = rows.iter()
+            .map(|row| Change::Delete { table: row.table.clone(), id: trash_id(&row.id) })
+            .collect();
+        let message = format!("Empty trash: {} row{}", rows.len(), if rows.len() == 1 { "" } else { "s" });
+        self.create_commit(&message, changes)
+    }
+}
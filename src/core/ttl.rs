@@ -0,0 +1,175 @@
+use crate::core::database::{decode_row_key, table_key_prefix, CommitStorage};
+use crate::core::models::Change;
+use crate::error::{BranchDBError, Result};
+use serde::Serialize;
+use sqlparser::dialect::GenericDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// A table's TTL is kept in its schema as written, `"ttl": "7d"`. A row expires once that long
+// has passed since the commit that last wrote it; `expire` deletes expired rows at HEAD in a
+// new commit, so older commits keep them.
+const UNITS: [(char, u64); 5] = [('w', 7 * 86_400), ('d', 86_400), ('h', 3_600), ('m', 60), ('s', 1)];
+
+// A row past its table's TTL at HEAD.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpiredRow {
+    pub table: String,
+    pub id: String,
+    // Milliseconds since the epoch, from the commit that last inserted or updated the row
+    pub written: u64,
+    // Seconds
+    pub ttl: u64,
+}
+
+// Seconds in a duration such as `90s`, `30m`, `12h`, `7d`, `2w` or `1d12h`.
+pub fn parse_duration(text: &str) -> Result<u64> {
+    let invalid = || BranchDBError::InvalidInput(format!(
+        "Invalid duration '{}'; expected a number and a unit (s, m, h, d or w), such as '7d' or '1d12h'", text
+    ));
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let (_, unit) = UNITS.iter().find(|(u, _)| *u == c.to_ascii_lowercase()).ok_or_else(invalid)?;
+        let count: u64 = number.parse().map_err(|_| invalid())?;
+        seconds = count.checked_mul(*unit).and_then(|s| seconds.checked_add(s)).ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        return Err(invalid());
+    }
+    Ok(seconds)
+}
+
+// A duration in its largest whole unit, such as `8d` for eight and a half days.
+pub fn format_age(seconds: u64) -> String {
+    let (unit, size) = UNITS.iter().find(|(_, size)| seconds >= *size).unwrap_or(&('s', 1));
+    format!("{}{}", seconds / size, unit)
+}
+
+// The TTL in a table's schema, in seconds.
+pub fn schema_ttl(schema: &serde_json::Value) -> Option<u64> {
+    schema.get("ttl").and_then(|ttl| ttl.as_str()).and_then(|ttl| parse_duration(ttl).ok())
+}
+
+// Splits a trailing `WITH TTL '<duration>'` off a CREATE TABLE statement, which sqlparser
+// doesn't read, returning the statement without it and the duration as written.
+pub(crate) fn split_ttl_clause(command: &str) -> (String, Option<String>) {
+    let Ok(mut tokens) = Tokenizer::new(&GenericDialect, command).tokenize() else {
+        return (command.to_string(), None);
+    };
+    while matches!(tokens.last(), Some(Token::Whitespace(_) | Token::SemiColon)) {
+        tokens.pop();
+    }
+    let significant: Vec<usize> = (0..tokens.len()).filter(|&i| !matches!(tokens[i], Token::Whitespace(_))).collect();
+    let [.., with, ttl, value] = significant[..] else { return (command.to_string(), None) };
+    match (&tokens[with], &tokens[ttl], &tokens[value]) {
+        (Token::Word(w), Token::Word(t), Token::SingleQuotedString(duration))
+            if w.value.eq_ignore_ascii_case("WITH") && t.value.eq_ignore_ascii_case("TTL") =>
+        {
+            let statement: String = tokens[..with].iter().map(Token::to_string).collect();
+            (statement.trim_end().to_string(), Some(duration.clone()))
+        }
+        _ => (command.to_string(), None),
+    }
+}
+
+// `ALTER TABLE <table> SET TTL '<duration>'` gives the duration, `ALTER TABLE <table> DROP
+// TTL` gives None. Other statements aren't a TTL change.
+pub(crate) fn parse_alter_ttl(command: &str) -> Option<Result<Option<String>>> {
+    let tokens = Tokenizer::new(&GenericDialect, command).tokenize().ok()?;
+    let tokens: Vec<Token> = tokens.into_iter().filter(|t| !matches!(t, Token::Whitespace(_) | Token::SemiColon)).collect();
+    let word = |i: usize, word: &str| matches!(tokens.get(i), Some(Token::Word(w)) if w.value.eq_ignore_ascii_case(word));
+    if !word(4, "TTL") {
+        return None;
+    }
+    Some(match (tokens.get(5), tokens.len()) {
+        (Some(Token::SingleQuotedString(duration)), 6) if word(3, "SET") => parse_duration(duration).map(|_| Some(duration.clone())),
+        (None, 5) if word(3, "DROP") => Ok(None),
+        _ => Err(BranchDBError::InvalidInput("Expected ALTER TABLE <table> SET TTL '<duration>' or DROP TTL".into())),
+    })
+}
+
+impl CommitStorage {
+    // Rows of tables with a TTL that have outlived it at HEAD as of `now` (milliseconds since
+    // the epoch), ordered by table and id. Only `table` is checked when given, which then has
+    // to have a TTL.
+    pub fn expired_rows(&self, table: Option<&str>, now: u64) -> Result<Vec<ExpiredRow>> {
+        let Some(head) = self.get_head()? else { return Ok(Vec::new()) };
+        let tables = match table {
+            Some(table) => vec![table.to_string()],
+            None => self.list_tables()?,
+        };
+        let mut ttls = HashMap::new();
+        for name in tables {
+            match schema_ttl(&self.get_table_schema(&name, None)?) {
+                Some(ttl) => {
+                    ttls.insert(name, ttl);
+                }
+                None if table.is_some() => {
+                    return Err(BranchDBError::InvalidInput(format!("Table '{}' has no TTL", name)));
+                }
+                None => {}
+            }
+        }
+
+        let mut pending = HashSet::new();
+        for name in ttls.keys() {
+            let prefix = table_key_prefix(name);
+            for item in self.db.prefix_iterator(prefix.as_bytes()) {
+                let (key, _) = item?;
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                match decode_row_key(&key) {
+                    Some((_, id)) if !id.starts_with('!') => {
+                        pending.insert((name.clone(), id));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // The newest write of each live row on the first-parent chain, which for rows brought
+        // in by a merge is the merge commit
+        let mut expired = BTreeMap::new();
+        let mut current = Some(head);
+        while let Some(hash) = current.filter(|_| !pending.is_empty()) {
+            let commit = self.get_commit_by_hash(&hash)?;
+            for change in &commit.changes {
+                if matches!(change, Change::Delete { .. }) {
+                    continue;
+                }
+                let key = (change.table().to_string(), change.id().to_string());
+                if pending.remove(&key) {
+                    let ttl = ttls[&key.0];
+                    if now.saturating_sub(commit.timestamp) >= ttl * 1000 {
+                        expired.insert(key, (commit.timestamp, ttl));
+                    }
+                }
+            }
+            current = commit.parents.first().copied();
+        }
+        Ok(expired.into_iter()
+            .map(|((table, id), (written, ttl))| ExpiredRow { table, id, written, ttl })
+            .collect())
+    }
+
+    // Deletes the rows in one commit on HEAD, with a message counting them per table.
+    pub fn expire_rows(&self, rows: &[ExpiredRow]) -> Result<[u8; 32]> {
+        let mut per_table: BTreeMap<&str, usize> = BTreeMap::new();
+        for row in rows {
+            *per_table.entry(&row.table).or_default() += 1;
+        }
+        let counts: Vec<String> = per_table.iter().map(|(table, count)| format!("{}: {}", table, count)).collect();
+        let message = format!(
+            "Expire {} row{} past their TTL ({})", rows.len(), if rows.len() == 1 { "" } else { "s" }, counts.join(", ")
+        );
+        let changes = rows.iter().map(|row| Change::Delete { table: row.table.clone(), id: row.id.clone() }).collect();
+        self.create_commit(&message, changes)
+    }
+}
@@ -0,0 +1,632 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::database::{decode_row_key, table_key_prefix, CommitStorage};
+use crate::core::join::QueryRow;
+use crate::core::models::Change;
+use crate::core::schema::MultiKeyPrimaryKey;
+use crate::core::query::{eval_expr, is_true, QueryProcessor};
+use crate::core::{row, sql};
+use crate::error::{BranchDBError, Result};
+use sqlparser::ast::{Expr, SelectItem, SetExpr, Statement};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::collections::{BTreeMap, HashSet};
+
+// Column names with the expressions assigned to them.
+type Assignments = Vec<(String, Expr)>;
+
+// What to do with a target row that matched a source row, or with a source row that matched
+// nothing. The first clause whose predicate holds is applied.
+enum Clause {
+    Update { predicate: Option<Expr>, assignments: Assignments },
+    Delete { predicate: Option<Expr> },
+    // Without columns the values are in schema order
+    Insert { predicate: Option<Expr>, columns: Vec<String>, values: Vec<Expr> },
+}
+
+// A MERGE, or an INSERT ... ON CONFLICT translated into one.
+struct Merge {
+    target: String,
+    target_alias: String,
+    source_alias: String,
+    source_columns: Vec<String>,
+    source_rows: Vec<Vec<serde_json::Value>>,
+    on: Expr,
+    matched: Vec<Clause>,
+    not_matched: Vec<Clause>,
+}
+
+// `MERGE INTO <target> [[AS] alias] USING <table | (SELECT ...) | (VALUES ...)> [[AS] alias[(columns)]]
+// ON <condition> WHEN [NOT] MATCHED [AND <predicate>] THEN <action> ...`, as the inserts,
+// updates and deletes it makes to the rows at HEAD.
+pub(crate) fn merge_changes(storage: &CommitStorage, command: &str) -> Result<Vec<Change>> {
+    let command = command.trim().trim_end_matches(';');
+    let invalid = || BranchDBError::InvalidInput(
+        "Expected MERGE INTO <table> USING <source> ON <condition> WHEN [NOT] MATCHED THEN ...".into()
+    );
+    let rest = after_keywords(command, &["MERGE"]).ok_or_else(invalid)?;
+    let rest = after_keywords(rest, &["INTO"]).unwrap_or(rest);
+    let (target, rest) = split_at_keywords(rest, &["USING"]).ok_or_else(invalid)?;
+    let (source, rest) = split_at_keywords(rest, &["ON"]).ok_or_else(invalid)?;
+    let (on, mut rest) = split_at_keywords(rest, &["WHEN"]).ok_or_else(invalid)?;
+
+    let (target, target_alias) = name_and_alias(target)?;
+    let (source, source_alias, alias_columns) = source_and_alias(source)?;
+    let (source_columns, source_rows) = evaluate_source(storage, source, alias_columns)?;
+
+    let mut merge = Merge {
+        target_alias: target_alias.unwrap_or_else(|| target.clone()),
+        target,
+        // An unaliased table is qualified by its name, like in a SELECT
+        source_alias: source_alias.unwrap_or_else(|| source.to_string()),
+        source_columns,
+        source_rows,
+        on: parse_condition(on)?,
+        matched: Vec::new(),
+        not_matched: Vec::new(),
+    };
+    loop {
+        let (clause, next) = match split_at_keywords(rest, &["WHEN"]) {
+            Some((clause, next)) => (clause, Some(next)),
+            None => (rest, None),
+        };
+        parse_clause(&mut merge, clause)?;
+        match next {
+            Some(next) => rest = next,
+            None => break,
+        }
+    }
+    merge.changes(storage)
+}
+
+// `INSERT INTO <table> VALUES (...), ... ON CONFLICT [(<column>)] DO NOTHING | DO UPDATE SET
+// col = excluded.col, ... [WHERE ...]`, the PostgreSQL upsert, run as a MERGE on the row id.
+// None if the statement has no ON CONFLICT clause.
+pub(crate) fn on_conflict_changes(storage: &CommitStorage, command: &str) -> Option<Result<Vec<Change>>> {
+    let command = command.trim().trim_end_matches(';');
+    let (insert, action) = split_at_keywords(command, &["ON", "CONFLICT"])?;
+    Some(on_conflict(storage, insert, action))
+}
+
+fn on_conflict(storage: &CommitStorage, insert: &str, action: &str) -> Result<Vec<Change>> {
+    let rest = after_keywords(insert, &["INSERT", "INTO"])
+        .ok_or_else(|| BranchDBError::InvalidInput("Expected INSERT INTO <table> VALUES (...)".into()))?;
+    let (target, values) = split_at_keywords(rest, &["VALUES"])
+        .ok_or_else(|| BranchDBError::InvalidInput("Missing VALUES clause".into()))?;
+    let (target, _) = name_and_alias(target)?;
+    let source_rows = evaluate_values(values)?;
+    let width = source_rows.iter().map(Vec::len).max().unwrap_or(0);
+
+    // The new row is `excluded`, with the target's columns; the row id is the first column
+    // unless the table has a composite primary key
+    let schema = storage.get_table_schema(&target, None)?;
+    let mut columns = row::schema_columns(&schema);
+    columns.extend((columns.len()..width).map(|i| format!("column{}", i + 1)));
+    let key_columns = match MultiKeyPrimaryKey::from_schema(&schema) {
+        Some(key) => key.columns,
+        None => vec![columns.first().cloned().unwrap_or_else(|| "column1".to_string())],
+    };
+
+    let (conflict_target, action) = match action.trim_start().strip_prefix('(') {
+        Some(rest) => {
+            let (columns, action) = rest.split_once(')')
+                .ok_or_else(|| BranchDBError::InvalidInput("Unclosed ON CONFLICT column list".into()))?;
+            (Some(columns.trim()), action)
+        }
+        None => (None, action),
+    };
+    if let Some(named) = conflict_target {
+        let named: Vec<&str> = named.split(',').map(str::trim).collect();
+        let is_key = named.len() == key_columns.len()
+            && named.iter().all(|n| key_columns.iter().any(|c| c.eq_ignore_ascii_case(n)));
+        if !is_key && named != ["id"] {
+            return Err(BranchDBError::InvalidInput(format!(
+                "ON CONFLICT only supports the row id ({}) of '{}'", key_columns.join(", "), target
+            )));
+        }
+    }
+
+    let mut matched = Vec::new();
+    if let Some(set) = after_keywords(action, &["DO", "UPDATE"]) {
+        let (assignments, predicate) = parse_update_set(set)?;
+        matched.push(Clause::Update { predicate, assignments });
+    } else if after_keywords(action, &["DO", "NOTHING"]).is_none_or(|rest| !rest.is_empty()) {
+        return Err(BranchDBError::InvalidInput("Expected ON CONFLICT DO NOTHING or DO UPDATE SET ...".into()));
+    }
+
+    let values = columns.iter().take(width)
+        .map(|column| Expr::CompoundIdentifier(vec![ident("excluded"), ident(column)]))
+        .collect();
+    let merge = Merge {
+        on: parse_condition(&key_columns.iter()
+            .map(|column| format!("{}.{} = excluded.{}", target, column, column))
+            .collect::<Vec<_>>()
+            .join(" AND "))?,
+        target_alias: target.clone(),
+        target,
+        source_alias: "excluded".to_string(),
+        source_columns: columns,
+        source_rows,
+        matched,
+        not_matched: vec![Clause::Insert { predicate: None, columns: Vec::new(), values }],
+    };
+    merge.changes(storage)
+}
+
+impl Merge {
+    // Every source row is tested against every target row. A target row may be changed by
+    // only one source row, and rows inserted by the statement aren't matched by later ones.
+    fn changes(&self, storage: &CommitStorage) -> Result<Vec<Change>> {
+        let schema = storage.get_table_schema(&self.target, None)?;
+        let columns = row::schema_columns(&schema);
+        let targets = self.target_rows(storage, &schema)?;
+
+        let mut changed = HashSet::new();
+        let mut changes = Vec::new();
+        for values in &self.source_rows {
+            let source = self.source_row(values);
+            let mut found = false;
+            for (id, (target, stored)) in &targets {
+                let joined = self.joined_row(Some(target), &source);
+                if !is_true(&eval_expr(&self.on, &joined)?) {
+                    continue;
+                }
+                found = true;
+                let Some(clause) = first_applicable(&self.matched, &joined)? else { continue };
+                if !changed.insert(id.clone()) {
+                    return Err(BranchDBError::InvalidInput(format!(
+                        "MERGE would change row '{}' of '{}' more than once", id, self.target
+                    )));
+                }
+                changes.push(match clause {
+                    Clause::Update { assignments, .. } => {
+                        let updated = self.updated_row(target, stored, assignments, &joined, &schema, storage)?;
+                        Change::Update { table: self.target.clone(), id: id.clone(), value: updated }
+                    }
+                    Clause::Delete { .. } => Change::Delete { table: self.target.clone(), id: id.clone() },
+                    Clause::Insert { .. } => return Err(BranchDBError::InvalidInput(
+                        "WHEN MATCHED can't INSERT".into()
+                    )),
+                });
+            }
+            if found {
+                continue;
+            }
+
+            let joined = self.joined_row(None, &source);
+            let Some(clause) = first_applicable(&self.not_matched, &joined)? else { continue };
+            let Clause::Insert { columns: named, values, .. } = clause else {
+                return Err(BranchDBError::InvalidInput("WHEN NOT MATCHED can only INSERT".into()));
+            };
+            let (id, value) = self.inserted_row(named, values, &joined, &columns, &schema, storage)?;
+            if targets.contains_key(&id) {
+                return Err(BranchDBError::InvalidInput(format!(
+                    "MERGE would insert row '{}' into '{}', which already exists", id, self.target
+                )));
+            }
+            if !changed.insert(id.clone()) {
+                return Err(BranchDBError::InvalidInput(format!(
+                    "MERGE would insert row '{}' into '{}' more than once", id, self.target
+                )));
+            }
+            changes.push(Change::Insert { table: self.target.clone(), id, value });
+        }
+        Ok(changes)
+    }
+
+    // The live rows of the target as objects, with the JSON each was stored as.
+    fn target_rows(&self, storage: &CommitStorage, schema: &serde_json::Value) -> Result<BTreeMap<String, (QueryRow, serde_json::Value)>> {
+        let prefix = table_key_prefix(&self.target);
+        let mut rows = BTreeMap::new();
+        for item in storage.db.prefix_iterator(prefix.as_bytes()) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let Some((_, id)) = decode_row_key(&key) else { continue };
+            if id.starts_with('!') {
+                continue;
+            }
+            let CrdtValue::Register(data) = bincode::deserialize(&value)? else {
+                return Err(BranchDBError::TypeMismatch(format!("MERGE can't change counter row '{}'", id)));
+            };
+            let stored: serde_json::Value = serde_json::from_slice(&data)?;
+            let serde_json::Value::Object(object) = row::row_to_object(stored.clone(), schema) else {
+                return Err(BranchDBError::TypeMismatch(format!("Row '{}' of '{}' is not a table row", id, self.target)));
+            };
+            rows.insert(id, (object, stored));
+        }
+        Ok(rows)
+    }
+
+    fn source_row(&self, values: &[serde_json::Value]) -> QueryRow {
+        self.source_columns.iter().cloned().zip(values.iter().cloned()).collect()
+    }
+
+    // The target and source columns qualified by their aliases, as the ON condition and the
+    // clauses see them. Without a target row, as for WHEN NOT MATCHED, its columns are absent.
+    fn joined_row(&self, target: Option<&QueryRow>, source: &QueryRow) -> QueryRow {
+        let qualified = |alias: &str, row: &QueryRow| -> Vec<(String, serde_json::Value)> {
+            row.iter().map(|(column, value)| (format!("{}.{}", alias, column), value.clone())).collect()
+        };
+        let mut joined = QueryRow::new();
+        if let Some(target) = target {
+            joined.extend(qualified(&self.target_alias, target));
+        }
+        joined.extend(qualified(&self.source_alias, source));
+        joined
+    }
+
+    // Assignments are evaluated against the row before any of them is applied. Rows stored as
+    // positional arrays, as SQL INSERT writes them, stay arrays.
+    fn updated_row(
+        &self,
+        target: &QueryRow,
+        stored: &serde_json::Value,
+        assignments: &[(String, Expr)],
+        joined: &QueryRow,
+        schema: &serde_json::Value,
+        storage: &CommitStorage,
+    ) -> Result<Vec<u8>> {
+        let mut updated = target.clone();
+        for (column, expr) in assignments {
+            if !updated.contains_key(column) && column_type(schema, column).is_none() {
+                return Err(BranchDBError::InvalidInput(format!("Field '{}' not found in '{}'", column, self.target)));
+            }
+            let value = typed_value(eval_expr(expr, joined)?, column_type(schema, column), storage)?;
+            updated.insert(column.clone(), value);
+        }
+        let json = match stored {
+            serde_json::Value::Array(stored) => {
+                let columns = row::schema_columns(schema);
+                // A row written before ADD COLUMN keeps its width, as UPDATE leaves it, unless
+                // a column past it is assigned
+                let width = assignments.iter()
+                    .filter_map(|(column, _)| columns.iter().position(|c| c == column))
+                    .fold(stored.len(), |width, i| width.max(i + 1));
+                let mut values: Vec<serde_json::Value> = columns.iter()
+                    .take(width)
+                    .map(|c| updated.get(c).cloned().unwrap_or(serde_json::Value::Null))
+                    .collect();
+                values.extend((columns.len()..).map_while(|i| updated.get(&format!("column{}", i + 1)).cloned()));
+                serde_json::Value::Array(values)
+            }
+            _ => serde_json::Value::Object(updated),
+        };
+        Ok(bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&json)?))?)
+    }
+
+    // A new row, stored like SQL INSERT stores one: positionally, keyed by its first value or
+    // its primary key columns.
+    fn inserted_row(
+        &self,
+        named: &[String],
+        values: &[Expr],
+        joined: &QueryRow,
+        columns: &[String],
+        schema: &serde_json::Value,
+        storage: &CommitStorage,
+    ) -> Result<(String, Vec<u8>)> {
+        if !named.is_empty() && named.len() != values.len() {
+            return Err(BranchDBError::InvalidInput("INSERT column and value counts differ".into()));
+        }
+        let mut row = Vec::new();
+        if named.is_empty() {
+            for (i, expr) in values.iter().enumerate() {
+                let ty = columns.get(i).and_then(|c| column_type(schema, c));
+                row.push(typed_value(eval_expr(expr, joined)?, ty, storage)?);
+            }
+        } else {
+            for column in columns {
+                let value = match named.iter().position(|n| n.eq_ignore_ascii_case(column)) {
+                    Some(i) => typed_value(eval_expr(&values[i], joined)?, column_type(schema, column), storage)?,
+                    None => serde_json::Value::Null,
+                };
+                row.push(value);
+            }
+            if let Some(unknown) = named.iter().find(|n| !columns.iter().any(|c| c.eq_ignore_ascii_case(n))) {
+                return Err(BranchDBError::InvalidInput(format!("Field '{}' not found in '{}'", unknown, self.target)));
+            }
+        }
+
+        let text = |value: Option<&serde_json::Value>| match value? {
+            serde_json::Value::String(s) => Some(s.clone()),
+            serde_json::Value::Null => None,
+            other => Some(other.to_string()),
+        };
+        let id = match MultiKeyPrimaryKey::from_schema(schema) {
+            Some(key) => key.row_id(|column| text(columns.iter().position(|c| c == column).and_then(|i| row.get(i))))?,
+            None => text(row.first()).ok_or_else(|| BranchDBError::InvalidInput(format!(
+                "MERGE can't insert a row into '{}' without an id", self.target
+            )))?,
+        };
+        let value = bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&row)?))?;
+        Ok((id, value))
+    }
+}
+
+fn first_applicable<'c>(clauses: &'c [Clause], row: &QueryRow) -> Result<Option<&'c Clause>> {
+    for clause in clauses {
+        let (Clause::Update { predicate, .. } | Clause::Delete { predicate } | Clause::Insert { predicate, .. }) = clause;
+        let applies = match predicate {
+            Some(predicate) => is_true(&eval_expr(predicate, row)?),
+            None => true,
+        };
+        if applies {
+            return Ok(Some(clause));
+        }
+    }
+    Ok(None)
+}
+
+fn column_type<'s>(schema: &'s serde_json::Value, column: &str) -> Option<&'s str> {
+    schema.get("columns")?.get(column)?.as_str()
+}
+
+// Values are checked and stored as SQL INSERT stores them: as text, converted only under the
+// `coerce` policy. NULL stays NULL.
+fn typed_value(value: serde_json::Value, column_type: Option<&str>, storage: &CommitStorage) -> Result<serde_json::Value> {
+    let text = match value {
+        serde_json::Value::Null => return Ok(serde_json::Value::Null),
+        serde_json::Value::String(s) => s,
+        other => other.to_string(),
+    };
+    sql::validate_value_type(&text, column_type.unwrap_or("TEXT"), storage.options().coercion)
+}
+
+// `WHEN [NOT] MATCHED [AND <predicate>] THEN UPDATE SET ... | DELETE | INSERT [(columns)] VALUES (...)`
+fn parse_clause(merge: &mut Merge, clause: &str) -> Result<()> {
+    let (condition, action) = split_at_keywords(clause, &["THEN"])
+        .ok_or_else(|| BranchDBError::InvalidInput(format!("Missing THEN in WHEN {}", clause.trim())))?;
+    let (not_matched, predicate) = if let Some(rest) = after_keywords(condition, &["NOT", "MATCHED"]) {
+        (true, rest)
+    } else if let Some(rest) = after_keywords(condition, &["MATCHED"]) {
+        (false, rest)
+    } else {
+        return Err(BranchDBError::InvalidInput(format!("Expected WHEN [NOT] MATCHED, got WHEN {}", condition.trim())));
+    };
+    let predicate = match after_keywords(predicate, &["AND"]) {
+        Some(predicate) => Some(parse_condition(predicate)?),
+        None if predicate.trim().is_empty() => None,
+        None => return Err(BranchDBError::InvalidInput(format!("Unexpected '{}' after MATCHED", predicate.trim()))),
+    };
+
+    let parsed = if let Some(set) = after_keywords(action, &["UPDATE", "SET"]) {
+        let (assignments, filter) = parse_update_set(&format!("SET {}", set))?;
+        if filter.is_some() {
+            return Err(BranchDBError::InvalidInput("Use WHEN MATCHED AND <predicate> instead of WHERE".into()));
+        }
+        Clause::Update { predicate, assignments }
+    } else if after_keywords(action, &["DELETE"]).is_some_and(str::is_empty) {
+        Clause::Delete { predicate }
+    } else if let Some(rest) = after_keywords(action, &["INSERT"]) {
+        let (columns, values) = split_at_keywords(rest, &["VALUES"])
+            .ok_or_else(|| BranchDBError::InvalidInput("Missing VALUES in WHEN NOT MATCHED THEN INSERT".into()))?;
+        let columns = match columns.trim() {
+            "" => Vec::new(),
+            list => list.strip_prefix('(').and_then(|l| l.strip_suffix(')'))
+                .ok_or_else(|| BranchDBError::InvalidInput(format!("Invalid INSERT column list: {}", list)))?
+                .split(',')
+                .map(|c| c.trim().to_string())
+                .collect(),
+        };
+        let mut rows = parse_tuples(values)?;
+        if rows.len() != 1 {
+            return Err(BranchDBError::InvalidInput("WHEN NOT MATCHED THEN INSERT takes one row of VALUES".into()));
+        }
+        Clause::Insert { predicate, columns, values: rows.remove(0) }
+    } else {
+        return Err(BranchDBError::InvalidInput(format!("Unsupported MERGE action: {}", action.trim())));
+    };
+
+    match (&parsed, not_matched) {
+        (Clause::Insert { .. }, true) => merge.not_matched.push(parsed),
+        (Clause::Insert { .. }, false) => return Err(BranchDBError::InvalidInput("WHEN MATCHED can't INSERT".into())),
+        (_, true) => return Err(BranchDBError::InvalidInput("WHEN NOT MATCHED can only INSERT".into())),
+        (_, false) => merge.matched.push(parsed),
+    }
+    Ok(())
+}
+
+// `SET col = <expr>, ... [WHERE <predicate>]`, read as the tail of an UPDATE.
+fn parse_update_set(set: &str) -> Result<(Assignments, Option<Expr>)> {
+    let ast = Parser::parse_sql(&GenericDialect, &format!("UPDATE merge_target {}", set.trim()))
+        .map_err(|e| BranchDBError::InvalidInput(format!("SQL parse error: {}", e)))?;
+    let [Statement::Update { assignments, selection, .. }] = ast.as_slice() else {
+        return Err(BranchDBError::InvalidInput(format!("Expected SET col = value, ...: {}", set.trim())));
+    };
+    let assignments = assignments.iter()
+        .map(|a| {
+            let column = a.id.last().map(|c| c.value.clone()).unwrap_or_default();
+            (column, a.value.clone())
+        })
+        .collect();
+    Ok((assignments, selection.clone()))
+}
+
+// A boolean expression, read as the WHERE clause of a SELECT.
+fn parse_condition(condition: &str) -> Result<Expr> {
+    let ast = Parser::parse_sql(&GenericDialect, &format!("SELECT * FROM merge_target WHERE {}", condition.trim()))
+        .map_err(|e| BranchDBError::InvalidInput(format!("SQL parse error: {}", e)))?;
+    let selection = match ast.as_slice() {
+        [Statement::Query(query)] => match &*query.body {
+            SetExpr::Select(select) => select.selection.clone(),
+            _ => None,
+        },
+        _ => None,
+    };
+    selection.ok_or_else(|| BranchDBError::InvalidInput(format!("Invalid condition: {}", condition.trim())))
+}
+
+// `(a, b), (c, d)`: the expressions of each tuple, each read as the projection of a SELECT.
+fn parse_tuples(values: &str) -> Result<Vec<Vec<Expr>>> {
+    let mut rows = Vec::new();
+    let mut rest = values.trim();
+    while !rest.is_empty() {
+        let end = closing_paren(rest)
+            .ok_or_else(|| BranchDBError::InvalidInput(format!("Expected a parenthesized row of values: {}", rest)))?;
+        let ast = Parser::parse_sql(&GenericDialect, &format!("SELECT {}", &rest[1..end]))
+            .map_err(|e| BranchDBError::InvalidInput(format!("SQL parse error: {}", e)))?;
+        let [Statement::Query(query)] = ast.as_slice() else {
+            return Err(BranchDBError::InvalidInput(format!("Invalid row of values: {}", &rest[..=end])));
+        };
+        let SetExpr::Select(select) = &*query.body else {
+            return Err(BranchDBError::InvalidInput(format!("Invalid row of values: {}", &rest[..=end])));
+        };
+        let row = select.projection.iter()
+            .map(|item| match item {
+                SelectItem::UnnamedExpr(expr) => Ok(expr.clone()),
+                other => Err(BranchDBError::InvalidInput(format!("Invalid value: {}", other))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        rows.push(row);
+        rest = rest[end + 1..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Ok(rows)
+}
+
+// Tuples of constant expressions, evaluated.
+fn evaluate_values(values: &str) -> Result<Vec<Vec<serde_json::Value>>> {
+    let empty = QueryRow::new();
+    parse_tuples(values)?.iter()
+        .map(|row| row.iter().map(|expr| eval_expr(expr, &empty)).collect())
+        .collect()
+}
+
+// The source's columns and rows. VALUES columns are named by the alias's column list, or
+// `column1`, `column2`, ... without one; a table or SELECT is read at HEAD.
+fn evaluate_source(storage: &CommitStorage, source: &str, alias_columns: Vec<String>) -> Result<(Vec<String>, Vec<Vec<serde_json::Value>>)> {
+    let inner = source.strip_prefix('(').and_then(|s| s.strip_suffix(')')).map(str::trim);
+    if let Some(values) = inner.and_then(|inner| after_keywords(inner, &["VALUES"])) {
+        let rows = evaluate_values(values)?;
+        let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let mut columns = alias_columns;
+        columns.extend((columns.len()..width).map(|i| format!("column{}", i + 1)));
+        return Ok((columns, rows));
+    }
+    let query = match inner {
+        Some(query) => query.to_string(),
+        None => format!("SELECT * FROM {}", source),
+    };
+    let result = QueryProcessor::new(&storage.db).query(&query)?;
+    let mut columns = alias_columns;
+    columns.extend(result.columns.iter().skip(columns.len()).cloned());
+    Ok((columns, result.rows))
+}
+
+// `<name> [[AS] alias]`
+fn name_and_alias(text: &str) -> Result<(String, Option<String>)> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    match words.as_slice() {
+        [name] => Ok((name.to_string(), None)),
+        [name, alias] => Ok((name.to_string(), Some(alias.to_string()))),
+        [name, r#as, alias] if r#as.eq_ignore_ascii_case("AS") => Ok((name.to_string(), Some(alias.to_string()))),
+        _ => Err(BranchDBError::InvalidInput(format!("Expected <table> [AS alias], got '{}'", text.trim()))),
+    }
+}
+
+// `<table | (subquery)> [[AS] alias[(columns)]]`
+fn source_and_alias(text: &str) -> Result<(&str, Option<String>, Vec<String>)> {
+    let text = text.trim();
+    let (source, alias) = if text.starts_with('(') {
+        let end = closing_paren(text)
+            .ok_or_else(|| BranchDBError::InvalidInput("Unclosed parenthesis in MERGE source".into()))?;
+        (&text[..=end], text[end + 1..].trim())
+    } else {
+        text.split_once(char::is_whitespace).map_or((text, ""), |(s, a)| (s, a.trim()))
+    };
+    let alias = after_keywords(alias, &["AS"]).unwrap_or(alias);
+    if alias.is_empty() {
+        return Ok((source, None, Vec::new()));
+    }
+    let (name, columns) = match alias.split_once('(') {
+        Some((name, columns)) => {
+            let columns = columns.trim_end().strip_suffix(')')
+                .ok_or_else(|| BranchDBError::InvalidInput(format!("Unclosed column list in alias '{}'", alias)))?;
+            (name.trim(), columns.split(',').map(|c| c.trim().to_string()).collect())
+        }
+        None => (alias, Vec::new()),
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(BranchDBError::InvalidInput(format!("Invalid MERGE source alias '{}'", alias)));
+    }
+    Ok((source, Some(name.to_string()), columns))
+}
+
+fn ident(name: &str) -> sqlparser::ast::Ident {
+    sqlparser::ast::Ident::new(name)
+}
+
+// Byte offset of the parenthesis closing the one `text` starts with, skipping quoted text.
+fn closing_paren(text: &str) -> Option<usize> {
+    if !text.starts_with('(') {
+        return None;
+    }
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (c, quote) {
+            (q, Some(open)) if q == open => quote = None,
+            (_, Some(_)) => {}
+            ('\'' | '"', None) => quote = Some(c),
+            ('(', None) => depth += 1,
+            (')', None) => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// The bare words of `text` outside quotes and parentheses, as byte ranges. Only these are
+// searched for keywords, so a keyword inside a string or a subquery never splits a statement.
+fn top_level_words(text: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        let in_word = quote.is_none() && depth == 0 && (c.is_alphanumeric() || c == '_');
+        match (start, in_word) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                words.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+        match (c, quote) {
+            (q, Some(open)) if q == open => quote = None,
+            (_, Some(_)) => {}
+            ('\'' | '"', None) => quote = Some(c),
+            ('(', None) => depth += 1,
+            (')', None) => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    words
+}
+
+// Splits `text` around the first top-level occurrence of `keywords` in sequence, dropping them.
+fn split_at_keywords<'t>(text: &'t str, keywords: &[&str]) -> Option<(&'t str, &'t str)> {
+    let words = top_level_words(text);
+    words.windows(keywords.len()).find_map(|window| {
+        let found = window.iter().zip(keywords)
+            .all(|(&(s, e), keyword)| text[s..e].eq_ignore_ascii_case(keyword));
+        found.then(|| (&text[..window[0].0], &text[window[keywords.len() - 1].1..]))
+    })
+}
+
+// The rest of `text` if it starts with `keywords`, trimmed.
+fn after_keywords<'t>(text: &'t str, keywords: &[&str]) -> Option<&'t str> {
+    let text = text.trim_start();
+    let words = top_level_words(text);
+    let prefix = words.get(..keywords.len())?;
+    let found = prefix.first()?.0 == 0 && prefix.iter().zip(keywords)
+        .all(|(&(s, e), keyword)| text[s..e].eq_ignore_ascii_case(keyword));
+    found.then(|| text[prefix[keywords.len() - 1].1..].trim())
+}
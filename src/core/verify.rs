@@ -0,0 +1,221 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::database::{decode_row_key, encode_row_key, table_key_prefix, CommitStorage};
+use crate::core::models::Change;
+use crate::core::query::TypeCoercer;
+use crate::core::row;
+use crate::error::{BranchDBError, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// What is wrong with one stored row.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DataProblem {
+    // The stored bytes aren't a CrdtValue
+    Undecodable { error: String },
+    // A register whose payload isn't JSON
+    InvalidJson { error: String },
+    // JSON that is neither a positional array nor an object, in a table that declares columns
+    NotARow,
+    UnknownColumn { column: String },
+    TypeMismatch { column: String, expected: String, value: serde_json::Value },
+    NullInNotNull { column: String },
+}
+
+impl fmt::Display for DataProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataProblem::Undecodable { error } => write!(f, "value can't be decoded ({})", error),
+            DataProblem::InvalidJson { error } => write!(f, "value isn't valid JSON ({})", error),
+            DataProblem::NotARow => write!(f, "value is neither a row array nor an object"),
+            DataProblem::UnknownColumn { column } => write!(f, "column '{}' is not in the schema", column),
+            DataProblem::TypeMismatch { column, expected, value } => {
+                write!(f, "column '{}' holds {}, expected {}", column, value, expected)
+            }
+            DataProblem::NullInNotNull { column } => write!(f, "column '{}' is NOT NULL but holds NULL", column),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DataViolation {
+    pub table: String,
+    pub id: String,
+    #[serde(flatten)]
+    pub problem: DataProblem,
+}
+
+impl fmt::Display for DataViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}: {}", self.table, self.id, self.problem)
+    }
+}
+
+// What `repair_data` does about the violations it is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFix {
+    // Values that don't fit their column's type become NULL, unless the column is NOT NULL
+    Nulls,
+    // Every row with a violation is deleted
+    Drop,
+}
+
+impl CommitStorage {
+    // Checks that every row of `table`, or of every table, decodes and fits its schema: the
+    // live rows, or those as of `commit`. Tables without declared columns accept any JSON.
+    // Committed values are decoded while the table is replayed, so one that can't be fails
+    // the whole check rather than being reported.
+    pub fn verify_data(&self, table: Option<&str>, commit: Option<&[u8; 32]>) -> Result<Vec<DataViolation>> {
+        let tables = match commit {
+            Some(hash) => self.list_tables_at(hash)?,
+            None => self.list_tables()?,
+        };
+        if let Some(table) = table {
+            if !tables.iter().any(|t| t == table) {
+                return Err(BranchDBError::TableNotFound { name: table.to_string() });
+            }
+        }
+
+        let mut violations = Vec::new();
+        for table in tables.iter().filter(|t| table.is_none_or(|table| table == t.as_str())) {
+            let schema = self.get_table_schema(table, commit.map(|hash| &hash[..]))?;
+            for (id, value) in self.stored_rows(table, commit)? {
+                violations.extend(check_row(value, &schema).into_iter().map(|problem| DataViolation {
+                    table: table.clone(),
+                    id: id.clone(),
+                    problem,
+                }));
+            }
+        }
+        Ok(violations)
+    }
+
+    // The changes that apply `fix` to the live rows named in `violations`. Violations `fix`
+    // doesn't cover are left alone.
+    pub fn repair_data(&self, violations: &[DataViolation], fix: DataFix) -> Result<Vec<Change>> {
+        if fix == DataFix::Drop {
+            let rows: BTreeSet<(&str, &str)> = violations.iter().map(|v| (v.table.as_str(), v.id.as_str())).collect();
+            return Ok(rows.into_iter()
+                .map(|(table, id)| Change::Delete { table: table.to_string(), id: id.to_string() })
+                .collect());
+        }
+
+        let mut mismatched: BTreeMap<(&str, &str), Vec<&str>> = BTreeMap::new();
+        for violation in violations {
+            if let DataProblem::TypeMismatch { column, .. } = &violation.problem {
+                mismatched.entry((&violation.table, &violation.id)).or_default().push(column);
+            }
+        }
+        let mut changes = Vec::new();
+        for ((table, id), columns) in mismatched {
+            let schema = self.get_table_schema(table, None)?;
+            let not_null = not_null_columns(&schema);
+            let Some(stored) = self.db.get(encode_row_key(table, id).as_bytes())? else { continue };
+            let CrdtValue::Register(data) = bincode::deserialize(&stored)? else { continue };
+            let mut row: serde_json::Value = serde_json::from_slice(&data)?;
+            let positions = row::schema_columns(&schema);
+            let mut changed = false;
+            for column in columns.into_iter().filter(|c| !not_null.iter().any(|n| n == c)) {
+                let slot = match &mut row {
+                    serde_json::Value::Array(values) => positions.iter()
+                        .position(|c| c == column)
+                        .and_then(|i| values.get_mut(i)),
+                    serde_json::Value::Object(fields) => fields.get_mut(column),
+                    _ => None,
+                };
+                if let Some(slot) = slot {
+                    *slot = serde_json::Value::Null;
+                    changed = true;
+                }
+            }
+            if changed {
+                changes.push(Change::Update {
+                    table: table.to_string(),
+                    id: id.to_string(),
+                    value: bincode::serialize(&CrdtValue::Register(serde_json::to_vec(&row)?))?,
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    // Rows of a table, metadata keys left out, each with its decoded value or why it didn't decode.
+    fn stored_rows(&self, table: &str, commit: Option<&[u8; 32]>) -> Result<Vec<(String, std::result::Result<CrdtValue, String>)>> {
+        let mut rows = Vec::new();
+        if let Some(hash) = commit {
+            for item in self.iter_table(table, hash)? {
+                let (id, value) = item?;
+                if !id.starts_with('!') {
+                    rows.push((id, Ok(value)));
+                }
+            }
+            return Ok(rows);
+        }
+
+        let prefix = table_key_prefix(table);
+        for item in self.db.prefix_iterator(prefix.as_bytes()) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let Some((_, id)) = decode_row_key(&key) else { continue };
+            if !id.starts_with('!') {
+                rows.push((id, bincode::deserialize(&value).map_err(|e| e.to_string())));
+            }
+        }
+        Ok(rows)
+    }
+}
+
+// Counters aren't rows and are never checked against the columns. A value missing from a
+// positional row, as after ADD COLUMN, reads as NULL.
+fn check_row(value: std::result::Result<CrdtValue, String>, schema: &serde_json::Value) -> Vec<DataProblem> {
+    let data = match value {
+        Err(error) => return vec![DataProblem::Undecodable { error }],
+        Ok(CrdtValue::Counter(_)) => return Vec::new(),
+        Ok(CrdtValue::Register(data)) => data,
+    };
+    let json: serde_json::Value = match serde_json::from_slice(&data) {
+        Ok(json) => json,
+        Err(e) => return vec![DataProblem::InvalidJson { error: e.to_string() }],
+    };
+    let Some(types) = schema.get("columns").and_then(|c| c.as_object()).filter(|c| !c.is_empty()) else {
+        return Vec::new();
+    };
+    if !json.is_array() && !json.is_object() {
+        return vec![DataProblem::NotARow];
+    }
+
+    let object = row::row_to_object(json, schema);
+    let Some(fields) = object.as_object() else { return vec![DataProblem::NotARow] };
+    let not_null = not_null_columns(schema);
+    let mut problems: Vec<DataProblem> = fields.keys()
+        .filter(|field| !types.contains_key(field.as_str()))
+        .map(|field| DataProblem::UnknownColumn { column: field.clone() })
+        .collect();
+    for (column, expected) in types {
+        let expected = expected.as_str().unwrap_or_default();
+        match fields.get(column).unwrap_or(&serde_json::Value::Null) {
+            serde_json::Value::Null if not_null.contains(column) => {
+                problems.push(DataProblem::NullInNotNull { column: column.clone() });
+            }
+            value if TypeCoercer::coerce(value, expected).is_err() => {
+                problems.push(DataProblem::TypeMismatch {
+                    column: column.clone(),
+                    expected: expected.to_string(),
+                    value: value.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+    problems
+}
+
+fn not_null_columns(schema: &serde_json::Value) -> Vec<String> {
+    schema.get("not_null")
+        .and_then(|n| n.as_array())
+        .map(|columns| columns.iter().filter_map(|c| c.as_str().map(str::to_string)).collect())
+        .unwrap_or_default()
+}
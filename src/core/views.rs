@@ -0,0 +1,241 @@
+use crate::core::crdt::CrdtValue;
+use crate::core::database::CommitStorage;
+use crate::core::models::Change;
+use crate::core::query::{split_commit_suffix, QueryProcessor};
+use crate::error::{BranchDBError, Result};
+use sqlparser::ast::{Query, SetExpr, Statement, TableFactor};
+use sqlparser::dialect::GenericDialect;
+use sqlparser::parser::Parser;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use tracing::info;
+
+// `view:<name>` holds the SELECT a materialized view is defined by. Its rows are an ordinary
+// table of the same name, so they are queried, diffed and versioned like any other table.
+const VIEW_PREFIX: &str = "view:";
+
+// `CREATE MATERIALIZED VIEW <name> AS <select>`: the name and the SELECT.
+pub(crate) fn parse_create_view(command: &str) -> Option<Result<(String, String)>> {
+    let rest = strip_keywords(command, &["CREATE", "MATERIALIZED", "VIEW"])?;
+    let (name, query) = rest.split_once(char::is_whitespace).unzip();
+    let query = query.and_then(|q| strip_keywords(q, &["AS"]));
+    Some(match (name, query) {
+        (Some(name), Some(query)) if !query.is_empty() => Ok((name.to_string(), query.to_string())),
+        _ => Err(BranchDBError::InvalidInput("Expected CREATE MATERIALIZED VIEW <name> AS SELECT ...".into())),
+    })
+}
+
+// `REFRESH MATERIALIZED VIEW <name>`: the name.
+pub(crate) fn parse_refresh_view(command: &str) -> Option<String> {
+    let name = strip_keywords(command, &["REFRESH", "MATERIALIZED", "VIEW"])?;
+    Some(name.trim_end_matches(';').trim().to_string())
+}
+
+pub(crate) fn strip_keywords<'a>(command: &'a str, keywords: &[&str]) -> Option<&'a str> {
+    let mut rest = command.trim();
+    for keyword in keywords {
+        let (word, tail) = rest.split_once(char::is_whitespace)?;
+        if !word.eq_ignore_ascii_case(keyword) {
+            return None;
+        }
+        rest = tail.trim_start();
+    }
+    Some(rest)
+}
+
+impl CommitStorage {
+    // Stores the view's definition and materializes it. The name may not already be a table.
+    // Returns the commit holding the view's first rows, if there were any.
+    pub fn create_view(&self, name: &str, query: &str) -> Result<Option<[u8; 32]>> {
+        let key = format!("{}{}", VIEW_PREFIX, name);
+        if self.db.get(key.as_bytes())?.is_some() || self.list_tables()?.iter().any(|t| t == name) {
+            return Err(BranchDBError::InvalidInput(format!("'{}' already exists", name)));
+        }
+        if source_tables(query)?.contains(name) {
+            return Err(BranchDBError::InvalidInput(format!("View '{}' can't select from itself", name)));
+        }
+        // Materialized first, so a query that fails leaves no definition behind
+        let hash = self.refresh(name, query)?;
+        self.db.put(key.as_bytes(), query.as_bytes())?;
+        Ok(hash)
+    }
+
+    // Re-runs a view's query against HEAD. Returns the commit with the changed rows, or None
+    // if the view was already up to date.
+    pub fn refresh_view(&self, name: &str) -> Result<Option<[u8; 32]>> {
+        let query = self.views()?.remove(name)
+            .ok_or_else(|| BranchDBError::InvalidInput(format!("No materialized view named '{}'", name)))?;
+        self.refresh(name, &query)
+    }
+
+    // Refreshes the views reading a table changed by the HEAD commit, views that read other
+    // views after those. Called after every commit when `incremental_view_maintenance` is on.
+    // Returns the number of views whose rows changed.
+    pub fn maintain_views(&self) -> Result<usize> {
+        let Some(head) = self.get_head()? else { return Ok(0) };
+        let views = self.views()?;
+        if views.is_empty() {
+            return Ok(0);
+        }
+
+        let mut changed: HashSet<String> = self.get_commit_by_hash(&head)?.changes.iter()
+            .map(|c| c.table().to_string())
+            .collect();
+        let mut refreshed = 0;
+        for (name, sources) in dependency_order(&views)? {
+            if sources.iter().any(|table| changed.contains(table)) && self.refresh(&name, &views[&name])?.is_some() {
+                changed.insert(name);
+                refreshed += 1;
+            }
+        }
+        Ok(refreshed)
+    }
+
+    fn views(&self) -> Result<BTreeMap<String, String>> {
+        let mut views = BTreeMap::new();
+        for item in self.db.prefix_iterator(VIEW_PREFIX) {
+            let (key, value) = item?;
+            let Some(name) = key.strip_prefix(VIEW_PREFIX.as_bytes()) else { break };
+            views.insert(String::from_utf8_lossy(name).into_owned(), String::from_utf8_lossy(&value).into_owned());
+        }
+        Ok(views)
+    }
+
+    // Rows are keyed by their `id` column when the result has one with distinct values, and
+    // by position otherwise.
+    fn refresh(&self, name: &str, query: &str) -> Result<Option<[u8; 32]>> {
+        let result = QueryProcessor::new(&self.db).query(query)?;
+        let id_column = result.columns.iter().position(|c| c == "id");
+        let ids: Vec<String> = match id_column {
+            Some(i) => result.rows.iter().map(|row| match &row[i] {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            }).collect(),
+            None => Vec::new(),
+        };
+        let keyed_by_id = id_column.is_some() && ids.iter().collect::<HashSet<_>>().len() == ids.len();
+
+        let mut rows = BTreeMap::new();
+        for (position, values) in result.rows.iter().enumerate() {
+            let id = if keyed_by_id { ids[position].clone() } else { (position + 1).to_string() };
+            let object: serde_json::Map<String, serde_json::Value> = result.columns.iter().cloned()
+                .zip(values.iter().cloned())
+                .collect();
+            rows.insert(id, CrdtValue::Register(serde_json::to_vec(&object)?));
+        }
+
+        let current = match self.get_head()? {
+            Some(head) => self.table_state_at(name, &head)?,
+            None => Default::default(),
+        };
+        let mut changes: Vec<Change> = current.keys()
+            .filter(|id| !id.starts_with('!') && !rows.contains_key(*id))
+            .map(|id| Change::Delete { table: name.to_string(), id: id.clone() })
+            .collect();
+        for (id, value) in rows {
+            let serialized = bincode::serialize(&value)?;
+            match current.get(&id) {
+                Some(existing) if *existing == value => {}
+                Some(_) => changes.push(Change::Update { table: name.to_string(), id, value: serialized }),
+                None => changes.push(Change::Insert { table: name.to_string(), id, value: serialized }),
+            }
+        }
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        info!(view = name, changes = changes.len(), "refreshing materialized view");
+        self.commit_without_view_maintenance(&format!("Refresh materialized view {}", name), changes).map(Some)
+    }
+}
+
+// Every table a SELECT reads, including those of derived tables and joins.
+fn source_tables(query: &str) -> Result<BTreeSet<String>> {
+    let (query, _) = split_commit_suffix(query);
+    let ast = Parser::parse_sql(&GenericDialect, query)
+        .map_err(|e| BranchDBError::InvalidInput(format!("SQL parse error: {}", e)))?;
+    let [Statement::Query(query)] = ast.as_slice() else {
+        return Err(BranchDBError::InvalidInput("A materialized view must be defined by a SELECT".into()));
+    };
+    let mut tables = BTreeSet::new();
+    collect_tables(query, &mut tables);
+    Ok(tables)
+}
+
+fn collect_tables(query: &Query, tables: &mut BTreeSet<String>) {
+    let SetExpr::Select(select) = &*query.body else { return };
+    for from in &select.from {
+        for factor in std::iter::once(&from.relation).chain(from.joins.iter().map(|j| &j.relation)) {
+            match factor {
+                TableFactor::Table { name, .. } => {
+                    tables.insert(name.to_string());
+                }
+                TableFactor::Derived { subquery, .. } => collect_tables(subquery, tables),
+                _ => {}
+            }
+        }
+    }
+}
+
+// Views with the tables each reads, ordered so that a view comes after every view it reads.
+fn dependency_order(views: &BTreeMap<String, String>) -> Result<Vec<(String, BTreeSet<String>)>> {
+    let mut pending: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (name, query) in views {
+        pending.insert(name.clone(), source_tables(query)?);
+    }
+    let mut ordered = Vec::new();
+    while !pending.is_empty() {
+        let ready: Vec<String> = pending.iter()
+            .filter(|(_, sources)| !sources.iter().any(|s| pending.contains_key(s)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        if ready.is_empty() {
+            let names: Vec<&str> = pending.keys().map(String::as_str).collect();
+            return Err(BranchDBError::InvalidInput(format!("Materialized views read each other in a cycle: {}", names.join(", "))));
+        }
+        for name in ready {
+            let sources = pending.remove(&name).expect("ready views are pending");
+            ordered.push((name, sources));
+        }
+    }
+    Ok(ordered)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::database::{CommitStorage, StorageOptions};
+    use crate::core::test_support::{data_path, rows, run};
+    use serde_json::json;
+
+    fn open(dir: &tempfile::TempDir, incremental_view_maintenance: bool) -> CommitStorage {
+        let options = StorageOptions { incremental_view_maintenance, ..StorageOptions::default() };
+        CommitStorage::open_with_options(&data_path(dir), options).unwrap()
+    }
+
+    #[test]
+    fn views_follow_their_tables_only_with_incremental_maintenance() {
+        for maintained in [true, false] {
+            let dir = tempfile::tempdir().unwrap();
+            let storage = open(&dir, maintained);
+            run(&storage, "CREATE TABLE users (id TEXT, name TEXT, team TEXT)");
+            run(&storage, "INSERT INTO users VALUES ('1', 'ann', 'a')");
+            run(&storage, "CREATE MATERIALIZED VIEW team_a AS SELECT id, name FROM users WHERE team = 'a'");
+            // Reads the view above, so it is refreshed after it
+            run(&storage, "CREATE MATERIALIZED VIEW team_a_names AS SELECT name FROM team_a");
+
+            run(&storage, "INSERT INTO users VALUES ('2', 'bob', 'a')");
+            run(&storage, "INSERT INTO users VALUES ('3', 'cy', 'b')");
+            let names = |view: &str| rows(&storage, &format!("SELECT name FROM {} ORDER BY name", view));
+            let both = vec![vec![json!("ann")], vec![json!("bob")]];
+            if maintained {
+                assert_eq!(names("team_a"), both);
+                assert_eq!(names("team_a_names"), both);
+            } else {
+                assert_eq!(names("team_a"), vec![vec![json!("ann")]]);
+                // What a commit would have done with maintenance on
+                assert_eq!(storage.maintain_views().unwrap(), 2);
+                assert_eq!(names("team_a_names"), both);
+            }
+            // Nothing left to refresh
+            assert_eq!(storage.maintain_views().unwrap(), 0);
+        }
+    }
+}
@@ -1,79 +1,129 @@
-use std::fmt;
 use std::time::SystemTimeError;
-use serde::Serialize;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use thiserror::Error;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Error)]
 pub enum BranchDBError {
-    StorageError(String),          // Changed from rocksdb::Error
+    #[error("Storage error: {0}")]
+    StorageError(#[from] rocksdb::Error),
+    #[error("Invalid input: {0}")]
     InvalidInput(String),
+    #[error("Commit has no parent")]
     OrphanCommit,
+    #[error("Type mismatch: {0}")]
     TypeMismatch(String),
-    SerializationError(String),    // Changed from Box<bincode::ErrorKind>
-    CsvError(String),             // Changed from csv::Error
-    HexError(String),             // Changed from hex::FromHexError
-    IoError(String),
-    JsonError(String),            // Changed from serde_json::Error
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] bincode::Error),
+    #[error("CSV error: {0}")]
+    CsvError(#[from] csv::Error),
+    #[error("Hex conversion error: {0}")]
+    HexError(#[from] hex::FromHexError),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("System clock error: {0}")]
+    ClockError(#[from] SystemTimeError),
+    #[error("Data corruption detected: {0}")]
     CorruptData(String),
+    #[error("Commit {hash} not found")]
+    CommitNotFound { hash: String },
+    #[error("Branch '{name}' not found")]
+    BranchNotFound { name: String },
+    #[error("Table '{name}' not found")]
+    TableNotFound { name: String },
+    #[error("Row '{id}' not found in table '{table}'")]
+    RowNotFound { table: String, id: String },
+    #[error("Constraint violation: {0}")]
+    ConstraintViolation(String),
+    #[error("Concurrent modification: {0}")]
+    ConcurrentModification(String),
+    #[error("Nothing to commit")]
+    NothingToCommit,
+    #[error("Aborted: {0}")]
+    Aborted(String),
+    #[error("Would discard work: {0}")]
+    WouldDiscardWork(String),
+    #[error("Repository format version {found} is newer than this build supports (version {supported})")]
+    FormatTooNew { found: u32, supported: u32 },
+    #[error("Repository format version {found} is older than the current version {current}")]
+    MigrationRequired { found: u32, current: u32 },
+    #[error("Another BranchDB process is using this repository (pid {pid})")]
+    RepositoryLocked { pid: u32 },
+    #[error("git {command} failed: {stderr}")]
+    GitCommand { command: String, stderr: String },
+    // No status when the server couldn't be reached at all
+    #[error("HTTP request to {url} failed ({}): {message}", .status.map_or("no response".to_string(), |status| format!("status {}", status)))]
+    HttpRequest { url: String, status: Option<u16>, message: String },
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
 }
 
 pub type Result<T, E = BranchDBError> = std::result::Result<T, E>;
 
-impl fmt::Display for BranchDBError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+#[deprecated(note = "renamed to BranchDBError")]
+pub type GitDBError = BranchDBError;
+
+impl BranchDBError {
+    // Stable identifier for scripts and JSON output. Never change an existing code.
+    pub fn code(&self) -> &'static str {
         match self {
-            BranchDBError::StorageError(e) => write!(f, "Storage error: {}", e),
-            BranchDBError::InvalidInput(s) => write!(f, "Invalid input: {}", s),
-            BranchDBError::OrphanCommit => write!(f, "Commit has no parent"),
-            BranchDBError::TypeMismatch(s) => write!(f, "Type mismatch: {}", s),
-            BranchDBError::SerializationError(e) => write!(f, "Serialization error: {}", e),
-            BranchDBError::CsvError(e) => write!(f, "CSV error: {}", e),
-            BranchDBError::HexError(e) => write!(f, "Hex conversion error: {}", e),
-            BranchDBError::IoError(s) => write!(f, "IO error: {}", s),
-            BranchDBError::JsonError(e) => write!(f, "JSON error: {}", e),
-            BranchDBError::CorruptData(s) => write!(f, "Data corruption detected: {}", s),
+            BranchDBError::StorageError(_) => "storage_error",
+            BranchDBError::InvalidInput(_) => "invalid_input",
+            BranchDBError::OrphanCommit => "orphan_commit",
+            BranchDBError::TypeMismatch(_) => "type_mismatch",
+            BranchDBError::SerializationError(_) => "serialization_error",
+            BranchDBError::CsvError(_) => "csv_error",
+            BranchDBError::HexError(_) => "hex_error",
+            BranchDBError::IoError(_) => "io_error",
+            BranchDBError::JsonError(_) => "json_error",
+            BranchDBError::ClockError(_) => "clock_error",
+            BranchDBError::CorruptData(_) => "corrupt_data",
+            BranchDBError::CommitNotFound { .. } => "commit_not_found",
+            BranchDBError::BranchNotFound { .. } => "branch_not_found",
+            BranchDBError::TableNotFound { .. } => "table_not_found",
+            BranchDBError::RowNotFound { .. } => "row_not_found",
+            BranchDBError::ConstraintViolation(_) => "constraint_violation",
+            BranchDBError::ConcurrentModification(_) => "concurrent_modification",
+            BranchDBError::NothingToCommit => "nothing_to_commit",
+            BranchDBError::Aborted(_) => "aborted",
+            BranchDBError::WouldDiscardWork(_) => "would_discard_work",
+            BranchDBError::FormatTooNew { .. } => "format_too_new",
+            BranchDBError::MigrationRequired { .. } => "migration_required",
+            BranchDBError::RepositoryLocked { .. } => "repository_locked",
+            BranchDBError::GitCommand { .. } => "git_command_failed",
+            BranchDBError::HttpRequest { .. } => "http_request_failed",
+            BranchDBError::EncryptionError(_) => "encryption_error",
         }
     }
-}
-
-// Conversion implementations
-impl From<rocksdb::Error> for BranchDBError {
-    fn from(err: rocksdb::Error) -> Self {
-        BranchDBError::StorageError(err.to_string())
-    }
-}
-
-impl From<Box<bincode::ErrorKind>> for BranchDBError {
-    fn from(err: Box<bincode::ErrorKind>) -> Self {
-        BranchDBError::SerializationError(err.to_string())
-    }
-}
-
-impl From<csv::Error> for BranchDBError {
-    fn from(err: csv::Error) -> Self {
-        BranchDBError::CsvError(err.to_string())
-    }
-}
-
-impl From<hex::FromHexError> for BranchDBError {
-    fn from(err: hex::FromHexError) -> Self {
-        BranchDBError::HexError(err.to_string())
-    }
-}
 
-impl From<std::io::Error> for BranchDBError {
-    fn from(err: std::io::Error) -> Self {
-        BranchDBError::IoError(err.to_string())
+    // Suggested next step for the user, shown by the CLI below the error message.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            BranchDBError::CommitNotFound { .. } => Some("run `log` to list the commits reachable from HEAD"),
+            BranchDBError::BranchNotFound { .. } => Some("run `branch-list` to see existing branches"),
+            BranchDBError::TableNotFound { .. } => Some("create the table first with `sql \"CREATE TABLE ...\"`"),
+            BranchDBError::RowNotFound { .. } => Some("use `show-table` to list the rows of the table"),
+            BranchDBError::ConcurrentModification(_) => Some("another commit landed first; retry the operation"),
+            BranchDBError::NothingToCommit => Some("make a change before committing"),
+            BranchDBError::HexError(_) => Some("commit hashes are 64 hexadecimal characters"),
+            BranchDBError::Aborted(_) => Some("pass --yes to skip the confirmation"),
+            BranchDBError::WouldDiscardWork(_) => Some("pass --force to discard it"),
+            BranchDBError::FormatTooNew { .. } => Some("upgrade branchdb to open this repository"),
+            BranchDBError::MigrationRequired { .. } => Some("run `branchdb migrate` to upgrade it in place"),
+            BranchDBError::RepositoryLocked { .. } => Some("wait for it to finish, or raise core.lock-timeout (seconds)"),
+            BranchDBError::HttpRequest { status: Some(401 | 403), .. } => Some("pass credentials with --header 'Authorization: ...'"),
+            BranchDBError::EncryptionError(_) => Some("set BRANCHDB_KEY, or point core.key-file at the file holding the key"),
+            _ => None,
+        }
     }
 }
 
-impl From<serde_json::Error> for BranchDBError {
-    fn from(err: serde_json::Error) -> Self {
-        BranchDBError::JsonError(err.to_string())
+impl Serialize for BranchDBError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("BranchDBError", 2)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
     }
 }
-
-impl From<SystemTimeError> for BranchDBError {
-    fn from(err: SystemTimeError) -> Self {
-        BranchDBError::IoError(err.to_string())
-    }
-}
\ No newline at end of file
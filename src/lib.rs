@@ -1,3 +1,16 @@
 pub mod error;
+pub mod metrics;
 pub mod core;
-pub mod cli;
\ No newline at end of file
+pub mod cli;
+
+pub use crate::core::branch::BranchManager;
+pub use crate::core::crdt::CrdtValue;
+pub use crate::core::database::CommitStorage;
+pub use crate::core::models::{Change, Commit};
+pub use crate::core::query::QueryProcessor;
+pub use crate::core::row::Row;
+pub use crate::core::transaction::Transaction;
+pub use crate::error::{BranchDBError, Result};
+
+#[allow(deprecated)]
+pub use crate::error::GitDBError;
@@ -1,51 +1,50 @@
 use clap::Parser;
-use gitdb::cli::commands::{self, CommandsWrapper, Commands};
-use gitdb::core::database::CommitStorage;
-use gitdb::core::branch::BranchManager;
-use gitdb::error::BranchDBError;
-use std::fs;
-use std::path::Path;
+use branchdb::cli::commands::{CommandContext, CommandsWrapper, DATA_DIR};
+use branchdb::cli::logging::{self, LogFormat};
+use branchdb::cli::output::Output;
+use branchdb::cli::pager;
+use branchdb::cli::suggest;
 
-fn ensure_data_dir() -> Result<(), BranchDBError> {
-    if !Path::new("./data").exists() {
-        fs::create_dir("./data").map_err(|e| BranchDBError::InvalidInput(format!("Failed to create data dir: {}", e)))?;
-    }
-    Ok(())
-}
-
-fn run() -> Result<(), BranchDBError> {
-    ensure_data_dir()?;
-    let args = CommandsWrapper::parse().command;
-    
-    // Open storage
-    let storage = CommitStorage::open("./data")?;
-    
-    // Create branch manager with shared DB
-    let branch_mgr = BranchManager::new(storage.db.clone());
+fn main() {
+    let cli = CommandsWrapper::parse();
+    logging::init(cli.verbosity, cli.quiet, cli.log_format);
 
-    match args {
-        Commands::Init { path } => commands::handle_init(&path),
-        Commands::Commit { message } => commands::handle_commit(&storage, &message),
-        Commands::Branch { name, delete } => commands::handle_branch(&branch_mgr, &name, delete),
-        Commands::Query { sql } => commands::handle_query(&sql, &storage.db),
-        Commands::Sql { command } => commands::handle_sql(&storage, &command),
-        Commands::ImportCsv { file, table } => commands::handle_import_csv(&storage, &file, &table),
-        Commands::ShowTable { table_name, commit_hash } => {
-            commands::handle_show_table(&*storage.db, &table_name, commit_hash.as_deref())
+    let mut options = cli.command.storage_options();
+    options.commit_context.source = cli.source.clone();
+    options.namespace = cli.namespace.clone().unwrap_or_default();
+    let ctx = CommandContext::new(DATA_DIR, options, cli.yes);
+    let mut out = Output::new(cli.json);
+    if cli.command.pages() && !cli.no_pager {
+        if let Some(pager) = pager::command(ctx.data_dir()) {
+            out.start_pager(&pager);
         }
-        Commands::Checkout { target } => commands::handle_checkout(&storage, &target),
-        Commands::Log { verbose } => commands::handle_log(&storage, verbose),
-        Commands::Revert { commit_hash } => commands::handle_revert(&storage, &commit_hash),
-        Commands::Diff { from, to } => commands::handle_diff(&storage, &from, &to),
-        Commands::History { limit } => commands::handle_history(&storage, limit),
-        Commands::BranchList { verbose } => commands::handle_branch_list(&branch_mgr, verbose),
-        Commands::Merge { branch } => commands::handle_merge(&storage, &branch),
     }
-}
-
-fn main() {
-    if let Err(e) = run() {
-        eprintln!("Error: {e}");
+    let result = match cli.command.run(&ctx, &mut out) {
+        Ok(()) => out.finish(),
+        Err(e) => out.finish_failed().and(Err(e)),
+    };
+    if let Err(e) = result {
+        // JSON consumers get the failure as an event; humans get the message and hint
+        let suggestions = ctx.suggestions(&e);
+        if cli.json {
+            let mut event = serde_json::json!({ "error": e });
+            if !suggestions.is_empty() {
+                event["suggestions"] = serde_json::json!(suggestions);
+            }
+            eprintln!("{}", event);
+        } else if cli.log_format == LogFormat::Json {
+            tracing::error!(code = e.code(), hint = e.hint(), ?suggestions, "{e}");
+        } else {
+            match suggest::did_you_mean(&suggestions) {
+                Some(did_you_mean) => eprintln!("Error: {e}; {did_you_mean}"),
+                None => eprintln!("Error: {e}"),
+            }
+            if let Some(hint) = e.hint() {
+                eprintln!("hint: {hint}");
+            }
+        }
+        // exit() skips destructors; the storage has to go first to release the repository lock
+        drop(ctx);
         std::process::exit(1);
     }
 }
\ No newline at end of file
@@ -0,0 +1,301 @@
+use once_cell::sync::{Lazy, OnceCell};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Metrics are always tallied in-process (for `stats --internal`) and forwarded to an optional
+// user-installed sink. The default sink does nothing.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    CommitsCreated,
+    RowsRead,
+    RowsWritten,
+    RowsImported,
+    MergeConflicts,
+    StateCacheHits,
+    StateCacheMisses,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 7] = [
+        Counter::CommitsCreated,
+        Counter::RowsRead,
+        Counter::RowsWritten,
+        Counter::RowsImported,
+        Counter::MergeConflicts,
+        Counter::StateCacheHits,
+        Counter::StateCacheMisses,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Counter::CommitsCreated => "commits_created",
+            Counter::RowsRead => "rows_read",
+            Counter::RowsWritten => "rows_written",
+            Counter::RowsImported => "rows_imported",
+            Counter::MergeConflicts => "merge_conflicts",
+            Counter::StateCacheHits => "state_cache_hits",
+            Counter::StateCacheMisses => "state_cache_misses",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Histogram {
+    CommitLatencySeconds,
+    HistoryWalkDepth,
+}
+
+impl Histogram {
+    pub const ALL: [Histogram; 2] = [Histogram::CommitLatencySeconds, Histogram::HistoryWalkDepth];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Histogram::CommitLatencySeconds => "commit_latency_seconds",
+            Histogram::HistoryWalkDepth => "history_walk_depth",
+        }
+    }
+
+    pub fn buckets(&self) -> &'static [f64] {
+        match self {
+            Histogram::CommitLatencySeconds => &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0],
+            Histogram::HistoryWalkDepth => &[1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0],
+        }
+    }
+}
+
+pub trait MetricsSink: Send + Sync {
+    fn increment(&self, _counter: Counter, _by: u64) {}
+
+    fn observe(&self, _histogram: Histogram, _value: f64) {}
+}
+
+pub struct NoopSink;
+
+impl MetricsSink for NoopSink {}
+
+static SINK: OnceCell<Box<dyn MetricsSink>> = OnceCell::new();
+
+// Installs the process-wide sink. Only the first call takes effect; returns false afterwards.
+pub fn set_sink(sink: Box<dyn MetricsSink>) -> bool {
+    SINK.set(sink).is_ok()
+}
+
+struct HistogramState {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_bits: AtomicU64,
+}
+
+impl HistogramState {
+    fn new(histogram: Histogram) -> Self {
+        Self {
+            buckets: histogram.buckets().iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_bits: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+}
+
+struct Registry {
+    counters: Vec<AtomicU64>,
+    histograms: Vec<HistogramState>,
+}
+
+static REGISTRY: Lazy<Registry> = Lazy::new(|| Registry {
+    counters: Counter::ALL.iter().map(|_| AtomicU64::new(0)).collect(),
+    histograms: Histogram::ALL.iter().map(|h| HistogramState::new(*h)).collect(),
+});
+
+pub fn increment(counter: Counter, by: u64) {
+    REGISTRY.counters[counter as usize].fetch_add(by, Ordering::Relaxed);
+    if let Some(sink) = SINK.get() {
+        sink.increment(counter, by);
+    }
+}
+
+pub fn observe(histogram: Histogram, value: f64) {
+    let state = &REGISTRY.histograms[histogram as usize];
+    for (bound, bucket) in histogram.buckets().iter().zip(&state.buckets) {
+        if value <= *bound {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    state.count.fetch_add(1, Ordering::Relaxed);
+    let _ = state.sum_bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+        Some((f64::from_bits(bits) + value).to_bits())
+    });
+    if let Some(sink) = SINK.get() {
+        sink.observe(histogram, value);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistogramSnapshot {
+    pub histogram: Histogram,
+    // Cumulative counts for each upper bound in `Histogram::buckets`
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsSnapshot {
+    pub counters: Vec<(Counter, u64)>,
+    pub histograms: Vec<HistogramSnapshot>,
+}
+
+pub fn snapshot() -> MetricsSnapshot {
+    let counters = Counter::ALL.iter()
+        .map(|c| (*c, REGISTRY.counters[*c as usize].load(Ordering::Relaxed)))
+        .collect();
+    let histograms = Histogram::ALL.iter()
+        .map(|h| {
+            let state = &REGISTRY.histograms[*h as usize];
+            HistogramSnapshot {
+                histogram: *h,
+                buckets: h.buckets().iter()
+                    .zip(&state.buckets)
+                    .map(|(bound, count)| (*bound, count.load(Ordering::Relaxed)))
+                    .collect(),
+                count: state.count.load(Ordering::Relaxed),
+                sum: f64::from_bits(state.sum_bits.load(Ordering::Relaxed)),
+            }
+        })
+        .collect();
+    MetricsSnapshot { counters, histograms }
+}
+
+// Renders the current snapshot in the Prometheus text exposition format.
+#[cfg(feature = "prometheus")]
+pub fn render_prometheus() -> String {
+    use std::fmt::Write;
+
+    let snapshot = snapshot();
+    let mut out = String::new();
+    for (counter, value) in &snapshot.counters {
+        let name = format!("branchdb_{}_total", counter.name());
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    for h in &snapshot.histograms {
+        let name = format!("branchdb_{}", h.histogram.name());
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in &h.buckets {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, h.count);
+        let _ = writeln!(out, "{}_sum {}", name, h.sum);
+        let _ = writeln!(out, "{}_count {}", name, h.count);
+    }
+    out
+}
+
+// Serves `render_prometheus` at `GET /metrics` from a background thread for as long as the
+// process runs; any other request gets a 404. Returns the address bound, which tells the
+// caller the port when `addr` asked for port 0.
+#[cfg(feature = "prometheus")]
+pub fn serve_prometheus(addr: &str) -> std::io::Result<std::net::SocketAddr> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+        // A client that stops sending mustn't hold up the next scrape for long
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(&stream);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // The headers are read and ignored, so that closing the connection doesn't reset it
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+            header.clear();
+        }
+        let target = request.split_whitespace().take(2).collect::<Vec<_>>();
+        let (status, body) = match target[..] {
+            ["GET", path] if path == "/metrics" || path.starts_with("/metrics?") => ("200 OK", render_prometheus()),
+            _ => ("404 Not Found", "Only /metrics is served\n".to_string()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    let listener = TcpListener::bind(addr)?;
+    let bound = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream);
+        }
+    });
+    Ok(bound)
+}
+
+#[cfg(all(test, feature = "prometheus"))]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept: text/plain\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn prometheus_text_declares_every_family_before_its_samples() {
+        increment(Counter::MergeConflicts, 2);
+        observe(Histogram::CommitLatencySeconds, 0.02);
+        let text = render_prometheus();
+        assert!(text.ends_with('\n'));
+
+        let sample = Regex::new(r#"^(branchdb_[a-z_]+?)(_total|_bucket\{le="([0-9.]+|\+Inf)"\}|_sum|_count) [0-9.e+-]+$"#).unwrap();
+        let mut declared = Vec::new();
+        for line in text.lines() {
+            if let Some(declaration) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = declaration.split_once(' ').unwrap();
+                assert!(kind == "counter" || kind == "histogram", "{}", line);
+                declared.push(name.to_string());
+                continue;
+            }
+            let captures = sample.captures(line).unwrap_or_else(|| panic!("not a sample: {}", line));
+            let family = match &captures[2] {
+                "_total" => format!("{}_total", &captures[1]),
+                _ => captures[1].to_string(),
+            };
+            assert_eq!(declared.last(), Some(&family), "{} is outside its family", line);
+        }
+        let families: Vec<_> = Counter::ALL.iter().map(|c| format!("branchdb_{}_total", c.name()))
+            .chain(Histogram::ALL.iter().map(|h| format!("branchdb_{}", h.name())))
+            .collect();
+        assert_eq!(declared, families);
+
+        let conflicts = text.lines().find_map(|line| line.strip_prefix("branchdb_merge_conflicts_total ")).unwrap();
+        assert!(conflicts.parse::<u64>().unwrap() >= 2);
+        let buckets: Vec<_> = text.lines().filter(|line| line.starts_with("branchdb_commit_latency_seconds_bucket")).collect();
+        assert_eq!(buckets.len(), Histogram::CommitLatencySeconds.buckets().len() + 1);
+        assert!(buckets[0].starts_with(r#"branchdb_commit_latency_seconds_bucket{le="0.001"} "#));
+        assert!(buckets.last().unwrap().starts_with(r#"branchdb_commit_latency_seconds_bucket{le="+Inf"} "#));
+    }
+
+    #[test]
+    fn the_endpoint_serves_metrics_and_nothing_else() {
+        let addr = serve_prometheus("127.0.0.1:0").unwrap();
+        let response = get(addr, "/metrics");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert!(body.contains("# TYPE branchdb_commits_created_total counter\n"));
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get(addr, "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
@@ -0,0 +1,54 @@
+// Its own test binary, so the allocator below only counts what this test allocates.
+use branchdb::{Change, CommitStorage, CrdtValue};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Tracking;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Tracking {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(current, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Tracking = Tracking;
+
+const ROWS: usize = 10_000;
+
+#[test]
+fn iterating_a_large_table_at_head_stays_under_a_megabyte() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = CommitStorage::open(dir.path().to_str().unwrap()).unwrap();
+    // About 2 MB of rows in all, so holding the table in memory would fail the test
+    let changes = (0..ROWS)
+        .map(|i| Change::Insert {
+            table: "events".to_string(),
+            id: format!("{:05}", i),
+            value: bincode::serialize(&CrdtValue::Register(format!("{{\"payload\":\"{:0200}\"}}", i).into_bytes())).unwrap(),
+        })
+        .collect();
+    let head = storage.create_commit("Add events", changes).unwrap();
+
+    let baseline = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(baseline, Ordering::SeqCst);
+    let mut count = 0;
+    for row in storage.iter_table("events", &head).unwrap() {
+        row.unwrap();
+        count += 1;
+    }
+    let peak = PEAK.load(Ordering::SeqCst) - baseline;
+
+    assert_eq!(count, ROWS);
+    assert!(peak < 1024 * 1024, "iterating used {} bytes at its peak", peak);
+}